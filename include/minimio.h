@@ -0,0 +1,58 @@
+/* C bindings for minimio. Build the library with:
+ *
+ *     cargo rustc --release --features capi --crate-type cdylib
+ *
+ * which leaves it in target/release as libminimio.so, libminimio.dylib or
+ * minimio.dll. The crate isn't a cdylib by default, since crates depending on it
+ * would all build a shared library nobody uses.
+ *
+ * Functions returning int return 0 (or a count) on success and -errno on failure,
+ * or -1 if the error didn't come from the OS.
+ */
+#ifndef MINIMIO_H
+#define MINIMIO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MINIMIO_READABLE 0x02
+#define MINIMIO_WRITABLE 0x01
+
+typedef struct MinimioSelector MinimioSelector;
+
+typedef struct {
+    uintptr_t token;
+} MinimioEvent;
+
+/* Caller owned. minimio_select writes at most `capacity` events and sets `len`. */
+typedef struct {
+    MinimioEvent *events;
+    size_t capacity;
+    size_t len;
+} MinimioEvents;
+
+/* Returns NULL if the OS event queue couldn't be created. */
+MinimioSelector *minimio_selector_new(void);
+void minimio_selector_free(MinimioSelector *selector);
+
+#ifndef _WIN32
+/* Registrations are oneshot, register again to be notified again. */
+int minimio_register_fd(MinimioSelector *selector, int fd, uintptr_t token, uint8_t interests);
+#endif
+
+/* A negative timeout blocks until there is an event. */
+int minimio_select(MinimioSelector *selector, MinimioEvents *events, int timeout_ms);
+
+/* Wakes up and closes a selector, minimio_select returns an error from then on.
+ * Can be called from another thread while one is blocked in minimio_select. */
+int minimio_close(const MinimioSelector *selector);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MINIMIO_H */
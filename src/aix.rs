@@ -0,0 +1,760 @@
+//! The AIX backend, on a pollset. A pollset is the kernel keeping the array of a
+//! `poll` for us, so it's level-triggered and knows nothing but fds: there's no room
+//! for a token, and an fd keeps being reported for as long as it's ready. We keep
+//! the tokens in a table, and to make registrations oneshot like they are on the
+//! other backends, we take an fd out of the pollset as soon as it's been reported.
+//!
+//! Changing what an fd is watched for with `PS_MOD` adds to its events instead of
+//! replacing them, so registering an fd again takes it out and adds it back.
+use crate::backlog::FullSelects;
+use crate::clock::ceil_millis;
+use crate::registrations::Registrations;
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::unix::{validate_token, Change, KICK_TOKEN};
+use crate::{Events, Interests, Source, Token};
+use std::collections::HashMap;
+use std::io::{self, IoSliceMut, Read, Write};
+use std::net;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex, MutexGuard,
+};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Registrator {
+    /// Shared with the `Selector`, so the pollset stays open for as long as anybody
+    /// can register with it
+    pollset: Arc<Pollset>,
+    is_poll_dead: Arc<AtomicBool>,
+    changes: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+}
+
+impl Registrator {
+    pub fn register(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let fd = source.as_fd().as_raw_fd();
+        self.stats.change();
+        self.pollset.watch(fd, token, interests)?;
+        self.registrations.insert(fd, token, interests);
+        Ok(())
+    }
+
+    /// A pollset can't wait for a send low-water mark, so this returns an error of
+    /// kind `Unsupported`.
+    pub fn register_with_send_lowat(
+        &self,
+        _source: &impl Source,
+        _token: usize,
+        interests: Interests,
+        _send_lowat: usize,
+    ) -> io::Result<()> {
+        if !interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A send low-water mark needs WRITABLE interest.",
+            ));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "AIX pollsets have no send low-water mark.",
+        ))
+    }
+
+    /// Like `register`, but the registration is queued for the polling thread, which
+    /// applies it at the start of its next `select`. We write to the pollset's kick
+    /// socket so that happens right away.
+    ///
+    /// Since the registration is applied later, errors from applying it can't be
+    /// returned from here. They're logged, and the registration is dropped.
+    pub fn register_deferred(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let change = Change {
+            fd: source.as_fd().as_raw_fd(),
+            token,
+            interests,
+        };
+        if self.changes.send(change).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        trace!(
+            "queued registration of fd {} with token {}",
+            change.fd,
+            token
+        );
+        self.stats.wakeup();
+        self.pollset.kick()
+    }
+
+    /// Takes `source` out of the pollset, so no more events are reported for it (one
+    /// that `select` has already returned can't be taken back). Deregistering a
+    /// source that isn't registered, which includes one that has been reported since
+    /// it was last registered, is not an error.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        if self.pollset.is_watched(fd) {
+            self.stats.change();
+        }
+        self.pollset.unwatch(fd)?;
+        self.registrations.remove(fd);
+        Ok(())
+    }
+
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self
+            .is_poll_dead
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        // Like on Linux, something that's readable right away wakes up `select` with
+        // an event with token 0. We leak the socket since the pollset will be closed
+        // soon anyway.
+        debug!("closing event loop on pollset {}", self.pollset.ps);
+        let (reader, mut writer) = UnixStream::pair()?;
+        writer.write_all(&[1])?;
+        self.pollset
+            .watch(reader.into_raw_fd(), 0, Interests::READABLE)
+    }
+}
+
+/// A pollset, and the tokens of the fds in it.
+#[derive(Debug)]
+struct Pollset {
+    ps: ffi::pollset_t,
+    /// The fds in the pollset, by the token they're registered with. An fd is taken
+    /// out, of both, once it's been reported.
+    watched: Mutex<HashMap<RawFd, Token>>,
+    /// Registrators write to this socket to wake up `select`. The other end stays in
+    /// the pollset for as long as it exists, with `KICK_TOKEN`.
+    kick_reader: UnixStream,
+    kick_writer: UnixStream,
+}
+
+impl Pollset {
+    fn new() -> io::Result<Pollset> {
+        // No limit on the number of fds, other than the process's
+        let ps = unsafe { ffi::pollset_create(-1) };
+        if ps < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (kick_reader, kick_writer) = UnixStream::pair()?;
+        let pollset = Pollset {
+            ps,
+            watched: Mutex::default(),
+            kick_reader,
+            kick_writer,
+        };
+        pollset.kick_reader.set_nonblocking(true)?;
+        pollset.kick_writer.set_nonblocking(true)?;
+        pollset.ctl(&mut [ffi::poll_ctl::new(
+            ffi::PS_ADD,
+            pollset.kick_reader.as_raw_fd(),
+            ffi::POLLIN,
+        )])?;
+        Ok(pollset)
+    }
+
+    /// Adds `fd` to the pollset, replacing what it was watched for if it's in there
+    /// already. An fd that's already ready is reported right away.
+    fn watch(&self, fd: RawFd, token: Token, interests: Interests) -> io::Result<()> {
+        let mut events = 0;
+        if interests.is_readable() {
+            events |= ffi::POLLIN;
+        }
+        if interests.is_writable() {
+            events |= ffi::POLLOUT;
+        }
+        // Locked while we change the pollset, so the table always agrees with it
+        let mut watched = self.watched();
+        let add = ffi::poll_ctl::new(ffi::PS_ADD, fd, events);
+        let res = if watched.contains_key(&fd) {
+            self.ctl(&mut [ffi::poll_ctl::new(ffi::PS_DELETE, fd, 0), add])
+        } else {
+            self.ctl(&mut [add])
+        };
+        if let Err(e) = res {
+            debug!(
+                "registering fd {} with token {} failed: {} (os error {:?})",
+                fd,
+                token,
+                e,
+                e.raw_os_error()
+            );
+            // Whether the delete went through is anybody's guess, so we stop
+            // expecting events for the fd either way
+            watched.remove(&fd);
+            return Err(e);
+        }
+        watched.insert(fd, token);
+        debug!(
+            "registered fd {} with token {} for {}",
+            fd, token, interests
+        );
+        Ok(())
+    }
+
+    fn unwatch(&self, fd: RawFd) -> io::Result<()> {
+        let mut watched = self.watched();
+        if watched.remove(&fd).is_none() {
+            return Ok(());
+        }
+        if let Err(e) = self.ctl(&mut [ffi::poll_ctl::new(ffi::PS_DELETE, fd, 0)]) {
+            debug!("deregistering fd {} failed: {}", fd, e);
+            return Err(e);
+        }
+        debug!("deregistered fd {}", fd);
+        Ok(())
+    }
+
+    fn is_watched(&self, fd: RawFd) -> bool {
+        self.watched().contains_key(&fd)
+    }
+
+    /// Turns what `pollset_poll` returned into events, and takes the fds they're for
+    /// out of the pollset, since they've fired. Fds that aren't in the table anymore
+    /// were deregistered after the pollset reported them, and are dropped.
+    fn disarm(&self, ready: &[ffi::pollfd], events: &mut Events) -> io::Result<()> {
+        let kick_fd = self.kick_reader.as_raw_fd();
+        let mut watched = self.watched();
+        let mut stop = Vec::new();
+        for pollfd in ready {
+            if pollfd.fd == kick_fd {
+                events.push(Event {
+                    token: KICK_TOKEN,
+                    revents: pollfd.revents,
+                });
+                continue;
+            }
+            let token = match watched.remove(&pollfd.fd) {
+                Some(token) => token,
+                None => {
+                    trace!("dropping stale event for fd {}", pollfd.fd);
+                    continue;
+                }
+            };
+            stop.push(ffi::poll_ctl::new(ffi::PS_DELETE, pollfd.fd, 0));
+            events.push(Event {
+                token,
+                revents: pollfd.revents,
+            });
+        }
+        if stop.is_empty() {
+            return Ok(());
+        }
+        self.ctl(&mut stop)
+    }
+
+    /// Takes every fd out of the pollset, except the kick socket.
+    fn clear(&self) -> io::Result<()> {
+        let mut watched = self.watched();
+        let mut stop: Vec<_> = watched
+            .keys()
+            .map(|&fd| ffi::poll_ctl::new(ffi::PS_DELETE, fd, 0))
+            .collect();
+        if !stop.is_empty() {
+            self.ctl(&mut stop)?;
+        }
+        watched.clear();
+        Ok(())
+    }
+
+    fn kick(&self) -> io::Result<()> {
+        match (&self.kick_writer).write(&[1]) {
+            // A full socket wakes up `select` just the same
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    fn drain_kick(&self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        loop {
+            match (&self.kick_reader).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn ctl(&self, changes: &mut [ffi::poll_ctl]) -> io::Result<()> {
+        // Anything but 0 means one of the changes failed, and `errno` says why
+        let res = unsafe { ffi::pollset_ctl(self.ps, changes.as_mut_ptr(), changes.len() as i32) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn watched(&self) -> MutexGuard<'_, HashMap<RawFd, Token>> {
+        // Every change is a single map operation, so a panic while holding the lock
+        // can't leave the map half changed
+        self.watched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Pollset {
+    fn drop(&mut self) {
+        if unsafe { ffi::pollset_destroy(self.ps) } < 0 {
+            debug!(
+                "destroying pollset {} failed: {}",
+                self.ps,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Selector {
+    pollset: Arc<Pollset>,
+    changes: mpsc::Receiver<Change>,
+    change_sender: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+    full_selects: FullSelects,
+}
+
+impl Selector {
+    pub fn new() -> io::Result<Self> {
+        let (change_sender, changes) = mpsc::channel();
+        Ok(Selector {
+            pollset: Arc::new(Pollset::new()?),
+            changes,
+            change_sender,
+            registrations: Arc::default(),
+            stats: Arc::default(),
+            full_selects: FullSelects::default(),
+        })
+    }
+
+    /// Deregisters every source registered with this selector or one of its
+    /// registrators, and drops the registrations still queued with
+    /// `Registrator::register_deferred`, so the selector can be reused as if it was
+    /// new. Wakers are deregistered as well and need to be registered again.
+    ///
+    /// A `select` already waiting in another thread keeps waiting on the old
+    /// registrations, and registrations made while this runs may be lost, so it's
+    /// meant for when nothing else is using the selector.
+    pub fn clear(&self) -> io::Result<()> {
+        for change in self.changes.try_iter() {
+            trace!("dropping deferred registration of fd {}", change.fd);
+        }
+        self.pollset.clear()?;
+        self.registrations.clear();
+        debug!("cleared all registrations on pollset {}", self.pollset.ps);
+        Ok(())
+    }
+
+    /// Applies the registrations queued with `Registrator::register_deferred`.
+    fn apply_changes(&self) {
+        for change in self.changes.try_iter() {
+            self.stats.change();
+            match self
+                .pollset
+                .watch(change.fd, change.token, change.interests)
+            {
+                Ok(()) => self
+                    .registrations
+                    .insert(change.fd, change.token, change.interests),
+                Err(e) => debug!(
+                    "dropping deferred registration of fd {} with token {}: {}",
+                    change.fd, change.token, e
+                ),
+            }
+        }
+    }
+
+    /// This function blocks and waits until an event has been recieved. `timeout` None means
+    /// the poll will never time out.
+    pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        let timeout = timeout_ms.map(|ms| Duration::from_millis(ms.max(0) as u64));
+        self.select_timeout(events, timeout)
+    }
+
+    /// Like `select`, but takes a `Duration`. `pollset_poll` only knows milliseconds,
+    /// so the timeout is rounded up to the next one.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            self.apply_changes();
+            let timeout_ms = deadline.map_or(-1, |deadline| {
+                ceil_millis(deadline.saturating_duration_since(Instant::now()))
+            });
+            self.wait(events, timeout_ms)?;
+
+            // Being kicked is all the kick socket tells us. If nothing else happened
+            // and there's time left, we go back to waiting.
+            if events.iter().any(|event| event.token == KICK_TOKEN) {
+                events.retain(|event| event.token != KICK_TOKEN);
+                self.pollset.drain_kick()?;
+                let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if events.is_empty() && !timed_out {
+                    continue;
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    fn wait(&self, events: &mut Events, timeout_ms: i32) -> io::Result<()> {
+        // The pollset fills an array of its own, which is turned into events after
+        let max_events = events.capacity();
+        events.clear();
+        if max_events == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`events` has no room for any event.",
+            ));
+        }
+        let mut ready = vec![ffi::pollfd::default(); max_events];
+        trace!(
+            "pollset_poll on pollset {} with timeout {}",
+            self.pollset.ps,
+            timeout_ms
+        );
+        self.stats.wait();
+        let res = unsafe {
+            ffi::pollset_poll(
+                self.pollset.ps,
+                ready.as_mut_ptr(),
+                max_events.min(i32::MAX as usize) as i32,
+                timeout_ms,
+            )
+        };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            debug!(
+                "pollset_poll on pollset {} failed: {} (os error {:?})",
+                self.pollset.ps,
+                e,
+                e.raw_os_error()
+            );
+            return Err(e);
+        }
+        let n_events = res as usize;
+        trace!(
+            "pollset_poll on pollset {} woke up with {} events",
+            self.pollset.ps,
+            n_events
+        );
+        self.full_selects.record(n_events, max_events);
+        self.pollset.disarm(&ready[..n_events], events)
+    }
+
+    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
+        Registrator {
+            pollset: self.pollset.clone(),
+            is_poll_dead,
+            changes: self.change_sender.clone(),
+            registrations: self.registrations.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Each of those probably left ready fds unreported, so a count that keeps
+    /// growing means the loop isn't keeping up and should shed load or poll with a
+    /// larger buffer. 0 if the last select had room to spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Event {
+    token: Token,
+    revents: i16,
+}
+
+impl Event {
+    pub fn id(&self) -> Token {
+        self.token
+    }
+
+    /// Returns true if the source can be read from, or has hung up.
+    pub fn is_readable(&self) -> bool {
+        self.revents & (ffi::POLLIN | ffi::POLLHUP) != 0
+    }
+
+    /// Returns true if the source can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.revents & ffi::POLLOUT != 0
+    }
+
+    /// An event the way the pollset would have reported it, for events that are rebuilt
+    /// after being held back.
+    pub(crate) fn with_readiness(token: Token, readable: bool, writable: bool) -> Event {
+        let mut revents = 0;
+        if readable {
+            revents |= ffi::POLLIN;
+        }
+        if writable {
+            revents |= ffi::POLLOUT;
+        }
+        Event { token, revents }
+    }
+
+    /// Adds the readiness of `other`, an event for the same token, to this one. The
+    /// pollset reports all of an fd's readiness at once, so they can always be merged.
+    pub(crate) fn merge(&mut self, other: &Event) -> bool {
+        self.revents |= other.revents;
+        true
+    }
+}
+
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    pub fn connect(adr: impl net::ToSocketAddrs) -> io::Result<Self> {
+        // Like on the other platforms this blocks while the connection is established
+        let stream = net::TcpStream::connect(adr)?;
+        TcpStream::from_std(stream)
+    }
+
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream { inner: stream })
+    }
+
+    /// `read` leaves the socket blocking, so anything that relies on getting
+    /// `WouldBlock` has to set it back first.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        self.inner.set_nonblocking(true)
+    }
+
+    /// Reads without changing the blocking mode, unlike `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+/// AIX keeps its connection statistics in `netstat`, not behind a socket option.
+pub(crate) fn tcp_info(_stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "AIX doesn't have TCP_INFO.",
+    ))
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Blocking while we read, like the other backends do. A caller that wants
+        // `WouldBlock` uses `read_ready` instead.
+        self.inner.set_nonblocking(false)?;
+
+        (&self.inner).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        (&self.inner).read_vectored(bufs)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    pub type pollset_t = i32;
+
+    pub const PS_ADD: i16 = 0;
+    pub const PS_DELETE: i16 = 2;
+
+    pub const POLLIN: i16 = 0x0001;
+    pub const POLLOUT: i16 = 0x0002;
+    pub const POLLHUP: i16 = 0x2000;
+
+    // https://www.ibm.com/docs/en/aix/7.3?topic=p-pollset-create-pollset-ctl-pollset-destroy-pollset-poll-pollset-query-subroutines
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C)]
+    pub struct poll_ctl {
+        pub cmd: i16,
+        pub events: i16,
+        pub fd: i32,
+    }
+
+    impl poll_ctl {
+        pub fn new(cmd: i16, fd: i32, events: i16) -> Self {
+            poll_ctl { cmd, events, fd }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, Default)]
+    #[repr(C)]
+    pub struct pollfd {
+        pub fd: i32,
+        pub events: i16,
+        pub revents: i16,
+    }
+
+    #[link(name = "c")]
+    extern "C" {
+        pub fn pollset_create(maxfd: i32) -> pollset_t;
+        pub fn pollset_ctl(ps: pollset_t, pollctl_array: *mut poll_ctl, array_length: i32) -> i32;
+        pub fn pollset_destroy(ps: pollset_t) -> i32;
+        pub fn pollset_poll(
+            ps: pollset_t,
+            polldata_array: *mut pollfd,
+            array_length: i32,
+            timeout: i32,
+        ) -> i32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_pair;
+
+    fn selector() -> (Selector, Registrator) {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        (selector, registrator)
+    }
+
+    #[test]
+    fn registrations_are_oneshot() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        registrator.register(&a, 3, Interests::READABLE).unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![3], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        assert!(events[0].is_readable());
+
+        // Still readable, but out of the pollset until it's registered again
+        b.write_all(b"pong").unwrap();
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+        registrator.register(&a, 3, Interests::READABLE).unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+    }
+
+    #[test]
+    fn registering_again_replaces_the_interests() {
+        let (selector, registrator) = selector();
+        let (a, _b) = socket_pair().unwrap();
+        registrator.register(&a, 1, Interests::WRITABLE).unwrap();
+        // `PS_MOD` would keep WRITABLE, and `a` is always writable
+        registrator.register(&a, 2, Interests::READABLE).unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn deferred_registrations_wake_up_select() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        b.write_all(b"ping").unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            registrator
+                .register_deferred(&a, 5, Interests::READABLE)
+                .unwrap();
+            a
+        });
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, None).unwrap();
+        assert_eq!(5, events[0].id());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn deregistered_and_cleared_sources_are_quiet() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        let (c, mut d) = socket_pair().unwrap();
+        registrator.register(&a, 1, Interests::READABLE).unwrap();
+        registrator.register(&c, 2, Interests::READABLE).unwrap();
+        registrator.deregister(&a).unwrap();
+        // Nothing is registered anymore, which isn't an error either
+        registrator.deregister(&a).unwrap();
+        selector.clear().unwrap();
+        b.write_all(b"ping").unwrap();
+        d.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+    }
+}
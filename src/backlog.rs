@@ -0,0 +1,43 @@
+//! A hint at whether the event loop is falling behind. None of the selectors can tell
+//! how many events are still queued: epoll and kqueue don't expose it, and neither does
+//! a completion port. What we can tell is whether a wait returned as many events as
+//! there was room for, which means there were probably more, and a loop that keeps
+//! filling its buffer isn't keeping up.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many waits in a row have filled the events buffer
+#[derive(Debug, Default)]
+pub(crate) struct FullSelects(AtomicUsize);
+
+impl FullSelects {
+    /// Records a wait that returned `received` events into a buffer with room for
+    /// `capacity`.
+    pub(crate) fn record(&self, received: usize, capacity: usize) {
+        if received > 0 && received == capacity {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.0.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_wait_with_room_to_spare_resets_the_count() {
+        let full = FullSelects::default();
+        full.record(4, 4);
+        full.record(4, 4);
+        assert_eq!(2, full.get());
+        full.record(3, 4);
+        assert_eq!(0, full.get());
+        full.record(0, 0);
+        assert_eq!(0, full.get());
+    }
+}
@@ -0,0 +1,108 @@
+//! A thread pool for work that would block the polling thread, like DNS lookups,
+//! file I/O or crypto. Each closure runs on one of the pool's threads and its result
+//! is sent back through a `channel`, so the polling thread is woken up when it's done.
+use crate::channel::{self, Receiver, Sender};
+use crate::{Poll, Token};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+type Job<R> = (Token, Box<dyn FnOnce() -> R + Send>);
+
+/// What a finished closure hands back: the token it was spawned with, and what it
+/// returned or the payload it panicked with.
+pub type Completion<R> = (Token, thread::Result<R>);
+
+/// Runs closures on a fixed number of threads. When one finishes, `poll` returns an
+/// event with the pool's token, and `completed` returns the result along with the
+/// token the closure was spawned with.
+///
+/// Dropping the pool waits for the closures that have been spawned to finish.
+#[derive(Debug)]
+pub struct BlockingPool<R> {
+    jobs: Option<mpsc::Sender<Job<R>>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    completions: Receiver<Completion<R>>,
+}
+
+impl<R: Send + 'static> BlockingPool<R> {
+    /// Starts `threads` threads (at least one) that report to `poll` with `token`.
+    pub fn new(poll: &Poll, token: Token, threads: usize) -> io::Result<Self> {
+        let (completed, completions) = channel::channel(poll, token)?;
+        let (jobs, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+
+        let workers = (0..threads.max(1))
+            .map(|n| {
+                let queue = queue.clone();
+                let completed = completed.clone();
+                thread::Builder::new()
+                    .name(format!("minimio-blocking-{}", n))
+                    .spawn(move || work(&queue, &completed))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(BlockingPool {
+            jobs: Some(jobs),
+            workers,
+            completions,
+        })
+    }
+
+    pub fn token(&self) -> Token {
+        self.completions.token()
+    }
+
+    /// Runs `f` on one of the pool's threads. Closures start in the order they're
+    /// spawned, but can finish in any order, which is what `token` is for.
+    pub fn spawn_blocking<F>(&self, token: Token, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let jobs = self.jobs.as_ref().expect("jobs sender lives until drop");
+        if jobs.send((token, Box::new(f))).is_err() {
+            // Only happens if every worker has died, which `work` prevents
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The blocking pool has no threads left.",
+            ));
+        }
+        trace!("spawned blocking closure with token {}", token);
+        Ok(())
+    }
+
+    /// Re-arms the pool after its event was returned from `poll` and returns the
+    /// closures that have finished since, in the order they finished.
+    pub fn completed(&self) -> io::Result<mpsc::TryIter<'_, Completion<R>>> {
+        self.completions.drain()
+    }
+}
+
+fn work<R>(queue: &Mutex<mpsc::Receiver<Job<R>>>, completed: &Sender<Completion<R>>) {
+    loop {
+        // The lock is only held while waiting for a job, not while running it
+        let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let (token, f) = match job {
+            Ok(job) => job,
+            // The pool was dropped
+            Err(_) => return,
+        };
+
+        // A panicking closure mustn't take the thread down with it
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        if let Err(e) = completed.send((token, result)) {
+            debug!("dropping result of blocking closure {}: {}", token, e);
+        }
+    }
+}
+
+impl<R> Drop for BlockingPool<R> {
+    fn drop(&mut self) {
+        // Closing the queue makes the workers return once it's empty
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Buffered reading and writing on top of the event loop. `std::io::BufReader` would
+//! work for reading if our streams never blocked, but `TcpStream::read` blocks on Linux
+//! and macOS, so `BufStream` reads through `NonBlockingRead` instead and `fill_buf`
+//! returns `WouldBlock` once the socket has nothing more. That's all a line based
+//! parser on `BufRead` needs to be driven by read events.
+use crate::TcpStream;
+use std::io::{self, BufRead, Read, Write};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::UnixStream;
+
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// Reading that returns an error of kind `WouldBlock` when there's nothing to read,
+/// instead of waiting for something to arrive.
+pub trait NonBlockingRead {
+    fn read_nonblocking(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl NonBlockingRead for TcpStream {
+    fn read_nonblocking(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.set_nonblocking()?;
+        self.read_ready(buf)
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+impl NonBlockingRead for UnixStream {
+    fn read_nonblocking(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+}
+
+/// Buffers reads from and writes to a non-blocking stream. Reading goes through
+/// `BufRead`, and returns `WouldBlock` when the buffer is empty and so is the socket.
+/// Writes are collected until the buffer is full or `flush` is called. A flush that
+/// can't finish because the socket's send buffer is full returns `WouldBlock` and
+/// keeps the rest, so call `flush` again when the stream is writable.
+#[derive(Debug)]
+pub struct BufStream<T> {
+    inner: T,
+    read_buf: Box<[u8]>,
+    /// Where the data in `read_buf` that hasn't been consumed starts and ends
+    pos: usize,
+    filled: usize,
+    write_buf: Vec<u8>,
+    write_capacity: usize,
+}
+
+impl<T> BufStream<T> {
+    pub fn new(inner: T) -> BufStream<T> {
+        BufStream::with_capacity(DEFAULT_CAPACITY, DEFAULT_CAPACITY, inner)
+    }
+
+    /// Buffers up to `read_capacity` bytes read from `inner` and `write_capacity` bytes
+    /// written to it.
+    pub fn with_capacity(read_capacity: usize, write_capacity: usize, inner: T) -> BufStream<T> {
+        BufStream {
+            inner,
+            read_buf: vec![0; read_capacity].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+            write_buf: Vec::with_capacity(write_capacity),
+            write_capacity,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// The stream itself, for registering it. Reading from it directly skips what's
+    /// already buffered.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// How many bytes have been written but not flushed to the stream yet.
+    pub fn pending_writes(&self) -> usize {
+        self.write_buf.len()
+    }
+}
+
+impl<T: Write> BufStream<T> {
+    /// Writes out as much of the write buffer as the stream takes. What's left is kept
+    /// at the front of the buffer when it stops taking any.
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let mut res = Ok(());
+        while written < self.write_buf.len() {
+            match self.inner.write(&self.write_buf[written..]) {
+                Ok(0) => {
+                    res = Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        self.write_buf.drain(..written);
+        res
+    }
+}
+
+impl<T: NonBlockingRead> Read for BufStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Nothing to gain from copying a large read through our buffer
+        if self.pos == self.filled && buf.len() >= self.read_buf.len() {
+            return self.inner.read_nonblocking(buf);
+        }
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<T: NonBlockingRead> BufRead for BufStream<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            let n = loop {
+                match self.inner.read_nonblocking(&mut self.read_buf) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    res => break res?,
+                }
+            };
+            self.pos = 0;
+            self.filled = n;
+        }
+        Ok(&self.read_buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+
+impl<T: Write> Write for BufStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let capacity = self.write_capacity;
+        if self.write_buf.len() + buf.len() > capacity {
+            match self.flush_buf() {
+                Ok(()) => (),
+                // Take what still fits, the rest has to wait for the stream
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let n = (capacity - self.write_buf.len()).min(buf.len());
+                    if n == 0 {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    self.write_buf.extend_from_slice(&buf[..n]);
+                    return Ok(n);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if buf.len() >= capacity {
+            return self.inner.write(buf);
+        }
+        self.write_buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tcp_pair;
+
+    #[test]
+    fn lines_are_read_as_they_arrive() {
+        let (a, mut b) = tcp_pair();
+        let mut stream = BufStream::new(a);
+        let err = stream.fill_buf().unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+        b.write_all(b"hello\nworld\n").unwrap();
+        let mut line = String::new();
+        let start = std::time::Instant::now();
+        loop {
+            match stream.read_line(&mut line) {
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(start.elapsed().as_secs() < 5, "data never arrived");
+                    std::thread::yield_now();
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!("hello\n", line);
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!("world\n", line);
+        let err = stream.read_line(&mut line).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+    }
+
+    #[test]
+    fn writes_are_buffered_until_flushed() {
+        let (a, mut b) = tcp_pair();
+        let mut stream = BufStream::with_capacity(16, 16, a);
+        stream.write_all(b"ping").unwrap();
+        assert_eq!(4, stream.pending_writes());
+
+        stream.flush().unwrap();
+        assert_eq!(0, stream.pending_writes());
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+    }
+}
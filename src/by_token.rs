@@ -0,0 +1,35 @@
+//! Handling the events of one poll a connection at a time. Under load the same token
+//! can show up more than once in a single poll, readable and writable as separate
+//! events on macOS, and interleaved with other connections' events, which means a
+//! handler that runs per event runs several times per connection per tick.
+use crate::{Event, Token};
+
+/// Iterator over the events of one poll grouped by token, in the order of their
+/// tokens. Returned from `EventsExt::by_token`.
+#[derive(Debug)]
+pub struct ByToken<'a> {
+    events: &'a [Event],
+}
+
+impl<'a> ByToken<'a> {
+    /// `events` have to be sorted by token.
+    pub(crate) fn new(events: &'a [Event]) -> ByToken<'a> {
+        ByToken { events }
+    }
+}
+
+impl<'a> Iterator for ByToken<'a> {
+    type Item = (Token, &'a [Event]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.events.first()?.id();
+        let len = self
+            .events
+            .iter()
+            .position(|event| event.id() != token)
+            .unwrap_or(self.events.len());
+        let (group, rest) = self.events.split_at(len);
+        self.events = rest;
+        Some((token, group))
+    }
+}
@@ -0,0 +1,299 @@
+//! C bindings so minimio can be embedded in C or C++ programs as their event queue.
+//! The matching declarations are in `include/minimio.h`. Build the shared library
+//! with:
+//!
+//! ```text
+//! cargo rustc --release --features capi --crate-type cdylib
+//! ```
+//!
+//! Functions returning `int` return `0` (or a count) on success and a negative OS
+//! error code (`-errno`) on failure, or `-1` if the error didn't come from the OS.
+//!
+//! Registering raw file descriptors is only available on Linux and macOS since IOCP
+//! needs to own the buffers of the sockets it reads from.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::unix::RawSource;
+use crate::{Events, Poll, Registrator};
+use std::io;
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+
+/// Opaque handle owning a `Poll` and a `Registrator` tied to it. Every function takes
+/// it by shared reference, since `minimio_close` is called from another thread while
+/// one is blocked in `minimio_select`.
+pub struct MinimioSelector {
+    waiting: Mutex<Waiting>,
+    registrator: Registrator,
+}
+
+/// What `minimio_select` needs exclusive access to
+struct Waiting {
+    poll: Poll,
+    events: Events,
+}
+
+impl MinimioSelector {
+    fn waiting(&self) -> MutexGuard<'_, Waiting> {
+        // A panic while polling is turned into an error, and doesn't leave the `Poll`
+        // unusable
+        self.waiting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A single event as seen from C.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MinimioEvent {
+    pub token: usize,
+}
+
+/// A caller owned array of events. `minimio_select` fills in up to `capacity`
+/// events and sets `len` to how many it wrote.
+#[repr(C)]
+#[derive(Debug)]
+pub struct MinimioEvents {
+    pub events: *mut MinimioEvent,
+    pub capacity: usize,
+    pub len: usize,
+}
+
+pub const MINIMIO_READABLE: u8 = 0b0000_0010;
+pub const MINIMIO_WRITABLE: u8 = 0b0000_0001;
+
+fn error_code(e: &io::Error) -> c_int {
+    match e.raw_os_error() {
+        Some(code) if code > 0 => -code,
+        _ => -1,
+    }
+}
+
+/// Creates a new selector. Returns null if the OS event queue couldn't be created.
+#[no_mangle]
+pub extern "C" fn minimio_selector_new() -> *mut MinimioSelector {
+    match Poll::new() {
+        Ok(poll) => {
+            let registrator = poll.registrator();
+            Box::into_raw(Box::new(MinimioSelector {
+                waiting: Mutex::new(Waiting {
+                    poll,
+                    events: Events::new(),
+                }),
+                registrator,
+            }))
+        }
+        Err(e) => {
+            debug!("minimio_selector_new failed: {}", e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Closes the selector and frees it.
+///
+/// # Safety
+///
+/// `selector` must be null or a pointer returned from `minimio_selector_new` that
+/// hasn't been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn minimio_selector_free(selector: *mut MinimioSelector) {
+    if !selector.is_null() {
+        drop(Box::from_raw(selector));
+    }
+}
+
+/// Registers interest in events on `fd`. `interests` is a combination of
+/// `MINIMIO_READABLE` and `MINIMIO_WRITABLE`. Like the Rust API the registration is
+/// oneshot, so register again to be notified again.
+///
+/// # Safety
+///
+/// `selector` must be a valid pointer returned from `minimio_selector_new`, and `fd`
+/// must stay open until it's done being used with the selector.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+#[no_mangle]
+pub unsafe extern "C" fn minimio_register_fd(
+    selector: *mut MinimioSelector,
+    fd: c_int,
+    token: usize,
+    interests: u8,
+) -> c_int {
+    if selector.is_null() || interests & !(MINIMIO_READABLE | MINIMIO_WRITABLE) != 0 {
+        return -1;
+    }
+    let selector = &*selector;
+    let source = RawSource(fd);
+    // Unwinding into C is undefined behavior so a panic is turned into an error
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        selector
+            .registrator
+            .register(&source, token, crate::Interests(interests))
+    }));
+    match res {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => error_code(&e),
+        Err(_) => -1,
+    }
+}
+
+/// Waits for events, blocking for at most `timeout_ms` milliseconds or forever if
+/// it's negative. Returns the number of events written to `events`.
+///
+/// # Safety
+///
+/// `selector` must be a valid pointer returned from `minimio_selector_new` and
+/// `events` must point to a `MinimioEvents` whose `events` array has room for
+/// `capacity` events.
+#[no_mangle]
+pub unsafe extern "C" fn minimio_select(
+    selector: *mut MinimioSelector,
+    events: *mut MinimioEvents,
+    timeout_ms: c_int,
+) -> c_int {
+    if selector.is_null() || events.is_null() {
+        return -1;
+    }
+    let selector = &*selector;
+    let out = &mut *events;
+    out.len = 0;
+    if out.capacity > 0 && out.events.is_null() {
+        return -1;
+    }
+
+    // We never ask for more events than the caller has room for
+    let capacity = out.capacity.min(c_int::MAX as usize);
+    let mut waiting = selector.waiting();
+    let Waiting { poll, events } = &mut *waiting;
+    if events.capacity() != capacity {
+        *events = Events::with_capacity(capacity);
+    }
+    let timeout = if timeout_ms < 0 {
+        None
+    } else {
+        Some(timeout_ms)
+    };
+
+    let res = panic::catch_unwind(AssertUnwindSafe(|| poll.poll(events, timeout)));
+    match res {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => return error_code(&e),
+        Err(_) => return -1,
+    }
+
+    let n = events.len().min(capacity);
+    for (i, event) in events.iter().take(n).enumerate() {
+        *out.events.add(i) = MinimioEvent { token: event.id() };
+    }
+    out.len = n;
+    n as c_int
+}
+
+/// Closes the selector so a thread blocked in `minimio_select` on it returns.
+/// The selector still has to be freed with `minimio_selector_free`.
+///
+/// # Safety
+///
+/// `selector` must be a valid pointer returned from `minimio_selector_new`.
+#[no_mangle]
+pub unsafe extern "C" fn minimio_close(selector: *const MinimioSelector) -> c_int {
+    if selector.is_null() {
+        return -1;
+    }
+    match (*selector).registrator.close_loop() {
+        Ok(()) => 0,
+        Err(e) => error_code(&e),
+    }
+}
+
+#[cfg(all(
+    test,
+    any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    )
+))]
+mod tests {
+    use super::*;
+    use crate::socket_pair;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn register_and_select_through_c_api() {
+        let (a, mut b) = socket_pair().unwrap();
+        let mut buffer = [MinimioEvent { token: 0 }; 4];
+        let mut events = MinimioEvents {
+            events: buffer.as_mut_ptr(),
+            capacity: buffer.len(),
+            len: 0,
+        };
+
+        unsafe {
+            let selector = minimio_selector_new();
+            assert!(!selector.is_null());
+            let res = minimio_register_fd(selector, a.as_raw_fd(), 42, MINIMIO_READABLE);
+            assert_eq!(0, res);
+            assert_eq!(-1, minimio_register_fd(selector, a.as_raw_fd(), 42, 0xf0));
+
+            b.write_all(b"ping").unwrap();
+            assert_eq!(1, minimio_select(selector, &mut events, 1000));
+            assert_eq!(1, events.len);
+
+            assert_eq!(0, minimio_close(selector));
+            assert!(minimio_select(selector, &mut events, 0) < 0);
+            minimio_selector_free(selector);
+        }
+        assert_eq!(42, buffer[0].token);
+    }
+
+    #[test]
+    fn close_wakes_up_select_on_another_thread() {
+        #[derive(Clone, Copy)]
+        struct Selector(*mut MinimioSelector);
+        unsafe impl Send for Selector {}
+
+        let selector = Selector(minimio_selector_new());
+        assert!(!selector.0.is_null());
+        let selecting = std::thread::spawn(move || {
+            let selector = selector;
+            let mut buffer = [MinimioEvent { token: 0 }; 4];
+            let mut events = MinimioEvents {
+                events: buffer.as_mut_ptr(),
+                capacity: buffer.len(),
+                len: 0,
+            };
+            unsafe { minimio_select(selector.0, &mut events, -1) }
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        unsafe {
+            assert_eq!(0, minimio_close(selector.0));
+            assert!(selecting.join().unwrap() < 0);
+            minimio_selector_free(selector.0);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! A channel whose receiving end is part of the event loop, so worker threads can
+//! hand results back to the thread polling a `Poll`.
+//!
+//! There is no `Handler` with a `notify` callback in this crate; the event loop is the
+//! caller's own loop around `Poll::poll`. When it gets an event with the channel's
+//! token it calls `Receiver::drain` and handles the messages right there:
+//!
+//! ```no_run
+//! use minimio::{channel, Events, Poll};
+//!
+//! const RESULTS: usize = 1;
+//!
+//! let mut poll = Poll::new()?;
+//! let (sender, receiver) = channel::channel(&poll, RESULTS)?;
+//! std::thread::spawn(move || sender.send(6 * 7));
+//!
+//! let mut events = Events::with_capacity(16);
+//! poll.poll(&mut events, None)?;
+//! for event in &events {
+//!     if event.id() == RESULTS {
+//!         for result in receiver.drain()? {
+//!             println!("got {}", result);
+//!         }
+//!     }
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::{Poll, Token, Waker};
+use std::io;
+use std::sync::mpsc;
+
+/// Creates a channel that wakes up `poll` with an event with `token` as its id when
+/// messages are sent on it.
+pub fn channel<T>(poll: &Poll, token: Token) -> io::Result<(Sender<T>, Receiver<T>)> {
+    let waker = Waker::new(poll, token)?;
+    let (tx, rx) = mpsc::channel();
+    let sender = Sender {
+        tx,
+        waker: waker.clone(),
+    };
+    Ok((sender, Receiver { rx, waker }))
+}
+
+/// The sending half of a `channel`. It can be cloned and sent to other threads.
+#[derive(Debug)]
+pub struct Sender<T> {
+    tx: mpsc::Sender<T>,
+    waker: Waker,
+}
+
+impl<T> Sender<T> {
+    /// Queues `msg` and wakes up the polling thread. Messages from one sender are
+    /// received in the order they were sent. Returns an error of kind `BrokenPipe`
+    /// if the `Receiver` has been dropped, in which case `msg` is dropped too.
+    pub fn send(&self, msg: T) -> io::Result<()> {
+        if self.tx.send(msg).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The receiving end of the channel has been dropped.",
+            ));
+        }
+        self.waker.wake()
+    }
+}
+
+// Derive would require `T: Clone`
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            tx: self.tx.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+/// The receiving half of a `channel`, which belongs to the thread polling the `Poll`
+/// it was created with.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    rx: mpsc::Receiver<T>,
+    waker: Waker,
+}
+
+impl<T> Receiver<T> {
+    pub fn token(&self) -> Token {
+        self.waker.token()
+    }
+
+    /// Re-arms the channel after its event was returned from `poll` and returns an
+    /// iterator over the messages that are waiting, oldest first. It doesn't block.
+    ///
+    /// The channel is re-armed before anything is taken off the queue, so a message
+    /// that isn't yielded by this iterator always causes a new event.
+    pub fn drain(&self) -> io::Result<mpsc::TryIter<'_, T>> {
+        self.waker.reset()?;
+        Ok(self.rx.try_iter())
+    }
+}
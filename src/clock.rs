@@ -0,0 +1,128 @@
+//! Where timers get the time from. A `TimerWheel` asks its clock what time it is
+//! instead of calling `Instant::now` itself, so logic built on timers can be tested
+//! with a `ManualClock` that only moves when the test says so, and a test of a
+//! 30 second idle timeout doesn't have to sleep for 30 seconds.
+//!
+//! ```
+//! use minimio::{Clock, ManualClock, TimerWheel};
+//! use std::time::Duration;
+//!
+//! let clock = ManualClock::new();
+//! let mut wheel = TimerWheel::with_clock(clock.clone(), Duration::from_millis(1));
+//! wheel.insert_after(Duration::from_secs(30), 7);
+//!
+//! let mut expired = Vec::new();
+//! clock.advance(Duration::from_secs(29));
+//! wheel.advance(clock.now(), &mut expired);
+//! assert!(expired.is_empty());
+//! clock.advance(Duration::from_secs(1));
+//! wheel.advance(clock.now(), &mut expired);
+//! assert_eq!(vec![7], expired);
+//! ```
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of the current time. It has to be monotonic: `now` never returns an
+/// earlier instant than it did before.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// The system's monotonic clock, which is what `Instant::now` reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it's advanced. Clones share the time, so a test
+/// can keep one and hand the other to what it's testing.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// A clock stopped at the current time.
+    pub fn new() -> ManualClock {
+        ManualClock::starting_at(Instant::now())
+    }
+
+    /// A clock stopped at `start`.
+    pub fn starting_at(start: Instant) -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    /// Moves the time forward by `by`, for this clock and all its clones.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `timeout` in whole milliseconds, rounded up so a wait for it never returns before
+/// the deadline.
+// `is_multiple_of` needs Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+pub(crate) fn ceil_millis_u128(timeout: Duration) -> u128 {
+    timeout.as_millis() + u128::from(timeout.subsec_nanos() % 1_000_000 != 0)
+}
+
+/// Like `ceil_millis_u128`, for the waits that take an `int` of milliseconds.
+pub(crate) fn ceil_millis(timeout: Duration) -> i32 {
+    ceil_millis_u128(timeout).min(i32::MAX as u128) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+
+        shared.advance(Duration::from_secs(5));
+        assert_eq!(start + Duration::from_secs(5), clock.now());
+        assert_eq!(clock.now(), Arc::new(shared).now());
+    }
+
+    #[test]
+    fn timeouts_round_up_to_whole_milliseconds() {
+        assert_eq!(0, ceil_millis(Duration::from_secs(0)));
+        assert_eq!(1, ceil_millis(Duration::from_micros(300)));
+        assert_eq!(2, ceil_millis(Duration::from_micros(1001)));
+        assert_eq!(i32::MAX, ceil_millis(Duration::from_secs(u64::MAX)));
+    }
+}
@@ -0,0 +1,45 @@
+//! Merging the events of one poll that share a token. The selectors already report a
+//! source that's readable and writable as one event, but a busy IOCP socket can
+//! complete several reads between two polls, and sources registered with the same
+//! token each get their own events, so a connection can get its handler called more
+//! than once per poll. With coalescing on, each token gets a single event whose
+//! readiness is that of all of them.
+use crate::{Events, Poll, Token};
+use std::collections::HashMap;
+
+impl Poll {
+    /// Turns merging the events `poll` returns for the same token on or off. It's off
+    /// by default, since it hides how the OS reported the events: merged events can't
+    /// tell which of them came first, and on Windows only the total of the bytes
+    /// transferred is left. Timer and job events are never merged.
+    pub fn coalesce_events(&mut self, on: bool) {
+        self.coalesce = on;
+    }
+
+    /// Merges each event into the first one with the same token, where the backend
+    /// can merge them.
+    pub(crate) fn coalesce(&self, events: &mut Events) {
+        if !self.coalesce || events.len() < 2 {
+            return;
+        }
+        let mut first: HashMap<Token, usize> = HashMap::with_capacity(events.len());
+        let mut kept = 0;
+        for i in 0..events.len() {
+            let token = events[i].id();
+            if let Some(&at) = first.get(&token) {
+                let (head, tail) = events.split_at_mut(i);
+                if head[at].merge(&tail[0]) {
+                    continue;
+                }
+            } else {
+                first.insert(token, kept);
+            }
+            events.swap(kept, i);
+            kept += 1;
+        }
+        if kept < events.len() {
+            trace!("coalesced {} events into {}", events.len(), kept);
+        }
+        events.truncate(kept);
+    }
+}
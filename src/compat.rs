@@ -0,0 +1,15 @@
+//! APIs shaped like those of other polling crates, implemented on minimio's selectors,
+//! so a project written against one of them can try minimio in its place without
+//! rewriting its event loop. They cover the commonly used parts, not everything.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+pub mod mio;
+pub mod polling;
@@ -0,0 +1,558 @@
+//! The `Poll`, `Registry`, `Events` and `net` types of mio 0.8, for running code
+//! written against mio, like its examples, on minimio. It's only there on Linux and
+//! macOS, since the Windows backend has no listener to accept connections with.
+//!
+//! mio's registrations are edge-triggered: a source reports again whenever it becomes
+//! ready again, without registering it again. minimio's are oneshot, so `Poll::poll`
+//! registers the sources that had events at its last call again before it waits. To
+//! code that reads and writes until `WouldBlock`, like mio requires, that looks the
+//! same. Code that stops early gets another event, rather than waiting forever.
+//!
+//! ```no_run
+//! use minimio::compat::mio::net::TcpListener;
+//! use minimio::compat::mio::{Events, Interest, Poll, Token};
+//!
+//! const SERVER: Token = Token(0);
+//!
+//! let mut poll = Poll::new()?;
+//! let mut events = Events::with_capacity(128);
+//! let mut server = TcpListener::bind("127.0.0.1:9000".parse().unwrap())?;
+//! poll.registry()
+//!     .register(&mut server, SERVER, Interest::READABLE)?;
+//! loop {
+//!     poll.poll(&mut events, None)?;
+//!     for event in events.iter() {
+//!         if event.token() == SERVER {
+//!             let (_connection, address) = server.accept()?;
+//!             println!("accepted a connection from {}", address);
+//!         }
+//!     }
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::unix::RawSource;
+use crate::{Interests, Registrator};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::ops::{BitOr, BitOrAssign};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+pub use self::event::Events;
+
+/// How a source and its events are told apart, like minimio's tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Token(pub usize);
+
+impl From<Token> for usize {
+    fn from(token: Token) -> usize {
+        token.0
+    }
+}
+
+/// The readiness a source is registered for. Unlike minimio's `Interests`, there's
+/// no empty `Interest`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Interest(Interests);
+
+impl Interest {
+    pub const READABLE: Interest = Interest(Interests::READABLE);
+    pub const WRITABLE: Interest = Interest(Interests::WRITABLE);
+
+    /// Returns the union of `self` and `other`. Same as `self | other`.
+    #[allow(clippy::should_implement_trait)]
+    pub const fn add(self, other: Interest) -> Interest {
+        Interest(self.0.add(other.0))
+    }
+
+    /// Returns `self` without the interests in `other`, or `None` if that leaves none.
+    pub fn remove(self, other: Interest) -> Option<Interest> {
+        let interests = self.0.remove(other.0);
+        if interests.is_empty() {
+            None
+        } else {
+            Some(Interest(interests))
+        }
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.0.contains(Interests::READABLE)
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.0.contains(Interests::WRITABLE)
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        self.add(other)
+    }
+}
+
+impl BitOrAssign for Interest {
+    fn bitor_assign(&mut self, other: Interest) {
+        *self = self.add(other);
+    }
+}
+
+impl fmt::Debug for Interest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub mod event {
+    //! Events, and the `Source` trait of what can be registered.
+    use super::{Registry, Token};
+    use std::io;
+    use std::slice;
+
+    /// The readiness of a source, with the token it was registered with.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Event {
+        token: Token,
+        readable: bool,
+        writable: bool,
+    }
+
+    impl Event {
+        pub fn token(&self) -> Token {
+            self.token
+        }
+
+        /// Returns true if the source can be read from, or has hung up.
+        pub fn is_readable(&self) -> bool {
+            self.readable
+        }
+
+        pub fn is_writable(&self) -> bool {
+            self.writable
+        }
+    }
+
+    /// The events of a `Poll::poll` call.
+    #[derive(Debug)]
+    pub struct Events {
+        pub(super) inner: crate::Events,
+        pub(super) events: Vec<Event>,
+    }
+
+    impl Events {
+        /// Room for `capacity` events, which is as many as a poll returns at most.
+        pub fn with_capacity(capacity: usize) -> Events {
+            Events {
+                inner: crate::Events::with_capacity(capacity),
+                events: Vec::with_capacity(capacity),
+            }
+        }
+
+        pub fn capacity(&self) -> usize {
+            self.inner.capacity()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.events.is_empty()
+        }
+
+        pub fn iter(&self) -> slice::Iter<'_, Event> {
+            self.events.iter()
+        }
+
+        pub fn clear(&mut self) {
+            self.inner.clear();
+            self.events.clear();
+        }
+
+        /// Takes the events `poll` selected, and returns the tokens that had one.
+        pub(super) fn fill(&mut self) -> impl Iterator<Item = usize> + '_ {
+            self.events.clear();
+            self.events.extend(self.inner.iter().map(|event| Event {
+                token: Token(event.id()),
+                readable: event.is_readable(),
+                writable: event.is_writable(),
+            }));
+            self.events.iter().map(|event| event.token.0)
+        }
+    }
+
+    impl<'a> IntoIterator for &'a Events {
+        type Item = &'a Event;
+        type IntoIter = slice::Iter<'a, Event>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.iter()
+        }
+    }
+
+    /// What can be registered with a `Registry`: the `net` types, and any fd through
+    /// `unix::SourceFd`.
+    pub trait Source {
+        fn register(
+            &mut self,
+            registry: &Registry,
+            token: Token,
+            interests: super::Interest,
+        ) -> io::Result<()>;
+
+        fn reregister(
+            &mut self,
+            registry: &Registry,
+            token: Token,
+            interests: super::Interest,
+        ) -> io::Result<()>;
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()>;
+    }
+}
+
+/// The sources registered through a `Registry`, and the clones of it
+#[derive(Debug)]
+struct Shared {
+    registrator: Registrator,
+    /// The token and interests of every fd, to register them again with
+    sources: Mutex<HashMap<RawFd, (Token, Interests)>>,
+}
+
+impl Shared {
+    fn sources(&self) -> MutexGuard<'_, HashMap<RawFd, (Token, Interests)>> {
+        self.sources.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Registers sources with a `Poll`, from any thread.
+#[derive(Debug)]
+pub struct Registry {
+    shared: Arc<Shared>,
+}
+
+impl Registry {
+    pub fn register<S: event::Source + ?Sized>(
+        &self,
+        source: &mut S,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        source.register(self, token, interests)
+    }
+
+    pub fn reregister<S: event::Source + ?Sized>(
+        &self,
+        source: &mut S,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        source.reregister(self, token, interests)
+    }
+
+    pub fn deregister<S: event::Source + ?Sized>(&self, source: &mut S) -> io::Result<()> {
+        source.deregister(self)
+    }
+
+    /// Another `Registry` for the same `Poll`, to hand to another thread.
+    pub fn try_clone(&self) -> io::Result<Registry> {
+        Ok(Registry {
+            shared: self.shared.clone(),
+        })
+    }
+
+    fn register_fd(&self, fd: RawFd, token: Token, interests: Interest) -> io::Result<()> {
+        self.shared
+            .registrator
+            .register(&RawSource(fd), token.0, interests.0)?;
+        // An fd closed without being deregistered can come back for a new source, so
+        // the fd is what's replaced, not the token
+        self.shared.sources().insert(fd, (token, interests.0));
+        Ok(())
+    }
+
+    fn deregister_fd(&self, fd: RawFd) -> io::Result<()> {
+        self.shared.sources().remove(&fd);
+        self.shared.registrator.deregister(&RawSource(fd))
+    }
+}
+
+/// Polls for the events of the sources registered through its `Registry`.
+#[derive(Debug)]
+pub struct Poll {
+    poll: crate::Poll,
+    registry: Registry,
+    /// The tokens that had events at the last poll, whose sources are registered
+    /// again at the next
+    fired: HashSet<usize>,
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let poll = crate::Poll::new()?;
+        Ok(Poll {
+            registry: Registry {
+                shared: Arc::new(Shared {
+                    registrator: poll.registrator(),
+                    sources: Mutex::default(),
+                }),
+            },
+            poll,
+            fired: HashSet::new(),
+        })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Waits for at least one event, for no longer than `timeout`, and replaces the
+    /// ones in `events` with them.
+    pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        self.rearm();
+        self.poll.poll_timeout(&mut events.inner, timeout)?;
+        self.fired.extend(events.fill());
+        Ok(())
+    }
+
+    /// Registers the sources that had events at the last poll again, so they report
+    /// readiness again like mio's would.
+    fn rearm(&mut self) {
+        if self.fired.is_empty() {
+            return;
+        }
+        let shared = &self.registry.shared;
+        let fired = &self.fired;
+        shared.sources().retain(|&fd, &mut (token, interests)| {
+            if !fired.contains(&token.0) {
+                return true;
+            }
+            match shared
+                .registrator
+                .register(&RawSource(fd), token.0, interests)
+            {
+                Ok(()) => true,
+                // Closed without being deregistered
+                Err(e) => {
+                    trace!("not registering fd {} again: {}", fd, e);
+                    false
+                }
+            }
+        });
+        self.fired.clear();
+    }
+}
+
+pub mod net {
+    //! TCP sockets that never block. minimio's `TcpStream` reads blocking, and code
+    //! written for mio reads until `WouldBlock`, so these wrap the standard library's.
+    use super::event::Source;
+    use super::{Interest, Registry, Token};
+    use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+    use std::net::{self, Shutdown, SocketAddr};
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    #[derive(Debug)]
+    pub struct TcpListener {
+        inner: net::TcpListener,
+    }
+
+    impl TcpListener {
+        pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+            TcpListener::from_std(net::TcpListener::bind(addr)?)
+        }
+
+        /// Wraps a listener from the standard library, setting it to non-blocking.
+        pub fn from_std(listener: net::TcpListener) -> io::Result<TcpListener> {
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener { inner: listener })
+        }
+
+        /// Accepts a connection, or fails with `WouldBlock` if there's none waiting.
+        pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+            let (stream, addr) = self.inner.accept()?;
+            Ok((TcpStream::from_std(stream)?, addr))
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+
+        pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+            self.inner.take_error()
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct TcpStream {
+        inner: net::TcpStream,
+    }
+
+    impl TcpStream {
+        /// Connects to `addr`. Unlike mio's, it waits for the connection to be
+        /// established, so the stream is writable as soon as it's registered.
+        pub fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+            TcpStream::from_std(net::TcpStream::connect(addr)?)
+        }
+
+        /// Wraps a stream from the standard library, setting it to non-blocking.
+        pub fn from_std(stream: net::TcpStream) -> io::Result<TcpStream> {
+            stream.set_nonblocking(true)?;
+            Ok(TcpStream { inner: stream })
+        }
+
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.peer_addr()
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+
+        pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.inner.shutdown(how)
+        }
+
+        pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.inner.set_nodelay(nodelay)
+        }
+
+        pub fn nodelay(&self) -> io::Result<bool> {
+            self.inner.nodelay()
+        }
+
+        pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+            self.inner.take_error()
+        }
+
+        pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.peek(buf)
+        }
+    }
+
+    impl Read for TcpStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            (&*self).read(buf)
+        }
+
+        fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            (&*self).read_vectored(bufs)
+        }
+    }
+
+    impl Read for &TcpStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            (&self.inner).read(buf)
+        }
+
+        fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            (&self.inner).read_vectored(bufs)
+        }
+    }
+
+    impl Write for TcpStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            (&*self).write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            (&*self).write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            (&*self).flush()
+        }
+    }
+
+    impl Write for &TcpStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            (&self.inner).write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            (&self.inner).write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            (&self.inner).flush()
+        }
+    }
+
+    impl AsRawFd for TcpListener {
+        fn as_raw_fd(&self) -> RawFd {
+            self.inner.as_raw_fd()
+        }
+    }
+
+    impl AsRawFd for TcpStream {
+        fn as_raw_fd(&self) -> RawFd {
+            self.inner.as_raw_fd()
+        }
+    }
+
+    // Both are registered by their fd
+    macro_rules! impl_source {
+        ($($ty:ty),*) => {$(
+            impl Source for $ty {
+                fn register(
+                    &mut self,
+                    registry: &Registry,
+                    token: Token,
+                    interests: Interest,
+                ) -> io::Result<()> {
+                    registry.register_fd(self.as_raw_fd(), token, interests)
+                }
+
+                fn reregister(
+                    &mut self,
+                    registry: &Registry,
+                    token: Token,
+                    interests: Interest,
+                ) -> io::Result<()> {
+                    registry.register_fd(self.as_raw_fd(), token, interests)
+                }
+
+                fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+                    registry.deregister_fd(self.as_raw_fd())
+                }
+            }
+        )*};
+    }
+
+    impl_source!(TcpListener, TcpStream);
+}
+
+pub mod unix {
+    //! Registering any fd.
+    use super::event::Source;
+    use super::{Interest, Registry, Token};
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    /// Registers the fd it borrows, which has to stay open while it's registered.
+    #[derive(Debug)]
+    pub struct SourceFd<'a>(pub &'a RawFd);
+
+    impl Source for SourceFd<'_> {
+        fn register(
+            &mut self,
+            registry: &Registry,
+            token: Token,
+            interests: Interest,
+        ) -> io::Result<()> {
+            registry.register_fd(*self.0, token, interests)
+        }
+
+        fn reregister(
+            &mut self,
+            registry: &Registry,
+            token: Token,
+            interests: Interest,
+        ) -> io::Result<()> {
+            registry.register_fd(*self.0, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            registry.deregister_fd(*self.0)
+        }
+    }
+}
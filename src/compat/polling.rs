@@ -0,0 +1,259 @@
+//! The `Poller` API of the `polling` crate. Like there, interest is oneshot: once an
+//! event has been delivered for a source, `modify` has to be called to get another.
+//!
+//! ```no_run
+//! use minimio::compat::polling::{Event, Poller};
+//! # let (mut socket, _peer) = minimio::socket_pair()?;
+//! let poller = Poller::new()?;
+//! poller.add(&mut socket, Event::readable(7))?;
+//! let mut events = Vec::new();
+//! poller.wait(&mut events, None)?;
+//! for event in &events {
+//!     assert_eq!(7, event.key);
+//!     poller.modify(&mut socket, Event::readable(7))?;
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::{Events, Interests, Poll, Registrator, Waker};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::Source;
+#[cfg(target_os = "windows")]
+use crate::TcpStream;
+
+/// The key `notify` wakes `wait` up with, which sources can't be added with
+const NOTIFY_KEY: usize = usize::MAX - 1;
+
+/// How many events a `Poller` takes off the queue per `wait`
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Interest in, or readiness of, the source added with `key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Event {
+    pub key: usize,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Event {
+    pub fn all(key: usize) -> Event {
+        Event {
+            key,
+            readable: true,
+            writable: true,
+        }
+    }
+
+    pub fn readable(key: usize) -> Event {
+        Event {
+            key,
+            readable: true,
+            writable: false,
+        }
+    }
+
+    pub fn writable(key: usize) -> Event {
+        Event {
+            key,
+            readable: false,
+            writable: true,
+        }
+    }
+
+    pub fn none(key: usize) -> Event {
+        Event {
+            key,
+            readable: false,
+            writable: false,
+        }
+    }
+
+    /// The interests to register for, `None` for none at all
+    fn interests(&self) -> Option<Interests> {
+        match (self.readable, self.writable) {
+            (true, true) => Some(Interests::READABLE | Interests::WRITABLE),
+            (true, false) => Some(Interests::READABLE),
+            (false, true) => Some(Interests::WRITABLE),
+            (false, false) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Waiting {
+    poll: Poll,
+    events: Events,
+}
+
+// On Windows the events point to the `Operation`s of registered streams, which are
+// only dereferenced while the lock is held.
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+unsafe impl Send for Waiting {}
+
+/// Waits for the sources added to it to be ready. Every method takes `&self`, so a
+/// `Poller` can be shared between the thread waiting and the threads adding sources.
+#[derive(Debug)]
+pub struct Poller {
+    waiting: Mutex<Waiting>,
+    registrator: Registrator,
+    notify: Waker,
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        let poll = Poll::new()?;
+        Ok(Poller {
+            registrator: poll.registrator(),
+            notify: Waker::new(&poll, NOTIFY_KEY)?,
+            waiting: Mutex::new(Waiting {
+                poll,
+                events: Events::with_capacity(EVENTS_CAPACITY),
+            }),
+        })
+    }
+
+    /// Starts watching `source` for the interest in `interest`, with events delivered
+    /// with its key. Adding it with `Event::none` watches nothing until `modify` is
+    /// called.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn add(&self, source: &impl Source, interest: Event) -> io::Result<()> {
+        self.modify(source, interest)
+    }
+
+    /// Sets the interest `source` is watched for again, after an event for it has been
+    /// delivered or to change it. `Event::none` stops watching it.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn modify(&self, source: &impl Source, interest: Event) -> io::Result<()> {
+        check_key(interest.key)?;
+        match interest.interests() {
+            Some(interests) => self.registrator.register(source, interest.key, interests),
+            None => match self.registrator.deregister(source) {
+                // It wasn't watched for anything to begin with
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                res => res,
+            },
+        }
+    }
+
+    /// Stops watching `source`.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn delete(&self, source: &impl Source) -> io::Result<()> {
+        self.registrator.deregister(source)
+    }
+
+    /// Starts watching `source` for the interest in `interest`, with events delivered
+    /// with its key. IOCP only tells us about reads, so writable interest fails with
+    /// `Unsupported` unless the `wsapoll` backend is used.
+    #[cfg(target_os = "windows")]
+    pub fn add(&self, source: &mut TcpStream, interest: Event) -> io::Result<()> {
+        self.modify(source, interest)
+    }
+
+    /// Sets the interest `source` is watched for again, after an event for it has been
+    /// delivered. `Event::none` stops watching it and cancels its pending read.
+    #[cfg(target_os = "windows")]
+    pub fn modify(&self, source: &mut TcpStream, interest: Event) -> io::Result<()> {
+        check_key(interest.key)?;
+        #[cfg(not(feature = "wsapoll"))]
+        if interest.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Writable interest is not supported on Windows.",
+            ));
+        }
+        match interest.interests() {
+            Some(interests) => self.registrator.register(source, interest.key, interests),
+            None => self.registrator.deregister(source),
+        }
+    }
+
+    /// Stops watching `source`, and cancels its pending read.
+    #[cfg(target_os = "windows")]
+    pub fn delete(&self, source: &mut TcpStream) -> io::Result<()> {
+        self.registrator.deregister(source)
+    }
+
+    /// Waits for at least one event, for no longer than `timeout`, or until `notify`
+    /// is called, and appends the events to `events`. Returns how many it appended.
+    /// Only one thread can wait at a time, others block until it's done.
+    pub fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<usize> {
+        let mut waiting = self.waiting();
+        let Waiting {
+            poll,
+            events: received,
+        } = &mut *waiting;
+        poll.poll_timeout(received, timeout)?;
+
+        let before = events.len();
+        for event in received.iter() {
+            if event.id() == NOTIFY_KEY {
+                self.notify.reset()?;
+                continue;
+            }
+            events.push(Event {
+                key: event.id(),
+                readable: event.is_readable(),
+                writable: event.is_writable(),
+            });
+        }
+        Ok(events.len() - before)
+    }
+
+    /// Wakes up the thread in `wait`, or the next one to call it.
+    pub fn notify(&self) -> io::Result<()> {
+        self.notify.wake()
+    }
+
+    fn waiting(&self) -> MutexGuard<'_, Waiting> {
+        self.waiting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn check_key(key: usize) -> io::Result<()> {
+    if key >= NOTIFY_KEY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The two highest keys are reserved.",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,330 @@
+//! The bookkeeping every server ends up writing: which stream belongs to which
+//! token, what the server knows about it, and when it's been quiet or open for too
+//! long. `Connections` registers the streams handed to it, keeps a timer per
+//! connection in a `TimerWheel`, and closes the ones that run out of time.
+//!
+//! ```no_run
+//! use minimio::connections::Connections;
+//! use minimio::{socket_pair, Events, Interests, Poll};
+//! use std::time::Duration;
+//!
+//! let mut poll = Poll::new()?;
+//! let mut connections = Connections::new(poll.registrator())
+//!     .idle_timeout(Duration::from_secs(30))
+//!     .max_lifetime(Duration::from_secs(600));
+//! let (stream, _peer) = socket_pair()?;
+//! connections.insert(1, stream, Interests::READABLE, "first client")?;
+//! let mut events = Events::with_capacity(1024);
+//! loop {
+//!     poll.poll(&mut events, connections.poll_timeout())?;
+//!     for event in &events {
+//!         // Read from `connections.get_mut(event.id())` here
+//!         connections.reregister(event.id(), Interests::READABLE)?;
+//!     }
+//!     for closed in connections.expire() {
+//!         println!("closed {}: {:?}", closed.meta, closed.reason);
+//!     }
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::{Clock, Interests, MonotonicClock, Registrator, TimerKey, TimerWheel, Token};
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::Source;
+#[cfg(target_os = "windows")]
+use crate::TcpStream;
+
+/// How precisely connections are timed out. Timeouts are in the seconds, so there's no
+/// point in waking up for every millisecond.
+const RESOLUTION: Duration = Duration::from_millis(10);
+
+/// A stream `Connections` can manage: anything that can be registered on Unix, a
+/// `TcpStream` on Windows.
+pub trait ManagedStream {
+    fn register(
+        &mut self,
+        registrator: &Registrator,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()>;
+
+    fn deregister(&mut self, registrator: &Registrator) -> io::Result<()>;
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+impl<S: Source> ManagedStream for S {
+    fn register(
+        &mut self,
+        registrator: &Registrator,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        registrator.register(self, token, interests)
+    }
+
+    fn deregister(&mut self, registrator: &Registrator) -> io::Result<()> {
+        registrator.deregister(self)
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl ManagedStream for TcpStream {
+    fn register(
+        &mut self,
+        registrator: &Registrator,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        registrator.register(self, token, interests)
+    }
+
+    fn deregister(&mut self, registrator: &Registrator) -> io::Result<()> {
+        registrator.deregister(self)
+    }
+}
+
+/// Why `Connections` closed a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// Nothing happened on it for the idle timeout
+    Idle,
+    /// It was open for the maximum lifetime
+    Lifetime,
+}
+
+/// A connection `Connections::expire` closed, with what the server knew about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Closed<M> {
+    pub token: Token,
+    pub reason: CloseReason,
+    pub meta: M,
+    /// How long it was open
+    pub age: Duration,
+}
+
+#[derive(Debug)]
+struct Entry<S, M> {
+    stream: S,
+    meta: M,
+    opened: Instant,
+    /// When it's closed no matter how busy it is
+    end_of_life: Option<Instant>,
+    timer: Option<TimerKey>,
+}
+
+/// Streams by token, each with metadata of type `M` and closed once it's been idle or
+/// open for too long. Which happens in `expire`, so the timeout passed to `poll` has
+/// to come from `poll_timeout`.
+#[derive(Debug)]
+pub struct Connections<S, M = (), C = MonotonicClock> {
+    registrator: Registrator,
+    entries: HashMap<Token, Entry<S, M>>,
+    timers: TimerWheel<C>,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+}
+
+impl<S: ManagedStream, M> Connections<S, M> {
+    /// Tracks connections registered with `registrator`, without any timeouts.
+    pub fn new(registrator: Registrator) -> Self {
+        Connections::with_clock(registrator, MonotonicClock)
+    }
+}
+
+impl<S: ManagedStream, M, C: Clock> Connections<S, M, C> {
+    /// Like `new`, with the timeouts measured by `clock`.
+    pub fn with_clock(registrator: Registrator, clock: C) -> Self {
+        Connections {
+            registrator,
+            entries: HashMap::new(),
+            timers: TimerWheel::with_clock(clock, RESOLUTION),
+            idle_timeout: None,
+            max_lifetime: None,
+        }
+    }
+
+    /// Closes connections nothing has happened on for `timeout`. What counts as
+    /// something happening is calling `touch`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections once they've been open for `lifetime`, however busy they
+    /// are.
+    pub fn max_lifetime(mut self, lifetime: Duration) -> Self {
+        self.max_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Registers `stream` with `token` and starts tracking it. Fails with
+    /// `AlreadyExists` if a connection with `token` is tracked already.
+    pub fn insert(
+        &mut self,
+        token: Token,
+        mut stream: S,
+        interests: Interests,
+        meta: M,
+    ) -> io::Result<()> {
+        if self.entries.contains_key(&token) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "There already is a connection with this token.",
+            ));
+        }
+        stream.register(&self.registrator, token, interests)?;
+        let opened = self.timers.clock().now();
+        let end_of_life = self.max_lifetime.map(|lifetime| opened + lifetime);
+        let mut entry = Entry {
+            stream,
+            meta,
+            opened,
+            end_of_life,
+            timer: None,
+        };
+        if let Some(deadline) = self.deadline(&entry, opened) {
+            entry.timer = Some(self.timers.insert(deadline, token));
+        }
+        self.entries.insert(token, entry);
+        Ok(())
+    }
+
+    /// Registers the connection with `token` again, to re-arm it after an event or to
+    /// change its interests. Counts as activity, like `touch`.
+    pub fn reregister(&mut self, token: Token, interests: Interests) -> io::Result<()> {
+        let entry = self.entries.get_mut(&token).ok_or_else(not_found)?;
+        entry.stream.register(&self.registrator, token, interests)?;
+        self.touch(token);
+        Ok(())
+    }
+
+    /// Records activity on the connection with `token`, which pushes its idle timeout
+    /// back. Returns false if there is no such connection.
+    pub fn touch(&mut self, token: Token) -> bool {
+        let now = self.timers.clock().now();
+        let (deadline, timer) = match self.entries.get(&token) {
+            Some(entry) => (self.deadline(entry, now), entry.timer),
+            None => return false,
+        };
+        if let (Some(deadline), Some(timer)) = (deadline, timer) {
+            self.timers.reset(timer, deadline);
+        }
+        true
+    }
+
+    /// Stops tracking the connection with `token` and deregisters it, handing back
+    /// its stream and metadata. The stream isn't closed until it's dropped, so it can
+    /// still be flushed.
+    pub fn remove(&mut self, token: Token) -> io::Result<(S, M)> {
+        let mut entry = self.entries.remove(&token).ok_or_else(not_found)?;
+        if let Some(timer) = entry.timer {
+            self.timers.cancel(timer);
+        }
+        entry.stream.deregister(&self.registrator)?;
+        Ok((entry.stream, entry.meta))
+    }
+
+    pub fn get(&self, token: Token) -> Option<&S> {
+        self.entries.get(&token).map(|entry| &entry.stream)
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut S> {
+        self.entries.get_mut(&token).map(|entry| &mut entry.stream)
+    }
+
+    pub fn meta(&self, token: Token) -> Option<&M> {
+        self.entries.get(&token).map(|entry| &entry.meta)
+    }
+
+    pub fn meta_mut(&mut self, token: Token) -> Option<&mut M> {
+        self.entries.get_mut(&token).map(|entry| &mut entry.meta)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How long `poll` can wait before the next connection times out, in the
+    /// milliseconds it takes, or `None` if no connection can time out.
+    /// It can be earlier than that when the wheel has to move timers along.
+    pub fn poll_timeout(&self) -> Option<i32> {
+        // Cancelled timers stay in the wheel until it gets to them
+        if self.timers.is_empty() {
+            return None;
+        }
+        self.timers.poll_timeout(self.timers.clock().now())
+    }
+
+    /// Closes the connections that have timed out and returns them, earliest first.
+    /// To be called after every `poll`.
+    pub fn expire(&mut self) -> Vec<Closed<M>> {
+        let now = self.timers.clock().now();
+        let mut expired = Vec::new();
+        self.timers.advance(now, &mut expired);
+
+        let mut closed = Vec::with_capacity(expired.len());
+        for token in expired {
+            let mut entry = match self.entries.remove(&token) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let reason = match entry.end_of_life {
+                Some(end_of_life) if end_of_life <= now => CloseReason::Lifetime,
+                _ => CloseReason::Idle,
+            };
+            trace!("closing connection {} ({:?})", token, reason);
+            // It's dropped, which closes it, whether or not this works
+            if let Err(e) = entry.stream.deregister(&self.registrator) {
+                debug!("deregistering connection {} failed: {}", token, e);
+            }
+            closed.push(Closed {
+                token,
+                reason,
+                meta: entry.meta,
+                age: now.saturating_duration_since(entry.opened),
+            });
+        }
+        closed
+    }
+
+    /// When `entry` times out if nothing happens on it from `now` on
+    fn deadline(&self, entry: &Entry<S, M>, now: Instant) -> Option<Instant> {
+        let idle = self.idle_timeout.map(|timeout| now + timeout);
+        match (idle, entry.end_of_life) {
+            (Some(idle), Some(end_of_life)) => Some(idle.min(end_of_life)),
+            (idle, end_of_life) => idle.or(end_of_life),
+        }
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        "There is no connection with this token.",
+    )
+}
@@ -0,0 +1,131 @@
+//! A callback for each token, for programs too small to need a `runtime::Handler` or a
+//! `match` on tokens of their own. The callback usually owns the source it handles,
+//! and registers it again through the `Registrator` it's called with.
+use crate::{Event, Events, Poll, Registrator, Token};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+/// How many events a `Dispatcher` created with `new` dispatches per poll
+const DEFAULT_EVENTS: usize = 256;
+
+type Callback = Box<dyn FnMut(&Registrator, &Event) -> ControlFlow<()>>;
+
+/// A `Poll` that calls the callback inserted for the token of each event.
+///
+/// ```no_run
+/// # use minimio::{Dispatcher, Interests, TcpStream};
+/// # use std::io::Read;
+/// # use std::ops::ControlFlow;
+/// let mut dispatcher = Dispatcher::new()?;
+/// let mut stream = TcpStream::connect("127.0.0.1:8080")?;
+/// dispatcher.registrator().register(&mut stream, 1, Interests::READABLE)?;
+/// dispatcher.insert(1, move |registrator, _event| {
+///     let mut buf = [0; 1024];
+///     match stream.read(&mut buf) {
+///         Ok(0) | Err(_) => ControlFlow::Break(()),
+///         Ok(_) => match registrator.register(&mut stream, 1, Interests::READABLE) {
+///             Ok(()) => ControlFlow::Continue(()),
+///             Err(_) => ControlFlow::Break(()),
+///         },
+///     }
+/// });
+/// while !dispatcher.is_empty() {
+///     dispatcher.poll_and_dispatch(None)?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Dispatcher {
+    poll: Poll,
+    registrator: Registrator,
+    events: Events,
+    callbacks: HashMap<Token, Callback>,
+}
+
+impl Dispatcher {
+    pub fn new() -> io::Result<Dispatcher> {
+        Dispatcher::with_capacity(DEFAULT_EVENTS)
+    }
+
+    /// A dispatcher that takes up to `events` events off the queue per poll.
+    pub fn with_capacity(events: usize) -> io::Result<Dispatcher> {
+        let poll = Poll::new()?;
+        Ok(Dispatcher {
+            registrator: poll.registrator(),
+            poll,
+            events: Events::with_capacity(events),
+            callbacks: HashMap::new(),
+        })
+    }
+
+    /// Registers sources with the dispatcher's `Poll`.
+    pub fn registrator(&self) -> &Registrator {
+        &self.registrator
+    }
+
+    /// The `Poll` the events are dispatched from, for what the dispatcher doesn't
+    /// wrap.
+    pub fn poll(&mut self) -> &mut Poll {
+        &mut self.poll
+    }
+
+    /// Calls `callback` for every event with `token` as its id, until it returns
+    /// `ControlFlow::Break` or is removed. Replaces the callback `token` had.
+    pub fn insert(
+        &mut self,
+        token: Token,
+        callback: impl FnMut(&Registrator, &Event) -> ControlFlow<()> + 'static,
+    ) {
+        self.callbacks.insert(token, Box::new(callback));
+    }
+
+    /// Removes the callback of `token`, dropping it along with everything it owns.
+    /// Returns false if there was none.
+    pub fn remove(&mut self, token: Token) -> bool {
+        self.callbacks.remove(&token).is_some()
+    }
+
+    /// How many tokens have a callback.
+    pub fn len(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    /// Polls for events, waiting no longer than `timeout`, and calls the callback of
+    /// each event's token. A callback that returns `ControlFlow::Break` is removed
+    /// right away, and the events for a token without a callback are dropped. Returns
+    /// how many callbacks were called.
+    pub fn poll_and_dispatch(&mut self, timeout: Option<Duration>) -> io::Result<usize> {
+        self.poll.poll_timeout(&mut self.events, timeout)?;
+        let mut called = 0;
+        for event in self.events.iter() {
+            let token = event.id();
+            let callback = match self.callbacks.get_mut(&token) {
+                Some(callback) => callback,
+                None => {
+                    trace!("no callback for event with token {}", token);
+                    continue;
+                }
+            };
+            called += 1;
+            if callback(&self.registrator, event).is_break() {
+                self.callbacks.remove(&token);
+            }
+        }
+        Ok(called)
+    }
+}
+
+impl fmt::Debug for Dispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("poll", &self.poll)
+            .field("tokens", &self.callbacks.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
@@ -0,0 +1,55 @@
+//! A minimal executor that runs a single future to completion on the current thread.
+//! It's meant for examples and small tools, not as a replacement for a real runtime.
+//!
+//! While the future is pending the thread blocks in a `Poll` waiting for the
+//! future's `Waker` to be called. The future's waker wraps one of our own `Waker`s,
+//! so a wakeup is just another event.
+use crate::{Events, Poll, Token};
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::task::{self, Context, Wake};
+
+/// The token our wake source is registered with
+const WAKE_TOKEN: Token = 0;
+
+/// Runs `future` to completion, blocking the current thread while it's pending.
+/// Returns an error if setting up or waiting on the selector fails.
+pub fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    let mut poll = Poll::new()?;
+    let wake_source = Arc::new(WakeSource(crate::Waker::new(&poll, WAKE_TOKEN)?));
+    let waker = task::Waker::from(wake_source.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut future = Box::pin(future);
+    let mut events = Events::with_capacity(8);
+    loop {
+        if let task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Ok(output);
+        }
+
+        // Nobody but the waker produces events on this selector, but we check the
+        // token anyway to not be fooled by spurious wakeups
+        loop {
+            poll.poll(&mut events, None)?;
+            if events.iter().any(|event| event.id() == WAKE_TOKEN) {
+                break;
+            }
+        }
+        wake_source.0.reset()?;
+    }
+}
+
+struct WakeSource(crate::Waker);
+
+impl Wake for WakeSource {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Err(e) = self.0.wake() {
+            debug!("waking up block_on failed: {}", e);
+        }
+    }
+}
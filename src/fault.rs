@@ -0,0 +1,514 @@
+//! Fault injection, for testing how an application copes with the errors and odd
+//! timing a real network produces now and then but a test on the loopback interface
+//! practically never does.
+//!
+//! A `FaultInjector` decides when a fault happens, either from a script of faults to
+//! inject in order, or at random with set probabilities from a seed, so a failing run
+//! can be repeated. It's shared by the wrappers that inject the faults:
+//!
+//! - `FaultySelector` wraps a `Selector`. `select` can fail with `Interrupted`, or hold
+//!   back the events it got until the next `select`.
+//! - `FaultyRegistrator` wraps a `Registrator`. Registering can fail.
+//! - `FaultyStream` wraps a stream. Reads and writes can fail with `WouldBlock` or
+//!   `Interrupted`, and reads can return less than there is.
+//!
+//! ```no_run
+//! # #[cfg(any(
+//! #     target_os = "linux",
+//! #     target_os = "macos",
+//! #     target_os = "dragonfly",
+//! #     target_os = "redox",
+//! #     target_os = "fuchsia",
+//! #     target_os = "aix",
+//! #     target_os = "haiku",
+//! #     target_os = "nto"
+//! # ))]
+//! # fn main() -> std::io::Result<()> {
+//! use minimio::fault::{Fault, FaultInjector, FaultyStream, Probabilities};
+//! use minimio::TcpStream;
+//! use std::io::Read;
+//!
+//! let injector = FaultInjector::new(42, Probabilities::default());
+//! injector.push(Fault::ShortRead(1));
+//! let mut stream = FaultyStream::new(TcpStream::connect("127.0.0.1:8080")?, injector);
+//! let mut buf = [0; 1024];
+//! assert!(stream.read(&mut buf)? <= 1);
+//! # Ok(())
+//! # }
+//! # #[cfg(target_os = "windows")]
+//! # fn main() {}
+//! ```
+use crate::buf_stream::NonBlockingRead;
+use crate::{Events, Interests, Registrator, Selector, Token};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{atomic::AtomicBool, Arc, Mutex, MutexGuard};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::Source;
+#[cfg(target_os = "windows")]
+use crate::TcpStream;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use std::os::unix::io::{AsFd, BorrowedFd};
+
+/// A fault to inject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A read or write fails with `WouldBlock` without touching the stream.
+    WouldBlock,
+    /// A read, write or select fails with `Interrupted`.
+    Interrupted,
+    /// A read returns at most this many bytes. At least one byte is read, since a read
+    /// of 0 would look like the end of the stream.
+    ShortRead(usize),
+    /// The events a select got are held back, and returned from the next select
+    /// instead of waiting for new ones. They look like that select dequeued them, so
+    /// a dispatch latency measured from when it returned doesn't include the delay.
+    DelayEvents,
+    /// A registration fails with an error of this kind.
+    FailRegistration(io::ErrorKind),
+}
+
+/// How likely each fault is at every point it can be injected, from 0.0 (never) to 1.0
+/// (always).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Probabilities {
+    /// Of a read or write failing with `WouldBlock`
+    pub would_block: f64,
+    /// Of a read, write or select failing with `Interrupted`
+    pub interrupted: f64,
+    /// Of a read returning a single byte
+    pub short_read: f64,
+    /// Of the events of a select being held back until the next one
+    pub delayed_events: f64,
+    /// Of a registration failing with `Other`
+    pub registration_failure: f64,
+}
+
+/// Where a fault is injected, which decides which faults can happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Point {
+    Select,
+    Register,
+    Read,
+    Write,
+}
+
+impl Fault {
+    fn applies_to(&self, point: Point) -> bool {
+        match self {
+            Fault::WouldBlock => point == Point::Read || point == Point::Write,
+            Fault::Interrupted => point != Point::Register,
+            Fault::ShortRead(_) => point == Point::Read,
+            Fault::DelayEvents => point == Point::Select,
+            Fault::FailRegistration(_) => point == Point::Register,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    /// xorshift64* state, never 0
+    rng: u64,
+    probabilities: Probabilities,
+    script: VecDeque<Fault>,
+    injected: usize,
+}
+
+impl State {
+    fn next_f64(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let n = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (n >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        // Only roll when it matters, so adding a fault with probability 0 doesn't
+        // change which faults a seed produces
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    fn next_fault(&mut self, point: Point) -> Option<Fault> {
+        let fault = match self.script.front() {
+            Some(fault) if fault.applies_to(point) => self.script.pop_front(),
+            _ => self.roll(point),
+        };
+        if let Some(fault) = fault {
+            self.injected += 1;
+            trace!("injecting {:?}", fault);
+        }
+        fault
+    }
+
+    fn roll(&mut self, point: Point) -> Option<Fault> {
+        let p = self.probabilities;
+        match point {
+            Point::Select if self.chance(p.interrupted) => Some(Fault::Interrupted),
+            Point::Select if self.chance(p.delayed_events) => Some(Fault::DelayEvents),
+            Point::Register if self.chance(p.registration_failure) => {
+                Some(Fault::FailRegistration(io::ErrorKind::Other))
+            }
+            Point::Read | Point::Write if self.chance(p.would_block) => Some(Fault::WouldBlock),
+            Point::Read | Point::Write if self.chance(p.interrupted) => Some(Fault::Interrupted),
+            Point::Read if self.chance(p.short_read) => Some(Fault::ShortRead(1)),
+            _ => None,
+        }
+    }
+}
+
+/// Decides which faults to inject and when. Clones share their script, random state and
+/// count, so one injector can drive all the wrappers of a test.
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    state: Arc<Mutex<State>>,
+}
+
+impl FaultInjector {
+    /// Injects faults at random with `probabilities`. The same `seed` gives the same
+    /// faults at the same points every time.
+    pub fn new(seed: u64, probabilities: Probabilities) -> FaultInjector {
+        FaultInjector {
+            state: Arc::new(Mutex::new(State {
+                rng: seed.max(1),
+                probabilities,
+                script: VecDeque::new(),
+                injected: 0,
+            })),
+        }
+    }
+
+    /// Adds `fault` to the end of the script. Scripted faults are injected in order,
+    /// each at the first point after the one before it where it can happen, and before
+    /// any random ones.
+    pub fn push(&self, fault: Fault) {
+        self.state().script.push_back(fault);
+    }
+
+    pub fn set_probabilities(&self, probabilities: Probabilities) {
+        self.state().probabilities = probabilities;
+    }
+
+    /// How many faults have been injected so far.
+    pub fn injected(&self) -> usize {
+        self.state().injected
+    }
+
+    fn next_fault(&self, point: Point) -> Option<Fault> {
+        self.state().next_fault(point)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn interrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "injected fault")
+}
+
+/// A `Selector` that fails or holds back events when its `FaultInjector` says so.
+#[derive(Debug)]
+pub struct FaultySelector {
+    selector: Selector,
+    injector: FaultInjector,
+    delayed: Mutex<Events>,
+}
+
+impl FaultySelector {
+    pub fn new(selector: Selector, injector: FaultInjector) -> FaultySelector {
+        FaultySelector {
+            selector,
+            injector,
+            delayed: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        let mut delayed = self.delayed.lock().unwrap_or_else(|e| e.into_inner());
+        if !delayed.is_empty() {
+            events.clear();
+            events.append(&mut delayed);
+            return Ok(());
+        }
+
+        match self.injector.next_fault(Point::Select) {
+            Some(Fault::Interrupted) => Err(interrupted()),
+            Some(Fault::DelayEvents) => {
+                self.selector.select(events, timeout_ms)?;
+                delayed.append(events);
+                Ok(())
+            }
+            _ => self.selector.select(events, timeout_ms),
+        }
+    }
+
+    /// A registrator for the wrapped selector whose registrations can fail.
+    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> FaultyRegistrator {
+        FaultyRegistrator {
+            registrator: self.selector.registrator(is_poll_dead),
+            injector: self.injector.clone(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &Selector {
+        &self.selector
+    }
+}
+
+/// A `Registrator` whose registrations fail when its `FaultInjector` says so. A failed
+/// registration doesn't reach the OS.
+#[derive(Debug)]
+pub struct FaultyRegistrator {
+    registrator: Registrator,
+    injector: FaultInjector,
+}
+
+impl FaultyRegistrator {
+    pub fn new(registrator: Registrator, injector: FaultInjector) -> FaultyRegistrator {
+        FaultyRegistrator {
+            registrator,
+            injector,
+        }
+    }
+
+    fn fail_registration(&self) -> io::Result<()> {
+        match self.injector.next_fault(Point::Register) {
+            Some(Fault::FailRegistration(kind)) => Err(io::Error::new(kind, "injected fault")),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn register(
+        &self,
+        source: &impl Source,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        self.fail_registration()?;
+        self.registrator.register(source, token, interests)
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn register(
+        &self,
+        soc: &mut TcpStream,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        self.fail_registration()?;
+        self.registrator.register(soc, token, interests)
+    }
+
+    pub fn get_ref(&self) -> &Registrator {
+        &self.registrator
+    }
+}
+
+/// A stream whose reads and writes fail or come up short when its `FaultInjector`
+/// says so.
+#[derive(Debug)]
+pub struct FaultyStream<T> {
+    inner: T,
+    injector: FaultInjector,
+}
+
+impl<T> FaultyStream<T> {
+    pub fn new(inner: T, injector: FaultInjector) -> FaultyStream<T> {
+        FaultyStream { inner, injector }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Injects a read fault, or returns how much of `len` bytes the read may fill
+    fn read_fault(&self, len: usize) -> io::Result<usize> {
+        match self.injector.next_fault(Point::Read) {
+            Some(Fault::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Some(Fault::Interrupted) => Err(interrupted()),
+            Some(Fault::ShortRead(n)) => Ok(len.min(n.max(1))),
+            _ => Ok(len),
+        }
+    }
+}
+
+impl<T: Read> Read for FaultyStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.read_fault(buf.len())?;
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl<T: NonBlockingRead> NonBlockingRead for FaultyStream<T> {
+    fn read_nonblocking(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.read_fault(buf.len())?;
+        self.inner.read_nonblocking(&mut buf[..len])
+    }
+}
+
+impl<T: Write> Write for FaultyStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.injector.next_fault(Point::Write) {
+            Some(Fault::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Some(Fault::Interrupted) => Err(interrupted()),
+            _ => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+impl<T: AsFd> AsFd for FaultyStream<T> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_faults_are_injected_in_order_where_they_apply() {
+        let injector = FaultInjector::new(1, Probabilities::default());
+        injector.push(Fault::ShortRead(2));
+        injector.push(Fault::Interrupted);
+
+        assert_eq!(None, injector.next_fault(Point::Register));
+        assert_eq!(Some(Fault::ShortRead(2)), injector.next_fault(Point::Read));
+        assert_eq!(Some(Fault::Interrupted), injector.next_fault(Point::Select));
+        assert_eq!(None, injector.next_fault(Point::Read));
+        assert_eq!(2, injector.injected());
+    }
+
+    #[test]
+    fn the_same_seed_injects_the_same_faults() {
+        let probabilities = Probabilities {
+            would_block: 0.3,
+            short_read: 0.3,
+            ..Probabilities::default()
+        };
+        let faults = |seed| {
+            let injector = FaultInjector::new(seed, probabilities);
+            (0..100)
+                .map(|_| injector.next_fault(Point::Read))
+                .collect::<Vec<_>>()
+        };
+        let first = faults(7);
+        assert_eq!(first, faults(7));
+        assert!(first.contains(&Some(Fault::WouldBlock)));
+        assert!(first.contains(&Some(Fault::ShortRead(1))));
+        assert!(first.contains(&None));
+    }
+
+    #[test]
+    fn short_reads_and_would_block_reach_the_reader() {
+        let injector = FaultInjector::new(1, Probabilities::default());
+        let mut stream = FaultyStream::new(&b"hello"[..], injector.clone());
+        injector.push(Fault::WouldBlock);
+        injector.push(Fault::ShortRead(2));
+
+        let mut buf = [0; 8];
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        assert_eq!(2, stream.read(&mut buf).unwrap());
+        assert_eq!(3, stream.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn select_can_be_interrupted() {
+        let injector = FaultInjector::new(1, Probabilities::default());
+        let selector = FaultySelector::new(Selector::new().unwrap(), injector.clone());
+        let mut events = Events::with_capacity(4);
+
+        injector.push(Fault::Interrupted);
+        let err = selector.select(&mut events, Some(0)).unwrap_err();
+        assert_eq!(io::ErrorKind::Interrupted, err.kind());
+        selector.select(&mut events, Some(0)).unwrap();
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    #[test]
+    fn registrations_fail_and_events_are_delayed() {
+        use crate::socket_pair;
+
+        let injector = FaultInjector::new(1, Probabilities::default());
+        let selector = FaultySelector::new(Selector::new().unwrap(), injector.clone());
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let (a, mut b) = socket_pair().unwrap();
+        let mut events = Events::with_capacity(4);
+
+        injector.push(Fault::FailRegistration(io::ErrorKind::PermissionDenied));
+        let err = registrator
+            .register(&a, 1, Interests::READABLE)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+        registrator.register(&a, 1, Interests::READABLE).unwrap();
+
+        b.write_all(b"ping").unwrap();
+        injector.push(Fault::DelayEvents);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert!(events.is_empty());
+        selector.select(&mut events, Some(0)).unwrap();
+        assert_eq!(1, events[0].id());
+    }
+}
@@ -0,0 +1,109 @@
+//! Suppressing events that don't tell the consumer anything new. A source that's
+//! registered again before everything it had to offer was consumed reports the same
+//! readiness again right away, and a level-triggered source keeps doing so. With the
+//! filter on for its token, such an event is only delivered if the readiness is
+//! different from what was delivered last, or once the consumer says it's caught up.
+use crate::{Event, Events, Poll, Registry, Token};
+use std::collections::HashMap;
+use std::sync::MutexGuard;
+
+const READABLE: u8 = 0b01;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto",
+    all(target_os = "windows", feature = "wsapoll")
+))]
+const WRITABLE: u8 = 0b10;
+
+/// The readiness last delivered for each filtered token, or `None` if nothing has
+/// been delivered since the consumer caught up.
+pub(crate) type Filters = HashMap<Token, Option<u8>>;
+
+impl Registry {
+    /// Turns the filter on for `token`: after an event for it has been delivered,
+    /// events with the same readiness are dropped from what `poll` returns until
+    /// `readiness_consumed` is called for the token.
+    pub fn suppress_unchanged(&self, token: Token) {
+        self.filters().entry(token).or_insert(None);
+    }
+
+    /// Tells the filter that everything the source of `token` was ready for has been
+    /// consumed (typically, reading returned `WouldBlock`), so the next event for it
+    /// is delivered whatever its readiness.
+    pub fn readiness_consumed(&self, token: Token) {
+        if let Some(last) = self.filters().get_mut(&token) {
+            *last = None;
+        }
+    }
+
+    /// Turns the filter off for `token`, for example when its source is closed and
+    /// the token might be reused for another one.
+    pub fn remove_filter(&self, token: Token) {
+        self.filters().remove(&token);
+    }
+
+    fn filters(&self) -> MutexGuard<'_, Filters> {
+        self.filters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Poll {
+    /// Drops the events the filter says were delivered already.
+    pub(crate) fn filter_events(&mut self, events: &mut Events) {
+        let filters = self
+            .registry
+            .filters
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        if filters.is_empty() {
+            return;
+        }
+        events.retain(|event| {
+            let last = match filters.get_mut(&event.id()) {
+                Some(last) => last,
+                None => return true,
+            };
+            let readiness = readiness(event);
+            if *last == Some(readiness) {
+                trace!("suppressed unchanged event for token {}", event.id());
+                return false;
+            }
+            *last = Some(readiness);
+            true
+        });
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto",
+    all(target_os = "windows", feature = "wsapoll")
+))]
+fn readiness(event: &Event) -> u8 {
+    let mut readiness = 0;
+    if event.is_readable() {
+        readiness |= READABLE;
+    }
+    if event.is_writable() {
+        readiness |= WRITABLE;
+    }
+    readiness
+}
+
+/// IOCP only tells us a read completed
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+fn readiness(_event: &Event) -> u8 {
+    READABLE
+}
@@ -0,0 +1,864 @@
+//! The Fuchsia backend, on a Zircon port. Fuchsia has no fds in the kernel: fdio
+//! emulates them on top of kernel objects, and tells us which handle to wait on for
+//! an fd and which of its signals mean the fd is ready. We then ask the kernel to
+//! queue a packet on the port the next time one of those signals is asserted. The
+//! kernel only does that once per wait, so registrations are oneshot on their own,
+//! like they are on the other backends.
+//!
+//! A packet's key identifies the fd it's for, and the registration too, so packets
+//! from a wait that was replaced before we got to them can be told apart.
+use crate::backlog::FullSelects;
+use crate::registrations::Registrations;
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::unix::{validate_token, Change};
+use crate::{Events, Interests, Source, Token};
+use std::collections::HashMap;
+use std::io::{self, IoSliceMut, Read, Write};
+use std::net;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    mpsc, Arc, Mutex, MutexGuard,
+};
+use std::time::Duration;
+
+/// The key of the user packet that registrators queue to wake up `select`
+const KICK_KEY: u64 = u64::MAX;
+/// The key of the user packet `close_loop` queues
+const CLOSE_KEY: u64 = u64::MAX - 1;
+
+#[derive(Debug)]
+pub struct Registrator {
+    /// Shared with the `Selector`, so the port stays open for as long as anybody can
+    /// register with it
+    port: Arc<Port>,
+    is_poll_dead: Arc<AtomicBool>,
+    changes: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+}
+
+impl Registrator {
+    pub fn register(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let fd = source.as_fd().as_raw_fd();
+        self.stats.change();
+        self.port.watch(fd, token, interests)?;
+        self.registrations.insert(fd, token, interests);
+        Ok(())
+    }
+
+    /// fdio has no send low-water mark, so this returns an error of kind
+    /// `Unsupported`.
+    pub fn register_with_send_lowat(
+        &self,
+        _source: &impl Source,
+        _token: usize,
+        interests: Interests,
+        _send_lowat: usize,
+    ) -> io::Result<()> {
+        if !interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A send low-water mark needs WRITABLE interest.",
+            ));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Fuchsia has no send low-water mark.",
+        ))
+    }
+
+    /// Like `register`, but the registration is queued for the polling thread, which
+    /// applies it at the start of its next `select`. We queue a packet on the port so
+    /// that happens right away.
+    ///
+    /// Since the registration is applied later, errors from applying it can't be
+    /// returned from here. They're logged, and the registration is dropped.
+    pub fn register_deferred(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let change = Change {
+            fd: source.as_fd().as_raw_fd(),
+            token,
+            interests,
+        };
+        if self.changes.send(change).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        trace!(
+            "queued registration of fd {} with token {}",
+            change.fd,
+            token
+        );
+        self.stats.wakeup();
+        self.port.kick()
+    }
+
+    /// Cancels the wait for `source`, so no more events are reported for it (one that
+    /// `select` has already returned can't be taken back). Deregistering a source that
+    /// isn't registered, which includes one that has been reported since it was last
+    /// registered, is not an error.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        if self.port.is_watched(fd) {
+            self.stats.change();
+        }
+        self.port.unwatch(fd)?;
+        self.registrations.remove(fd);
+        Ok(())
+    }
+
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self
+            .is_poll_dead
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        // `select` turns this into a readable event with token 0, which is what the
+        // other backends wake it up with
+        debug!("closing event loop on port {}", self.port.handle);
+        self.port.queue(CLOSE_KEY)
+    }
+}
+
+/// A port, and the waits it has pending.
+#[derive(Debug)]
+struct Port {
+    handle: ffi::zx_handle_t,
+    /// The fds with a pending wait. An fd is taken out once its packet has been
+    /// received.
+    watched: Mutex<HashMap<RawFd, Watch>>,
+    /// Bumped for every wait, so every wait has a key of its own
+    generation: AtomicU32,
+    /// Whether a kick packet is queued and not received yet. One is enough to wake up
+    /// `select`, and the port would keep every one of them otherwise.
+    kicked: AtomicBool,
+}
+
+/// A pending wait for an fd.
+#[derive(Debug)]
+struct Watch {
+    key: u64,
+    token: Token,
+    /// Held until the wait is over, since fdio needs it to make sense of the signals
+    io: *mut ffi::fdio_t,
+    /// The handle fdio told us to wait on. It belongs to `io`.
+    handle: ffi::zx_handle_t,
+}
+
+// fdio objects are reference counted and can be used from any thread
+unsafe impl Send for Watch {}
+
+impl Port {
+    fn new() -> io::Result<Port> {
+        let mut handle = ffi::ZX_HANDLE_INVALID;
+        zx_result(unsafe { ffi::zx_port_create(0, &mut handle) })?;
+        Ok(Port {
+            handle,
+            watched: Mutex::default(),
+            generation: AtomicU32::new(0),
+            kicked: AtomicBool::new(false),
+        })
+    }
+
+    /// Starts waiting for `fd` to become ready for `interests`, replacing the wait
+    /// it already has. An fd that's already ready is reported right away.
+    fn watch(&self, fd: RawFd, token: Token, interests: Interests) -> io::Result<()> {
+        let mut events = 0;
+        if interests.is_readable() {
+            events |= ffi::POLLIN | ffi::POLLRDHUP;
+        }
+        if interests.is_writable() {
+            events |= ffi::POLLOUT;
+        }
+        // Locked while we wait, so the table always agrees with the kernel
+        let mut watched = self.watched();
+        if let Some(watch) = watched.remove(&fd) {
+            self.cancel(fd, watch);
+        }
+
+        let io = unsafe { ffi::fdio_unsafe_fd_to_io(fd) };
+        if io.is_null() {
+            return Err(io::Error::from_raw_os_error(ffi::EBADF));
+        }
+        let mut handle = ffi::ZX_HANDLE_INVALID;
+        let mut signals = 0;
+        unsafe { ffi::fdio_unsafe_wait_begin(io, events, &mut handle, &mut signals) };
+        if handle == ffi::ZX_HANDLE_INVALID {
+            unsafe { ffi::fdio_unsafe_release(io) };
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "fd can't be waited on.",
+            ));
+        }
+
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let key = (generation as u64) << 32 | fd as u32 as u64;
+        let status = unsafe {
+            ffi::zx_object_wait_async(handle, self.handle, key, signals, ffi::ZX_WAIT_ASYNC_ONCE)
+        };
+        if let Err(e) = zx_result(status) {
+            unsafe { ffi::fdio_unsafe_release(io) };
+            debug!("registering fd {} with token {} failed: {}", fd, token, e);
+            return Err(e);
+        }
+        watched.insert(
+            fd,
+            Watch {
+                key,
+                token,
+                io,
+                handle,
+            },
+        );
+        debug!(
+            "registered fd {} with token {} for {}",
+            fd, token, interests
+        );
+        Ok(())
+    }
+
+    fn unwatch(&self, fd: RawFd) -> io::Result<()> {
+        if let Some(watch) = self.watched().remove(&fd) {
+            self.cancel(fd, watch);
+            debug!("deregistered fd {}", fd);
+        }
+        Ok(())
+    }
+
+    /// Cancels the wait, and takes its packet off the port if it was queued already.
+    fn cancel(&self, fd: RawFd, watch: Watch) {
+        let status = unsafe { ffi::zx_port_cancel(self.handle, watch.handle, watch.key) };
+        // Not finding the wait only means its packet is already out of the port, and
+        // will be dropped as stale when it's received
+        if status != ffi::ZX_OK && status != ffi::ZX_ERR_NOT_FOUND {
+            debug!(
+                "cancelling the wait for fd {} failed: {}",
+                fd,
+                zx_error(status)
+            );
+        }
+        unsafe { ffi::fdio_unsafe_release(watch.io) };
+    }
+
+    fn is_watched(&self, fd: RawFd) -> bool {
+        self.watched().contains_key(&fd)
+    }
+
+    /// Turns the packet of a wait that's over into an event, and forgets the wait.
+    /// Packets of waits that were cancelled or replaced since give `None`.
+    fn finish(&self, packet: &ffi::zx_port_packet_t) -> Option<Event> {
+        let fd = packet.key as u32 as RawFd;
+        let mut watched = self.watched();
+        match watched.get(&fd) {
+            Some(watch) if watch.key == packet.key => (),
+            _ => {
+                trace!("dropping stale packet for fd {}", fd);
+                return None;
+            }
+        }
+        let watch = watched.remove(&fd)?;
+        let mut events = 0;
+        unsafe {
+            ffi::fdio_unsafe_wait_end(watch.io, packet.signal.observed, &mut events);
+            ffi::fdio_unsafe_release(watch.io);
+        }
+        Some(Event {
+            token: watch.token,
+            events,
+        })
+    }
+
+    /// Cancels every wait.
+    fn clear(&self) {
+        for (fd, watch) in self.watched().drain() {
+            self.cancel(fd, watch);
+        }
+    }
+
+    fn kick(&self) -> io::Result<()> {
+        if self.kicked.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.queue(KICK_KEY)
+    }
+
+    fn queue(&self, key: u64) -> io::Result<()> {
+        let packet = ffi::zx_port_packet_t {
+            key,
+            ..Default::default()
+        };
+        zx_result(unsafe { ffi::zx_port_queue(self.handle, &packet) })
+    }
+
+    /// Waits until `deadline` for a packet. Returns `None` if there was none by then.
+    fn wait(&self, deadline: ffi::zx_time_t) -> io::Result<Option<ffi::zx_port_packet_t>> {
+        let mut packet = ffi::zx_port_packet_t::default();
+        match unsafe { ffi::zx_port_wait(self.handle, deadline, &mut packet) } {
+            ffi::ZX_OK => Ok(Some(packet)),
+            ffi::ZX_ERR_TIMED_OUT => Ok(None),
+            status => Err(zx_error(status)),
+        }
+    }
+
+    fn watched(&self) -> MutexGuard<'_, HashMap<RawFd, Watch>> {
+        // Every change is a single map operation, so a panic while holding the lock
+        // can't leave the map half changed
+        self.watched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        self.clear();
+        unsafe { ffi::zx_handle_close(self.handle) };
+    }
+}
+
+#[derive(Debug)]
+pub struct Selector {
+    port: Arc<Port>,
+    changes: mpsc::Receiver<Change>,
+    change_sender: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+    full_selects: FullSelects,
+}
+
+impl Selector {
+    pub fn new() -> io::Result<Self> {
+        let (change_sender, changes) = mpsc::channel();
+        Ok(Selector {
+            port: Arc::new(Port::new()?),
+            changes,
+            change_sender,
+            registrations: Arc::default(),
+            stats: Arc::default(),
+            full_selects: FullSelects::default(),
+        })
+    }
+
+    /// Deregisters every source registered with this selector or one of its
+    /// registrators, and drops the registrations still queued with
+    /// `Registrator::register_deferred`, so the selector can be reused as if it was
+    /// new. Wakers are deregistered as well and need to be registered again.
+    ///
+    /// A `select` already waiting in another thread keeps waiting on the old
+    /// registrations, and registrations made while this runs may be lost, so it's
+    /// meant for when nothing else is using the selector.
+    pub fn clear(&self) -> io::Result<()> {
+        for change in self.changes.try_iter() {
+            trace!("dropping deferred registration of fd {}", change.fd);
+        }
+        self.port.clear();
+        self.registrations.clear();
+        debug!("cleared all registrations on port {}", self.port.handle);
+        Ok(())
+    }
+
+    /// Applies the registrations queued with `Registrator::register_deferred`.
+    fn apply_changes(&self) {
+        for change in self.changes.try_iter() {
+            self.stats.change();
+            match self.port.watch(change.fd, change.token, change.interests) {
+                Ok(()) => self
+                    .registrations
+                    .insert(change.fd, change.token, change.interests),
+                Err(e) => debug!(
+                    "dropping deferred registration of fd {} with token {}: {}",
+                    change.fd, change.token, e
+                ),
+            }
+        }
+    }
+
+    /// This function blocks and waits until an event has been recieved. `timeout` None means
+    /// the poll will never time out.
+    pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        let timeout = timeout_ms.map(|ms| Duration::from_millis(ms.max(0) as u64));
+        self.select_timeout(events, timeout)
+    }
+
+    /// Like `select`, but the timeout isn't limited to whole milliseconds. Zircon
+    /// deadlines are in nanoseconds, so it's as precise as the kernel's timers are.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        // The port hands out one packet at a time, and the wait for it is over once
+        // we have it, so there has to be somewhere to put its event
+        if events.capacity() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No room for events.",
+            ));
+        }
+        let deadline = match timeout {
+            Some(timeout) => unsafe {
+                ffi::zx_deadline_after(timeout.as_nanos().min(i64::MAX as u128) as i64)
+            },
+            None => ffi::ZX_TIME_INFINITE,
+        };
+        events.clear();
+        loop {
+            self.apply_changes();
+            trace!(
+                "waiting on port {} with timeout {:?}",
+                self.port.handle,
+                timeout
+            );
+            self.stats.wait();
+            let mut packet = match self.port.wait(deadline) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("waiting on port {} failed: {}", self.port.handle, e);
+                    return Err(e);
+                }
+            };
+            // Whatever else is queued already comes along
+            let mut kicked = false;
+            loop {
+                match packet.key {
+                    KICK_KEY => {
+                        self.port.kicked.store(false, Ordering::SeqCst);
+                        kicked = true;
+                    }
+                    CLOSE_KEY => events.push(Event {
+                        token: 0,
+                        events: ffi::POLLIN,
+                    }),
+                    _ => events.extend(self.port.finish(&packet)),
+                }
+                if events.len() == events.capacity() {
+                    break;
+                }
+                match self.port.wait(0)? {
+                    Some(next) => packet = next,
+                    None => break,
+                }
+            }
+            // Being kicked only means there are registrations to apply, so if nothing
+            // else happened we go back to waiting
+            if events.is_empty() && kicked {
+                continue;
+            }
+            break;
+        }
+        trace!(
+            "port {} woke up with {} events",
+            self.port.handle,
+            events.len()
+        );
+        self.full_selects.record(events.len(), events.capacity());
+        Ok(())
+    }
+
+    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
+        Registrator {
+            port: self.port.clone(),
+            is_poll_dead,
+            changes: self.change_sender.clone(),
+            registrations: self.registrations.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Each of those probably left packets behind on the port, so a count that
+    /// keeps growing means the loop isn't keeping up and should shed load or poll with
+    /// a larger buffer. 0 if the last select had room to spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Event {
+    token: Token,
+    /// `poll` events, as fdio translated the signals
+    events: u32,
+}
+
+impl Event {
+    pub fn id(&self) -> Token {
+        self.token
+    }
+
+    /// Returns true if the source can be read from, or has hung up.
+    pub fn is_readable(&self) -> bool {
+        self.events & (ffi::POLLIN | ffi::POLLRDHUP | ffi::POLLHUP) != 0
+    }
+
+    /// Returns true if the source can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.events & ffi::POLLOUT != 0
+    }
+
+    /// An event the way a wait would have reported it, for events that are rebuilt
+    /// after being held back.
+    pub(crate) fn with_readiness(token: Token, readable: bool, writable: bool) -> Event {
+        let mut events = 0;
+        if readable {
+            events |= ffi::POLLIN;
+        }
+        if writable {
+            events |= ffi::POLLOUT;
+        }
+        Event { token, events }
+    }
+
+    /// Adds the readiness of `other`, an event for the same token, to this one. A
+    /// wait covers all of an fd's readiness, so they can always be merged.
+    pub(crate) fn merge(&mut self, other: &Event) -> bool {
+        self.events |= other.events;
+        true
+    }
+}
+
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    pub fn connect(adr: impl net::ToSocketAddrs) -> io::Result<Self> {
+        // Like on the other platforms this blocks while the connection is established
+        let stream = net::TcpStream::connect(adr)?;
+        TcpStream::from_std(stream)
+    }
+
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream { inner: stream })
+    }
+
+    /// `read` leaves the socket blocking, so anything that relies on getting
+    /// `WouldBlock` has to set it back first.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        self.inner.set_nonblocking(true)
+    }
+
+    /// Reads without changing the blocking mode, unlike `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+/// The netstack's connection statistics aren't read on Fuchsia.
+pub(crate) fn tcp_info(_stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_INFO isn't supported on Fuchsia.",
+    ))
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Blocking while we read, like the other backends do. A caller that wants
+        // `WouldBlock` uses `read_ready` instead.
+        self.inner.set_nonblocking(false)?;
+
+        (&self.inner).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        (&self.inner).read_vectored(bufs)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+fn zx_result(status: ffi::zx_status_t) -> io::Result<()> {
+    if status == ffi::ZX_OK {
+        Ok(())
+    } else {
+        Err(zx_error(status))
+    }
+}
+
+/// Zircon statuses aren't errnos, so they're mapped to the closest kind, and kept
+/// in the message.
+fn zx_error(status: ffi::zx_status_t) -> io::Error {
+    let kind = match status {
+        ffi::ZX_ERR_NO_MEMORY | ffi::ZX_ERR_NO_RESOURCES => io::ErrorKind::OutOfMemory,
+        ffi::ZX_ERR_INVALID_ARGS | ffi::ZX_ERR_BAD_HANDLE => io::ErrorKind::InvalidInput,
+        ffi::ZX_ERR_ACCESS_DENIED => io::ErrorKind::PermissionDenied,
+        ffi::ZX_ERR_TIMED_OUT => io::ErrorKind::TimedOut,
+        ffi::ZX_ERR_NOT_SUPPORTED => io::ErrorKind::Unsupported,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("zx status {}", status))
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    pub type zx_handle_t = u32;
+    pub type zx_status_t = i32;
+    pub type zx_signals_t = u32;
+    pub type zx_time_t = i64;
+    pub type zx_duration_t = i64;
+
+    /// What fdio knows about an fd. We only ever hold pointers to it.
+    #[repr(C)]
+    pub struct fdio_t {
+        _private: [u8; 0],
+    }
+
+    pub const ZX_HANDLE_INVALID: zx_handle_t = 0;
+    pub const ZX_TIME_INFINITE: zx_time_t = i64::MAX;
+    pub const ZX_WAIT_ASYNC_ONCE: u32 = 0;
+
+    // https://fuchsia.dev/fuchsia-src/reference/errors
+    pub const ZX_OK: zx_status_t = 0;
+    pub const ZX_ERR_NOT_SUPPORTED: zx_status_t = -2;
+    pub const ZX_ERR_NO_RESOURCES: zx_status_t = -3;
+    pub const ZX_ERR_NO_MEMORY: zx_status_t = -4;
+    pub const ZX_ERR_INVALID_ARGS: zx_status_t = -10;
+    pub const ZX_ERR_BAD_HANDLE: zx_status_t = -11;
+    pub const ZX_ERR_TIMED_OUT: zx_status_t = -21;
+    pub const ZX_ERR_NOT_FOUND: zx_status_t = -25;
+    pub const ZX_ERR_ACCESS_DENIED: zx_status_t = -30;
+
+    pub const EBADF: i32 = 9;
+
+    pub const POLLIN: u32 = 0x001;
+    pub const POLLOUT: u32 = 0x004;
+    pub const POLLHUP: u32 = 0x010;
+    pub const POLLRDHUP: u32 = 0x2000;
+
+    /// https://fuchsia.dev/fuchsia-src/reference/syscalls/port_wait
+    ///
+    /// The union at the end is only ever a `zx_packet_signal_t` for the waits we
+    /// queue. User packets, which we queue ourselves, only need the key.
+    #[derive(Debug, Clone, Copy, Default)]
+    #[repr(C)]
+    pub struct zx_port_packet_t {
+        pub key: u64,
+        pub packet_type: u32,
+        pub status: zx_status_t,
+        pub signal: zx_packet_signal_t,
+    }
+
+    #[derive(Debug, Clone, Copy, Default)]
+    #[repr(C)]
+    pub struct zx_packet_signal_t {
+        pub trigger: zx_signals_t,
+        pub observed: zx_signals_t,
+        pub count: u64,
+        pub timestamp: zx_time_t,
+        pub reserved1: u64,
+    }
+
+    #[link(name = "zircon")]
+    extern "C" {
+        /// https://fuchsia.dev/fuchsia-src/reference/syscalls/port_create
+        pub fn zx_port_create(options: u32, out: *mut zx_handle_t) -> zx_status_t;
+
+        /// https://fuchsia.dev/fuchsia-src/reference/syscalls/object_wait_async
+        pub fn zx_object_wait_async(
+            handle: zx_handle_t,
+            port: zx_handle_t,
+            key: u64,
+            signals: zx_signals_t,
+            options: u32,
+        ) -> zx_status_t;
+
+        pub fn zx_port_wait(
+            handle: zx_handle_t,
+            deadline: zx_time_t,
+            packet: *mut zx_port_packet_t,
+        ) -> zx_status_t;
+
+        /// https://fuchsia.dev/fuchsia-src/reference/syscalls/port_queue
+        pub fn zx_port_queue(handle: zx_handle_t, packet: *const zx_port_packet_t) -> zx_status_t;
+
+        /// https://fuchsia.dev/fuchsia-src/reference/syscalls/port_cancel
+        pub fn zx_port_cancel(port: zx_handle_t, source: zx_handle_t, key: u64) -> zx_status_t;
+
+        pub fn zx_deadline_after(nanoseconds: zx_duration_t) -> zx_time_t;
+
+        pub fn zx_handle_close(handle: zx_handle_t) -> zx_status_t;
+    }
+
+    // https://fuchsia.dev/reference/cpp/fdio/unsafe.h
+    #[link(name = "fdio")]
+    extern "C" {
+        /// Takes a reference to the fdio object of `fd`, or returns null if it isn't
+        /// open
+        pub fn fdio_unsafe_fd_to_io(fd: i32) -> *mut fdio_t;
+
+        pub fn fdio_unsafe_release(io: *mut fdio_t);
+
+        /// Translates `poll` events into the handle and signals to wait on
+        pub fn fdio_unsafe_wait_begin(
+            io: *mut fdio_t,
+            events: u32,
+            handle: *mut zx_handle_t,
+            signals: *mut zx_signals_t,
+        );
+
+        /// Translates the signals a wait observed back into `poll` events
+        pub fn fdio_unsafe_wait_end(io: *mut fdio_t, signals: zx_signals_t, events: *mut u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_pair;
+    use std::time::Instant;
+
+    fn selector() -> (Selector, Registrator) {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        (selector, registrator)
+    }
+
+    #[test]
+    fn registrations_are_oneshot() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        registrator.register(&a, 3, Interests::READABLE).unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![3], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        assert!(events[0].is_readable());
+
+        // Still readable, but not waited for anymore until it's registered again
+        b.write_all(b"pong").unwrap();
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+        registrator.register(&a, 3, Interests::READABLE).unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+    }
+
+    #[test]
+    fn registering_again_replaces_the_token() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        b.write_all(b"ping").unwrap();
+        // The first wait's packet is queued right away, and has to be cancelled
+        registrator.register(&a, 1, Interests::READABLE).unwrap();
+        registrator.register(&a, 2, Interests::READABLE).unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![2], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn deferred_registrations_wake_up_select() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        b.write_all(b"ping").unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            registrator
+                .register_deferred(&a, 5, Interests::READABLE)
+                .unwrap();
+            a
+        });
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, None).unwrap();
+        assert_eq!(5, events[0].id());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn select_times_out() {
+        let (selector, _registrator) = selector();
+        let mut events = Vec::with_capacity(4);
+        let started = Instant::now();
+        selector
+            .select_timeout(&mut events, Some(Duration::from_millis(30)))
+            .unwrap();
+        assert!(events.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn deregistered_and_cleared_sources_are_quiet() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        let (c, mut d) = socket_pair().unwrap();
+        registrator.register(&a, 1, Interests::READABLE).unwrap();
+        registrator.register(&c, 2, Interests::READABLE).unwrap();
+        registrator.deregister(&a).unwrap();
+        // Nothing is registered anymore, which isn't an error either
+        registrator.deregister(&a).unwrap();
+        selector.clear().unwrap();
+        b.write_all(b"ping").unwrap();
+        d.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,507 @@
+//! An alternative to the kqueue selector for programs that have to do their work on a
+//! dispatch queue, GUI apps on the main queue in particular, where nothing may block
+//! in `kevent`. Every registration is a dispatch source on the queue, and its event
+//! handler queues an `Event` just like `kevent` would have returned it, so the rest of
+//! the program keeps using the same tokens and `Events`.
+//!
+//! Registrations are oneshot like with the kqueue selector: a source is suspended once
+//! it has reported, and registering it again resumes it. To stay on the main queue,
+//! set a ready handler with `DispatchSelector::set_ready_handler`, which runs on the
+//! queue when events arrive, and `select` with a timeout of 0 from there.
+use crate::macos::merge_read_write;
+use crate::unix::validate_token;
+use crate::{Event, Events, Interests, Source, Token};
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The queue the dispatch sources run their handlers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchTarget {
+    /// The main queue, serviced by the main thread's run loop
+    Main,
+    /// A serial queue of the selector's own
+    Private,
+}
+
+type ReadyHandler = Box<dyn Fn() + Send + Sync>;
+
+struct Queue {
+    raw: ffi::dispatch_queue_t,
+    target: DispatchTarget,
+}
+
+// Dispatch queues can be used from any thread
+unsafe impl Send for Queue {}
+unsafe impl Sync for Queue {}
+
+impl Queue {
+    /// Runs `work` with `context` on the queue, after everything queued before it.
+    fn run(&self, work: ffi::dispatch_function_t, context: *const Handler) {
+        unsafe { ffi::dispatch_async_f(self.raw, context as *mut c_void, work) };
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        // The main queue isn't ours to release. Sources and queued work hold their own
+        // reference to a private one.
+        if self.target == DispatchTarget::Private {
+            unsafe { ffi::dispatch_release(self.raw) };
+        }
+    }
+}
+
+#[derive(Default)]
+struct Ready {
+    events: Vec<Event>,
+    woken: bool,
+}
+
+/// What the selector and the sources' handlers share
+#[derive(Default)]
+struct Shared {
+    ready: Mutex<Ready>,
+    cond: Condvar,
+    on_ready: Mutex<Option<ReadyHandler>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn ready(&self) -> MutexGuard<'_, Ready> {
+        self.ready.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, event: Event) {
+        let mut ready = self.ready();
+        let was_empty = ready.events.is_empty();
+        ready.events.push(event);
+        drop(ready);
+        self.cond.notify_all();
+        // Once per batch is enough, the handler takes everything that's ready
+        if was_empty {
+            if let Some(on_ready) = &*self.on_ready.lock().unwrap_or_else(|e| e.into_inner()) {
+                on_ready();
+            }
+        }
+    }
+}
+
+/// The context of a dispatch source, shared between the source and the registrators
+struct Handler {
+    shared: Arc<Shared>,
+    source: ffi::dispatch_source_t,
+    fd: RawFd,
+    writable: bool,
+    token: AtomicUsize,
+    /// Only changed on the queue, which runs one thing at a time, so suspending and
+    /// resuming the source stay balanced
+    suspended: AtomicBool,
+}
+
+// The source is only suspended, resumed and cancelled on its queue
+unsafe impl Send for Handler {}
+unsafe impl Sync for Handler {}
+
+impl Handler {
+    fn create(
+        queue: &Queue,
+        shared: &Arc<Shared>,
+        fd: RawFd,
+        writable: bool,
+        token: Token,
+    ) -> io::Result<Arc<Handler>> {
+        let kind = if writable {
+            ffi::source_type_write()
+        } else {
+            ffi::source_type_read()
+        };
+        let source = unsafe { ffi::dispatch_source_create(kind, fd as usize, 0, queue.raw) };
+        if source.is_null() {
+            return Err(io::Error::other("dispatch_source_create failed."));
+        }
+        let handler = Arc::new(Handler {
+            shared: shared.clone(),
+            source,
+            fd,
+            writable,
+            token: AtomicUsize::new(token),
+            suspended: AtomicBool::new(false),
+        });
+        unsafe {
+            // The source holds a reference to its handler, which `on_cancel` drops
+            ffi::dispatch_set_context(source, Arc::into_raw(handler.clone()) as *mut c_void);
+            ffi::dispatch_source_set_event_handler_f(source, on_event);
+            ffi::dispatch_source_set_cancel_handler_f(source, on_cancel);
+            // Sources are created suspended
+            ffi::dispatch_resume(source);
+        }
+        Ok(handler)
+    }
+}
+
+extern "C" fn on_event(context: *mut c_void) {
+    let handler = unsafe { &*(context as *const Handler) };
+    // Nothing more until it's registered again
+    handler.suspended.store(true, Ordering::SeqCst);
+    unsafe { ffi::dispatch_suspend(handler.source) };
+    let data = unsafe { ffi::dispatch_source_get_data(handler.source) };
+    let token = handler.token.load(Ordering::SeqCst);
+    trace!(
+        "dispatch source for fd {} fired with token {}",
+        handler.fd,
+        token
+    );
+    handler.shared.push(Event::dispatched(
+        handler.fd,
+        handler.writable,
+        data.min(i64::MAX as u64) as i64,
+        token,
+    ));
+}
+
+extern "C" fn on_cancel(context: *mut c_void) {
+    let handler = unsafe { Arc::from_raw(context as *const Handler) };
+    unsafe { ffi::dispatch_release(handler.source) };
+}
+
+/// Resumes a source that has fired, run on the queue with a reference to its handler
+extern "C" fn rearm(context: *mut c_void) {
+    let handler = unsafe { Arc::from_raw(context as *const Handler) };
+    if handler.suspended.swap(false, Ordering::SeqCst) {
+        unsafe { ffi::dispatch_resume(handler.source) };
+    }
+}
+
+/// Cancels a source, run on the queue with a reference to its handler
+extern "C" fn cancel(context: *mut c_void) {
+    let handler = unsafe { Arc::from_raw(context as *const Handler) };
+    // A suspended source never gets to run its cancel handler
+    if handler.suspended.swap(false, Ordering::SeqCst) {
+        unsafe { ffi::dispatch_resume(handler.source) };
+    }
+    unsafe { ffi::dispatch_source_cancel(handler.source) };
+}
+
+type Sources = Arc<Mutex<HashMap<(RawFd, bool), Arc<Handler>>>>;
+
+fn lock(sources: &Sources) -> MutexGuard<'_, HashMap<(RawFd, bool), Arc<Handler>>> {
+    sources.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Waits for the events dispatch sources deliver on a dispatch queue. It can block in
+/// `select` like the kqueue `Selector`, or be drained from a ready handler on the
+/// queue.
+pub struct DispatchSelector {
+    queue: Arc<Queue>,
+    shared: Arc<Shared>,
+    sources: Sources,
+}
+
+impl DispatchSelector {
+    pub fn new(target: DispatchTarget) -> io::Result<DispatchSelector> {
+        let raw = match target {
+            DispatchTarget::Main => ffi::main_queue(),
+            DispatchTarget::Private => {
+                let queue = unsafe {
+                    ffi::dispatch_queue_create(b"minimio\0".as_ptr() as *const _, ptr::null())
+                };
+                if queue.is_null() {
+                    return Err(io::Error::other("dispatch_queue_create failed."));
+                }
+                queue
+            }
+        };
+        Ok(DispatchSelector {
+            queue: Arc::new(Queue { raw, target }),
+            shared: Arc::default(),
+            sources: Arc::default(),
+        })
+    }
+
+    pub fn registrator(&self) -> DispatchRegistrator {
+        DispatchRegistrator {
+            queue: self.queue.clone(),
+            shared: self.shared.clone(),
+            sources: self.sources.clone(),
+        }
+    }
+
+    /// Calls `on_ready` on the queue whenever events arrive while none were waiting.
+    /// Polling from there with a timeout of 0 never blocks the queue.
+    pub fn set_ready_handler(&self, on_ready: impl Fn() + Send + Sync + 'static) {
+        *self
+            .shared
+            .on_ready
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(on_ready));
+    }
+
+    /// Blocks until there are events, `DispatchRegistrator::wake` is called or
+    /// `timeout_ms` milliseconds have passed. `None` means it never times out. Don't
+    /// block on the queue the sources deliver to, they can't deliver anything while
+    /// it's blocked.
+    pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        events.clear();
+        let deadline =
+            timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms.max(0) as u64));
+        let mut ready = self.shared.ready();
+        while ready.events.is_empty() && !ready.woken {
+            ready = match deadline {
+                None => self
+                    .shared
+                    .cond
+                    .wait(ready)
+                    .unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    self.shared
+                        .cond
+                        .wait_timeout(ready, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+        ready.woken = false;
+        let n = ready.events.len().min(events.capacity());
+        events.extend(ready.events.drain(..n));
+        // A source for each filter, like kqueue, and merged the same way
+        merge_read_write(events);
+        Ok(())
+    }
+}
+
+impl Drop for DispatchSelector {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        for (_, handler) in lock(&self.sources).drain() {
+            self.queue.run(cancel, Arc::into_raw(handler));
+        }
+    }
+}
+
+impl fmt::Debug for DispatchSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DispatchSelector")
+            .field("target", &self.queue.target)
+            .field("sources", &lock(&self.sources).len())
+            .finish()
+    }
+}
+
+/// Registers sources with a `DispatchSelector`, from any thread.
+pub struct DispatchRegistrator {
+    queue: Arc<Queue>,
+    shared: Arc<Shared>,
+    sources: Sources,
+}
+
+impl DispatchRegistrator {
+    /// Creates a dispatch source for each of `interests` that reports to `token`
+    /// once, or rearms the one `source` already has with the new token. Registering
+    /// only one of the interests leaves the other registered, like with kqueue.
+    pub fn register(
+        &self,
+        source: &impl Source,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Selector closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+
+        let fd = source.as_fd().as_raw_fd();
+        let mut sources = lock(&self.sources);
+        for &(wanted, writable) in &[
+            (interests.is_readable(), false),
+            (interests.is_writable(), true),
+        ] {
+            if !wanted {
+                continue;
+            }
+            match sources.get(&(fd, writable)) {
+                Some(handler) => {
+                    handler.token.store(token, Ordering::SeqCst);
+                    self.queue.run(rearm, Arc::into_raw(handler.clone()));
+                }
+                None => {
+                    let handler = Handler::create(&self.queue, &self.shared, fd, writable, token)?;
+                    sources.insert((fd, writable), handler);
+                }
+            }
+        }
+        debug!(
+            "registered fd {} with token {} for {} on a dispatch queue",
+            fd, token, interests
+        );
+        Ok(())
+    }
+
+    /// Cancels the dispatch sources of `source` and drops its events that haven't
+    /// been returned from `select` yet. The cancellation happens on the queue, after
+    /// this returns, and like with any dispatch source the fd should stay open until
+    /// it has.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let mut sources = lock(&self.sources);
+        for &writable in &[false, true] {
+            if let Some(handler) = sources.remove(&(fd, writable)) {
+                self.queue.run(cancel, Arc::into_raw(handler));
+            }
+        }
+        drop(sources);
+        self.shared
+            .ready()
+            .events
+            .retain(|event| event.ident != fd as u64);
+        debug!("deregistered fd {} from its dispatch queue", fd);
+        Ok(())
+    }
+
+    /// Makes a blocking `select` return, with whatever events there are.
+    pub fn wake(&self) {
+        self.shared.ready().woken = true;
+        self.shared.cond.notify_all();
+    }
+}
+
+impl fmt::Debug for DispatchRegistrator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DispatchRegistrator")
+            .field("target", &self.queue.target)
+            .finish()
+    }
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::ffi::c_void;
+    use std::os::raw::{c_char, c_ulong};
+
+    // https://developer.apple.com/documentation/dispatch
+    pub type dispatch_object_t = *mut c_void;
+    pub type dispatch_queue_t = *mut c_void;
+    pub type dispatch_source_t = *mut c_void;
+    pub type dispatch_source_type_t = *const c_void;
+    pub type dispatch_function_t = extern "C" fn(context: *mut c_void);
+
+    /// What's behind the main queue and the `DISPATCH_SOURCE_TYPE_*` constants, which
+    /// are only ever used by address
+    #[repr(C)]
+    pub struct dispatch_opaque {
+        _private: [u8; 0],
+    }
+
+    #[link(name = "c")]
+    extern "C" {
+        static _dispatch_main_q: dispatch_opaque;
+        static _dispatch_source_type_read: dispatch_opaque;
+        static _dispatch_source_type_write: dispatch_opaque;
+
+        /// https://developer.apple.com/documentation/dispatch/1453030-dispatch_queue_create
+        pub fn dispatch_queue_create(label: *const c_char, attr: *const c_void)
+            -> dispatch_queue_t;
+        /// https://developer.apple.com/documentation/dispatch/1385630-dispatch_source_create
+        pub fn dispatch_source_create(
+            kind: dispatch_source_type_t,
+            handle: usize,
+            mask: c_ulong,
+            queue: dispatch_queue_t,
+        ) -> dispatch_source_t;
+        /// https://developer.apple.com/documentation/dispatch/1453005-dispatch_set_context
+        pub fn dispatch_set_context(object: dispatch_object_t, context: *mut c_void);
+        /// https://developer.apple.com/documentation/dispatch/1385642-dispatch_source_set_event_handler_f
+        pub fn dispatch_source_set_event_handler_f(
+            source: dispatch_source_t,
+            handler: dispatch_function_t,
+        );
+        /// https://developer.apple.com/documentation/dispatch/1385634-dispatch_source_set_cancel_handler_f
+        pub fn dispatch_source_set_cancel_handler_f(
+            source: dispatch_source_t,
+            handler: dispatch_function_t,
+        );
+        /// For read sources the bytes available, for write sources the space in the
+        /// send buffer
+        pub fn dispatch_source_get_data(source: dispatch_source_t) -> c_ulong;
+        pub fn dispatch_source_cancel(source: dispatch_source_t);
+        pub fn dispatch_resume(object: dispatch_object_t);
+        pub fn dispatch_suspend(object: dispatch_object_t);
+        pub fn dispatch_release(object: dispatch_object_t);
+        /// https://developer.apple.com/documentation/dispatch/1452834-dispatch_async_f
+        pub fn dispatch_async_f(
+            queue: dispatch_queue_t,
+            context: *mut c_void,
+            work: dispatch_function_t,
+        );
+    }
+
+    /// `dispatch_get_main_queue()` is a macro for the address of `_dispatch_main_q`
+    pub fn main_queue() -> dispatch_queue_t {
+        unsafe { &_dispatch_main_q as *const dispatch_opaque as dispatch_queue_t }
+    }
+
+    /// `DISPATCH_SOURCE_TYPE_READ`
+    pub fn source_type_read() -> dispatch_source_type_t {
+        unsafe { &_dispatch_source_type_read as *const dispatch_opaque as dispatch_source_type_t }
+    }
+
+    /// `DISPATCH_SOURCE_TYPE_WRITE`
+    pub fn source_type_write() -> dispatch_source_type_t {
+        unsafe { &_dispatch_source_type_write as *const dispatch_opaque as dispatch_source_type_t }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_pair;
+    use std::io::Write;
+
+    #[test]
+    fn sources_report_once_per_registration() {
+        let selector = DispatchSelector::new(DispatchTarget::Private).unwrap();
+        let registrator = selector.registrator();
+        let (a, mut b) = socket_pair().unwrap();
+        let mut events = Events::with_capacity(16);
+
+        registrator.register(&a, 7, Interests::READABLE).unwrap();
+        b.write_all(b"ping").unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(7, events[0].id());
+        assert!(events[0].is_readable());
+
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+
+        registrator.register(&a, 8, Interests::READABLE).unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(8, events[0].id());
+        registrator.deregister(&a).unwrap();
+    }
+
+    #[test]
+    fn wake_ends_a_blocking_select() {
+        let selector = DispatchSelector::new(DispatchTarget::Private).unwrap();
+        selector.registrator().wake();
+        let mut events = Events::with_capacity(16);
+        selector.select(&mut events, None).unwrap();
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+//! Telling events for a reused token apart from those of its previous registration.
+//! Events that were queued before a source was deregistered can still be delivered
+//! after it, IOCP completions in particular, and if the token has been given to a new
+//! connection in the meantime they look like they're for that one. A generational
+//! token keeps the index a handler looks its connection up by in its low bits, and a
+//! generation that's bumped every time the index is retired in its top bits, so the
+//! old registration's events no longer match.
+use crate::{Events, Poll, Registry, Token};
+use std::collections::HashMap;
+use std::io;
+use std::sync::MutexGuard;
+
+/// How many of the top bits of a generational token hold the generation. A
+/// generation wraps around after being retired `1 << GENERATION_BITS` times.
+pub const GENERATION_BITS: u32 = 16;
+
+const INDEX_BITS: u32 = usize::BITS - GENERATION_BITS;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+/// The index a generational token was made for.
+pub fn token_index(token: Token) -> usize {
+    token & INDEX_MASK
+}
+
+/// The generation of a generational token.
+pub fn token_generation(token: Token) -> u16 {
+    (token >> INDEX_BITS) as u16
+}
+
+fn make_token(index: usize, generation: u16) -> Token {
+    (generation as usize) << INDEX_BITS | index
+}
+
+/// The current generation of every index handed out by `Registry::generational_token`
+pub(crate) type Generations = HashMap<usize, u16>;
+
+impl Registry {
+    /// The token to register a source with for `index`, in the index's current
+    /// generation. Its events carry the same token, and `token_index` gets the index
+    /// back from it. Once the source is deregistered, `retire_token` makes sure any of
+    /// its events still in flight are recognized as stale when the index is reused.
+    ///
+    /// Don't register sources with plain tokens that could be mistaken for one of
+    /// these: a token whose low bits are a tracked index is checked against that
+    /// index's generation like any other.
+    ///
+    /// Fails if `index` doesn't fit next to the generation, or if the token would be
+    /// `usize::MAX`, which is reserved.
+    pub fn generational_token(&self, index: usize) -> io::Result<Token> {
+        if index >= INDEX_MASK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Index is too large for a generational token.",
+            ));
+        }
+        let generation = *self.generations().entry(index).or_insert(0);
+        Ok(make_token(index, generation))
+    }
+
+    /// Starts the next generation of `token`'s index, so events for `token` that are
+    /// still queued are dropped by `poll` rather than delivered under the token the
+    /// index gets next. Retiring a token that's stale already does nothing.
+    pub fn retire_token(&self, token: Token) {
+        let mut generations = self.generations();
+        if let Some(generation) = generations.get_mut(&token_index(token)) {
+            if *generation == token_generation(token) {
+                *generation = generation.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Returns true if `token` is a generational token whose index has been retired
+    /// since it was handed out. A token whose index `generational_token` has never
+    /// been asked for is never stale.
+    pub fn is_stale(&self, token: Token) -> bool {
+        is_stale(&self.generations(), token)
+    }
+
+    fn generations(&self) -> MutexGuard<'_, Generations> {
+        self.generations.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn is_stale(generations: &Generations, token: Token) -> bool {
+    match generations.get(&token_index(token)) {
+        Some(&generation) => generation != token_generation(token),
+        None => false,
+    }
+}
+
+impl Poll {
+    /// Drops the events for tokens that have been retired.
+    pub(crate) fn drop_stale_events(&mut self, events: &mut Events) {
+        let generations = self
+            .registry
+            .generations
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        if generations.is_empty() {
+            return;
+        }
+        events.retain(|event| {
+            if is_stale(generations, event.id()) {
+                trace!("dropped stale event for token {}", event.id());
+                return false;
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_index_and_generation_come_back_out_of_the_token() {
+        let token = make_token(42, 7);
+        assert_eq!(42, token_index(token));
+        assert_eq!(7, token_generation(token));
+        assert_eq!(INDEX_MASK, token_index(make_token(INDEX_MASK, u16::MAX)));
+        assert_eq!(usize::MAX, make_token(INDEX_MASK, u16::MAX));
+    }
+}
@@ -0,0 +1,345 @@
+//! The Haiku and QNX backend, on `select(2)`. Neither has epoll or kqueue, so
+//! `Selector` is the `FdSetSelector` from the `select` module with what the other
+//! backends have around it: closing the loop and the registrations for `debug`.
+//!
+//! Only fds below `select::FD_SETSIZE` can be registered, 1024 on Haiku and 256 on
+//! QNX, so a program that opens lots of files should create its sources early.
+use crate::registrations::Registrations;
+use crate::select::{FdSetRegistrator, FdSetSelector};
+use crate::syscall_stats::SyscallStats;
+use crate::{Events, Interests, Source, Token};
+use std::io::{self, IoSliceMut, Read, Write};
+use std::net;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct Registrator {
+    inner: FdSetRegistrator,
+    is_poll_dead: Arc<AtomicBool>,
+    registrations: Arc<Registrations>,
+}
+
+impl Registrator {
+    pub fn register(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        self.inner.register(source, token, interests)?;
+        self.registrations
+            .insert(source.as_fd().as_raw_fd(), token, interests);
+        Ok(())
+    }
+
+    /// `select` can't wait for a send low-water mark, so this returns an error of
+    /// kind `Unsupported`.
+    pub fn register_with_send_lowat(
+        &self,
+        _source: &impl Source,
+        _token: usize,
+        interests: Interests,
+        _send_lowat: usize,
+    ) -> io::Result<()> {
+        if !interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A send low-water mark needs WRITABLE interest.",
+            ));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "select has no send low-water mark.",
+        ))
+    }
+
+    /// Like `register`. Registering with `select` is only a change to the sets the
+    /// next `select` builds, which wakes up one that's waiting already, so there's
+    /// nothing to defer.
+    pub fn register_deferred(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        self.register(source, token, interests)
+    }
+
+    /// Forgets `source`, so no more events are reported for it (one that `select` has
+    /// already returned can't be taken back). Deregistering a source that isn't
+    /// registered is not an error.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        self.inner.deregister(source)?;
+        self.registrations.remove(source.as_fd().as_raw_fd());
+        Ok(())
+    }
+
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self
+            .is_poll_dead
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        // Waking up `select` is enough, `Poll` sees the loop is closed once it returns
+        debug!("closing event loop on select");
+        self.inner.wake()
+    }
+}
+
+#[derive(Debug)]
+pub struct Selector {
+    inner: FdSetSelector,
+    registrations: Arc<Registrations>,
+}
+
+impl Selector {
+    pub fn new() -> io::Result<Self> {
+        Ok(Selector {
+            inner: FdSetSelector::new()?,
+            registrations: Arc::default(),
+        })
+    }
+
+    /// Deregisters every source registered with this selector or one of its
+    /// registrators, so the selector can be reused as if it was new. Wakers are
+    /// deregistered as well and need to be registered again.
+    ///
+    /// A `select` already waiting in another thread keeps waiting on the old
+    /// registrations, so it's meant for when nothing else is using the selector.
+    pub fn clear(&self) -> io::Result<()> {
+        self.inner.clear();
+        self.registrations.clear();
+        Ok(())
+    }
+
+    /// This function blocks and waits until an event has been recieved. `timeout` None means
+    /// the poll will never time out.
+    pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        self.inner.select(events, timeout_ms)
+    }
+
+    /// Like `select`, but takes a `Duration`. `select(2)` takes microseconds, so the
+    /// timeout is rounded up to the next one.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.select_timeout(events, timeout)
+    }
+
+    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
+        Registrator {
+            inner: self.inner.registrator(),
+            is_poll_dead,
+            registrations: self.registrations.clone(),
+        }
+    }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Each of those left ready fds unreported, so a count that keeps growing
+    /// means the loop isn't keeping up and should shed load or poll with a larger
+    /// buffer. 0 if the last select had room to spare.
+    pub fn pending_hint(&self) -> usize {
+        self.inner.pending_hint()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.inner.syscall_stats()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Event {
+    token: Token,
+    readable: bool,
+    writable: bool,
+}
+
+impl Event {
+    pub fn id(&self) -> Token {
+        self.token
+    }
+
+    /// Returns true if the source can be read from. `select` counts a hang up as
+    /// readable, so that's true as well.
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    /// Returns true if the source can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// An event the way `select` would have reported it, which is also how the
+    /// `select` module builds them.
+    pub(crate) fn with_readiness(token: Token, readable: bool, writable: bool) -> Event {
+        Event {
+            token,
+            readable,
+            writable,
+        }
+    }
+
+    /// Adds the readiness of `other`, an event for the same token, to this one. `select`
+    /// reports all of an fd's readiness at once, so they can always be merged.
+    pub(crate) fn merge(&mut self, other: &Event) -> bool {
+        self.readable |= other.readable;
+        self.writable |= other.writable;
+        true
+    }
+}
+
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    pub fn connect(adr: impl net::ToSocketAddrs) -> io::Result<Self> {
+        // Like on the other platforms this blocks while the connection is established
+        let stream = net::TcpStream::connect(adr)?;
+        TcpStream::from_std(stream)
+    }
+
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream { inner: stream })
+    }
+
+    /// `read` leaves the socket blocking, so anything that relies on getting
+    /// `WouldBlock` has to set it back first.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        self.inner.set_nonblocking(true)
+    }
+
+    /// Reads without changing the blocking mode, unlike `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+/// Neither Haiku nor QNX has `TCP_INFO`.
+pub(crate) fn tcp_info(_stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Haiku and QNX don't have TCP_INFO.",
+    ))
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Blocking while we read, like the other backends do. A caller that wants
+        // `WouldBlock` uses `read_ready` instead.
+        self.inner.set_nonblocking(false)?;
+
+        (&self.inner).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        (&self.inner).read_vectored(bufs)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_pair;
+
+    fn selector() -> (Selector, Registrator) {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        (selector, registrator)
+    }
+
+    #[test]
+    fn registrations_are_oneshot() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        registrator.register(&a, 3, Interests::READABLE).unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![3], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        assert!(events[0].is_readable());
+
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn close_loop_wakes_up_select() {
+        let (selector, registrator) = selector();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            registrator.close_loop().unwrap();
+            registrator
+        });
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, None).unwrap();
+        let registrator = handle.join().unwrap();
+        let (a, _b) = socket_pair().unwrap();
+        let err = registrator
+            .register(&a, 1, Interests::READABLE)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::Interrupted, err.kind());
+    }
+
+    #[test]
+    fn cleared_sources_are_quiet() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        registrator.register(&a, 1, Interests::READABLE).unwrap();
+        selector.clear().unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+    }
+}
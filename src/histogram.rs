@@ -0,0 +1,137 @@
+//! Histograms of how long `poll` spends blocked and how many events each wakeup brings,
+//! a cheap way to spot an event loop that's saturated: it stops blocking at all, and
+//! every wakeup fills the `Events` buffer.
+use crate::Poll;
+use std::time::Duration;
+
+/// Counts of values falling into buckets. A value lands in the first bucket whose
+/// upper bound it doesn't exceed, or in the last one, which has no upper bound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Histogram {
+    bounds: Vec<u64>,
+    counts: Vec<u64>,
+    sum: u64,
+}
+
+impl Histogram {
+    /// A histogram with a bucket for each of the inclusive upper `bounds`, plus one for
+    /// everything above the last of them. The bounds are sorted.
+    pub fn new(mut bounds: Vec<u64>) -> Histogram {
+        bounds.sort_unstable();
+        bounds.dedup();
+        let counts = vec![0; bounds.len() + 1];
+        Histogram {
+            bounds,
+            counts,
+            sum: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[bucket] += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// Every bucket's upper bound, `None` for the last one, and how many values fell
+    /// into it.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<u64>, u64)> + '_ {
+        self.bounds
+            .iter()
+            .map(|&bound| Some(bound))
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+
+    /// How many values have been recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The sum of all recorded values, saturating at `u64::MAX`.
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+        self.sum = 0;
+    }
+}
+
+/// The histograms `Poll` keeps once `Poll::enable_histograms` has been called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PollHistograms {
+    /// Microseconds spent in the selector on each `poll`
+    pub select_micros: Histogram,
+    /// Events returned from each `poll`
+    pub events_per_wakeup: Histogram,
+}
+
+impl PollHistograms {
+    pub fn new(select_micros: Histogram, events_per_wakeup: Histogram) -> PollHistograms {
+        PollHistograms {
+            select_micros,
+            events_per_wakeup,
+        }
+    }
+
+    pub(crate) fn record(&mut self, selecting: Duration, events: usize) {
+        let micros = selecting.as_micros().min(u64::MAX as u128) as u64;
+        self.select_micros.record(micros);
+        self.events_per_wakeup.record(events as u64);
+    }
+}
+
+/// Powers of ten from 10µs to 1s for the select time, and powers of two up to 1024
+/// for the events, which covers the usual sizes of `Events` buffers.
+impl Default for PollHistograms {
+    fn default() -> PollHistograms {
+        PollHistograms {
+            select_micros: Histogram::new(vec![10, 100, 1_000, 10_000, 100_000, 1_000_000]),
+            events_per_wakeup: Histogram::new(
+                std::iter::once(0).chain((0..=10).map(|n| 1 << n)).collect(),
+            ),
+        }
+    }
+}
+
+impl Poll {
+    /// Starts recording every `poll` into `histograms`, replacing any that were being
+    /// recorded before.
+    pub fn enable_histograms(&mut self, histograms: PollHistograms) {
+        self.histograms = Some(histograms);
+    }
+
+    /// A copy of the histograms recorded so far, or `None` if they aren't enabled.
+    pub fn histograms(&self) -> Option<PollHistograms> {
+        self.histograms.clone()
+    }
+
+    /// Stops recording and hands back what was recorded.
+    pub fn take_histograms(&mut self) -> Option<PollHistograms> {
+        self.histograms.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_land_in_the_first_bucket_they_fit() {
+        let mut histogram = Histogram::new(vec![10, 1]);
+        for value in [0, 1, 2, 10, 11, 1000] {
+            histogram.record(value);
+        }
+        let buckets: Vec<_> = histogram.buckets().collect();
+        assert_eq!(vec![(Some(1), 2), (Some(10), 2), (None, 2)], buckets);
+        assert_eq!(6, histogram.count());
+        assert_eq!(1024, histogram.sum());
+
+        histogram.clear();
+        assert_eq!(0, histogram.count());
+    }
+}
@@ -0,0 +1,356 @@
+//! Being asked to stop, the same way everywhere. An `Interrupt` is an event with its
+//! own token that fires on SIGINT and SIGTERM on Unix, and on Ctrl-C, Ctrl-Break and
+//! the console window being closed on Windows, so a server can shut down gracefully
+//! with a single registration on every platform.
+//!
+//! ```no_run
+//! use minimio::{Events, Interrupt, Poll};
+//! const INTERRUPT: usize = 0;
+//! let mut poll = Poll::new()?;
+//! let interrupt = Interrupt::new(&poll, INTERRUPT)?;
+//! let mut events = Events::with_capacity(1024);
+//! loop {
+//!     poll.poll(&mut events, None)?;
+//!     if events.iter().any(|event| event.id() == INTERRUPT) {
+//!         println!("stopping after {:?}", interrupt.reset()?);
+//!         break;
+//!     }
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! The handlers are process wide. They're installed when the first `Interrupt` is
+//! created, replacing whatever handled the signals before, and the default behaviour
+//! of ending the process comes back when the last one is dropped. Every `Interrupt`
+//! that exists when a signal arrives gets an event.
+use crate::{Poll, Token, Waker};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Why we're being asked to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InterruptKind {
+    /// SIGINT, or Ctrl-C or Ctrl-Break in a Windows console
+    Interrupt,
+    /// SIGTERM, or the Windows console being closed
+    Terminate,
+}
+
+impl InterruptKind {
+    fn to_raw(self) -> usize {
+        match self {
+            InterruptKind::Interrupt => 1,
+            InterruptKind::Terminate => 2,
+        }
+    }
+
+    fn from_raw(raw: usize) -> Option<InterruptKind> {
+        match raw {
+            1 => Some(InterruptKind::Interrupt),
+            2 => Some(InterruptKind::Terminate),
+            _ => None,
+        }
+    }
+}
+
+/// The wakers of every `Interrupt`, with the id it removes its own by
+static LISTENERS: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Makes an event with its token show up when the process is asked to stop. See the
+/// module documentation for which signals that is.
+///
+/// Like a `Waker` it's oneshot: `reset` has to be called after its event to get
+/// another one.
+#[derive(Debug)]
+pub struct Interrupt {
+    waker: Waker,
+    id: u64,
+}
+
+impl Interrupt {
+    /// Creates an interrupt that wakes up `poll` with an event with `token` as its id.
+    pub fn new(poll: &Poll, token: Token) -> io::Result<Interrupt> {
+        let waker = Waker::new(poll, token)?;
+        let mut listeners = listeners();
+        if listeners.is_empty() {
+            sys::install()?;
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        listeners.push((id, waker.clone()));
+        Ok(Interrupt { waker, id })
+    }
+
+    pub fn token(&self) -> Token {
+        self.waker.token()
+    }
+
+    /// Re-arms the interrupt after its event was returned from `poll`, and returns
+    /// what arrived since the last reset, oldest first.
+    pub fn reset(&self) -> io::Result<Vec<InterruptKind>> {
+        let raw = self.waker.reset()?;
+        Ok(raw
+            .into_iter()
+            .filter_map(InterruptKind::from_raw)
+            .collect())
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        let mut listeners = listeners();
+        listeners.retain(|(id, _)| *id != self.id);
+        if listeners.is_empty() {
+            if let Err(e) = sys::uninstall() {
+                debug!("restoring the default interrupt handlers failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Wakes up every `Interrupt`. Never called from a signal handler, so it can lock.
+fn dispatch(kind: InterruptKind) {
+    trace!("dispatching {:?}", kind);
+    for (_, waker) in listeners().iter() {
+        if let Err(e) = waker.wake_with(kind.to_raw()) {
+            debug!(
+                "waking up interrupt with token {} failed: {}",
+                waker.token(),
+                e
+            );
+        }
+    }
+}
+
+/// A panic while holding the lock can't leave the list in a bad state, so we ignore
+/// poisoning.
+fn listeners() -> MutexGuard<'static, Vec<(u64, Waker)>> {
+    LISTENERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A signal handler can do next to nothing safely, certainly not lock, so ours writes
+/// the kind of signal to a socket. A thread reading from the other end does the
+/// dispatching. The socket and thread are created with the first `Interrupt` and live
+/// as long as the process.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+mod sys {
+    use super::{dispatch, InterruptKind};
+    use std::io::{self, Read};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    /// The end of the socket the signal handler writes to, -1 until there is one
+    static SIGNALS: AtomicI32 = AtomicI32::new(-1);
+
+    /// Called with the lock on the listeners held, so only ever by one thread at a
+    /// time.
+    pub fn install() -> io::Result<()> {
+        if SIGNALS.load(Ordering::SeqCst) < 0 {
+            start_forwarding()?;
+        }
+        for &signal in &[ffi::SIGINT, ffi::SIGTERM] {
+            if unsafe { ffi::signal(signal, on_signal as extern "C" fn(i32) as usize) }
+                == ffi::SIG_ERR
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn uninstall() -> io::Result<()> {
+        for &signal in &[ffi::SIGINT, ffi::SIGTERM] {
+            if unsafe { ffi::signal(signal, ffi::SIG_DFL) } == ffi::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn start_forwarding() -> io::Result<()> {
+        let (mut receiver, sender) = UnixStream::pair()?;
+        // The handler mustn't block, a full buffer has plenty to dispatch already
+        sender.set_nonblocking(true)?;
+        thread::Builder::new()
+            .name("minimio-interrupt".to_string())
+            .spawn(move || {
+                let mut buf = [0u8; 16];
+                loop {
+                    match receiver.read(&mut buf) {
+                        Ok(0) => return,
+                        Ok(n) => buf[..n]
+                            .iter()
+                            .filter_map(|&raw| InterruptKind::from_raw(raw as usize))
+                            .for_each(dispatch),
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(e) => {
+                            debug!("reading interrupts failed: {}", e);
+                            return;
+                        }
+                    }
+                }
+            })?;
+        SIGNALS.store(sender.into_raw_fd(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub(super) extern "C" fn on_signal(signal: i32) {
+        let kind = match signal {
+            ffi::SIGINT => InterruptKind::Interrupt,
+            _ => InterruptKind::Terminate,
+        };
+        let byte = kind.to_raw() as u8;
+        // `write` is async-signal-safe, but it can change `errno` under the code we
+        // interrupted
+        unsafe {
+            let errno = *ffi::errno();
+            ffi::write(SIGNALS.load(Ordering::SeqCst), &byte, 1);
+            *ffi::errno() = errno;
+        }
+    }
+
+    mod ffi {
+        pub const SIGINT: i32 = 2;
+        pub const SIGTERM: i32 = 15;
+        pub const SIG_DFL: usize = 0;
+        pub const SIG_ERR: usize = usize::MAX;
+
+        extern "C" {
+            /// Both glibc and macOS give `signal` BSD semantics: the handler stays
+            /// installed and interrupted system calls are restarted.
+            /// https://man7.org/linux/man-pages/man2/signal.2.html
+            pub fn signal(signum: i32, handler: usize) -> usize;
+            pub fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+            #[cfg_attr(
+                any(
+                    target_os = "linux",
+                    target_os = "dragonfly",
+                    target_os = "redox",
+                    target_os = "fuchsia"
+                ),
+                link_name = "__errno_location"
+            )]
+            #[cfg_attr(target_os = "macos", link_name = "__error")]
+            #[cfg_attr(target_os = "aix", link_name = "_Errno")]
+            #[cfg_attr(target_os = "haiku", link_name = "_errnop")]
+            #[cfg_attr(target_os = "nto", link_name = "__get_errno_ptr")]
+            pub fn errno() -> *mut i32;
+        }
+    }
+}
+
+/// Windows runs console control handlers on a thread of their own, so ours can
+/// dispatch right away.
+#[cfg(target_os = "windows")]
+mod sys {
+    use super::{dispatch, InterruptKind};
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    /// How long we keep the process alive after the console is closed. Windows ends
+    /// it as soon as the handler returns, and after 5 seconds if it doesn't.
+    const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+    pub fn install() -> io::Result<()> {
+        if unsafe { ffi::SetConsoleCtrlHandler(Some(on_ctrl), 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn uninstall() -> io::Result<()> {
+        if unsafe { ffi::SetConsoleCtrlHandler(Some(on_ctrl), 0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Reference: https://docs.microsoft.com/en-us/windows/console/handlerroutine
+    pub(super) extern "system" fn on_ctrl(ctrl_type: u32) -> i32 {
+        let kind = match ctrl_type {
+            ffi::CTRL_C_EVENT | ffi::CTRL_BREAK_EVENT => InterruptKind::Interrupt,
+            ffi::CTRL_CLOSE_EVENT => InterruptKind::Terminate,
+            // Let the next handler deal with it
+            _ => return 0,
+        };
+        dispatch(kind);
+        if kind == InterruptKind::Terminate {
+            // Returning ends the process, so we give the event loop its chance to
+            // shut down first. If it's done sooner the process exits from under us.
+            thread::sleep(CLOSE_GRACE);
+        }
+        1
+    }
+
+    mod ffi {
+        pub const CTRL_C_EVENT: u32 = 0;
+        pub const CTRL_BREAK_EVENT: u32 = 1;
+        pub const CTRL_CLOSE_EVENT: u32 = 2;
+
+        pub type HandlerRoutine = extern "system" fn(ctrl_type: u32) -> i32;
+
+        #[link(name = "Kernel32")]
+        extern "system" {
+            /// https://docs.microsoft.com/en-us/windows/console/setconsolectrlhandler
+            pub fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Events;
+    use std::time::Duration;
+
+    #[test]
+    fn handler_wakes_up_the_poll() {
+        let mut poll = Poll::new().unwrap();
+        let interrupt = Interrupt::new(&poll, 5).unwrap();
+
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "dragonfly",
+            target_os = "redox",
+            target_os = "fuchsia",
+            target_os = "aix",
+            target_os = "haiku",
+            target_os = "nto"
+        ))]
+        sys::on_signal(15);
+        #[cfg(target_os = "windows")]
+        sys::on_ctrl(0);
+
+        let mut events = Events::with_capacity(16);
+        poll.poll_timeout(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(vec![5], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "dragonfly",
+            target_os = "redox",
+            target_os = "fuchsia",
+            target_os = "aix",
+            target_os = "haiku",
+            target_os = "nto"
+        ))]
+        assert_eq!(vec![InterruptKind::Terminate], interrupt.reset().unwrap());
+        #[cfg(target_os = "windows")]
+        assert_eq!(vec![InterruptKind::Interrupt], interrupt.reset().unwrap());
+    }
+}
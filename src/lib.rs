@@ -1,25 +1,281 @@
+use std::fmt;
 use std::io;
+use std::ops;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox"
+))]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+use std::os::windows::io::{AsHandle, AsRawHandle, BorrowedHandle, RawHandle};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex,
 };
+use std::time::{Duration, Instant};
 
-#[cfg(target_os = "windows")]
+#[macro_use]
+mod logging;
+
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
 mod windows;
-#[cfg(target_os = "windows")]
-pub use windows::{Event, Registrator, Selector, TcpStream};
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+pub use windows::{
+    socket_pair, Event, JobMessage, NamedPipe, NamedPipeListener, Registrator, Selector, TcpStream,
+    Timer, UdpSocket, DEFAULT_RECV_BUFFER_SIZE,
+};
+
+// A readiness-only alternative to IOCP
+#[cfg(all(target_os = "windows", feature = "wsapoll"))]
+mod wsapoll;
+#[cfg(all(target_os = "windows", feature = "wsapoll"))]
+pub use wsapoll::{socket_pair, Event, Registrator, Selector, TcpStream};
 
-#[cfg(target_os = "macos")]
+// The kqueue backend, which DragonFly shares with macOS
+#[cfg(any(target_os = "macos", target_os = "dragonfly"))]
 mod macos;
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "macos", target_os = "dragonfly"))]
 pub use macos::{Event, Registrator, Selector, TcpStream};
 
+#[cfg(target_os = "redox")]
+mod redox;
+#[cfg(target_os = "redox")]
+pub use redox::{Event, Registrator, Selector, TcpStream};
+
+#[cfg(target_os = "aix")]
+mod aix;
+#[cfg(target_os = "aix")]
+pub use aix::{Event, Registrator, Selector, TcpStream};
+
+#[cfg(target_os = "fuchsia")]
+mod fuchsia;
+#[cfg(target_os = "fuchsia")]
+pub use fuchsia::{Event, Registrator, Selector, TcpStream};
+
+// The select(2) backend, which QNX shares with Haiku
+#[cfg(any(target_os = "haiku", target_os = "nto"))]
+mod haiku;
+#[cfg(any(target_os = "haiku", target_os = "nto"))]
+pub use haiku::{Event, Registrator, Selector, TcpStream};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+mod unix;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+pub use unix::{socket_pair, PeerCred, Source, SourceFd, TcpListener, UdpSocket, UnixStream};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+mod socket;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+pub use socket::{TcpSocket, UdpSocketBuilder};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+mod seqpacket;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+pub use seqpacket::{UnixSeqpacket, UnixSeqpacketListener};
+
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
-pub use linux::{Event, Registrator, Selector, TcpStream};
+pub use linux::{
+    ClockId, Event, EventFd, Inotify, InotifyEvent, InotifyEvents, PidFd, Registrator, Selector,
+    SigInfo, SigSet, SignalFd, Signals, TcpStream, TimerFd, WatchDescriptor, WatchMask,
+};
+
+mod clock;
+pub use clock::{Clock, ManualClock, MonotonicClock};
+
+mod timer;
+pub use timer::{TimerKey, TimerWheel};
+
+mod waker;
+pub use waker::Waker;
+
+mod interrupt;
+pub use interrupt::{Interrupt, InterruptKind};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "aix"
+))]
+mod reaper;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "aix"
+))]
+pub use reaper::{ChildReaper, ReapedChild};
+
+mod scope;
+pub use scope::Scope;
+
+mod dispatch;
+pub use dispatch::Dispatcher;
+
+mod buf_stream;
+pub use buf_stream::{BufStream, NonBlockingRead};
+
+mod read_ready;
+pub use read_ready::{ReadAccumulator, ReadProgress};
+
+mod write_ready;
+pub use write_ready::WriteProgress;
+
+mod user_data;
+pub use user_data::FIRST_DATA_TOKEN;
+
+mod task_waker;
+
+mod registrable;
+
+mod filter;
+
+mod generation;
+pub use generation::{token_generation, token_index, GENERATION_BITS};
+
+mod coalesce;
+
+mod rate_limit;
+
+mod by_token;
+pub use by_token::ByToken;
+
+mod histogram;
+pub use histogram::{Histogram, PollHistograms};
+
+mod backlog;
+
+mod syscall_stats;
+pub use syscall_stats::SyscallStats;
+
+mod tcp_info;
+pub use tcp_info::{TcpInfo, TcpState};
+
+mod registrations;
+#[cfg(feature = "debug")]
+pub use registrations::RawSource;
+
+pub mod channel;
+
+mod blocking;
+pub use blocking::{BlockingPool, Completion};
+
+#[cfg(feature = "executor")]
+pub mod executor;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+#[cfg(feature = "runtime")]
+pub mod runtime;
+
+#[cfg(feature = "connections")]
+pub mod connections;
+
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+
+#[cfg(all(target_os = "macos", feature = "gcd"))]
+pub mod gcd;
+
+#[cfg(any(
+    all(
+        any(target_os = "linux", target_os = "macos", target_os = "dragonfly"),
+        feature = "select"
+    ),
+    target_os = "haiku",
+    target_os = "nto"
+))]
+pub mod select;
+
+#[cfg(feature = "compat")]
+pub mod compat;
+
+#[doc(hidden)]
+pub mod test_util;
+
+#[cfg(feature = "futures")]
+mod stream;
+#[cfg(feature = "futures")]
+pub use stream::EventStream;
+
+#[cfg(feature = "futures")]
+mod readiness;
+#[cfg(feature = "futures")]
+pub use readiness::{Reactor, ReactorHandle, Readiness};
+
+/// Re-exports the types most programs need, so a single `use minimio::prelude::*;`
+/// is enough to get started.
+pub mod prelude {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub use crate::Source;
+    pub use crate::{
+        Event, Events, EventsExt, Interests, Poll, Registrator, Selector, TcpStream, Token, Waker,
+    };
+}
 
 pub type Events = Vec<Event>;
+
+/// Methods on `Events` that `Vec` doesn't have.
+pub trait EventsExt {
+    /// Returns true if the last `poll` filled the buffer to its capacity. There might be
+    /// more events waiting, so a latency sensitive loop should poll again right away
+    /// with a timeout of 0 before blocking.
+    fn is_full(&self) -> bool;
+
+    /// Sorts the events by token and returns them grouped, each token once with all of
+    /// its events, so a handler can run once per connection per poll. Events with the
+    /// same token stay in the order they were delivered in.
+    fn by_token(&mut self) -> ByToken<'_>;
+}
+
+impl EventsExt for Events {
+    fn is_full(&self) -> bool {
+        // The selectors never return more events than there is capacity for
+        !self.is_empty() && self.len() == self.capacity()
+    }
+
+    fn by_token(&mut self) -> ByToken<'_> {
+        self.sort_by_key(|event| event.id());
+        ByToken::new(self)
+    }
+}
+
+/// Identifies a registration in the events returned from `poll`. It's a plain `usize`
+/// so it works with serde (behind the `serde` feature) like any other integer.
 pub type Token = usize;
 
 /// `Poll` represents the event queue. The `poll` method will block the current thread
@@ -34,64 +290,301 @@ pub type Token = usize;
 #[derive(Debug)]
 pub struct Poll {
     registry: Registry,
-    is_poll_dead: Arc<AtomicBool>,
+    received_at: Option<Instant>,
+    histograms: Option<PollHistograms>,
+    coalesce: bool,
 }
 
 impl Poll {
     pub fn new() -> io::Result<Poll> {
-        Selector::new().map(|selector| Poll {
-            registry: Registry { selector },
-            is_poll_dead: Arc::new(AtomicBool::new(false)),
-        })
+        Selector::new().map(Poll::from_selector)
+    }
+
+    /// Creates a `Poll` that lends IOCP a receive buffer of `size` bytes for every
+    /// `TcpStream` that hasn't set its own size with `TcpStream::set_recv_buffer_size`.
+    #[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+    pub fn with_recv_buffer_size(size: usize) -> io::Result<Poll> {
+        Selector::with_recv_buffer_size(size).map(Poll::from_selector)
+    }
+
+    fn from_selector(selector: Selector) -> Poll {
+        Poll {
+            registry: Registry {
+                selector,
+                is_poll_dead: Arc::new(AtomicBool::new(false)),
+                data: Mutex::default(),
+                filters: Mutex::default(),
+                generations: Mutex::default(),
+                rate_limits: Mutex::default(),
+            },
+            received_at: None,
+            histograms: None,
+            coalesce: false,
+        }
     }
 
     pub fn registrator(&self) -> Registrator {
-        self.registry
-            .selector
-            .registrator(self.is_poll_dead.clone())
+        self.registry.registrator()
+    }
+
+    /// Makes `poll` wait alertably, so APCs queued to the polling thread run while it
+    /// waits. See `Selector::set_alertable`.
+    #[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+    pub fn set_alertable(&mut self, alertable: bool) {
+        self.registry.selector.set_alertable(alertable);
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// When the events of the last `poll` were taken off the queue, or `None` before
+    /// the first one. Comparing it to the time a handler starts gives the dispatch
+    /// latency.
+    ///
+    /// This is kept here rather than as an `Event::received_at`: the events are the
+    /// OS's own structs with no room for anything else, and every event of a `poll`
+    /// is dequeued at once, so they'd all carry the same time anyway. None of the
+    /// backends report when an event was queued.
+    ///
+    /// It's the time of the `poll` that returned an event, which isn't always the one
+    /// that dequeued it. An event held back by a `Registry::rate_limit` gets the time
+    /// of the `poll` that delivers it once the interval is over, so the time it was
+    /// held back doesn't count as dispatch latency.
+    pub fn received_at(&self) -> Option<Instant> {
+        self.received_at
     }
 
     /// Polls the event loop. The thread yields to the OS while witing for either
     /// an event to retur or a timeout to occur. A negative timeout will be treated
     /// as a timeout of 0.
+    ///
+    /// Events dropped by `Registry::suppress_unchanged` filters, for tokens retired
+    /// with `Registry::retire_token`, held back by a `Registry::rate_limit`, or that
+    /// woke the task of a `Registry::register_waker`, aren't waited for again, so
+    /// `poll` can return 0 before the timeout.
     pub fn poll(&mut self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<usize> {
         // A negative timout is converted to a 0 timeout
-        let timeout = timeout_ms.map(|n| if n < 0 { 0 } else { n });
+        let timeout = timeout_ms.map(|n| Duration::from_millis(if n < 0 { 0 } else { n as u64 }));
+        self.poll_timeout(events, timeout)
+    }
+
+    /// Like `poll`, but the timeout isn't limited to whole milliseconds, for timer
+    /// wheels and pacing that need to wake up more precisely than that. Linux (from
+    /// 5.11) and macOS wait with the kernel's precision, Windows rounds the timeout up
+    /// to the next millisecond.
+    pub fn poll_timeout(
+        &mut self,
+        events: &mut Events,
+        timeout: Option<Duration>,
+    ) -> io::Result<usize> {
+        let started = self.histograms.as_ref().map(|_| Instant::now());
+        let timeout = self.rate_limit_timeout(timeout);
         loop {
-            let res = self.registry.selector.select(events, timeout);
+            let res = self.registry.selector.select_timeout(events, timeout);
             match res {
                 Ok(()) => break,
                 Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
                 Err(e) => return Err(e),
             };
         }
+        self.finish_poll(events, started)
+    }
 
-        if self.is_poll_dead.load(Ordering::SeqCst) {
+    /// Like `poll_timeout`, but with the thread's signal mask replaced by `sigmask`
+    /// while waiting, see `Selector::select_with_sigmask`. A signal delivered while
+    /// waiting makes this return 0 rather than wait again, so the caller can act on
+    /// what its handler recorded.
+    #[cfg(target_os = "linux")]
+    pub fn poll_with_sigmask(
+        &mut self,
+        events: &mut Events,
+        timeout: Option<Duration>,
+        sigmask: &SigSet,
+    ) -> io::Result<usize> {
+        let started = self.histograms.as_ref().map(|_| Instant::now());
+        let timeout = self.rate_limit_timeout(timeout);
+        let res = self
+            .registry
+            .selector
+            .select_with_sigmask(events, timeout, Some(sigmask));
+        match res {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => events.clear(),
+            Err(e) => return Err(e),
+        }
+        self.finish_poll(events, started)
+    }
+
+    /// What every poll does once the selector has returned
+    fn finish_poll(&mut self, events: &mut Events, started: Option<Instant>) -> io::Result<usize> {
+        if self.registry.is_poll_dead.load(Ordering::SeqCst) {
             return Err(io::Error::new(io::ErrorKind::Interrupted, "Poll closed."));
         }
 
+        let received_at = Instant::now();
+        self.received_at = Some(received_at);
+        if let (Some(histograms), Some(started)) = (&mut self.histograms, started) {
+            histograms.record(received_at - started, events.len());
+        }
+        self.drop_stale_events(events);
+        self.wake_tasks(events);
+        self.coalesce(events);
+        self.filter_events(events);
+        self.limit_rate(events);
         Ok(events.len())
     }
 }
 
+/// The selector's fd, so a `Poll` can be registered with another one to nest it. See
+/// the `AsFd` implementation of `Selector`. Fuchsia and AIX selectors are a port and a
+/// pollset, not fds, and the one on Haiku and QNX keeps nothing in the kernel, so
+/// they can't be nested.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox"
+))]
+impl AsFd for Poll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.registry.selector.as_fd()
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox"
+))]
+impl AsRawFd for Poll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.registry.selector.as_raw_fd()
+    }
+}
+
+/// The selector's completion port. See the `AsRawHandle` implementation of
+/// `Selector`.
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+impl AsHandle for Poll {
+    fn as_handle(&self) -> BorrowedHandle<'_> {
+        self.registry.selector.as_handle()
+    }
+}
+
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+impl AsRawHandle for Poll {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.registry.selector.as_raw_handle()
+    }
+}
+
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+impl Registrator {
+    /// Nests `poll` in the `Poll` this registrator belongs to, see
+    /// `Registrator::register_selector`.
+    pub fn register_poll(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        self.register_selector(&poll.registry.selector, token)
+    }
+}
+
 #[derive(Debug)]
 pub struct Registry {
     selector: Selector,
+    is_poll_dead: Arc<AtomicBool>,
+    data: Mutex<user_data::UserData>,
+    filters: Mutex<filter::Filters>,
+    generations: Mutex<generation::Generations>,
+    rate_limits: Mutex<rate_limit::RateLimits>,
+}
+
+impl Registry {
+    pub fn registrator(&self) -> Registrator {
+        self.selector.registrator(self.is_poll_dead.clone())
+    }
+
+    /// What the selector is watching right now: the token, the interests and the OS
+    /// handle of every source registered through this registry's selector and its
+    /// registrators that hasn't been deregistered, ordered by token. A source that's
+    /// closed without being deregistered stays in here, which is what makes it useful
+    /// for finding connections that leak.
+    #[cfg(feature = "debug")]
+    pub fn registrations(&self) -> impl Iterator<Item = (Token, Interests, RawSource)> {
+        self.selector.registrations().snapshot().into_iter()
+    }
+
+    /// How many polls in a row have filled the `Events` buffer, a hint that events
+    /// are arriving faster than they're handled. See `Selector::pending_hint`.
+    pub fn pending_hint(&self) -> usize {
+        self.selector.pending_hint()
+    }
+
+    /// How many system calls the selector and its registrators have made so far, see
+    /// `SyscallStats`.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.selector.syscall_stats()
+    }
+
+    /// Deregisters every source and forgets the data, readiness filters and rate
+    /// limits attached to their tokens, along with the events the limits held back,
+    /// see `Selector::clear`. IOCP can only cancel the I/O of sockets, so job objects
+    /// and nested selectors keep reporting events there.
+    pub fn clear(&self) -> io::Result<()> {
+        self.selector.clear()?;
+        *self.data.lock().unwrap_or_else(|e| e.into_inner()) = Default::default();
+        self.filters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        *self.rate_limits.lock().unwrap_or_else(|e| e.into_inner()) = Default::default();
+        Ok(())
+    }
 }
 
 const WRITABLE: u8 = 0b0000_0001;
 const READABLE: u8 = 0b0000_0010;
 
 /// Represents interest in either Read or Write events. This struct is created
-/// by using one of the two constants:
+/// by using one of the two constants, which can be combined with `|` or `add`:
 ///
 /// - Interests::READABLE
 /// - Interests::WRITABLE
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Interests(u8);
 impl Interests {
     pub const READABLE: Interests = Interests(READABLE);
     pub const WRITABLE: Interests = Interests(WRITABLE);
 
+    const NAMES: [(u64, &'static str); 2] =
+        [(READABLE as u64, "READABLE"), (WRITABLE as u64, "WRITABLE")];
+
+    /// Interest in both Read and Write events
+    pub const fn all() -> Interests {
+        Interests(READABLE | WRITABLE)
+    }
+
+    /// Returns the union of `self` and `other`. Same as `self | other`.
+    #[allow(clippy::should_implement_trait)]
+    pub const fn add(self, other: Interests) -> Interests {
+        Interests(self.0 | other.0)
+    }
+
+    /// Returns `self` without the interests in `other`. Note that this can leave us
+    /// with no interests at all, which can't be registered.
+    pub const fn remove(self, other: Interests) -> Interests {
+        Interests(self.0 & !other.0)
+    }
+
+    pub const fn contains(&self, other: Interests) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
     pub fn is_readable(&self) -> bool {
         self.0 & READABLE != 0
     }
@@ -99,4 +592,71 @@ impl Interests {
     pub fn is_writable(&self) -> bool {
         self.0 & WRITABLE != 0
     }
+
+    /// Returns an error if there are no interests to register. Registering with no
+    /// interests would silently never produce any events.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Registering requires at least one of READABLE or WRITABLE interests.",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ops::BitOr for Interests {
+    type Output = Interests;
+
+    fn bitor(self, other: Interests) -> Interests {
+        self.add(other)
+    }
+}
+
+impl fmt::Display for Interests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", Flags(self.0 as u64, &Interests::NAMES))
+    }
+}
+
+impl fmt::Debug for Interests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interests({})", self)
+    }
+}
+
+/// Prints the names of the flags set in a bit mask separated by `|`, with any bits we
+/// don't have a name for printed as hex. Used to make the `Debug` output of the
+/// backends' event types readable.
+pub(crate) struct Flags<'a>(pub u64, pub &'a [(u64, &'static str)]);
+
+impl<'a> fmt::Debug for Flags<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Flags(bits, names) = *self;
+        if bits == 0 {
+            return write!(f, "(empty)");
+        }
+
+        let mut rest = bits;
+        let mut first = true;
+        for &(flag, name) in names {
+            if flag != 0 && bits & flag == flag {
+                if !first {
+                    write!(f, " | ")?;
+                }
+                write!(f, "{}", name)?;
+                rest &= !flag;
+                first = false;
+            }
+        }
+
+        if rest != 0 {
+            if !first {
+                write!(f, " | ")?;
+            }
+            write!(f, "{:#x}", rest)?;
+        }
+        Ok(())
+    }
 }
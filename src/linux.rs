@@ -1,21 +1,41 @@
-use crate::{Events, Interests, Token};
+use crate::backlog::FullSelects;
+use crate::clock::ceil_millis;
+use crate::registrations::Registrations;
+use crate::socket::{get_int_option, set_int_option};
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::unix::{validate_token, Change, KICK_TOKEN};
+use crate::{Events, Interests, Source, Token};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
 use std::io::{self, IoSliceMut, Read, Write};
 use std::net;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    mpsc, Arc,
 };
+use std::time::Duration;
 
+#[derive(Debug)]
 pub struct Registrator {
-    fd: RawFd,
+    /// Shared with the `Selector`, so the epoll instance stays open for as long as
+    /// anybody can register with it
+    fd: Arc<OwnedFd>,
     is_poll_dead: Arc<AtomicBool>,
+    changes: mpsc::Sender<Change>,
+    kick: Arc<EventFd>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
 }
 
 impl Registrator {
     pub fn register(
         &self,
-        stream: &TcpStream,
+        source: &impl Source,
         token: usize,
         interests: Interests,
     ) -> io::Result<()> {
@@ -25,25 +45,97 @@ impl Registrator {
                 "Poll instance closed.",
             ));
         }
-        let fd = stream.as_raw_fd();
-        if interests.is_readable() {
-            // We register the id (or most oftenly referred to as a Token) to the `udata` field
-            // if the `Kevent`
-            let mut event = ffi::Event::new(ffi::EPOLLIN | ffi::EPOLLONESHOT, token);
-            epoll_ctl(self.fd, ffi::EPOLL_CTL_ADD, fd, &mut event)?;
-        };
 
-        if interests.is_writable() {
-            unimplemented!();
+        interests.validate()?;
+        validate_token(token)?;
+        let fd = source.as_fd().as_raw_fd();
+        register_fd(self.fd.as_raw_fd(), fd, token, interests, &self.stats)?;
+        self.registrations.insert(fd, token, interests);
+        Ok(())
+    }
+
+    /// Like `register`, but writable events are only reported once at least
+    /// `send_lowat` bytes of the send buffer are free, using `SO_SNDLOWAT`. That
+    /// keeps a large response that drains slowly from waking us up for every few
+    /// bytes of space.
+    ///
+    /// Linux doesn't let us change `SO_SNDLOWAT`, so this returns an error of kind
+    /// `Unsupported` here. Linux only reports a TCP socket as writable once a third
+    /// of its send buffer is free anyway, and the send buffer size decides how
+    /// much that is.
+    pub fn register_with_send_lowat(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+        send_lowat: usize,
+    ) -> io::Result<()> {
+        if !interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A send low-water mark needs WRITABLE interest.",
+            ));
+        }
+        set_send_lowat(source.as_fd().as_raw_fd(), send_lowat)?;
+        self.register(source, token, interests)
+    }
+
+    /// Like `register`, but instead of changing the epoll interest list from this
+    /// thread, the registration is queued for the polling thread. It applies it at the
+    /// start of its next `select`, and we wake it up so that happens right away.
+    ///
+    /// Since the registration is applied later, errors from applying it can't be
+    /// returned from here. They're logged, and the registration is dropped.
+    pub fn register_deferred(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let change = Change {
+            fd: source.as_fd().as_raw_fd(),
+            token,
+            interests,
+        };
+        if self.changes.send(change).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
         }
+        trace!(
+            "queued registration of fd {} with token {}",
+            change.fd,
+            token
+        );
+        self.stats.wakeup();
+        self.kick.write(1)
+    }
 
+    /// Removes `source` from the interest list, so no more events are reported for it
+    /// (one that `select` has already returned can't be taken back). Deregistering a
+    /// source that isn't registered is not an error.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        deregister_fd(self.fd.as_raw_fd(), fd, &self.stats)?;
+        self.registrations.remove(fd);
         Ok(())
     }
 
     pub fn close_loop(&self) -> io::Result<()> {
         if self
             .is_poll_dead
-            .compare_and_swap(false, true, Ordering::SeqCst)
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
         {
             return Err(io::Error::new(
                 io::ErrorKind::Interrupted,
@@ -51,58 +143,346 @@ impl Registrator {
             ));
         }
 
-        // This is a little hacky but works for our needs right now
-        let wake_fd = eventfd(1, 0)?;
+        // This is a little hacky but works for our needs right now. The eventfd is
+        // created with a count of 1 so it's readable right away, and we leak it since
+        // the epoll instance will be closed soon anyway.
+        debug!("closing event loop on epoll fd {}", self.fd.as_raw_fd());
+        let wake = EventFd::new(1)?;
         let mut event = ffi::Event::new(ffi::EPOLLIN, 0);
-        epoll_ctl(self.fd, ffi::EPOLL_CTL_ADD, wake_fd, &mut event)?;
+        epoll_ctl(
+            self.fd.as_raw_fd(),
+            ffi::EPOLL_CTL_ADD,
+            wake.into_raw_fd(),
+            &mut event,
+        )?;
 
         Ok(())
     }
 }
 
+/// Adds `fd` to the interest list of the epoll instance `epfd`, or re-arms it if it's
+/// already there.
+fn register_fd(
+    epfd: RawFd,
+    fd: RawFd,
+    token: Token,
+    interests: Interests,
+    stats: &SyscallCounters,
+) -> io::Result<()> {
+    let mut flags = ffi::EPOLLONESHOT;
+    if interests.is_readable() {
+        flags |= ffi::EPOLLIN;
+    }
+    if interests.is_writable() {
+        flags |= ffi::EPOLLOUT;
+    }
+
+    // We register the id (or most oftenly referred to as a Token) to the `udata` field
+    // if the `Kevent`
+    let mut event = ffi::Event::new(flags, token);
+    // A oneshot registration stays in the interest list after it fires, so
+    // registering the same fd again means re-arming it. That makes registering
+    // work like it does with kqueue and IOCP.
+    stats.change();
+    let res = match epoll_ctl(epfd, ffi::EPOLL_CTL_ADD, fd, &mut event) {
+        Err(ref e) if e.raw_os_error() == Some(ffi::EEXIST) => {
+            stats.change();
+            epoll_ctl(epfd, ffi::EPOLL_CTL_MOD, fd, &mut event)
+        }
+        res => res,
+    };
+    if let Err(e) = res {
+        debug!(
+            "registering fd {} with token {} failed: {} (os error {:?})",
+            fd,
+            token,
+            e,
+            e.raw_os_error()
+        );
+        return Err(e);
+    }
+
+    debug!(
+        "registered fd {} with token {} for {}",
+        fd, token, interests
+    );
+    Ok(())
+}
+
+fn set_send_lowat(fd: RawFd, lowat: usize) -> io::Result<()> {
+    let lowat = lowat.min(i32::MAX as usize) as i32;
+    let res = unsafe {
+        ffi::setsockopt(
+            fd,
+            ffi::SOL_SOCKET,
+            ffi::SO_SNDLOWAT,
+            &lowat as *const i32 as *const std::ffi::c_void,
+            std::mem::size_of::<i32>() as u32,
+        )
+    };
+    if res < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(ffi::ENOPROTOOPT) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SO_SNDLOWAT can't be changed on Linux.",
+            ));
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn deregister_fd(epfd: RawFd, fd: RawFd, stats: &SyscallCounters) -> io::Result<()> {
+    // The event is ignored, but kernels before 2.6.9 require it to be there
+    let mut event = ffi::Event::new(0, 0);
+    stats.change();
+    match epoll_ctl(epfd, ffi::EPOLL_CTL_DEL, fd, &mut event) {
+        Ok(()) => (),
+        Err(ref e) if e.raw_os_error() == Some(ffi::ENOENT) => (),
+        Err(e) => {
+            debug!("deregistering fd {} failed: {}", fd, e);
+            return Err(e);
+        }
+    }
+    debug!("deregistered fd {}", fd);
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct Selector {
-    fd: RawFd,
+    fd: Arc<OwnedFd>,
+    changes: mpsc::Receiver<Change>,
+    change_sender: mpsc::Sender<Change>,
+    kick: Arc<EventFd>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+    full_selects: FullSelects,
+    /// Set once `epoll_pwait2` has failed with `ENOSYS`, or with `EPERM` from a
+    /// seccomp filter that doesn't know it
+    no_pwait2: AtomicBool,
 }
 
 impl Selector {
     pub fn new() -> io::Result<Self> {
+        let (change_sender, changes) = mpsc::channel();
+        let kick = EventFd::new(0)?;
         Ok(Selector {
-            fd: epoll_create()?,
+            fd: Arc::new(epoll_with_kick(&kick)?),
+            changes,
+            change_sender,
+            kick: Arc::new(kick),
+            registrations: Arc::default(),
+            stats: Arc::default(),
+            full_selects: FullSelects::default(),
+            no_pwait2: AtomicBool::new(false),
         })
     }
 
+    /// Deregisters every source registered with this selector or one of its
+    /// registrators, and drops the registrations still queued with
+    /// `Registrator::register_deferred`, so the selector can be reused as if it was
+    /// new. Wakers are deregistered as well and need to be registered again.
+    ///
+    /// A `select` already waiting in another thread keeps waiting on the old
+    /// registrations, and registrations made while this runs may be lost, so it's
+    /// meant for when nothing else is using the selector.
+    pub fn clear(&self) -> io::Result<()> {
+        for change in self.changes.try_iter() {
+            trace!("dropping deferred registration of fd {}", change.fd);
+        }
+        // epoll can't tell us what's registered with it, so we put a new instance in
+        // place of the old one under the fd number our registrators already have
+        let fresh = epoll_with_kick(&self.kick)?;
+        if unsafe { ffi::dup2(fresh.as_raw_fd(), self.fd.as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.registrations.clear();
+        debug!("cleared all registrations on epoll {}", self.fd.as_raw_fd());
+        Ok(())
+    }
+
+    /// Applies the registrations queued with `Registrator::register_deferred`.
+    fn apply_changes(&self) {
+        for change in self.changes.try_iter() {
+            match register_fd(
+                self.fd.as_raw_fd(),
+                change.fd,
+                change.token,
+                change.interests,
+                &self.stats,
+            ) {
+                Ok(()) => self
+                    .registrations
+                    .insert(change.fd, change.token, change.interests),
+                Err(e) => debug!(
+                    "dropping deferred registration of fd {} with token {}: {}",
+                    change.fd, change.token, e
+                ),
+            }
+        }
+    }
+
     /// This function blocks and waits until an event has been recieved. `timeout` None means
     /// the poll will never time out.
     pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        self.select_timeout(events, timeout_ms.map(millis))
+    }
+
+    /// Like `select`, but the timeout isn't limited to whole milliseconds. It's passed
+    /// to `epoll_pwait2` as is, so sub-millisecond timer wheels and pacing get the
+    /// kernel's precision. On kernels older than 5.11, which don't have
+    /// `epoll_pwait2`, it's rounded up to the next millisecond for `epoll_pwait`.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        self.select_with_sigmask(events, timeout, None)
+    }
+
+    /// Like `select_timeout`, but with the thread's signal mask replaced by `sigmask`
+    /// while it waits, and restored afterwards, as one atomic step like `ppoll` does.
+    /// Signals that are blocked everywhere else can then only be delivered while
+    /// we're waiting, which makes the wait return with `Interrupted` after their
+    /// handlers have run, with no window in between for one to get lost in.
+    pub fn select_with_sigmask(
+        &self,
+        events: &mut Events,
+        timeout: Option<Duration>,
+        sigmask: Option<&SigSet>,
+    ) -> io::Result<()> {
+        loop {
+            self.apply_changes();
+            self.wait(events, timeout, sigmask)?;
+
+            // Being kicked means there are new registrations to apply. If that's all that
+            // happened and we're supposed to block, we apply them and go back to waiting.
+            if events.iter().any(|event| event.id() == KICK_TOKEN) {
+                events.retain(|event| event.id() != KICK_TOKEN);
+                match self.kick.read() {
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(e),
+                }
+                if events.is_empty() && timeout.is_none() {
+                    continue;
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    fn wait(
+        &self,
+        events: &mut Events,
+        timeout: Option<Duration>,
+        sigmask: Option<&SigSet>,
+    ) -> io::Result<()> {
+        // epoll writes straight into the spare capacity of `events` so we can't let it
+        // return more events than there is room for
+        let max_events = events.capacity() as i32;
         events.clear();
-        let timeout = timeout_ms.unwrap_or(-1);
-        epoll_wait(self.fd, events, 1024, timeout).map(|n_events| {
-            // This is safe because `syscall_kevent` ensures that `n_events` are
-            // assigned. We could check for a valid token for each event to verify so this is
-            // just a performance optimization used in `mio` and copied here.
-            unsafe { events.set_len(n_events as usize) };
-        })
+        trace!(
+            "epoll_wait on fd {} with timeout {:?}",
+            self.fd.as_raw_fd(),
+            timeout
+        );
+        self.stats.wait();
+        let mut res = None;
+        if !self.no_pwait2.load(Ordering::Relaxed) {
+            match epoll_pwait2(self.fd.as_raw_fd(), events, max_events, timeout, sigmask) {
+                // Seccomp filters, like the default ones of some container runtimes,
+                // reject syscalls they don't know with EPERM rather than ENOSYS
+                Err(ref e)
+                    if e.raw_os_error() == Some(ffi::ENOSYS)
+                        || e.raw_os_error() == Some(ffi::EPERM) =>
+                {
+                    debug!(
+                        "epoll_pwait2 isn't supported or isn't allowed ({}), timeouts are rounded up to milliseconds",
+                        e
+                    );
+                    self.no_pwait2.store(true, Ordering::Relaxed);
+                }
+                other => res = Some(other),
+            }
+        }
+        let res = res.unwrap_or_else(|| {
+            let timeout = timeout.map_or(-1, ceil_millis);
+            epoll_pwait(self.fd.as_raw_fd(), events, max_events, timeout, sigmask)
+        });
+        match res {
+            Ok(n_events) => {
+                trace!(
+                    "epoll_wait on fd {} woke up with {} events",
+                    self.fd.as_raw_fd(),
+                    n_events
+                );
+                // This is safe because `syscall_kevent` ensures that `n_events` are
+                // assigned. We could check for a valid token for each event to verify so this is
+                // just a performance optimization used in `mio` and copied here.
+                unsafe { events.set_len(n_events as usize) };
+                self.full_selects
+                    .record(n_events as usize, max_events as usize);
+                Ok(())
+            }
+            Err(e) => {
+                debug!(
+                    "epoll_wait on fd {} failed: {} (os error {:?})",
+                    self.fd.as_raw_fd(),
+                    e,
+                    e.raw_os_error()
+                );
+                Err(e)
+            }
+        }
     }
 
     pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
         Registrator {
-            fd: self.fd,
+            fd: self.fd.clone(),
             is_poll_dead,
+            changes: self.change_sender.clone(),
+            kick: self.kick.clone(),
+            registrations: self.registrations.clone(),
+            stats: self.stats.clone(),
         }
     }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Each of those probably left events behind in the queue, so a count that
+    /// keeps growing means the loop isn't keeping up and should shed load or poll with
+    /// a larger buffer. 0 if the last select had room to spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
 }
 
-impl Drop for Selector {
-    fn drop(&mut self) {
-        match close_fd(self.fd) {
-            Ok(..) => (),
-            Err(e) => {
-                if !std::thread::panicking() {
-                    panic!(e);
-                }
-            }
-        }
+/// The epoll fd is readable whenever `select` has events to return, so a selector can
+/// be registered with another one, like any other source, with `Interests::READABLE`.
+/// A library can keep its sources on a private selector that way and only tell the
+/// application's loop that it has something to do. Like every registration it's
+/// oneshot, so register it again once the events have been selected. A registrator
+/// kicking the selector makes it readable too, and selecting then returns nothing.
+///
+/// The fd also lets a loop that isn't minimio's, like a GUI toolkit's, wait for the
+/// selector's events among its own, and then `select` with a zero timeout. Changing
+/// the registrations with `epoll_ctl` behind the registrator's back is not supported.
+impl AsRawFd for Selector {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Selector {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
     }
 }
 
@@ -111,6 +491,36 @@ impl Event {
     pub fn id(&self) -> Token {
         self.data()
     }
+
+    /// Returns true if the source can be read from, or has hung up.
+    pub fn is_readable(&self) -> bool {
+        self.flags() & (ffi::EPOLLIN | ffi::EPOLLRDHUP | ffi::EPOLLHUP) != 0
+    }
+
+    /// Returns true if the source can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.flags() & ffi::EPOLLOUT != 0
+    }
+
+    /// An event the way `epoll_wait` would have returned it, for events that are
+    /// rebuilt after being held back, or that come from the `select` selector.
+    pub(crate) fn with_readiness(token: Token, readable: bool, writable: bool) -> Event {
+        let mut flags = 0;
+        if readable {
+            flags |= ffi::EPOLLIN;
+        }
+        if writable {
+            flags |= ffi::EPOLLOUT;
+        }
+        ffi::Event::new(flags, token)
+    }
+
+    /// Adds the readiness of `other`, an event for the same token, to this one. epoll
+    /// reports all of an fd's readiness in one event, so they can always be merged.
+    pub(crate) fn merge(&mut self, other: &Event) -> bool {
+        self.add_flags(other.flags());
+        true
+    }
 }
 
 pub struct TcpStream {
@@ -123,10 +533,26 @@ impl TcpStream {
         // we get from the stdlib but could do with a syscall. Let's skip that step in this example.
         // In other words this will block shortly establishing a connection to the remote server
         let stream = net::TcpStream::connect(adr)?;
-        stream.set_nonblocking(true)?;
+        TcpStream::from_std(stream)
+    }
 
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
         Ok(TcpStream { inner: stream })
     }
+
+    /// `read` leaves the socket blocking, so anything that relies on getting
+    /// `WouldBlock` has to set it back first.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        self.inner.set_nonblocking(true)
+    }
+
+    /// Reads without changing the blocking mode, unlike `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
 }
 
 impl Read for TcpStream {
@@ -168,14 +594,953 @@ impl AsRawFd for TcpStream {
     }
 }
 
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+impl TcpStream {
+    /// The CPU that handled the last packet the stream received, as reported by
+    /// `SO_INCOMING_CPU`. In a multi-reactor setup, handing the connection to the loop
+    /// pinned to that CPU keeps its data in that CPU's caches. Returns `None` if the
+    /// stream hasn't received anything yet.
+    pub fn incoming_cpu(&self) -> io::Result<Option<usize>> {
+        let cpu = get_int_option(self.as_raw_fd(), ffi::SO_INCOMING_CPU)?;
+        Ok(if cpu < 0 { None } else { Some(cpu as usize) })
+    }
+
+    /// Connects to `addr` with Multipath TCP, falling back to plain TCP if the kernel
+    /// or the peer doesn't do it, see `TcpSocket::new_mptcp_for_addr`.
+    pub fn connect_mptcp(addr: net::SocketAddr) -> io::Result<Self> {
+        crate::TcpSocket::new_mptcp_for_addr(addr)?.connect(addr)
+    }
+
+    /// Whether the stream is a Multipath TCP one. An MPTCP stream whose peer turned
+    /// out not to do MPTCP carries on as plain TCP but still says it is.
+    pub fn is_mptcp(&self) -> io::Result<bool> {
+        crate::socket::is_mptcp(self.as_raw_fd())
+    }
+}
+
+/// Reads `TCP_INFO`. The kernel copies as much of its `tcp_info` as we have room for,
+/// so we only declare the part we read.
+pub(crate) fn tcp_info(stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    let mut info = ffi::TcpInfo::default();
+    let mut len = std::mem::size_of::<ffi::TcpInfo>() as u32;
+    let res = unsafe {
+        ffi::getsockopt(
+            stream.as_raw_fd(),
+            ffi::IPPROTO_TCP,
+            ffi::TCP_INFO,
+            &mut info as *mut ffi::TcpInfo as *mut std::ffi::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    use crate::TcpState::*;
+    let state = match info.state {
+        1 => Established,
+        2 => SynSent,
+        3 => SynReceived,
+        4 => FinWait1,
+        5 => FinWait2,
+        6 => TimeWait,
+        7 => Closed,
+        8 => CloseWait,
+        9 => LastAck,
+        10 => Listen,
+        11 => Closing,
+        other => Unknown(other),
+    };
+    Ok(crate::TcpInfo {
+        state,
+        rtt: Duration::from_micros(info.rtt as u64),
+        retransmits: info.total_retrans as u64,
+        // The window is counted in segments
+        congestion_window: info.snd_cwnd as u64 * info.snd_mss as u64,
+        mss: info.snd_mss,
+    })
+}
+
+/// Steering connections to cores. Linux only lets several listeners share a port if
+/// `SO_REUSEPORT` is set before binding, which `std` doesn't do, so listeners meant to
+/// be steered between should be created with `bind_reuseport`.
+impl crate::TcpListener {
+    /// Binds a listener with `SO_REUSEPORT` set, so one listener per loop can be bound
+    /// to the same address and the kernel spreads connections between them.
+    pub fn bind_reuseport(addr: net::SocketAddr) -> io::Result<Self> {
+        let socket = crate::TcpSocket::new_for_addr(addr)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+        socket.listen(1024)
+    }
+
+    /// Binds a Multipath TCP listener, or a plain TCP one if the kernel has no MPTCP,
+    /// see `TcpSocket::new_mptcp_for_addr`.
+    pub fn bind_mptcp(addr: net::SocketAddr) -> io::Result<Self> {
+        let socket = crate::TcpSocket::new_mptcp_for_addr(addr)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        socket.listen(1024)
+    }
+
+    /// Whether the listener is a Multipath TCP one.
+    pub fn is_mptcp(&self) -> io::Result<bool> {
+        crate::socket::is_mptcp(self.as_raw_fd())
+    }
+
+    /// Sets `SO_INCOMING_CPU` on the listener. Of the listeners sharing a port with
+    /// `SO_REUSEPORT`, the kernel prefers the one whose CPU handled the incoming
+    /// connection's packets.
+    pub fn set_incoming_cpu(&self, cpu: usize) -> io::Result<()> {
+        let cpu = cpu.min(i32::MAX as usize) as i32;
+        set_int_option(self.as_raw_fd(), ffi::SO_INCOMING_CPU, cpu)
+    }
+
+    /// Attaches a classic BPF program to the `SO_REUSEPORT` group the listener is in,
+    /// which hands each connection to listener number `cpu % listeners`, numbered in
+    /// the order they were bound. With one listener per CPU, bound in CPU order, every
+    /// connection is accepted on the CPU that received it.
+    ///
+    /// Returns an error of kind `InvalidInput` if the listener wasn't bound with
+    /// `bind_reuseport`.
+    pub fn attach_reuseport_cpu_steering(&self, listeners: usize) -> io::Result<()> {
+        if listeners == 0 || listeners > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "There has to be at least one listener to steer to.",
+            ));
+        }
+        // A = cpu; A %= listeners; return A
+        let program = [
+            ffi::SockFilter::new(
+                ffi::BPF_LD | ffi::BPF_W | ffi::BPF_ABS,
+                ffi::SKF_AD_OFF + ffi::SKF_AD_CPU,
+            ),
+            ffi::SockFilter::new(ffi::BPF_ALU | ffi::BPF_MOD | ffi::BPF_K, listeners as u32),
+            ffi::SockFilter::new(ffi::BPF_RET | ffi::BPF_A, 0),
+        ];
+        let fprog = ffi::SockFprog {
+            len: program.len() as u16,
+            filter: program.as_ptr(),
+        };
+        let res = unsafe {
+            ffi::setsockopt(
+                self.as_raw_fd(),
+                ffi::SOL_SOCKET,
+                ffi::SO_ATTACH_REUSEPORT_CBPF,
+                &fprog as *const ffi::SockFprog as *const std::ffi::c_void,
+                std::mem::size_of::<ffi::SockFprog>() as u32,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        debug!(
+            "attached cpu steering over {} listeners to fd {}",
+            listeners,
+            self.as_raw_fd()
+        );
+        Ok(())
+    }
+}
+
+/// A wrapper around an eventfd, a kernel object holding a 64 bit counter. The
+/// eventfd is readable as long as the counter is larger than 0, which makes it a
+/// cheap way to wake up a thread blocked in `select` from another thread.
+///
+/// Reference: http://man7.org/linux/man-pages/man2/eventfd.2.html
+#[derive(Debug)]
+pub struct EventFd {
+    fd: OwnedFd,
+}
+
+impl EventFd {
+    /// Creates a new non-blocking eventfd with its counter set to `initval`.
+    pub fn new(initval: u32) -> io::Result<Self> {
+        let fd = eventfd(initval, ffi::EFD_NONBLOCK | ffi::EFD_CLOEXEC)?;
+        Ok(EventFd {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Adds `value` to the counter, which makes the eventfd readable.
+    pub fn write(&self, value: u64) -> io::Result<()> {
+        let buf = value.to_ne_bytes();
+        let res = unsafe { ffi::write(self.fd.as_raw_fd(), buf.as_ptr(), buf.len()) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the value of the counter and resets it to 0. Returns an error of kind
+    /// `WouldBlock` if the counter is already 0.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        let res = unsafe { ffi::read(self.fd.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(u64::from_ne_bytes(buf))
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for EventFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl FromRawFd for EventFd {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        EventFd {
+            fd: OwnedFd::from_raw_fd(fd),
+        }
+    }
+}
+
+impl IntoRawFd for EventFd {
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+/// The clock a `TimerFd` measures time against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockId {
+    /// Wall clock time, affected by changes to the system time
+    Realtime,
+    /// A clock that never jumps, unaffected by changes to the system time
+    Monotonic,
+}
+
+impl ClockId {
+    fn raw(self) -> i32 {
+        match self {
+            ClockId::Realtime => ffi::CLOCK_REALTIME,
+            ClockId::Monotonic => ffi::CLOCK_MONOTONIC,
+        }
+    }
+}
+
+/// A wrapper around a timerfd, a timer that delivers its expirations through a file
+/// descriptor. The timerfd is readable once the timer has expired, which gives us
+/// timers with the kernel's precision as ordinary events from `select`.
+///
+/// Reference: http://man7.org/linux/man-pages/man2/timerfd_create.2.html
+#[derive(Debug)]
+pub struct TimerFd {
+    fd: OwnedFd,
+}
+
+impl TimerFd {
+    /// Creates a new non-blocking timerfd which is not armed.
+    pub fn new(clock: ClockId) -> io::Result<Self> {
+        let fd = unsafe { ffi::timerfd_create(clock.raw(), ffi::TFD_NONBLOCK | ffi::TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TimerFd {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Arms the timer to expire once after `timeout`.
+    pub fn set_oneshot(&self, timeout: Duration) -> io::Result<()> {
+        // An expiration of 0 would disarm the timer instead
+        let timeout = timeout.max(Duration::from_nanos(1));
+        self.settime(timeout, Duration::from_secs(0))
+    }
+
+    /// Arms the timer to expire every `period`, starting one `period` from now. The
+    /// kernel schedules each expiration from the previous one, so ticks don't drift
+    /// even if we're late to handle them; `read` tells how many were missed.
+    pub fn set_interval(&self, period: Duration) -> io::Result<()> {
+        let period = period.max(Duration::from_nanos(1));
+        self.settime(period, period)
+    }
+
+    /// Stops the timer. Expirations that haven't been read yet are dropped too, so
+    /// `read` returns `WouldBlock` afterwards, but an event for the timer that `poll`
+    /// has already returned can't be taken back. To push the deadline back instead,
+    /// call `set_oneshot` again, which likewise drops unread expirations.
+    pub fn disarm(&self) -> io::Result<()> {
+        self.settime(Duration::from_secs(0), Duration::from_secs(0))
+    }
+
+    /// Returns the number of times the timer has expired since it was armed or last
+    /// read. Returns an error of kind `WouldBlock` if it hasn't expired.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        let res = unsafe { ffi::read(self.fd.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(u64::from_ne_bytes(buf))
+        }
+    }
+
+    fn settime(&self, value: Duration, interval: Duration) -> io::Result<()> {
+        let new_value = ffi::Itimerspec {
+            it_interval: ffi::Timespec::from_duration(interval),
+            it_value: ffi::Timespec::from_duration(value),
+        };
+        let res = unsafe {
+            ffi::timerfd_settime(self.fd.as_raw_fd(), 0, &new_value, std::ptr::null_mut())
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for TimerFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// A set of signals, used to tell a `SignalFd` which signals to watch.
+#[derive(Clone)]
+pub struct SigSet {
+    inner: ffi::SigsetT,
+}
+
+impl SigSet {
+    /// Creates a set without any signals
+    pub fn empty() -> Self {
+        let mut inner = ffi::SigsetT::default();
+        unsafe { ffi::sigemptyset(&mut inner) };
+        SigSet { inner }
+    }
+
+    /// Adds `signal` (e.g. 10 for SIGUSR1) to the set.
+    pub fn with_signal(mut self, signal: i32) -> io::Result<Self> {
+        let res = unsafe { ffi::sigaddset(&mut self.inner, signal) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(self)
+    }
+
+    pub fn contains(&self, signal: i32) -> bool {
+        unsafe { ffi::sigismember(&self.inner, signal) == 1 }
+    }
+}
+
+impl std::fmt::Debug for SigSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set()
+            .entries((1..ffi::NSIG).filter(|&signal| self.contains(signal)))
+            .finish()
+    }
+}
+
+/// A wrapper around a signalfd. The signals in the mask are blocked for the calling
+/// thread and instead of running a signal handler they're queued on the signalfd,
+/// which becomes readable. That turns signals into ordinary events from `select`.
+///
+/// Signals are only blocked in the thread creating the `SignalFd` (and threads
+/// spawned from it later), so this should be done before any other threads are
+/// started or the signals might be delivered to one of them instead.
+///
+/// Reference: http://man7.org/linux/man-pages/man2/signalfd.2.html
+#[derive(Debug)]
+pub struct SignalFd {
+    fd: OwnedFd,
+}
+
+impl SignalFd {
+    pub fn new(mask: &SigSet) -> io::Result<Self> {
+        let res =
+            unsafe { ffi::pthread_sigmask(ffi::SIG_BLOCK, &mask.inner, std::ptr::null_mut()) };
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res));
+        }
+
+        let fd = unsafe { ffi::signalfd(-1, &mask.inner, ffi::SFD_NONBLOCK | ffi::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SignalFd {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Reads one pending signal. Returns an error of kind `WouldBlock` if there are
+    /// no signals pending.
+    pub fn read(&self) -> io::Result<SigInfo> {
+        let mut info = SigInfo {
+            inner: ffi::SignalfdSiginfo::default(),
+        };
+        let size = std::mem::size_of::<ffi::SignalfdSiginfo>();
+        let buf: *mut ffi::SignalfdSiginfo = &mut info.inner;
+        let res = unsafe { ffi::read(self.fd.as_raw_fd(), buf as *mut u8, size) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(info)
+        }
+    }
+
+    /// Returns an iterator over the signals pending on the signalfd. The iterator
+    /// ends once there are no more signals to read.
+    pub fn pending(&self) -> Pending<'_> {
+        Pending { signalfd: self }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for SignalFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Iterator over the signals pending on a `SignalFd`, returned by `SignalFd::pending`.
+#[derive(Debug)]
+pub struct Pending<'a> {
+    signalfd: &'a SignalFd,
+}
+
+impl<'a> Iterator for Pending<'a> {
+    type Item = SigInfo;
+
+    fn next(&mut self) -> Option<SigInfo> {
+        // Anything but a signal means there is nothing more to read for now
+        self.signalfd.read().ok()
+    }
+}
+
+/// Information about a received signal as reported by a `SignalFd`.
+#[derive(Clone)]
+pub struct SigInfo {
+    inner: ffi::SignalfdSiginfo,
+}
+
+impl SigInfo {
+    /// The signal number
+    pub fn signal(&self) -> i32 {
+        self.inner.ssi_signo as i32
+    }
+
+    /// The signal code, telling what sent the signal
+    pub fn code(&self) -> i32 {
+        self.inner.ssi_code
+    }
+
+    /// The process id of the sender
+    pub fn pid(&self) -> u32 {
+        self.inner.ssi_pid
+    }
+
+    /// The real user id of the sender
+    pub fn uid(&self) -> u32 {
+        self.inner.ssi_uid
+    }
+
+    /// The exit status or signal of a child for SIGCHLD
+    pub fn status(&self) -> i32 {
+        self.inner.ssi_status
+    }
+}
+
+impl std::fmt::Debug for SigInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SigInfo")
+            .field("signal", &self.signal())
+            .field("code", &self.code())
+            .field("pid", &self.pid())
+            .field("uid", &self.uid())
+            .field("status", &self.status())
+            .finish()
+    }
+}
+
+/// Signals watched with a token each, so the token of an event says which signal
+/// arrived. Binding SIGHUP to a reload token, SIGUSR1 to a stats token and SIGTERM to
+/// a shutdown token lets the event loop dispatch on the token like it does for
+/// everything else.
+///
+/// Every bound signal gets a `SignalFd` of its own, created on the calling thread,
+/// so the same caveat about blocking signals before other threads are started
+/// applies to `bind`.
+#[derive(Debug)]
+pub struct Signals {
+    registrator: Registrator,
+    bound: HashMap<i32, (Token, SignalFd)>,
+}
+
+impl Signals {
+    pub fn new(registrator: Registrator) -> Self {
+        Signals {
+            registrator,
+            bound: HashMap::new(),
+        }
+    }
+
+    /// Starts watching `signal`, reported with an event with `token` as its id.
+    /// Binding a signal that's already bound moves it to `token`. Several signals
+    /// can share a token.
+    pub fn bind(&mut self, signal: i32, token: Token) -> io::Result<()> {
+        if let Some((bound, signalfd)) = self.bound.get_mut(&signal) {
+            self.registrator
+                .register(&*signalfd, token, Interests::READABLE)?;
+            *bound = token;
+            return Ok(());
+        }
+        let signalfd = SignalFd::new(&SigSet::empty().with_signal(signal)?)?;
+        self.registrator
+            .register(&signalfd, token, Interests::READABLE)?;
+        self.bound.insert(signal, (token, signalfd));
+        Ok(())
+    }
+
+    /// Stops watching `signal`. It stays blocked, so it's ignored from now on rather
+    /// than handled the way it was before it was bound.
+    pub fn unbind(&mut self, signal: i32) -> io::Result<()> {
+        match self.bound.remove(&signal) {
+            Some((_, signalfd)) => self.registrator.deregister(&signalfd),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Signal is not bound.",
+            )),
+        }
+    }
+
+    /// Reads the pending signals bound to `token` and re-arms them, to be called
+    /// when an event with `token` is returned from `poll`.
+    pub fn take(&self, token: Token) -> io::Result<Vec<SigInfo>> {
+        let mut received = Vec::new();
+        for (bound, signalfd) in self.bound.values() {
+            if *bound != token {
+                continue;
+            }
+            received.extend(signalfd.pending());
+            self.registrator
+                .register(signalfd, token, Interests::READABLE)?;
+        }
+        Ok(received)
+    }
+}
+
+/// A wrapper around a pidfd, a file descriptor referring to a process. The pidfd
+/// becomes readable when the process exits, after which the exit status can be
+/// collected with `wait_nonblocking`. Only works for child processes of ours if we
+/// want the exit status.
+///
+/// Reference: http://man7.org/linux/man-pages/man2/pidfd_open.2.html
+#[derive(Debug)]
+pub struct PidFd {
+    fd: OwnedFd,
+}
+
+impl PidFd {
+    pub fn open(pid: u32) -> io::Result<Self> {
+        let res = unsafe { ffi::syscall(ffi::SYS_PIDFD_OPEN, pid as i64, 0i64) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(res as RawFd) };
+        // Make sure the pidfd isn't leaked to programs we spawn later on
+        let res = unsafe { ffi::fcntl(fd.as_raw_fd(), ffi::F_SETFD, ffi::FD_CLOEXEC) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PidFd { fd })
+    }
+
+    /// Reaps the process if it has exited and returns its exit status. Returns
+    /// `None` if it's still running.
+    pub fn wait_nonblocking(&self) -> io::Result<Option<ExitStatus>> {
+        let mut info = ffi::SiginfoT::default();
+        let res = unsafe {
+            ffi::waitid(
+                ffi::P_PIDFD,
+                self.fd.as_raw_fd() as u32,
+                &mut info,
+                ffi::WEXITED | ffi::WNOHANG,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // With `WNOHANG` the pid is left as 0 if the process hasn't changed state
+        if info.si_pid == 0 {
+            return Ok(None);
+        }
+
+        // Re-create the status `waitpid` would have returned so we can use the std type
+        let status = match info.si_code {
+            ffi::CLD_EXITED => (info.si_status & 0xff) << 8,
+            ffi::CLD_DUMPED => info.si_status | 0x80,
+            _ => info.si_status,
+        };
+        Ok(Some(ExitStatus::from_raw(status)))
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Filesystem events to watch for with `Inotify::add_watch`, and the events
+/// reported by `InotifyEvent::mask`. Combine them with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchMask(u32);
+
+impl WatchMask {
+    pub const ACCESS: WatchMask = WatchMask(0x1);
+    pub const MODIFY: WatchMask = WatchMask(0x2);
+    pub const ATTRIB: WatchMask = WatchMask(0x4);
+    pub const CLOSE_WRITE: WatchMask = WatchMask(0x8);
+    pub const CLOSE_NOWRITE: WatchMask = WatchMask(0x10);
+    pub const OPEN: WatchMask = WatchMask(0x20);
+    pub const MOVED_FROM: WatchMask = WatchMask(0x40);
+    pub const MOVED_TO: WatchMask = WatchMask(0x80);
+    pub const CREATE: WatchMask = WatchMask(0x100);
+    pub const DELETE: WatchMask = WatchMask(0x200);
+    pub const DELETE_SELF: WatchMask = WatchMask(0x400);
+    pub const MOVE_SELF: WatchMask = WatchMask(0x800);
+    pub const ALL_EVENTS: WatchMask = WatchMask(0xfff);
+    /// Set in reported events when the event queue overflowed and events were lost
+    pub const Q_OVERFLOW: WatchMask = WatchMask(0x4000);
+    /// Set in reported events when the watch was removed
+    pub const IGNORED: WatchMask = WatchMask(0x8000);
+    /// Set in reported events when the subject of the event is a directory
+    pub const ISDIR: WatchMask = WatchMask(0x4000_0000);
+
+    pub fn contains(&self, other: WatchMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+impl std::ops::BitOr for WatchMask {
+    type Output = WatchMask;
+
+    fn bitor(self, other: WatchMask) -> WatchMask {
+        WatchMask(self.0 | other.0)
+    }
+}
+
+/// Identifies a watch added with `Inotify::add_watch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchDescriptor(i32);
+
+/// A wrapper around an inotify instance. The fd becomes readable when any of the
+/// watched files or directories change, after which `read_events` parses the
+/// records the kernel has queued.
+///
+/// Reference: http://man7.org/linux/man-pages/man7/inotify.7.html
+#[derive(Debug)]
+pub struct Inotify {
+    fd: OwnedFd,
+    buffer: Vec<u8>,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { ffi::inotify_init1(ffi::IN_NONBLOCK | ffi::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            // Room for plenty of events, and always at least one with the longest name
+            buffer: vec![0; 4096],
+        })
+    }
+
+    /// Starts watching `path` for the events in `mask`. Watching a path that is
+    /// already watched replaces its mask and returns the same descriptor.
+    pub fn add_watch(
+        &self,
+        path: impl AsRef<Path>,
+        mask: WatchMask,
+    ) -> io::Result<WatchDescriptor> {
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        let wd = unsafe { ffi::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), mask.0) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(WatchDescriptor(wd))
+    }
+
+    pub fn rm_watch(&self, wd: WatchDescriptor) -> io::Result<()> {
+        let res = unsafe { ffi::inotify_rm_watch(self.fd.as_raw_fd(), wd.0) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads the queued events and returns an iterator over them. Returns an error of
+    /// kind `WouldBlock` if there are no events.
+    pub fn read_events(&mut self) -> io::Result<InotifyEvents<'_>> {
+        let res = unsafe {
+            ffi::read(
+                self.fd.as_raw_fd(),
+                self.buffer.as_mut_ptr(),
+                self.buffer.len(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(InotifyEvents {
+            buffer: &self.buffer[..res as usize],
+        })
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Inotify {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Iterator over the events returned by `Inotify::read_events`.
+#[derive(Debug)]
+pub struct InotifyEvents<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> Iterator for InotifyEvents<'a> {
+    type Item = InotifyEvent<'a>;
+
+    fn next(&mut self) -> Option<InotifyEvent<'a>> {
+        // Each record is a fixed size header followed by `len` bytes holding a null
+        // terminated (and null padded) name.
+        const HEADER: usize = std::mem::size_of::<ffi::InotifyEvent>();
+        if self.buffer.len() < HEADER {
+            return None;
+        }
+
+        let header =
+            unsafe { std::ptr::read_unaligned(self.buffer.as_ptr() as *const ffi::InotifyEvent) };
+        let end = HEADER + header.len as usize;
+        let name = self.buffer[HEADER..end]
+            .split(|&b| b == 0)
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(OsStr::from_bytes);
+        self.buffer = &self.buffer[end..];
+
+        Some(InotifyEvent {
+            wd: WatchDescriptor(header.wd),
+            mask: WatchMask(header.mask),
+            cookie: header.cookie,
+            name,
+        })
+    }
+}
+
+/// A filesystem event reported by `Inotify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InotifyEvent<'a> {
+    /// The watch the event belongs to
+    pub wd: WatchDescriptor,
+    /// What happened
+    pub mask: WatchMask,
+    /// Ties together the `MOVED_FROM` and `MOVED_TO` events of a rename
+    pub cookie: u32,
+    /// The name of the file inside a watched directory the event concerns
+    pub name: Option<&'a OsStr>,
+}
+
 mod ffi {
-    use std::io;
-    use std::os::raw::c_void;
+    use crate::Flags;
+    use std::fmt;
+    use std::os::raw::c_char;
+    use std::time::Duration;
 
     pub const EPOLL_CTL_ADD: i32 = 1;
     pub const EPOLL_CTL_DEL: i32 = 2;
+    pub const EPOLL_CTL_MOD: i32 = 3;
+    pub const EPERM: i32 = 1;
+    pub const ENOENT: i32 = 2;
+    pub const ENOSYS: i32 = 38;
+    pub const EEXIST: i32 = 17;
+    pub const ENOPROTOOPT: i32 = 92;
+    pub const SOL_SOCKET: i32 = 1;
+    pub const IPPROTO_TCP: i32 = 6;
+    pub const TCP_INFO: i32 = 11;
+    pub const SO_SNDLOWAT: i32 = 19;
+    pub const SO_INCOMING_CPU: i32 = 49;
+    pub const SO_ATTACH_REUSEPORT_CBPF: i32 = 51;
+    pub const BPF_LD: u16 = 0x00;
+    pub const BPF_W: u16 = 0x00;
+    pub const BPF_ABS: u16 = 0x20;
+    pub const BPF_ALU: u16 = 0x04;
+    pub const BPF_MOD: u16 = 0x90;
+    pub const BPF_K: u16 = 0x00;
+    pub const BPF_RET: u16 = 0x06;
+    pub const BPF_A: u16 = 0x10;
+    pub const SKF_AD_OFF: u32 = 0xfffff000;
+    pub const SKF_AD_CPU: u32 = 36;
     pub const EPOLLIN: i32 = 0x1;
+    pub const EPOLLOUT: i32 = 0x4;
+    pub const EPOLLHUP: i32 = 0x10;
+    pub const EPOLLRDHUP: i32 = 0x2000;
     pub const EPOLLONESHOT: i32 = 0x40000000;
+    pub const EFD_CLOEXEC: i32 = 0x80000;
+    pub const EFD_NONBLOCK: i32 = 0x800;
+    pub const CLOCK_REALTIME: i32 = 0;
+    pub const CLOCK_MONOTONIC: i32 = 1;
+    pub const TFD_CLOEXEC: i32 = 0x80000;
+    pub const TFD_NONBLOCK: i32 = 0x800;
+    pub const SFD_CLOEXEC: i32 = 0x80000;
+    pub const SFD_NONBLOCK: i32 = 0x800;
+    pub const SIG_BLOCK: i32 = 0;
+    pub const NSIG: i32 = 65;
+    pub const SYS_PIDFD_OPEN: i64 = 434;
+    pub const SYS_EPOLL_PWAIT2: i64 = 441;
+    pub const P_PIDFD: i32 = 3;
+    pub const WNOHANG: i32 = 1;
+    pub const WEXITED: i32 = 4;
+    pub const CLD_EXITED: i32 = 1;
+    pub const CLD_DUMPED: i32 = 3;
+    pub const F_SETFD: i32 = 2;
+    pub const FD_CLOEXEC: i32 = 1;
+    pub const IN_CLOEXEC: i32 = 0x80000;
+    pub const IN_NONBLOCK: i32 = 0x800;
+
+    // http://man7.org/linux/man-pages/man7/inotify.7.html
+    #[repr(C)]
+    pub struct InotifyEvent {
+        pub wd: i32,
+        pub mask: u32,
+        pub cookie: u32,
+        pub len: u32,
+    }
+
+    /// The part of `siginfo_t` that's filled in for child processes. The kernel
+    /// writes 128 bytes no matter the signal so we pad it out.
+    #[derive(Default)]
+    #[repr(C)]
+    pub struct SiginfoT {
+        pub si_signo: i32,
+        pub si_errno: i32,
+        pub si_code: i32,
+        _pad0: i32,
+        pub si_pid: i32,
+        pub si_uid: u32,
+        pub si_status: i32,
+        _pad: [u32; 25],
+    }
+
+    /// The 64 signals the kernel has fit in 8 bytes, the first 8 of `SigsetT`
+    pub const KERNEL_SIGSET_SIZE: i64 = 8;
+
+    /// glibc reserves room for 1024 signals in `sigset_t` even if the kernel only uses 64
+    #[derive(Clone, Default)]
+    #[repr(C)]
+    pub struct SigsetT {
+        val: [u64; 16],
+    }
+
+    // http://man7.org/linux/man-pages/man2/signalfd.2.html
+    #[derive(Clone, Default)]
+    #[repr(C)]
+    pub struct SignalfdSiginfo {
+        pub ssi_signo: u32,
+        pub ssi_errno: i32,
+        pub ssi_code: i32,
+        pub ssi_pid: u32,
+        pub ssi_uid: u32,
+        pub ssi_fd: i32,
+        pub ssi_tid: u32,
+        pub ssi_band: u32,
+        pub ssi_overrun: u32,
+        pub ssi_trapno: u32,
+        pub ssi_status: i32,
+        pub ssi_int: i32,
+        pub ssi_ptr: u64,
+        pub ssi_utime: u64,
+        pub ssi_stime: u64,
+        pub ssi_addr: u64,
+        pub ssi_addr_lsb: u16,
+        _pad2: u16,
+        pub ssi_syscall: i32,
+        pub ssi_call_addr: u64,
+        pub ssi_arch: u32,
+        // The struct is padded to 128 bytes
+        _pad: [u8; 28],
+    }
+
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct Timespec {
+        /// Seconds
+        tv_sec: isize,
+        /// Nanoseconds
+        tv_nsec: isize,
+    }
+
+    impl Timespec {
+        /// Durations too long for `tv_sec` are cut down to the longest one that
+        /// fits, which is as good as forever.
+        pub fn from_duration(duration: Duration) -> Self {
+            Timespec {
+                tv_sec: duration.as_secs().min(isize::MAX as u64) as isize,
+                tv_nsec: duration.subsec_nanos() as isize,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct Itimerspec {
+        /// The period for periodic timers, 0 for a timer that expires once
+        pub it_interval: Timespec,
+        /// The time until the first expiration, 0 disarms the timer
+        pub it_value: Timespec,
+    }
 
     /// Since the same name is used multiple times, it can be confusing but we have an `Event` structure.
     /// This structure ties a file descriptor and a field called `events` together. The field `events` holds information
@@ -197,6 +1562,110 @@ mod ffi {
         pub fn data(&self) -> usize {
             self.epoll_data
         }
+
+        pub fn flags(&self) -> i32 {
+            self.events as i32
+        }
+
+        pub fn add_flags(&mut self, flags: i32) {
+            self.events |= flags as u32;
+        }
+    }
+
+    // http://man7.org/linux/man-pages/man2/epoll_ctl.2.html
+    const EPOLL_FLAGS: &[(u64, &str)] = &[
+        (0x1, "EPOLLIN"),
+        (0x2, "EPOLLPRI"),
+        (0x4, "EPOLLOUT"),
+        (0x8, "EPOLLERR"),
+        (0x10, "EPOLLHUP"),
+        (0x40, "EPOLLRDNORM"),
+        (0x80, "EPOLLRDBAND"),
+        (0x100, "EPOLLWRNORM"),
+        (0x200, "EPOLLWRBAND"),
+        (0x400, "EPOLLMSG"),
+        (0x2000, "EPOLLRDHUP"),
+        (1 << 28, "EPOLLEXCLUSIVE"),
+        (1 << 29, "EPOLLWAKEUP"),
+        (1 << 30, "EPOLLONESHOT"),
+        (1 << 31, "EPOLLET"),
+    ];
+
+    impl fmt::Debug for Event {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            // The struct is packed so we copy the fields out instead of referencing them
+            let events = self.events;
+            let token = self.epoll_data;
+            f.debug_struct("Event")
+                .field("token", &token)
+                .field("events", &Flags(events as u64, EPOLL_FLAGS))
+                .finish()
+        }
+    }
+
+    // The start of `struct tcp_info`, up to `tcpi_total_retrans`, see
+    // http://man7.org/linux/man-pages/man7/tcp.7.html and include/uapi/linux/tcp.h
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct TcpInfo {
+        pub state: u8,
+        ca_state: u8,
+        retransmits: u8,
+        probes: u8,
+        backoff: u8,
+        options: u8,
+        wscale: u8,
+        flags: u8,
+        rto: u32,
+        ato: u32,
+        pub snd_mss: u32,
+        rcv_mss: u32,
+        unacked: u32,
+        sacked: u32,
+        lost: u32,
+        retrans: u32,
+        fackets: u32,
+        last_data_sent: u32,
+        last_ack_sent: u32,
+        last_data_recv: u32,
+        last_ack_recv: u32,
+        pmtu: u32,
+        rcv_ssthresh: u32,
+        pub rtt: u32,
+        rttvar: u32,
+        snd_ssthresh: u32,
+        pub snd_cwnd: u32,
+        advmss: u32,
+        reordering: u32,
+        rcv_rtt: u32,
+        rcv_space: u32,
+        pub total_retrans: u32,
+    }
+
+    // http://man7.org/linux/man-pages/man7/socket.7.html, under SO_ATTACH_FILTER
+    #[repr(C)]
+    pub struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    impl SockFilter {
+        pub fn new(code: u16, k: u32) -> Self {
+            SockFilter {
+                code,
+                jt: 0,
+                jf: 0,
+                k,
+            }
+        }
+    }
+
+    #[repr(C)]
+    pub struct SockFprog {
+        pub len: u16,
+        pub filter: *const SockFilter,
     }
 
     #[link(name = "c")]
@@ -204,8 +1673,8 @@ mod ffi {
         /// http://man7.org/linux/man-pages/man2/epoll_create1.2.html
         pub fn epoll_create(size: i32) -> i32;
 
-        /// http://man7.org/linux/man-pages/man2/close.2.html
-        pub fn close(fd: i32) -> i32;
+        /// http://man7.org/linux/man-pages/man2/dup.2.html
+        pub fn dup2(oldfd: i32, newfd: i32) -> i32;
 
         /// http://man7.org/linux/man-pages/man2/epoll_ctl.2.html
         pub fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut Event) -> i32;
@@ -214,30 +1683,107 @@ mod ffi {
         ///
         /// - epoll_event is a pointer to an array of Events
         /// - timeout of -1 means indefinite
-        pub fn epoll_wait(epfd: i32, events: *mut Event, maxevents: i32, timeout: i32) -> i32;
+        pub fn epoll_pwait(
+            epfd: i32,
+            events: *mut Event,
+            maxevents: i32,
+            timeout: i32,
+            sigmask: *const SigsetT,
+        ) -> i32;
 
-        /// http://man7.org/linux/man-pages/man2/timerfd_create.2.html
+        /// http://man7.org/linux/man-pages/man2/eventfd.2.html
         pub fn eventfd(initva: u32, flags: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/timerfd_create.2.html
+        pub fn timerfd_create(clockid: i32, flags: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/timerfd_settime.2.html
+        pub fn timerfd_settime(
+            fd: i32,
+            flags: i32,
+            new_value: *const Itimerspec,
+            old_value: *mut Itimerspec,
+        ) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/signalfd.2.html
+        pub fn signalfd(fd: i32, mask: *const SigsetT, flags: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man3/pthread_sigmask.3.html
+        pub fn pthread_sigmask(how: i32, set: *const SigsetT, oldset: *mut SigsetT) -> i32;
+
+        /// http://man7.org/linux/man-pages/man3/sigsetops.3.html
+        pub fn sigemptyset(set: *mut SigsetT) -> i32;
+        pub fn sigaddset(set: *mut SigsetT, signum: i32) -> i32;
+        pub fn sigismember(set: *const SigsetT, signum: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/syscall.2.html
+        pub fn syscall(number: i64, ...) -> i64;
+
+        /// http://man7.org/linux/man-pages/man2/waitid.2.html
+        pub fn waitid(idtype: i32, id: u32, infop: *mut SiginfoT, options: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/fcntl.2.html
+        pub fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/inotify_init1.2.html
+        pub fn inotify_init1(flags: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/inotify_add_watch.2.html
+        pub fn inotify_add_watch(fd: i32, pathname: *const c_char, mask: u32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/inotify_rm_watch.2.html
+        pub fn inotify_rm_watch(fd: i32, wd: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/read.2.html
+        pub fn read(fd: i32, buf: *mut u8, count: usize) -> isize;
+
+        /// http://man7.org/linux/man-pages/man2/write.2.html
+        pub fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+
+        /// http://man7.org/linux/man-pages/man2/setsockopt.2.html
+        pub fn setsockopt(
+            sockfd: i32,
+            level: i32,
+            optname: i32,
+            optval: *const std::ffi::c_void,
+            optlen: u32,
+        ) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/getsockopt.2.html
+        pub fn getsockopt(
+            sockfd: i32,
+            level: i32,
+            optname: i32,
+            optval: *mut std::ffi::c_void,
+            optlen: *mut u32,
+        ) -> i32;
+
     }
 }
 
-fn epoll_create() -> io::Result<i32> {
+fn epoll_create() -> io::Result<OwnedFd> {
     // Size argument is ignored but must be greater than zero
     let res = unsafe { ffi::epoll_create(1) };
     if res < 0 {
         Err(io::Error::last_os_error())
     } else {
-        Ok(res)
+        // Nobody else has the fd we just got
+        Ok(unsafe { OwnedFd::from_raw_fd(res) })
     }
 }
 
-fn close_fd(fd: i32) -> io::Result<()> {
-    let res = unsafe { ffi::close(fd) };
-    if res < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(())
-    }
+/// A new epoll instance with `kick` registered on it.
+fn epoll_with_kick(kick: &EventFd) -> io::Result<OwnedFd> {
+    let fd = epoll_create()?;
+    // Level triggered, so it stays readable until we've read the counter
+    let mut event = ffi::Event::new(ffi::EPOLLIN, KICK_TOKEN);
+    epoll_ctl(
+        fd.as_raw_fd(),
+        ffi::EPOLL_CTL_ADD,
+        kick.as_raw_fd(),
+        &mut event,
+    )?;
+    Ok(fd)
 }
 
 fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: &mut Event) -> io::Result<()> {
@@ -251,9 +1797,17 @@ fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: &mut Event) -> io::Result<()> {
 
 /// Waits for events on the epoll instance to occur. Returns the number file descriptors ready for the requested I/O.
 /// When successful, epoll_wait() returns the number of file descriptors ready for the requested
-/// I/O, or zero if no file descriptor became ready during the requested timeout milliseconds
-fn epoll_wait(epfd: i32, events: &mut [Event], maxevents: i32, timeout: i32) -> io::Result<i32> {
-    let res = unsafe { ffi::epoll_wait(epfd, events.as_mut_ptr(), maxevents, timeout) };
+/// I/O, or zero if no file descriptor became ready during the requested timeout milliseconds.
+/// With a `sigmask` it's used as the thread's signal mask while waiting.
+fn epoll_pwait(
+    epfd: i32,
+    events: &mut [Event],
+    maxevents: i32,
+    timeout: i32,
+    sigmask: Option<&SigSet>,
+) -> io::Result<i32> {
+    let sigmask = sigmask.map_or(std::ptr::null(), |sigmask| &sigmask.inner);
+    let res = unsafe { ffi::epoll_pwait(epfd, events.as_mut_ptr(), maxevents, timeout, sigmask) };
     if res < 0 {
         Err(io::Error::last_os_error())
     } else {
@@ -261,6 +1815,53 @@ fn epoll_wait(epfd: i32, events: &mut [Event], maxevents: i32, timeout: i32) ->
     }
 }
 
+/// Like `epoll_wait`, but with the timeout as a `Timespec` rather than milliseconds.
+/// glibc only has a wrapper since 2.35, so we make the system call ourselves.
+/// http://man7.org/linux/man-pages/man2/epoll_pwait2.2.html
+fn epoll_pwait2(
+    epfd: i32,
+    events: &mut [Event],
+    maxevents: i32,
+    timeout: Option<Duration>,
+    sigmask: Option<&SigSet>,
+) -> io::Result<i32> {
+    let timeout = timeout.map(ffi::Timespec::from_duration);
+    let timeout: *const ffi::Timespec = match &timeout {
+        Some(timeout) => timeout,
+        None => std::ptr::null(),
+    };
+    // The system call takes the size of the kernel's signal set, not glibc's
+    let (sigmask, sigsetsize) = match sigmask {
+        Some(sigmask) => (
+            &sigmask.inner as *const ffi::SigsetT,
+            ffi::KERNEL_SIGSET_SIZE,
+        ),
+        None => (std::ptr::null(), 0),
+    };
+    let res = unsafe {
+        ffi::syscall(
+            ffi::SYS_EPOLL_PWAIT2,
+            epfd as i64,
+            events.as_mut_ptr(),
+            maxevents as i64,
+            timeout,
+            sigmask,
+            sigsetsize,
+        )
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as i32)
+    }
+}
+
+/// A negative timeout in milliseconds is a timeout of 0, like `epoll_wait` treats it
+/// apart from -1.
+fn millis(ms: i32) -> Duration {
+    Duration::from_millis(ms.max(0) as u64)
+}
+
 fn eventfd(initva: u32, flags: i32) -> io::Result<i32> {
     let res = unsafe { ffi::eventfd(initva, flags) };
     if res < 0 {
@@ -269,3 +1870,255 @@ fn eventfd(initva: u32, flags: i32) -> io::Result<i32> {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eventfd_counts_and_resets() {
+        let efd = EventFd::new(0).unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, efd.read().unwrap_err().kind());
+
+        efd.write(2).unwrap();
+        efd.write(3).unwrap();
+        assert_eq!(5, efd.read().unwrap());
+        assert_eq!(io::ErrorKind::WouldBlock, efd.read().unwrap_err().kind());
+    }
+
+    #[test]
+    fn sub_millisecond_timeouts_fall_back_to_epoll_pwait_without_epoll_pwait2() {
+        let selector = Selector::new().unwrap();
+        selector.no_pwait2.store(true, Ordering::Relaxed);
+        let timeout = Duration::from_micros(300);
+        let mut events = Vec::with_capacity(8);
+        let start = std::time::Instant::now();
+        selector.select_timeout(&mut events, Some(timeout)).unwrap();
+        assert!(events.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(1));
+    }
+
+    #[test]
+    fn timeouts_too_long_for_a_timespec_are_accepted() {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let efd = EventFd::new(1).unwrap();
+        let mut events = Vec::with_capacity(8);
+        for no_pwait2 in [false, true] {
+            selector.no_pwait2.store(no_pwait2, Ordering::Relaxed);
+            registrator.register(&efd, 3, Interests::READABLE).unwrap();
+            selector
+                .select_timeout(&mut events, Some(Duration::MAX))
+                .unwrap();
+            assert_eq!(1, events.len());
+        }
+    }
+
+    #[test]
+    fn blocked_signals_are_delivered_while_waiting_with_a_sigmask() {
+        use std::sync::atomic::AtomicUsize;
+        const SIGUSR2: i32 = 12;
+        const SIG_UNBLOCK: i32 = 1;
+        static HANDLED: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn handler(_: i32) {
+            HANDLED.fetch_add(1, Ordering::SeqCst);
+        }
+        extern "C" {
+            fn signal(sig: i32, handler: usize) -> usize;
+            fn raise(sig: i32) -> i32;
+        }
+
+        // The signal stays pending while it's blocked on this thread, and is only
+        // delivered once the wait unblocks it.
+        let mask = SigSet::empty().with_signal(SIGUSR2).unwrap();
+        unsafe {
+            signal(SIGUSR2, handler as extern "C" fn(i32) as usize);
+            ffi::pthread_sigmask(ffi::SIG_BLOCK, &mask.inner, std::ptr::null_mut());
+        }
+
+        let selector = Selector::new().unwrap();
+        let mut events = Vec::with_capacity(8);
+        let unblocked = SigSet::empty();
+        for no_pwait2 in [false, true] {
+            selector.no_pwait2.store(no_pwait2, Ordering::Relaxed);
+            let handled = HANDLED.load(Ordering::SeqCst);
+            assert_eq!(0, unsafe { raise(SIGUSR2) });
+            assert_eq!(handled, HANDLED.load(Ordering::SeqCst));
+
+            let err = selector
+                .select_with_sigmask(&mut events, Some(Duration::from_secs(10)), Some(&unblocked))
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::Interrupted, err.kind());
+            assert_eq!(handled + 1, HANDLED.load(Ordering::SeqCst));
+        }
+
+        unsafe { ffi::pthread_sigmask(SIG_UNBLOCK, &mask.inner, std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn timerfd_expires_as_event() {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let timer = TimerFd::new(ClockId::Monotonic).unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, timer.read().unwrap_err().kind());
+
+        timer.set_oneshot(Duration::from_millis(10)).unwrap();
+        registrator
+            .register(&timer, 4, Interests::READABLE)
+            .unwrap();
+
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(4, events[0].id());
+        assert_eq!(1, timer.read().unwrap());
+    }
+
+    #[test]
+    fn disarmed_timerfd_drops_unread_expirations() {
+        let timer = TimerFd::new(ClockId::Monotonic).unwrap();
+        timer.set_oneshot(Duration::from_millis(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        timer.disarm().unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, timer.read().unwrap_err().kind());
+    }
+
+    #[test]
+    fn signalfd_reads_raised_signal() {
+        const SIGUSR1: i32 = 10;
+        let mask = SigSet::empty().with_signal(SIGUSR1).unwrap();
+        assert!(mask.contains(SIGUSR1));
+
+        // The test harness runs every test on its own thread, and `raise` sends the
+        // signal to the calling thread which is the one we block it for.
+        let signalfd = SignalFd::new(&mask).unwrap();
+        assert!(signalfd.pending().next().is_none());
+
+        extern "C" {
+            fn raise(sig: i32) -> i32;
+        }
+        assert_eq!(0, unsafe { raise(SIGUSR1) });
+        let received: Vec<SigInfo> = signalfd.pending().collect();
+        assert_eq!(1, received.len());
+        assert_eq!(SIGUSR1, received[0].signal());
+    }
+
+    #[test]
+    fn signals_are_reported_with_their_own_tokens() {
+        const SIGHUP: i32 = 1;
+        const SIGUSR1: i32 = 10;
+        extern "C" {
+            fn raise(sig: i32) -> i32;
+        }
+
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let mut signals = Signals::new(registrator);
+        signals.bind(SIGHUP, 1).unwrap();
+        signals.bind(SIGUSR1, 2).unwrap();
+
+        assert_eq!(0, unsafe { raise(SIGUSR1) });
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![2], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        assert!(signals.take(1).unwrap().is_empty());
+        let received = signals.take(2).unwrap();
+        assert_eq!(
+            vec![SIGUSR1],
+            received.iter().map(|s| s.signal()).collect::<Vec<_>>()
+        );
+
+        // Moving SIGHUP to the token of SIGUSR1 merges them
+        signals.bind(SIGHUP, 2).unwrap();
+        assert_eq!(0, unsafe { raise(SIGHUP) });
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![2], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        assert_eq!(SIGHUP, signals.take(2).unwrap()[0].signal());
+
+        signals.unbind(SIGHUP).unwrap();
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            signals.unbind(SIGHUP).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn pidfd_reports_child_exit() {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        // The child is reaped through the pidfd instead of `Child::wait`
+        #[allow(clippy::zombie_processes)]
+        let child = std::process::Command::new("sh")
+            .args(["-c", "exit 3"])
+            .spawn()
+            .unwrap();
+        let pidfd = PidFd::open(child.id()).unwrap();
+        registrator
+            .register(&pidfd, 5, Interests::READABLE)
+            .unwrap();
+
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, Some(5000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(5, events[0].id());
+
+        let status = pidfd.wait_nonblocking().unwrap().expect("child has exited");
+        assert_eq!(Some(3), status.code());
+    }
+
+    #[test]
+    fn inotify_reports_created_file() {
+        let dir = std::env::temp_dir().join(format!("minimio-inotify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let mut inotify = Inotify::new().unwrap();
+        let wd = inotify.add_watch(&dir, WatchMask::CREATE).unwrap();
+        registrator
+            .register(&inotify, 6, Interests::READABLE)
+            .unwrap();
+
+        std::fs::write(dir.join("created"), b"").unwrap();
+
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(6, events[0].id());
+
+        let received: Vec<_> = inotify
+            .read_events()
+            .unwrap()
+            .map(|e| (e.wd, e.mask, e.name.map(OsStr::to_owned)))
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            vec![(wd, WatchMask::CREATE, Some("created".into()))],
+            received
+        );
+    }
+
+    #[test]
+    fn event_debug_decodes_flags() {
+        let event = ffi::Event::new(ffi::EPOLLIN | ffi::EPOLLONESHOT | 0x1000, 42);
+        assert_eq!(
+            "Event { token: 42, events: EPOLLIN | EPOLLONESHOT | 0x1000 }",
+            format!("{:?}", event)
+        );
+    }
+
+    #[test]
+    fn eventfd_wakes_selector() {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let efd = EventFd::new(0).unwrap();
+        registrator.register(&efd, 3, Interests::READABLE).unwrap();
+
+        efd.write(1).unwrap();
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(3, events[0].id());
+    }
+}
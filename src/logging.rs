@@ -0,0 +1,29 @@
+//! Thin wrappers around the `log` crate's macros. With the `log` feature disabled
+//! they compile to nothing, but still type check their arguments so we don't get
+//! unused variable warnings in the code using them.
+
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        ::log::debug!($($arg)*);
+        #[cfg(not(feature = "log"))]
+        {
+            if false {
+                let _ = format_args!($($arg)*);
+            }
+        }
+    }};
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        ::log::trace!($($arg)*);
+        #[cfg(not(feature = "log"))]
+        {
+            if false {
+                let _ = format_args!($($arg)*);
+            }
+        }
+    }};
+}
@@ -1,26 +1,83 @@
-use crate::{Events, Interests, Token};
+use crate::backlog::FullSelects;
+use crate::clock::ceil_millis_u128;
+use crate::registrations::Registrations;
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::unix::{validate_token, Change, KICK_TOKEN};
+use crate::{Events, Interests, Source, Token};
+use std::collections::HashMap;
 use std::io::{self, IoSliceMut, Read, Write};
 use std::net;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
 use std::ptr;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    mpsc, Arc,
 };
+use std::time::Duration;
 
-pub type Source = std::os::unix::io::RawFd;
-
+#[derive(Debug)]
 pub struct Registrator {
-    kq: Source,
+    /// Shared with the `Selector`, so the kqueue stays open for as long as anybody
+    /// can register with it
+    kq: Arc<OwnedFd>,
     is_poll_dead: Arc<AtomicBool>,
+    changes: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
 }
 
 impl Registrator {
     pub fn register(
         &self,
-        stream: &TcpStream,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+
+        let fd = source.as_fd().as_raw_fd();
+        // We register the id (or most oftenly referred to as a Token) to the `udata` field
+        // if the `Kevent`
+        // Read and write interest are separate filters, but they're added with one
+        // `kevent` call
+        let mut changes = ffi::Event::new_events(fd, token as u64, interests, None);
+        if let Err(e) = apply_changes(self.kq.as_raw_fd(), &mut changes, &self.stats) {
+            debug!(
+                "registering fd {} with token {} failed: {} (os error {:?})",
+                fd,
+                token,
+                e,
+                e.raw_os_error()
+            );
+            return Err(e);
+        }
+
+        self.registrations.insert(fd, token, interests);
+        debug!(
+            "registered fd {} with token {} for {}",
+            fd, token, interests
+        );
+        Ok(())
+    }
+
+    /// Like `register`, but writable events are only reported once at least
+    /// `send_lowat` bytes of the send buffer are free, using `NOTE_LOWAT`. That keeps
+    /// a large response that drains slowly from waking us up for every few bytes of
+    /// space. The low-water mark only applies to this registration, not the socket.
+    pub fn register_with_send_lowat(
+        &self,
+        source: &impl Source,
         token: usize,
         interests: Interests,
+        send_lowat: usize,
     ) -> io::Result<()> {
         if self.is_poll_dead.load(Ordering::SeqCst) {
             return Err(io::Error::new(
@@ -29,19 +86,155 @@ impl Registrator {
             ));
         }
 
-        let fd = stream.as_raw_fd();
-        if interests.is_readable() {
-            // We register the id (or most oftenly referred to as a Token) to the `udata` field
-            // if the `Kevent`
-            let event = ffi::Event::new_read_event(fd, token as u64);
-            let event = [event];
-            kevent(self.kq, &event, &mut [], 0, None)?;
+        if !interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A send low-water mark needs WRITABLE interest.",
+            ));
+        }
+        validate_token(token)?;
+
+        let fd = source.as_fd().as_raw_fd();
+        let mut changes = ffi::Event::new_events(fd, token as u64, interests, Some(send_lowat));
+        apply_changes(self.kq.as_raw_fd(), &mut changes, &self.stats)?;
+        self.registrations.insert(fd, token, interests);
+        debug!(
+            "registered fd {} with token {} for {} with a send low-water mark of {}",
+            fd, token, interests, send_lowat
+        );
+        Ok(())
+    }
+
+    /// Removes `source` from the kqueue, so no more events are reported for it. Any
+    /// event for it that's pending in the queue is dropped too, but one that `select`
+    /// has already returned can't be taken back. Deregistering a source that isn't
+    /// registered, which includes a oneshot registration that has fired, is not an
+    /// error.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        let mut changes = [
+            ffi::Event::new_delete(fd, ffi::EVFILT_READ),
+            ffi::Event::new_delete(fd, ffi::EVFILT_WRITE),
+        ];
+        let results = receipts(self.kq.as_raw_fd(), &mut changes, &self.stats)?;
+        // A filter that isn't registered fails with `ENOENT`, which we don't mind
+        for (change, res) in changes.iter().zip(results) {
+            match res {
+                Ok(()) => (),
+                Err(ref e) if e.raw_os_error() == Some(ffi::ENOENT) => (),
+                Err(e) => {
+                    debug!(
+                        "deregistering fd {} from filter {} failed: {}",
+                        fd, change.filter, e
+                    );
+                    return Err(e);
+                }
+            }
+        }
+        self.registrations.remove(fd);
+        debug!("deregistered fd {}", fd);
+        Ok(())
+    }
+
+    /// Like `register`, but instead of changing the kqueue from this thread, the
+    /// registration is queued for the polling thread. It's passed as part of the
+    /// changelist of its next `kevent` call, and we wake it up so that happens right away.
+    ///
+    /// Since the registration is applied later, errors from applying it can't be
+    /// returned from here. They're logged, and the registration is dropped.
+    pub fn register_deferred(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let change = Change {
+            fd: source.as_fd().as_raw_fd(),
+            token,
+            interests,
         };
+        if self.changes.send(change).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        trace!(
+            "queued registration of fd {} with token {}",
+            change.fd,
+            token
+        );
+        let event = [ffi::Event::new_kick_trigger()];
+        self.stats.wakeup();
+        kevent(self.kq.as_raw_fd(), &event, &mut [], 0, None)?;
+        Ok(())
+    }
 
-        if interests.is_writable() {
-            unimplemented!();
+    /// Arms a timer that fires once after `timeout`, returning an event with `token` as
+    /// its id from `select`. Registering a timer with a token that's already armed
+    /// replaces the previous timer.
+    pub fn register_timer(&self, token: usize, timeout: Duration) -> io::Result<()> {
+        // Rounded up, so the timer never fires early
+        self.add_timer(token, ceil_millis_u128(timeout), true)
+    }
+
+    /// Arms a timer that fires every `period`, starting one `period` from now, until
+    /// it's registered again. Every tick returns an event with `token` as its id from
+    /// `select`. The kernel schedules each tick from the previous deadline rather than
+    /// from when we handled it, so ticks don't drift. The period is rounded to whole
+    /// milliseconds.
+    pub fn register_timer_interval(&self, token: usize, period: Duration) -> io::Result<()> {
+        if period == Duration::from_millis(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The period of an interval timer must be larger than zero.",
+            ));
+        }
+        self.add_timer(token, period.as_millis().max(1), false)
+    }
+
+    /// Removes a timer armed with `register_timer` or `register_timer_interval`.
+    /// Deleting a kevent also drops any expiration of it that's pending in the queue,
+    /// so once this returns the timer's token isn't returned from `select` again,
+    /// unless a `select` on another thread has already picked it up. To push a timer
+    /// back instead, just register it again with the same token.
+    pub fn deregister_timer(&self, token: usize) -> io::Result<()> {
+        validate_token(token)?;
+        let event = [ffi::Event::new_timer_delete(token as u64)];
+        self.stats.change();
+        kevent(self.kq.as_raw_fd(), &event, &mut [], 0, None)?;
+        trace!("removed timer with token {}", token);
+        Ok(())
+    }
+
+    fn add_timer(&self, token: usize, ms: u128, oneshot: bool) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
         }
 
+        validate_token(token)?;
+        let ms = ms.min(i64::MAX as u128) as i64;
+        let event = [ffi::Event::new_timer_event(token as u64, ms, oneshot)];
+        self.stats.change();
+        kevent(self.kq.as_raw_fd(), &event, &mut [], 0, None)?;
+        trace!(
+            "armed timer with token {} for {} ms (oneshot: {})",
+            token,
+            ms,
+            oneshot
+        );
         Ok(())
     }
 
@@ -50,16 +243,18 @@ impl Registrator {
         // event it will handle
         if self
             .is_poll_dead
-            .compare_and_swap(false, true, Ordering::SeqCst)
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
         {
             return Err(io::Error::new(
                 io::ErrorKind::Interrupted,
                 "Poll instance closed.",
             ));
         }
+        debug!("closing event loop on kqueue {}", self.kq.as_raw_fd());
         let event = ffi::Event::new_wakeup_event();
         let event = [event];
-        kevent(self.kq, &event, &mut [], 0, None)?;
+        kevent(self.kq.as_raw_fd(), &event, &mut [], 0, None)?;
 
         Ok(())
     }
@@ -67,46 +262,225 @@ impl Registrator {
 
 #[derive(Debug)]
 pub struct Selector {
-    kq: Source,
+    kq: Arc<OwnedFd>,
+    changes: mpsc::Receiver<Change>,
+    change_sender: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+    full_selects: FullSelects,
 }
 
 impl Selector {
     pub fn new() -> io::Result<Self> {
-        Ok(Selector { kq: kqueue()? })
+        let (change_sender, changes) = mpsc::channel();
+        Ok(Selector {
+            kq: Arc::new(kqueue_with_kick()?),
+            changes,
+            change_sender,
+            registrations: Arc::default(),
+            stats: Arc::default(),
+            full_selects: FullSelects::default(),
+        })
+    }
+
+    /// Deregisters every source registered with this selector or one of its
+    /// registrators, and drops the registrations still queued with
+    /// `Registrator::register_deferred`, so the selector can be reused as if it was
+    /// new. Wakers are deregistered as well and need to be registered again.
+    ///
+    /// A `select` already waiting in another thread keeps waiting on the old
+    /// registrations, and registrations made while this runs may be lost, so it's
+    /// meant for when nothing else is using the selector.
+    pub fn clear(&self) -> io::Result<()> {
+        for change in self.changes.try_iter() {
+            trace!("dropping deferred registration of fd {}", change.fd);
+        }
+        // Deleting the filters one by one would need us to keep track of them, so we
+        // put a new kqueue in place of the old one under the fd number our
+        // registrators already have
+        let fresh = kqueue_with_kick()?;
+        if unsafe { ffi::dup2(fresh.as_raw_fd(), self.kq.as_raw_fd()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.registrations.clear();
+        debug!(
+            "cleared all registrations on kqueue {}",
+            self.kq.as_raw_fd()
+        );
+        Ok(())
     }
 
     /// This function blocks and waits until an event has been recieved. It never times out.
     pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        let timeout = timeout_ms.map(|ms| Duration::from_millis(ms.max(0) as u64));
+        self.select_timeout(events, timeout)
+    }
+
+    /// Like `select`, but the timeout isn't limited to whole milliseconds. `kevent`
+    /// takes it as a `timespec`, so it's passed on with nanosecond precision.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        loop {
+            // Registrations queued with `register_deferred` are applied as part of the
+            // same `kevent` call we wait in
+            let changes: Vec<ffi::Kevent> = self
+                .changes
+                .try_iter()
+                .flat_map(|change| {
+                    self.registrations
+                        .insert(change.fd, change.token, change.interests);
+                    ffi::Event::new_events(change.fd, change.token as u64, change.interests, None)
+                })
+                .collect();
+            self.wait(&changes, events, timeout)?;
+
+            // A change that fails is reported as an event with `EV_ERROR` set
+            events.retain(|event| {
+                if event.flags & ffi::EV_ERROR != 0 && event.udata as usize != KICK_TOKEN {
+                    self.registrations.remove(event.ident as RawFd);
+                    debug!(
+                        "dropping deferred registration of fd {} with token {}: os error {}",
+                        event.ident, event.udata, event.data
+                    );
+                    return false;
+                }
+                true
+            });
+
+            // Being kicked means there are new registrations to apply. If that's all that
+            // happened and we're supposed to block, we apply them and go back to waiting.
+            if events.iter().any(|event| event.id() == KICK_TOKEN) {
+                events.retain(|event| event.id() != KICK_TOKEN);
+                if events.is_empty() && timeout.is_none() {
+                    continue;
+                }
+            }
+            merge_read_write(events);
+            return Ok(());
+        }
+    }
+
+    fn wait(
+        &self,
+        changes: &[ffi::Kevent],
+        events: &mut Events,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
         // TODO: get n_events from self
         let n_events = events.capacity() as i32;
         events.clear();
-        kevent(self.kq, &[], events, n_events, timeout_ms).map(|n_events| {
-            // This is safe because `syscall_kevent` ensures that `n_events` are
-            // assigned. We could check for a valid token for each event to verify so this is
-            // just a performance optimization used in `mio` and copied here.
-            unsafe { events.set_len(n_events as usize) };
-        })
+        trace!(
+            "kevent on kqueue {} with timeout {:?}",
+            self.kq.as_raw_fd(),
+            timeout
+        );
+        self.stats.wait();
+        match kevent(self.kq.as_raw_fd(), changes, events, n_events, timeout) {
+            Ok(n_events) => {
+                trace!(
+                    "kevent on kqueue {} woke up with {} events",
+                    self.kq.as_raw_fd(),
+                    n_events
+                );
+                // This is safe because `syscall_kevent` ensures that `n_events` are
+                // assigned. We could check for a valid token for each event to verify so this is
+                // just a performance optimization used in `mio` and copied here.
+                unsafe { events.set_len(n_events) };
+                self.full_selects.record(events.len(), events.capacity());
+                Ok(())
+            }
+            Err(e) => {
+                debug!(
+                    "kevent on kqueue {} failed: {} (os error {:?})",
+                    self.kq.as_raw_fd(),
+                    e,
+                    e.raw_os_error()
+                );
+                Err(e)
+            }
+        }
     }
 
     pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
         Registrator {
-            kq: self.kq,
+            kq: self.kq.clone(),
             is_poll_dead,
+            changes: self.change_sender.clone(),
+            registrations: self.registrations.clone(),
+            stats: self.stats.clone(),
         }
     }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Each of those probably left events behind in the queue, so a count that
+    /// keeps growing means the loop isn't keeping up and should shed load or poll with
+    /// a larger buffer. 0 if the last select had room to spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
 }
 
-impl Drop for Selector {
-    fn drop(&mut self) {
-        match close(self.kq) {
-            Ok(..) => (),
-            Err(e) => {
-                if !std::thread::panicking() {
-                    panic!(e);
-                }
+/// kqueue reports a kqueue fd as readable while it has events pending, so a selector
+/// can be registered with `Interests::READABLE` in another one to nest it. Register
+/// it again after selecting from it, and expect the odd wakeup with nothing to select
+/// when a registrator has kicked it.
+///
+/// That's also how to run the selector under a `CFRunLoop`: watch the fd with a
+/// `CFFileDescriptor` and `select` with a zero timeout from its callback, enabling
+/// its callbacks again after each one.
+impl AsRawFd for Selector {
+    fn as_raw_fd(&self) -> RawFd {
+        self.kq.as_raw_fd()
+    }
+}
+
+impl AsFd for Selector {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.kq.as_fd()
+    }
+}
+
+/// kqueue has a filter for reading and one for writing, so a socket that's both
+/// readable and writable comes back as two events. We merge them into the read event
+/// marked writable as well, the single event epoll would have returned, so handlers
+/// don't have to expect either.
+pub(crate) fn merge_read_write(events: &mut Events) {
+    if !events.iter().any(|event| event.filter == ffi::EVFILT_WRITE) {
+        return;
+    }
+    let mut first: HashMap<(u64, u64), usize> = HashMap::new();
+    let mut kept = 0;
+    for i in 0..events.len() {
+        let event = &events[i];
+        if event.filter != ffi::EVFILT_READ && event.filter != ffi::EVFILT_WRITE {
+            events.swap(kept, i);
+            kept += 1;
+            continue;
+        }
+        let key = (event.ident, event.udata);
+        match first.get(&key) {
+            // Each filter is only ever reported once per fd
+            Some(&at) if events[at].filter != event.filter => {
+                let (head, tail) = events.split_at_mut(i);
+                head[at].merge(&tail[0]);
+            }
+            _ => {
+                first.insert(key, kept);
+                events.swap(kept, i);
+                kept += 1;
             }
         }
     }
+    events.truncate(kept);
 }
 
 pub type Event = ffi::Kevent;
@@ -114,6 +488,80 @@ impl Event {
     pub fn id(&self) -> Token {
         self.udata as usize
     }
+
+    /// Returns true if the source can be read from, or has hung up.
+    pub fn is_readable(&self) -> bool {
+        self.filter == ffi::EVFILT_READ
+    }
+
+    /// Returns true if the source can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.filter == ffi::EVFILT_WRITE
+            || (self.filter == ffi::EVFILT_READ && self.fflags & ffi::MERGED_WRITABLE != 0)
+    }
+
+    /// A read or write event the way `kevent` would have returned it, for the
+    /// `gcd` selector to deliver.
+    #[cfg(all(target_os = "macos", feature = "gcd"))]
+    pub(crate) fn dispatched(fd: RawFd, writable: bool, data: i64, token: Token) -> Event {
+        Event {
+            ident: fd as u64,
+            filter: if writable {
+                ffi::EVFILT_WRITE
+            } else {
+                ffi::EVFILT_READ
+            },
+            flags: 0,
+            fflags: 0,
+            data,
+            udata: token as u64,
+        }
+    }
+
+    /// A read or write event the way `kevent` would have returned it, for events that
+    /// are rebuilt after being held back, or that come from the `select` selector. An
+    /// fd that's both is the read event marked writable as well, like
+    /// `merge_read_write` leaves it.
+    pub(crate) fn with_readiness(token: Token, readable: bool, writable: bool) -> Event {
+        Event {
+            ident: 0,
+            filter: if readable {
+                ffi::EVFILT_READ
+            } else {
+                ffi::EVFILT_WRITE
+            },
+            flags: 0,
+            fflags: if readable && writable {
+                ffi::MERGED_WRITABLE
+            } else {
+                0
+            },
+            data: 0,
+            udata: token as u64,
+        }
+    }
+
+    /// Adds the readiness of `other`, an event for the same token, to this one. A read
+    /// and a write event become the read event marked writable as well. Timers and
+    /// everything else only ever merge with an event of their own filter.
+    pub(crate) fn merge(&mut self, other: &Event) -> bool {
+        let is_socket = |filter| filter == ffi::EVFILT_READ || filter == ffi::EVFILT_WRITE;
+        if self.filter != other.filter && !(is_socket(self.filter) && is_socket(other.filter)) {
+            return false;
+        }
+        if self.filter == ffi::EVFILT_WRITE && other.filter == ffi::EVFILT_READ {
+            let flags = self.flags;
+            *self = other.clone();
+            self.flags |= flags;
+            self.fflags |= ffi::MERGED_WRITABLE;
+        } else {
+            if self.filter != other.filter {
+                self.fflags |= ffi::MERGED_WRITABLE;
+            }
+            self.flags |= other.flags;
+        }
+        true
+    }
 }
 
 pub struct TcpStream {
@@ -126,10 +574,76 @@ impl TcpStream {
         // we get from the stdlib but could do with a syscall. Let's skip that step in this example.
         // In other words this will block shortly establishing a connection to the remote server
         let stream = net::TcpStream::connect(adr)?;
-        stream.set_nonblocking(true)?;
+        TcpStream::from_std(stream)
+    }
 
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
         Ok(TcpStream { inner: stream })
     }
+
+    /// `read` leaves the socket blocking, so anything that relies on getting
+    /// `WouldBlock` has to set it back first.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        self.inner.set_nonblocking(true)
+    }
+
+    /// Reads without changing the blocking mode, unlike `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+/// Reads `TCP_CONNECTION_INFO`, the public counterpart of `TCP_INFO`.
+#[cfg(target_os = "macos")]
+pub(crate) fn tcp_info(stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    let mut info = ffi::TcpConnectionInfo::default();
+    let mut len = std::mem::size_of::<ffi::TcpConnectionInfo>() as u32;
+    let res = unsafe {
+        ffi::getsockopt(
+            stream.as_raw_fd(),
+            ffi::IPPROTO_TCP,
+            ffi::TCP_CONNECTION_INFO,
+            &mut info as *mut ffi::TcpConnectionInfo as *mut std::ffi::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    use crate::TcpState::*;
+    let state = match info.state {
+        0 => Closed,
+        1 => Listen,
+        2 => SynSent,
+        3 => SynReceived,
+        4 => Established,
+        5 => CloseWait,
+        6 => FinWait1,
+        7 => Closing,
+        8 => LastAck,
+        9 => FinWait2,
+        10 => TimeWait,
+        other => Unknown(other),
+    };
+    Ok(crate::TcpInfo {
+        state,
+        rtt: Duration::from_millis(info.srtt as u64),
+        retransmits: info.txretransmitpackets,
+        congestion_window: info.snd_cwnd as u64,
+        mss: info.maxseg,
+    })
+}
+
+/// DragonFly keeps the statistics of a connection to itself.
+#[cfg(target_os = "dragonfly")]
+pub(crate) fn tcp_info(_stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DragonFly doesn't have TCP_INFO.",
+    ))
 }
 
 impl Read for TcpStream {
@@ -170,15 +684,39 @@ impl AsRawFd for TcpStream {
     }
 }
 
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
 mod ffi {
     use super::*;
+    use crate::Flags;
+    use std::fmt;
 
     pub const EVFILT_READ: i16 = -1;
+    pub const EVFILT_WRITE: i16 = -2;
     pub const EVFILT_TIMER: i16 = -7;
+    // DragonFly numbers its filters like FreeBSD, which has no `EVFILT_MACHPORT`
+    #[cfg(target_os = "macos")]
+    pub const EVFILT_USER: i16 = -10;
+    #[cfg(target_os = "dragonfly")]
+    pub const EVFILT_USER: i16 = -9;
     pub const EV_ADD: u16 = 0x1;
+    pub const EV_DELETE: u16 = 0x2;
     pub const EV_ENABLE: u16 = 0x4;
     pub const EV_ONESHOT: u16 = 0x10;
     pub const EV_CLEAR: u16 = 0x20;
+    pub const EV_RECEIPT: u16 = 0x40;
+    pub const EV_ERROR: u16 = 0x4000;
+    pub const NOTE_TRIGGER: u32 = 0x0100_0000;
+    pub const NOTE_LOWAT: u32 = 0x1;
+    /// Not something the kernel sets: marks an `EVFILT_READ` event that a write event
+    /// for the same token was merged into. The kernel only
+    /// uses the low bits of `fflags` for socket events.
+    pub const MERGED_WRITABLE: u32 = 0x8000_0000;
+    pub const ENOENT: i32 = 2;
 
     #[derive(Debug)]
     #[repr(C)]
@@ -190,12 +728,10 @@ mod ffi {
     }
 
     impl Timespec {
-        pub fn from_millis(milliseconds: i32) -> Self {
-            let seconds = milliseconds / 1000;
-            let nanoseconds = (milliseconds % 1000) * 1000 * 1000;
+        pub fn from_duration(duration: Duration) -> Self {
             Timespec {
-                tv_sec: seconds as isize,
-                v_nsec: nanoseconds as usize,
+                tv_sec: duration.as_secs().min(isize::MAX as u64) as isize,
+                v_nsec: duration.subsec_nanos() as usize,
             }
         }
     }
@@ -215,7 +751,9 @@ mod ffi {
 
         pub fn new_wakeup_event() -> Self {
             Event {
-                ident: 0,
+                // Timers registered with `register_timer` use their token as the ident,
+                // so we use the one token nobody can register
+                ident: KICK_TOKEN as u64,
                 filter: EVFILT_TIMER,
                 flags: EV_ADD | EV_ENABLE | EV_CLEAR,
                 fflags: 0,
@@ -225,6 +763,104 @@ mod ffi {
             }
         }
 
+        /// A timer firing after `ms` milliseconds. Without `EV_ONESHOT` the kernel
+        /// re-arms it every `ms` milliseconds from its previous deadline.
+        pub fn new_timer_event(token: u64, ms: i64, oneshot: bool) -> Self {
+            let flags = if oneshot {
+                EV_ADD | EV_ENABLE | EV_ONESHOT
+            } else {
+                EV_ADD | EV_ENABLE
+            };
+            Event {
+                ident: token,
+                filter: EVFILT_TIMER,
+                flags,
+                fflags: 0,
+                // Milliseconds is the default unit for `EVFILT_TIMER`
+                data: ms,
+                udata: token,
+            }
+        }
+
+        pub fn new_write_event(fd: RawFd, id: u64, lowat: Option<usize>) -> Self {
+            let (fflags, data) = match lowat {
+                Some(lowat) => (NOTE_LOWAT, lowat.min(i64::MAX as usize) as i64),
+                None => (0, 0),
+            };
+            Event {
+                ident: fd as u64,
+                filter: EVFILT_WRITE,
+                flags: EV_ADD | EV_ENABLE | EV_ONESHOT,
+                fflags,
+                data,
+                udata: id,
+            }
+        }
+
+        /// The changes that register `fd` for `interests`. kqueue has a filter per
+        /// kind of readiness, so that's one for each interest.
+        pub fn new_events(
+            fd: RawFd,
+            id: u64,
+            interests: Interests,
+            lowat: Option<usize>,
+        ) -> Vec<Self> {
+            let mut events = Vec::with_capacity(2);
+            if interests.is_readable() {
+                events.push(Event::new_read_event(fd, id));
+            }
+            if interests.is_writable() {
+                events.push(Event::new_write_event(fd, id, lowat));
+            }
+            events
+        }
+
+        pub fn new_delete(fd: RawFd, filter: i16) -> Self {
+            Event {
+                ident: fd as u64,
+                filter,
+                flags: EV_DELETE,
+                fflags: 0,
+                data: 0,
+                udata: 0,
+            }
+        }
+
+        pub fn new_timer_delete(token: u64) -> Self {
+            Event {
+                ident: token,
+                filter: EVFILT_TIMER,
+                flags: EV_DELETE,
+                fflags: 0,
+                data: 0,
+                udata: token,
+            }
+        }
+
+        /// The user event `register_deferred` triggers to wake up the polling thread.
+        /// `EV_CLEAR` resets it once it has been returned.
+        pub fn new_kick_event() -> Self {
+            Event {
+                ident: KICK_TOKEN as u64,
+                filter: EVFILT_USER,
+                flags: EV_ADD | EV_CLEAR,
+                fflags: 0,
+                data: 0,
+                udata: KICK_TOKEN as u64,
+            }
+        }
+
+        pub fn new_kick_trigger() -> Self {
+            Event {
+                ident: KICK_TOKEN as u64,
+                filter: EVFILT_USER,
+                flags: 0,
+                fflags: NOTE_TRIGGER,
+                data: 0,
+                udata: KICK_TOKEN as u64,
+            }
+        }
+
         pub fn zero() -> Self {
             Event {
                 ident: 0,
@@ -239,7 +875,9 @@ mod ffi {
 
     // https://github.com/rust-lang/libc/blob/c8aa8ec72d631bc35099bcf5d634cf0a0b841be0/src/unix/bsd/apple/mod.rs#L497
     // https://github.com/rust-lang/libc/blob/c8aa8ec72d631bc35099bcf5d634cf0a0b841be0/src/unix/bsd/apple/mod.rs#L207
-    #[derive(Debug, Clone, Default)]
+    // DragonFly declares `ident`, `data` and `udata` pointer sized, which is the same
+    // thing on x86_64, the only architecture it runs on.
+    #[derive(Clone, Default)]
     #[repr(C)]
     pub struct Kevent {
         pub ident: u64,
@@ -250,6 +888,104 @@ mod ffi {
         pub udata: u64,
     }
 
+    // https://opensource.apple.com/source/xnu/xnu-4570.41.2/bsd/sys/event.h.auto.html
+    #[cfg(target_os = "macos")]
+    fn filter_name(filter: i16) -> Option<&'static str> {
+        let name = match filter {
+            -1 => "EVFILT_READ",
+            -2 => "EVFILT_WRITE",
+            -3 => "EVFILT_AIO",
+            -4 => "EVFILT_VNODE",
+            -5 => "EVFILT_PROC",
+            -6 => "EVFILT_SIGNAL",
+            -7 => "EVFILT_TIMER",
+            -8 => "EVFILT_MACHPORT",
+            -9 => "EVFILT_FS",
+            -10 => "EVFILT_USER",
+            -12 => "EVFILT_VM",
+            -15 => "EVFILT_EXCEPT",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    // https://github.com/DragonFlyBSD/DragonFlyBSD/blob/master/sys/sys/event.h
+    #[cfg(target_os = "dragonfly")]
+    fn filter_name(filter: i16) -> Option<&'static str> {
+        let name = match filter {
+            -1 => "EVFILT_READ",
+            -2 => "EVFILT_WRITE",
+            -3 => "EVFILT_AIO",
+            -4 => "EVFILT_VNODE",
+            -5 => "EVFILT_PROC",
+            -6 => "EVFILT_SIGNAL",
+            -7 => "EVFILT_TIMER",
+            -8 => "EVFILT_EXCEPT",
+            -9 => "EVFILT_USER",
+            -10 => "EVFILT_FS",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    #[cfg(target_os = "macos")]
+    const KEVENT_FLAGS: &[(u64, &str)] = &[
+        (0x1, "EV_ADD"),
+        (0x2, "EV_DELETE"),
+        (0x4, "EV_ENABLE"),
+        (0x8, "EV_DISABLE"),
+        (0x10, "EV_ONESHOT"),
+        (0x20, "EV_CLEAR"),
+        (0x40, "EV_RECEIPT"),
+        (0x80, "EV_DISPATCH"),
+        (0x100, "EV_UDATA_SPECIFIC"),
+        (0x200, "EV_VANISHED"),
+        (0x1000, "EV_FLAG0"),
+        (0x2000, "EV_FLAG1"),
+        (0x4000, "EV_ERROR"),
+        (0x8000, "EV_EOF"),
+    ];
+
+    #[cfg(target_os = "dragonfly")]
+    const KEVENT_FLAGS: &[(u64, &str)] = &[
+        (0x1, "EV_ADD"),
+        (0x2, "EV_DELETE"),
+        (0x4, "EV_ENABLE"),
+        (0x8, "EV_DISABLE"),
+        (0x10, "EV_ONESHOT"),
+        (0x20, "EV_CLEAR"),
+        (0x40, "EV_RECEIPT"),
+        (0x80, "EV_DISPATCH"),
+        (0x1000, "EV_NODATA"),
+        (0x2000, "EV_FLAG1"),
+        (0x4000, "EV_ERROR"),
+        (0x8000, "EV_EOF"),
+    ];
+
+    struct Filter(i16);
+
+    impl fmt::Debug for Filter {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match filter_name(self.0) {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "{}", self.0),
+            }
+        }
+    }
+
+    impl fmt::Debug for Kevent {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("Kevent")
+                .field("token", &self.udata)
+                .field("ident", &self.ident)
+                .field("filter", &Filter(self.filter))
+                .field("flags", &Flags(self.flags as u64, KEVENT_FLAGS))
+                .field("fflags", &format_args!("{:#x}", self.fflags))
+                .field("data", &self.data)
+                .finish()
+        }
+    }
+
     impl Kevent {
         pub fn token(&self) -> Option<Token> {
             // we have no realiable way of checking if this value is initialized or not but need
@@ -258,10 +994,55 @@ mod ffi {
         }
     }
 
+    #[cfg(target_os = "macos")]
+    pub(super) const IPPROTO_TCP: i32 = 6;
+    #[cfg(target_os = "macos")]
+    pub(super) const TCP_CONNECTION_INFO: i32 = 0x106;
+
+    // `struct tcp_connection_info` in netinet/tcp.h
+    #[cfg(target_os = "macos")]
+    #[repr(C)]
+    #[derive(Default)]
+    pub(super) struct TcpConnectionInfo {
+        pub state: u8,
+        snd_wscale: u8,
+        rcv_wscale: u8,
+        pad: u8,
+        options: u32,
+        flags: u32,
+        rto: u32,
+        pub maxseg: u32,
+        snd_ssthresh: u32,
+        pub snd_cwnd: u32,
+        snd_wnd: u32,
+        snd_sbbytes: u32,
+        rcv_wnd: u32,
+        rttcur: u32,
+        pub srtt: u32,
+        rttvar: u32,
+        tfo_flags: u32,
+        txpackets: u64,
+        txbytes: u64,
+        txretransmitbytes: u64,
+        rxpackets: u64,
+        rxbytes: u64,
+        rxoutoforderbytes: u64,
+        pub txretransmitpackets: u64,
+    }
+
     #[link(name = "c")]
     extern "C" {
         /// Returns: positive: file descriptor, negative: error
         pub(super) fn kqueue() -> i32;
+        /// https://developer.apple.com/library/archive/documentation/System/Conceptual/ManPages_iPhoneOS/man2/getsockopt.2.html
+        #[cfg(target_os = "macos")]
+        pub(super) fn getsockopt(
+            socket: i32,
+            level: i32,
+            option_name: i32,
+            option_value: *mut std::ffi::c_void,
+            option_len: *mut u32,
+        ) -> i32;
         /// Returns: nothing, all non zero return values is an error
         /// If the time limit expires, then kevent() returns 0
         pub(super) fn kevent(
@@ -272,17 +1053,26 @@ mod ffi {
             nevents: i32,
             timeout: *const Timespec,
         ) -> i32;
-
-        pub fn close(d: i32) -> i32;
+        /// https://developer.apple.com/library/archive/documentation/System/Conceptual/ManPages_iPhoneOS/man2/dup2.2.html
+        pub(super) fn dup2(fildes: i32, fildes2: i32) -> i32;
     }
 }
 
-pub fn kqueue() -> io::Result<i32> {
+pub fn kqueue() -> io::Result<OwnedFd> {
     let fd = unsafe { ffi::kqueue() };
     if fd < 0 {
         return Err(io::Error::last_os_error());
     }
-    Ok(fd)
+    // Nobody else has the fd we just got
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// A new kqueue with the user event `register_deferred` triggers added to it.
+fn kqueue_with_kick() -> io::Result<OwnedFd> {
+    let kq = kqueue()?;
+    let event = [ffi::Event::new_kick_event()];
+    kevent(kq.as_raw_fd(), &event, &mut [], 0, None)?;
+    Ok(kq)
 }
 
 pub fn kevent(
@@ -290,13 +1080,12 @@ pub fn kevent(
     cl: &[ffi::Kevent],
     el: &mut [ffi::Kevent],
     n_events: i32,
-    timeout_ms: Option<i32>,
+    timeout: Option<Duration>,
 ) -> io::Result<usize> {
     let res = unsafe {
-        let kq = kq as i32;
         let cl_len = cl.len() as i32;
 
-        let timeout = timeout_ms.map(ffi::Timespec::from_millis);
+        let timeout = timeout.map(ffi::Timespec::from_duration);
 
         let timeout: *const ffi::Timespec = match &timeout {
             Some(n) => n,
@@ -312,40 +1101,96 @@ pub fn kevent(
     Ok(res as usize)
 }
 
-pub fn close(fd: RawFd) -> io::Result<()> {
-    let res = unsafe { ffi::close(fd) };
-    if res < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(())
+/// Applies `changes` with a single `kevent` call and returns the outcome of each of
+/// them. With `EV_RECEIPT` the kernel reports every change instead of stopping at the
+/// first one that fails, and doesn't return any pending events.
+fn receipts(
+    kq: RawFd,
+    changes: &mut [ffi::Kevent],
+    stats: &SyscallCounters,
+) -> io::Result<Vec<io::Result<()>>> {
+    for change in changes.iter_mut() {
+        change.flags |= ffi::EV_RECEIPT;
     }
+    let mut receipts = changes.to_vec();
+    stats.change();
+    let n = kevent(
+        kq,
+        changes,
+        &mut receipts,
+        changes.len() as i32,
+        Some(Duration::from_secs(0)),
+    )?;
+    // Every receipt has `EV_ERROR` set, with the error in `data` or 0 for success
+    Ok(receipts[..n]
+        .iter()
+        .map(|receipt| match receipt.data {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno as i32)),
+        })
+        .collect())
+}
+
+/// Applies all of `changes` or none of them: if one fails, the ones that succeeded
+/// are deleted again and the first error is returned.
+fn apply_changes(
+    kq: RawFd,
+    changes: &mut [ffi::Kevent],
+    stats: &SyscallCounters,
+) -> io::Result<()> {
+    let results = receipts(kq, changes, stats)?;
+    if results.iter().all(|res| res.is_ok()) {
+        return Ok(());
+    }
+    let applied: Vec<_> = changes
+        .iter()
+        .zip(&results)
+        .filter(|(_, res)| res.is_ok())
+        .map(|(change, _)| ffi::Event::new_delete(change.ident as RawFd, change.filter))
+        .collect();
+    if !applied.is_empty() {
+        // If this fails too there's nothing better to report than the original error
+        stats.change();
+        let _ = kevent(kq, &applied, &mut [], 0, None);
+    }
+    results
+        .into_iter()
+        .find_map(|res| res.err())
+        .map_or(Ok(()), Err)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Interests;
+    use crate::{test_util, Interests};
+    use std::time::Duration;
+    #[test]
+    fn kevent_debug_decodes_filter_and_flags() {
+        let mut event = ffi::Event::new_read_event(5, 42);
+        event.flags |= 0x8000;
+        assert_eq!(
+            "Kevent { token: 42, ident: 5, filter: EVFILT_READ, \
+             flags: EV_ADD | EV_ENABLE | EV_ONESHOT | EV_EOF, fflags: 0x0, data: 0 }",
+            format!("{:?}", event)
+        );
+    }
+
     #[test]
     fn create_kevent_works() {
         let selector = Selector::new().unwrap();
-        let mut sock = TcpStream::connect("www.google.com:80").unwrap();
+        let sock = TcpStream::connect(test_util::echo_server()).unwrap();
         let poll_is_dead = Arc::new(AtomicBool::new(false));
         let registrator = selector.registrator(poll_is_dead.clone());
 
-        registrator
-            .register(&mut sock, 1, Interests::READABLE)
-            .unwrap();
+        registrator.register(&sock, 1, Interests::READABLE).unwrap();
     }
 
     #[test]
     fn select_kevent_works() {
         let selector = Selector::new().unwrap();
-        let mut sock: TcpStream = TcpStream::connect("slowwly.robertomurray.co.uk:80").unwrap();
-        let request = "GET /delay/1000/url/http://www.google.com HTTP/1.1\r\n\
-                       Host: slowwly.robertomurray.co.uk\r\n\
-                       Connection: close\r\n\
-                       \r\n";
-        sock.write_all(request.as_bytes())
+        let addr = test_util::delayed_responder(Duration::from_millis(200));
+        let mut sock: TcpStream = TcpStream::connect(addr).unwrap();
+        sock.write_all(test_util::HTTP_REQUEST)
             .expect("Error writing to stream");
         let poll_is_dead = Arc::new(AtomicBool::new(false));
         let registrator = selector.registrator(poll_is_dead.clone());
@@ -366,12 +1211,9 @@ mod tests {
     #[test]
     fn read_kevent_works() {
         let selector = Selector::new().unwrap();
-        let mut sock: TcpStream = TcpStream::connect("slowwly.robertomurray.co.uk:80").unwrap();
-        let request = "GET /delay/1000/url/http://www.google.com HTTP/1.1\r\n\
-                       Host: slowwly.robertomurray.co.uk\r\n\
-                       Connection: close\r\n\
-                       \r\n";
-        sock.write_all(request.as_bytes())
+        let addr = test_util::delayed_responder(Duration::from_millis(200));
+        let mut sock: TcpStream = TcpStream::connect(addr).unwrap();
+        sock.write_all(test_util::HTTP_REQUEST)
             .expect("Error writing to stream");
 
         let poll_is_dead = Arc::new(AtomicBool::new(false));
@@ -395,4 +1237,69 @@ mod tests {
         println!("{}", &buff);
         assert!(!buff.is_empty());
     }
+
+    #[test]
+    fn interval_timer_fires_repeatedly() {
+        let selector = Selector::new().unwrap();
+        let poll_is_dead = Arc::new(AtomicBool::new(false));
+        let registrator = selector.registrator(poll_is_dead.clone());
+
+        registrator
+            .register_timer_interval(7, Duration::from_millis(10))
+            .unwrap();
+
+        let mut events = vec![Event::zero()];
+        for _ in 0..3 {
+            selector
+                .select(&mut events, None)
+                .expect("waiting for event.");
+            assert_eq!(events[0].udata, 7);
+        }
+    }
+
+    #[test]
+    fn deregistered_timer_doesnt_fire() {
+        let selector = Selector::new().unwrap();
+        let poll_is_dead = Arc::new(AtomicBool::new(false));
+        let registrator = selector.registrator(poll_is_dead.clone());
+
+        registrator
+            .register_timer(1, Duration::from_millis(10))
+            .unwrap();
+        registrator
+            .register_timer(2, Duration::from_millis(50))
+            .unwrap();
+        registrator.deregister_timer(1).unwrap();
+
+        let mut events = vec![Event::zero()];
+        selector
+            .select(&mut events, None)
+            .expect("waiting for event.");
+        assert_eq!(events[0].udata, 2);
+    }
+
+    #[test]
+    fn read_and_write_interest_register_together() {
+        use std::io::Write;
+
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let (a, mut b) = crate::socket_pair().unwrap();
+        registrator
+            .register(&a, 3, Interests::READABLE | Interests::WRITABLE)
+            .unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector
+            .select(&mut events, Some(1000))
+            .expect("waiting for event.");
+        assert_eq!(2, events.len());
+        assert!(events.iter().any(|event| event.is_readable()));
+        assert!(events.iter().any(|event| event.is_writable()));
+
+        registrator.deregister(&a).unwrap();
+        // Nothing is registered anymore, which isn't an error either
+        registrator.deregister(&a).unwrap();
+    }
 }
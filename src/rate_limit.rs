@@ -0,0 +1,192 @@
+//! Limiting how many events a token gets per interval. A peer that sends its data a
+//! byte per segment makes its socket readable over and over, and every wakeup costs
+//! a call to the handler and a registration. With a limit set for its token, events
+//! beyond the limit are held back and merged, and delivered once the interval is
+//! over, so a storm of them costs the handler one call per interval.
+//!
+//! Only the readiness of a held event is kept, not the event. On IOCP an event points
+//! into the stream's buffers, which are freed if the stream is dropped while its
+//! event is held back, before anybody has seen the event. The event is rebuilt from
+//! the token and readiness when it's delivered, so what only the backend's own event
+//! carries, like the byte count of an IOCP completion or the data of a kqueue filter,
+//! is lost.
+use crate::{Event, Events, Poll, Registry, Token};
+use std::collections::HashMap;
+use std::io;
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+/// The limiters of every rate limited token
+#[derive(Debug, Default)]
+pub(crate) struct RateLimits {
+    limiters: HashMap<Token, Limiter>,
+}
+
+#[derive(Debug)]
+struct Limiter {
+    /// The events per interval, or `None` once the limit has been removed and only
+    /// the held events are left to deliver
+    limit: Option<(usize, Duration)>,
+    window_start: Instant,
+    delivered: usize,
+    /// Whether the events held back since the last delivery said readable and
+    /// writable, or `None` if nothing is held back
+    held: Option<(bool, bool)>,
+}
+
+impl Limiter {
+    fn window_end(&self) -> Instant {
+        match self.limit {
+            Some((_, interval)) => self.window_start + interval,
+            None => self.window_start,
+        }
+    }
+
+    /// Whether `event` can be delivered now. Once one is held back, the ones after it
+    /// are as well so they can be merged.
+    fn admit(&mut self, now: Instant) -> bool {
+        if self.held.is_some() {
+            return false;
+        }
+        let max = match self.limit {
+            Some((max, _)) => max,
+            None => return true,
+        };
+        if now >= self.window_end() {
+            self.window_start = now;
+            self.delivered = 0;
+        }
+        if self.delivered < max {
+            self.delivered += 1;
+            return true;
+        }
+        false
+    }
+
+    fn hold(&mut self, event: &Event) {
+        let (readable, writable) = self.held.unwrap_or_default();
+        self.held = Some((
+            readable || event.is_readable(),
+            writable || event.is_writable(),
+        ));
+    }
+
+    /// Delivers the held events, merged into one for `token`, if there's room in
+    /// `events` and the window they were held back in is over. It counts towards the
+    /// next window.
+    fn release(&mut self, token: Token, now: Instant, events: &mut Events) {
+        if now < self.window_end() || events.len() == events.capacity() {
+            return;
+        }
+        if let Some((readable, writable)) = self.held.take() {
+            events.push(Event::with_readiness(token, readable, writable));
+            self.window_start = now;
+            self.delivered = 1;
+        }
+    }
+}
+
+impl Registry {
+    /// Limits the events delivered for `token` to `max_events` per `interval`. Events
+    /// beyond that are held back, merged into one event, and delivered by the first
+    /// `poll` after the interval, which doesn't wait longer than that. Setting a
+    /// limit for a token that already has one replaces it.
+    ///
+    /// The source doesn't report anything more while its event is held back, since
+    /// the registration that reported it is used up, so a storm is held back in the
+    /// kernel too.
+    pub fn rate_limit(
+        &self,
+        token: Token,
+        max_events: usize,
+        interval: Duration,
+    ) -> io::Result<()> {
+        if max_events == 0 || interval == Duration::from_secs(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A rate limit needs at least one event per interval, and an interval.",
+            ));
+        }
+        let limit = Some((max_events, interval));
+        self.rate_limits()
+            .limiters
+            .entry(token)
+            .and_modify(|limiter| limiter.limit = limit)
+            .or_insert_with(|| Limiter {
+                limit,
+                window_start: Instant::now(),
+                delivered: 0,
+                held: None,
+            });
+        Ok(())
+    }
+
+    /// Removes the rate limit of `token`. The events held back for it are delivered
+    /// by the next `poll`.
+    pub fn remove_rate_limit(&self, token: Token) {
+        let mut rate_limits = self.rate_limits();
+        if let Some(limiter) = rate_limits.limiters.get_mut(&token) {
+            if limiter.held.is_none() {
+                rate_limits.limiters.remove(&token);
+            } else {
+                limiter.limit = None;
+            }
+        }
+    }
+
+    fn rate_limits(&self) -> MutexGuard<'_, RateLimits> {
+        self.rate_limits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Poll {
+    /// `timeout`, or less if held back events are due before it's over.
+    pub(crate) fn rate_limit_timeout(&mut self, timeout: Option<Duration>) -> Option<Duration> {
+        let rate_limits = self
+            .registry
+            .rate_limits
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        let due = rate_limits
+            .limiters
+            .values()
+            .filter(|limiter| limiter.held.is_some())
+            .map(Limiter::window_end)
+            .min();
+        let due = match due {
+            Some(due) => due.saturating_duration_since(Instant::now()),
+            None => return timeout,
+        };
+        Some(timeout.map_or(due, |timeout| timeout.min(due)))
+    }
+
+    /// Holds back the events of tokens that are over their limit, and delivers the
+    /// ones that were held back once they're due.
+    pub(crate) fn limit_rate(&mut self, events: &mut Events) {
+        let rate_limits = self
+            .registry
+            .rate_limits
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        if rate_limits.limiters.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let limiters = &mut rate_limits.limiters;
+        events.retain(|event| match limiters.get_mut(&event.id()) {
+            Some(limiter) => {
+                if limiter.admit(now) {
+                    return true;
+                }
+                trace!("held back event for rate limited token {}", event.id());
+                limiter.hold(event);
+                false
+            }
+            None => true,
+        });
+        for (&token, limiter) in limiters.iter_mut() {
+            limiter.release(token, now, events);
+        }
+        limiters.retain(|_, limiter| limiter.limit.is_some() || limiter.held.is_some());
+    }
+}
@@ -0,0 +1,184 @@
+//! Reading everything the socket has right now, the loop that goes with every read
+//! event: read until reading would block or the peer is done, growing the buffer as we
+//! go. `read_to_end` doesn't work for this since it treats `WouldBlock` as an error
+//! and forgets how much it read before it.
+use crate::TcpStream;
+use std::io;
+
+/// How much the buffer grows by at a time
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Why reading into a buffer stopped, and how many bytes were read before it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadProgress {
+    /// Reading would block. There will be an event once there's more to read.
+    Blocked(usize),
+    /// The peer closed its side of the connection, nothing more is coming.
+    Eof(usize),
+    /// The buffer reached its cap. There might be more to read once some of it has
+    /// been consumed.
+    Full(usize),
+}
+
+impl ReadProgress {
+    /// How many bytes were read.
+    pub fn read(&self) -> usize {
+        match *self {
+            ReadProgress::Blocked(n) | ReadProgress::Eof(n) | ReadProgress::Full(n) => n,
+        }
+    }
+
+    pub fn is_eof(&self) -> bool {
+        matches!(self, ReadProgress::Eof(_))
+    }
+}
+
+impl TcpStream {
+    /// Reads into the end of `buf` until reading would block or the peer closes the
+    /// connection, growing `buf` as needed. Returns which of the two it was and how
+    /// many bytes were read. If reading fails the bytes read before the error are
+    /// still in `buf`.
+    ///
+    /// Use a `ReadAccumulator` to cap how large the buffer can grow.
+    pub fn read_until_blocked(&mut self, buf: &mut Vec<u8>) -> io::Result<ReadProgress> {
+        read_until_blocked(self, buf, usize::MAX)
+    }
+}
+
+/// A buffer that reads are accumulated into until a message in it is complete, with a
+/// cap on how large it can grow, so a peer that sends faster than we consume can't
+/// make us buffer without bounds.
+#[derive(Debug)]
+pub struct ReadAccumulator {
+    buf: Vec<u8>,
+    cap: usize,
+}
+
+impl ReadAccumulator {
+    /// An empty accumulator that never holds more than `cap` bytes.
+    pub fn new(cap: usize) -> ReadAccumulator {
+        ReadAccumulator {
+            buf: Vec::new(),
+            cap,
+        }
+    }
+
+    /// Reads from `stream` like `TcpStream::read_until_blocked`, but stops with
+    /// `ReadProgress::Full` once the accumulator holds `cap` bytes.
+    pub fn read_from(&mut self, stream: &mut TcpStream) -> io::Result<ReadProgress> {
+        read_until_blocked(stream, &mut self.buf, self.cap)
+    }
+
+    /// What's been read and not consumed yet.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Drops the first `n` bytes of the buffer, once they've been handled.
+    pub fn consume(&mut self, n: usize) {
+        self.buf.drain(..n.min(self.buf.len()));
+    }
+
+    /// Takes everything that's been read out of the accumulator.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.buf.len() >= self.cap
+    }
+}
+
+fn read_until_blocked(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    cap: usize,
+) -> io::Result<ReadProgress> {
+    stream.set_nonblocking()?;
+    let mut read = 0;
+    loop {
+        let len = buf.len();
+        if len >= cap {
+            return Ok(ReadProgress::Full(read));
+        }
+        buf.resize(len + CHUNK_SIZE.min(cap - len), 0);
+        let res = stream.read_ready(&mut buf[len..]);
+        buf.truncate(len + *res.as_ref().unwrap_or(&0));
+        match res {
+            Ok(0) => return Ok(ReadProgress::Eof(read)),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!("read {} bytes before blocking", read);
+                return Ok(ReadProgress::Blocked(read));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tcp_pair;
+    use std::io::Write;
+
+    /// Keeps reading until `done` returns true, since data can take a moment to arrive
+    fn eventually(mut done: impl FnMut() -> bool) {
+        let start = std::time::Instant::now();
+        while !done() {
+            assert!(start.elapsed().as_secs() < 5, "data never arrived");
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn read_until_blocked_stops_at_would_block_and_at_eof() {
+        let (mut a, mut b) = tcp_pair();
+        let mut buf = b"x".to_vec();
+        assert_eq!(
+            ReadProgress::Blocked(0),
+            a.read_until_blocked(&mut buf).unwrap()
+        );
+
+        let data = vec![7; 3 * CHUNK_SIZE + 1];
+        b.write_all(&data).unwrap();
+        eventually(|| {
+            let progress = a.read_until_blocked(&mut buf).unwrap();
+            assert!(!progress.is_eof());
+            buf.len() == 1 + data.len()
+        });
+
+        drop(b);
+        eventually(|| a.read_until_blocked(&mut buf).unwrap().is_eof());
+    }
+
+    #[test]
+    fn accumulator_stops_at_its_cap() {
+        let (mut a, mut b) = tcp_pair();
+        let mut acc = ReadAccumulator::new(4);
+        b.write_all(b"hello").unwrap();
+
+        eventually(|| {
+            acc.read_from(&mut a).unwrap();
+            acc.is_full()
+        });
+        assert_eq!(b"hell", acc.buffer());
+        assert_eq!(ReadProgress::Full(0), acc.read_from(&mut a).unwrap());
+        acc.consume(2);
+        eventually(|| {
+            acc.read_from(&mut a).unwrap();
+            acc.len() == 3
+        });
+        assert_eq!(b"llo".to_vec(), acc.take());
+        assert!(acc.is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+//! Futures that resolve once a source is ready, for protocol code written with
+//! `async`/`await` rather than a loop over events. A `Reactor` owns the `Poll` and
+//! wakes the task waiting on each event's token when it's turned, and
+//! `TcpStream::readable` and `writable` register the stream with it.
+//!
+//! ```no_run
+//! # use minimio::{Reactor, TcpStream};
+//! # use std::io::Read;
+//! # async fn read(handle: minimio::ReactorHandle, mut stream: TcpStream) -> std::io::Result<()> {
+//! let mut buf = [0; 1024];
+//! loop {
+//!     stream.readable(&handle).await?;
+//!     match stream.read(&mut buf) {
+//!         Ok(0) => return Ok(()),
+//!         Ok(n) => println!("read {} bytes", n),
+//!         Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+//!         Err(e) => return Err(e),
+//!     }
+//! }
+//! # }
+//! ```
+use crate::{Events, Interests, Poll, Registrator, TcpStream, Token};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{self, Context};
+use std::time::Duration;
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::Source;
+
+/// How many events a `Reactor` takes off the queue per turn
+const EVENTS_CAPACITY: usize = 256;
+
+/// Where the task waiting for a token is
+#[derive(Debug)]
+enum Slot {
+    Waiting(task::Waker),
+    Ready,
+}
+
+#[derive(Debug)]
+struct Shared {
+    registrator: Registrator,
+    slots: Mutex<HashMap<Token, Slot>>,
+    next_token: AtomicUsize,
+}
+
+impl Shared {
+    fn slots(&self) -> MutexGuard<'_, HashMap<Token, Slot>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Owns a `Poll` and wakes the tasks waiting for readiness futures of a
+/// `ReactorHandle` as their sources become ready. Some thread has to keep turning it,
+/// usually the executor's between running tasks.
+#[derive(Debug)]
+pub struct Reactor {
+    poll: Poll,
+    events: Events,
+    shared: Arc<Shared>,
+}
+
+impl Reactor {
+    pub fn new() -> io::Result<Reactor> {
+        let poll = Poll::new()?;
+        Ok(Reactor {
+            shared: Arc::new(Shared {
+                registrator: poll.registrator(),
+                slots: Mutex::default(),
+                next_token: AtomicUsize::new(0),
+            }),
+            poll,
+            events: Events::with_capacity(EVENTS_CAPACITY),
+        })
+    }
+
+    /// What readiness futures are created with, from any thread.
+    pub fn handle(&self) -> ReactorHandle {
+        ReactorHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Polls for events, waiting no longer than `timeout`, and wakes the tasks
+    /// waiting for them. Returns how many were woken.
+    pub fn turn(&mut self, timeout: Option<Duration>) -> io::Result<usize> {
+        self.poll.poll_timeout(&mut self.events, timeout)?;
+        let mut slots = self.shared.slots();
+        let mut woken = 0;
+        for event in self.events.iter() {
+            let slot = match slots.get_mut(&event.id()) {
+                Some(slot) => slot,
+                // The future was dropped before its source was ready
+                None => continue,
+            };
+            if let Slot::Waiting(waker) = std::mem::replace(slot, Slot::Ready) {
+                waker.wake();
+                woken += 1;
+            }
+        }
+        Ok(woken)
+    }
+}
+
+/// Creates readiness futures for sources registered with a `Reactor`. Cloning it is
+/// cheap, and it can be sent to other threads.
+#[derive(Debug, Clone)]
+pub struct ReactorHandle {
+    shared: Arc<Shared>,
+}
+
+impl ReactorHandle {
+    /// A future that resolves once `source` is ready for `interests`. Like every
+    /// registration it's oneshot, and a source only has one registration, so wait for
+    /// one readiness future per source at a time.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn ready<'a, S: Source>(&'a self, source: &'a S, interests: Interests) -> Readiness<'a, S> {
+        Readiness {
+            handle: self,
+            source,
+            interests,
+            token: None,
+        }
+    }
+
+    /// Reserves a slot for a future that's about to register its source.
+    fn insert(&self, waker: task::Waker) -> Token {
+        let token = self.shared.next_token.fetch_add(1, Ordering::Relaxed);
+        self.shared.slots().insert(token, Slot::Waiting(waker));
+        token
+    }
+
+    /// Whether the source of `token` has been ready, or else the waker to wake when it
+    /// is.
+    fn poll_slot(&self, token: Token, waker: &task::Waker) -> task::Poll<()> {
+        let mut slots = self.shared.slots();
+        match slots.get_mut(&token) {
+            Some(Slot::Waiting(waiting)) => {
+                if !waiting.will_wake(waker) {
+                    *waiting = waker.clone();
+                }
+                task::Poll::Pending
+            }
+            _ => {
+                slots.remove(&token);
+                task::Poll::Ready(())
+            }
+        }
+    }
+
+    fn remove(&self, token: Token) {
+        self.shared.slots().remove(&token);
+    }
+}
+
+/// The future of `ReactorHandle::ready`, and of `TcpStream::readable` and `writable`.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+pub struct Readiness<'a, S> {
+    handle: &'a ReactorHandle,
+    source: &'a S,
+    interests: Interests,
+    /// Set once the source has been registered
+    token: Option<Token>,
+}
+
+/// The future of `TcpStream::readable`.
+#[cfg(target_os = "windows")]
+pub struct Readiness<'a, S> {
+    handle: &'a ReactorHandle,
+    source: &'a mut S,
+    interests: Interests,
+    /// Set once the source has been registered
+    token: Option<Token>,
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+impl<S: Source> Future for Readiness<'_, S> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let (registrator, source, interests) =
+            (&this.handle.shared.registrator, this.source, this.interests);
+        poll_readiness(this.handle, &mut this.token, cx, |token| {
+            registrator.register(source, token, interests)
+        })
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Future for Readiness<'_, TcpStream> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let (registrator, source, interests) = (
+            &this.handle.shared.registrator,
+            &mut *this.source,
+            this.interests,
+        );
+        poll_readiness(this.handle, &mut this.token, cx, |token| {
+            registrator.register(source, token, interests)
+        })
+    }
+}
+
+impl<S> Drop for Readiness<'_, S> {
+    fn drop(&mut self) {
+        // The source stays registered, and `turn` drops its event once there's no
+        // slot for it
+        if let Some(token) = self.token {
+            self.handle.remove(token);
+        }
+    }
+}
+
+impl<S> fmt::Debug for Readiness<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Readiness")
+            .field("interests", &self.interests)
+            .field("token", &self.token)
+            .finish()
+    }
+}
+
+/// Registers the source with a new token the first time, and checks its slot after.
+fn poll_readiness(
+    handle: &ReactorHandle,
+    token: &mut Option<Token>,
+    cx: &mut Context<'_>,
+    register: impl FnOnce(Token) -> io::Result<()>,
+) -> task::Poll<io::Result<()>> {
+    match *token {
+        Some(registered) => handle.poll_slot(registered, cx.waker()).map(|()| {
+            *token = None;
+            Ok(())
+        }),
+        None => {
+            let registered = handle.insert(cx.waker().clone());
+            if let Err(e) = register(registered) {
+                handle.remove(registered);
+                return task::Poll::Ready(Err(e));
+            }
+            *token = Some(registered);
+            task::Poll::Pending
+        }
+    }
+}
+
+impl TcpStream {
+    /// Resolves once the stream has data to read, or the peer has hung up.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn readable<'a>(&'a self, handle: &'a ReactorHandle) -> Readiness<'a, TcpStream> {
+        handle.ready(self, Interests::READABLE)
+    }
+
+    /// Resolves once the stream can be written to.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn writable<'a>(&'a self, handle: &'a ReactorHandle) -> Readiness<'a, TcpStream> {
+        handle.ready(self, Interests::WRITABLE)
+    }
+
+    /// Resolves once a read has completed into the stream's buffer, or the peer has
+    /// hung up. There's no `writable`, since writable interest isn't supported with
+    /// IOCP yet.
+    #[cfg(target_os = "windows")]
+    pub fn readable<'a>(&'a mut self, handle: &'a ReactorHandle) -> Readiness<'a, TcpStream> {
+        Readiness {
+            handle,
+            source: self,
+            interests: Interests::READABLE,
+            token: None,
+        }
+    }
+}
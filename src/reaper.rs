@@ -0,0 +1,219 @@
+//! Reaping child processes as they exit. A server that forks workers has to collect
+//! the exit status of each one, or it stays around as a zombie. A `ChildReaper`
+//! handles SIGCHLD and turns it into an event, and `reap` collects every child that
+//! has exited since.
+//!
+//! ```no_run
+//! use minimio::{ChildReaper, Events, Poll};
+//! const CHILDREN: usize = 0;
+//! let mut poll = Poll::new()?;
+//! let reaper = ChildReaper::new(&poll, CHILDREN)?;
+//! std::process::Command::new("true").spawn()?;
+//! let mut events = Events::with_capacity(1024);
+//! poll.poll(&mut events, None)?;
+//! for child in reaper.reap()? {
+//!     println!("{} exited with {}", child.pid, child.status);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::{Interests, Poll, Registrator, Token};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// The socket pair the signal handler writes to, created with the first reaper and
+/// kept for as long as the process lives so the handler never writes to a closed fd
+static PAIR: OnceLock<(Mutex<UnixStream>, UnixStream)> = OnceLock::new();
+/// The end of the pair the signal handler writes to, -1 until there is one
+static SIGNALS: AtomicI32 = AtomicI32::new(-1);
+/// Whether there is a `ChildReaper`. There can only be one, since each would reap
+/// the children of the others.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// A child that exited and was reaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReapedChild {
+    pub pid: u32,
+    pub status: ExitStatus,
+}
+
+/// Makes an event with its token show up when a child process exits. Once it's been
+/// returned from `poll`, `reap` collects the exit status of every child that exited.
+///
+/// The reaper reaps all children of the process, including ones spawned with
+/// `std::process::Command`, so `Child::wait` can't be used on them. There can only
+/// be one reaper at a time. SIGCHLD goes back to its default handling when it's
+/// dropped.
+#[derive(Debug)]
+pub struct ChildReaper {
+    token: Token,
+    registrator: Registrator,
+}
+
+impl ChildReaper {
+    /// Creates the reaper, which wakes up `poll` with an event with `token` as its id.
+    /// Fails with `AlreadyExists` if there already is one. Children that exited before
+    /// it was created are reported by the first event.
+    pub fn new(poll: &Poll, token: Token) -> io::Result<ChildReaper> {
+        if ACTIVE
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "There already is a child reaper.",
+            ));
+        }
+        match ChildReaper::install(poll, token) {
+            Ok(reaper) => Ok(reaper),
+            Err(e) => {
+                ACTIVE.store(false, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    fn install(poll: &Poll, token: Token) -> io::Result<ChildReaper> {
+        let (receiver, sender) = match PAIR.get() {
+            Some((receiver, sender)) => (receiver, sender),
+            None => {
+                let (receiver, sender) = UnixStream::pair()?;
+                receiver.set_nonblocking(true)?;
+                // The handler mustn't block, a full buffer means we'll reap anyway
+                sender.set_nonblocking(true)?;
+                let (receiver, sender) = PAIR.get_or_init(|| (Mutex::new(receiver), sender));
+                SIGNALS.store(sender.as_raw_fd(), Ordering::SeqCst);
+                (receiver, sender)
+            }
+        };
+
+        let registrator = poll.registrator();
+        registrator.register(&*lock(receiver), token, Interests::READABLE)?;
+        let handler = on_sigchld as extern "C" fn(i32) as usize;
+        if unsafe { ffi::signal(ffi::SIGCHLD, handler) } == ffi::SIG_ERR {
+            let err = io::Error::last_os_error();
+            let _ = registrator.deregister(&*lock(receiver));
+            return Err(err);
+        }
+        // Children might have exited before the handler was installed
+        notify(sender)?;
+        Ok(ChildReaper { token, registrator })
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Reaps every child that has exited, and re-arms the reaper. To be called when
+    /// an event with its token is returned from `poll`.
+    pub fn reap(&self) -> io::Result<Vec<ReapedChild>> {
+        let (receiver, _) = PAIR.get().expect("reaper was installed");
+        let mut receiver = lock(receiver);
+        let mut buf = [0u8; 64];
+        loop {
+            match receiver.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        // Drained before we reap, so a child that exits from now on makes a new event
+        self.registrator
+            .register(&*receiver, self.token, Interests::READABLE)?;
+
+        let mut reaped = Vec::new();
+        loop {
+            let mut status = 0;
+            let pid = unsafe { ffi::waitpid(-1, &mut status, ffi::WNOHANG) };
+            if pid > 0 {
+                trace!("reaped child {}", pid);
+                reaped.push(ReapedChild {
+                    pid: pid as u32,
+                    status: ExitStatus::from_raw(status),
+                });
+                continue;
+            }
+            if pid == 0 {
+                // Some children are still running
+                return Ok(reaped);
+            }
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(ffi::ECHILD) => return Ok(reaped),
+                Some(ffi::EINTR) => (),
+                _ => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for ChildReaper {
+    fn drop(&mut self) {
+        unsafe { ffi::signal(ffi::SIGCHLD, ffi::SIG_DFL) };
+        if let Some((receiver, _)) = PAIR.get() {
+            if let Err(e) = self.registrator.deregister(&*lock(receiver)) {
+                debug!("deregistering the child reaper failed: {}", e);
+            }
+        }
+        ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Makes the reaper readable, like the signal handler does. A full buffer already
+/// makes it readable.
+fn notify(mut sender: &UnixStream) -> io::Result<()> {
+    match sender.write(&[1]) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        res => res.map(|_| ()),
+    }
+}
+
+extern "C" fn on_sigchld(_signal: i32) {
+    let byte = 1u8;
+    // `write` is async-signal-safe, but it can change `errno` under the code we
+    // interrupted
+    unsafe {
+        let errno = *ffi::errno();
+        ffi::write(SIGNALS.load(Ordering::SeqCst), &byte, 1);
+        *ffi::errno() = errno;
+    }
+}
+
+/// A panic while holding the lock can't leave the socket in a bad state, so we
+/// ignore poisoning.
+fn lock(mutex: &Mutex<UnixStream>) -> MutexGuard<'_, UnixStream> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+mod ffi {
+    #[cfg(any(target_os = "linux", target_os = "redox"))]
+    pub const SIGCHLD: i32 = 17;
+    #[cfg(any(target_os = "macos", target_os = "dragonfly", target_os = "aix"))]
+    pub const SIGCHLD: i32 = 20;
+    pub const SIG_DFL: usize = 0;
+    pub const SIG_ERR: usize = usize::MAX;
+    pub const WNOHANG: i32 = 1;
+    pub const EINTR: i32 = 4;
+    pub const ECHILD: i32 = 10;
+
+    extern "C" {
+        /// https://man7.org/linux/man-pages/man2/signal.2.html
+        pub fn signal(signum: i32, handler: usize) -> usize;
+        /// https://man7.org/linux/man-pages/man2/waitpid.2.html
+        pub fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+        pub fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+        #[cfg_attr(
+            any(target_os = "linux", target_os = "dragonfly", target_os = "redox"),
+            link_name = "__errno_location"
+        )]
+        #[cfg_attr(target_os = "macos", link_name = "__error")]
+        #[cfg_attr(target_os = "aix", link_name = "_Errno")]
+        pub fn errno() -> *mut i32;
+    }
+}
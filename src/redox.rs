@@ -0,0 +1,820 @@
+//! The Redox backend, on the kernel's `event:` scheme. An event queue is a file:
+//! writing an `Event` naming an fd to it starts watching the fd, or stops watching it
+//! if the event has no flags, and reading from it waits for events. The queue keeps
+//! reporting an fd each time it becomes ready until it's told to stop, so to make
+//! registrations oneshot like the other backends do, we stop watching an fd as soon
+//! as it's been reported.
+//!
+//! The queue can't time out by itself either. Timeouts are files of the `time:`
+//! scheme, which become readable once the time written to them has passed.
+use crate::backlog::FullSelects;
+use crate::registrations::Registrations;
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::unix::{validate_token, Change, KICK_TOKEN};
+use crate::{Events, Interests, Source, Token};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSliceMut, Read, Write};
+use std::net;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex, MutexGuard,
+};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Registrator {
+    /// Shared with the `Selector`, so the queue stays open for as long as anybody can
+    /// register with it
+    queue: Arc<Queue>,
+    is_poll_dead: Arc<AtomicBool>,
+    changes: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+}
+
+impl Registrator {
+    pub fn register(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let fd = source.as_fd().as_raw_fd();
+        self.stats.change();
+        self.queue.watch(fd, token, interests)?;
+        self.registrations.insert(fd, token, interests);
+        Ok(())
+    }
+
+    /// Redox has no send low-water mark, so this returns an error of kind
+    /// `Unsupported`.
+    pub fn register_with_send_lowat(
+        &self,
+        _source: &impl Source,
+        _token: usize,
+        interests: Interests,
+        _send_lowat: usize,
+    ) -> io::Result<()> {
+        if !interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A send low-water mark needs WRITABLE interest.",
+            ));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Redox has no send low-water mark.",
+        ))
+    }
+
+    /// Like `register`, but the registration is queued for the polling thread, which
+    /// applies it at the start of its next `select`. We write to the queue's kick pipe
+    /// so that happens right away.
+    ///
+    /// Since the registration is applied later, errors from applying it can't be
+    /// returned from here. They're logged, and the registration is dropped.
+    pub fn register_deferred(
+        &self,
+        source: &impl Source,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let change = Change {
+            fd: source.as_fd().as_raw_fd(),
+            token,
+            interests,
+        };
+        if self.changes.send(change).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        trace!(
+            "queued registration of fd {} with token {}",
+            change.fd,
+            token
+        );
+        self.stats.wakeup();
+        self.queue.kick()
+    }
+
+    /// Stops watching `source`, so no more events are reported for it (one that
+    /// `select` has already returned can't be taken back). Deregistering a source that
+    /// isn't registered, which includes one that has been reported since it was last
+    /// registered, is not an error.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        if self.queue.is_watched(fd) {
+            self.stats.change();
+        }
+        self.queue.unwatch(fd)?;
+        self.registrations.remove(fd);
+        Ok(())
+    }
+
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self
+            .is_poll_dead
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        // Like on Linux, something that's readable right away wakes up `select` with
+        // an event with token 0. We leak the pipe since the queue will be closed soon
+        // anyway.
+        debug!("closing event loop on queue {}", self.queue.as_raw_fd());
+        let (reader, mut writer) = pipe()?;
+        writer.write_all(&[1])?;
+        self.queue
+            .watch(reader.into_raw_fd(), 0, Interests::READABLE)
+    }
+}
+
+/// An event queue, and which fds it's watching.
+#[derive(Debug)]
+struct Queue {
+    file: File,
+    /// The fds the queue is watching, by the token they're registered with. An fd
+    /// is taken out, and no longer watched, once it's been reported.
+    watched: Mutex<HashMap<RawFd, Token>>,
+    /// Registrators write to this pipe to wake up `select`. It's watched for as long
+    /// as the queue exists, with `KICK_TOKEN`.
+    kick_reader: File,
+    kick_writer: File,
+}
+
+impl Queue {
+    fn new() -> io::Result<Queue> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(ffi::EVENT_SCHEME)?;
+        let (kick_reader, kick_writer) = pipe()?;
+        let queue = Queue {
+            file,
+            watched: Mutex::default(),
+            kick_reader,
+            kick_writer,
+        };
+        queue.write(&[ffi::Event::new(
+            queue.kick_reader.as_raw_fd(),
+            ffi::EVENT_READ,
+            KICK_TOKEN,
+        )])?;
+        Ok(queue)
+    }
+
+    /// Starts watching `fd` for `interests`, or changes what it's watched for. An fd
+    /// that's already ready is reported right away.
+    fn watch(&self, fd: RawFd, token: Token, interests: Interests) -> io::Result<()> {
+        let mut flags = ffi::EVENT_NONE;
+        if interests.is_readable() {
+            flags |= ffi::EVENT_READ;
+        }
+        if interests.is_writable() {
+            flags |= ffi::EVENT_WRITE;
+        }
+        // Locked while we write, so the table always agrees with the kernel
+        let mut watched = self.watched();
+        if let Err(e) = self.write(&[ffi::Event::new(fd, flags, token)]) {
+            debug!(
+                "registering fd {} with token {} failed: {} (os error {:?})",
+                fd,
+                token,
+                e,
+                e.raw_os_error()
+            );
+            return Err(e);
+        }
+        watched.insert(fd, token);
+        debug!(
+            "registered fd {} with token {} for {}",
+            fd, token, interests
+        );
+        Ok(())
+    }
+
+    fn unwatch(&self, fd: RawFd) -> io::Result<()> {
+        let mut watched = self.watched();
+        if watched.remove(&fd).is_none() {
+            return Ok(());
+        }
+        if let Err(e) = self.write(&[ffi::Event::new(fd, ffi::EVENT_NONE, 0)]) {
+            debug!("deregistering fd {} failed: {}", fd, e);
+            return Err(e);
+        }
+        debug!("deregistered fd {}", fd);
+        Ok(())
+    }
+
+    fn is_watched(&self, fd: RawFd) -> bool {
+        self.watched().contains_key(&fd)
+    }
+
+    /// Keeps the events in `events` for fds that are watched with the token of the
+    /// event and stops watching those fds, since they've fired. The rest were reported
+    /// before their fd was deregistered or registered again, and are dropped.
+    fn disarm(&self, events: &mut Events) -> io::Result<()> {
+        let mut watched = self.watched();
+        let mut stop = Vec::new();
+        events.retain(|event| {
+            if event.data == KICK_TOKEN {
+                return true;
+            }
+            let fd = event.id as RawFd;
+            if watched.get(&fd) != Some(&event.data) {
+                trace!("dropping stale event for fd {}", fd);
+                return false;
+            }
+            watched.remove(&fd);
+            stop.push(ffi::Event::new(fd, ffi::EVENT_NONE, 0));
+            true
+        });
+        if stop.is_empty() {
+            return Ok(());
+        }
+        self.write(&stop)
+    }
+
+    /// Stops watching every fd, except the kick pipe.
+    fn clear(&self) -> io::Result<()> {
+        let mut watched = self.watched();
+        let stop: Vec<_> = watched
+            .keys()
+            .map(|&fd| ffi::Event::new(fd, ffi::EVENT_NONE, 0))
+            .collect();
+        if !stop.is_empty() {
+            self.write(&stop)?;
+        }
+        watched.clear();
+        Ok(())
+    }
+
+    fn kick(&self) -> io::Result<()> {
+        match (&self.kick_writer).write(&[1]) {
+            // A full pipe wakes up `select` just the same
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    fn drain_kick(&self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        loop {
+            match (&self.kick_reader).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Waits for events and reads as many as there is room for in `events`.
+    fn read(&self, events: &mut Events) -> io::Result<usize> {
+        events.clear();
+        let size = std::mem::size_of::<ffi::Event>();
+        // The queue writes straight into the spare capacity of `events`
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(events.as_mut_ptr() as *mut u8, events.capacity() * size)
+        };
+        let n = (&self.file).read(buf)? / size;
+        // The queue only ever returns whole events
+        unsafe { events.set_len(n) };
+        Ok(n)
+    }
+
+    fn write(&self, changes: &[ffi::Event]) -> io::Result<()> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                changes.as_ptr() as *const u8,
+                std::mem::size_of_val(changes),
+            )
+        };
+        (&self.file).write_all(bytes)
+    }
+
+    fn watched(&self) -> MutexGuard<'_, HashMap<RawFd, Token>> {
+        // Every change is a single map operation, so a panic while holding the lock
+        // can't leave the map half changed
+        self.watched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl AsRawFd for Queue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[derive(Debug)]
+pub struct Selector {
+    queue: Arc<Queue>,
+    changes: mpsc::Receiver<Change>,
+    change_sender: mpsc::Sender<Change>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+    full_selects: FullSelects,
+    /// A `time:` file on the monotonic clock, watched with `KICK_TOKEN`. It's readable
+    /// once the deadline `select` writes to it has passed.
+    timer: File,
+}
+
+impl Selector {
+    pub fn new() -> io::Result<Self> {
+        let (change_sender, changes) = mpsc::channel();
+        let queue = Queue::new()?;
+        let timer = OpenOptions::new().read(true).write(true).open(format!(
+            "{}/{}",
+            ffi::TIME_SCHEME,
+            ffi::CLOCK_MONOTONIC
+        ))?;
+        queue.write(&[ffi::Event::new(
+            timer.as_raw_fd(),
+            ffi::EVENT_READ,
+            KICK_TOKEN,
+        )])?;
+        Ok(Selector {
+            queue: Arc::new(queue),
+            changes,
+            change_sender,
+            registrations: Arc::default(),
+            stats: Arc::default(),
+            full_selects: FullSelects::default(),
+            timer,
+        })
+    }
+
+    /// Deregisters every source registered with this selector or one of its
+    /// registrators, and drops the registrations still queued with
+    /// `Registrator::register_deferred`, so the selector can be reused as if it was
+    /// new. Wakers are deregistered as well and need to be registered again.
+    ///
+    /// A `select` already waiting in another thread keeps waiting on the old
+    /// registrations, and registrations made while this runs may be lost, so it's
+    /// meant for when nothing else is using the selector.
+    pub fn clear(&self) -> io::Result<()> {
+        for change in self.changes.try_iter() {
+            trace!("dropping deferred registration of fd {}", change.fd);
+        }
+        self.queue.clear()?;
+        self.registrations.clear();
+        debug!(
+            "cleared all registrations on queue {}",
+            self.queue.as_raw_fd()
+        );
+        Ok(())
+    }
+
+    /// Applies the registrations queued with `Registrator::register_deferred`.
+    fn apply_changes(&self) {
+        for change in self.changes.try_iter() {
+            self.stats.change();
+            match self.queue.watch(change.fd, change.token, change.interests) {
+                Ok(()) => self
+                    .registrations
+                    .insert(change.fd, change.token, change.interests),
+                Err(e) => debug!(
+                    "dropping deferred registration of fd {} with token {}: {}",
+                    change.fd, change.token, e
+                ),
+            }
+        }
+    }
+
+    /// This function blocks and waits until an event has been recieved. `timeout` None means
+    /// the poll will never time out.
+    pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        let timeout = timeout_ms.map(|ms| Duration::from_millis(ms.max(0) as u64));
+        self.select_timeout(events, timeout)
+    }
+
+    /// Like `select`, but the timeout isn't limited to whole milliseconds. The
+    /// deadline is written to the timer with nanosecond precision, but it only fires
+    /// on the kernel's next tick after it, so even a timeout of 0 waits for that.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let deadline = match timeout {
+            Some(timeout) => Some(self.arm_timer(timeout)?),
+            None => None,
+        };
+        loop {
+            self.apply_changes();
+            self.wait(events)?;
+            self.queue.disarm(events)?;
+
+            // Being kicked or woken up by the timer is all the internal events tell
+            // us. If nothing else happened and we're supposed to keep waiting, which
+            // is the case for a timer that was armed for an earlier `select`, we go
+            // back to waiting.
+            if events.iter().any(|event| event.data == KICK_TOKEN) {
+                events.retain(|event| event.data != KICK_TOKEN);
+                self.queue.drain_kick()?;
+                let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if events.is_empty() && !timed_out {
+                    continue;
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    /// Arms the timer to fire after `timeout`, and returns when that is.
+    fn arm_timer(&self, timeout: Duration) -> io::Result<Instant> {
+        let deadline = Instant::now() + timeout;
+        let mut now = ffi::TimeSpec::default();
+        (&self.timer).read_exact(now.as_bytes_mut())?;
+        let then = now.add(timeout);
+        (&self.timer).write_all(then.as_bytes())?;
+        trace!(
+            "armed the timer of queue {} for {:?}",
+            self.queue.as_raw_fd(),
+            timeout
+        );
+        Ok(deadline)
+    }
+
+    fn wait(&self, events: &mut Events) -> io::Result<()> {
+        trace!("reading events from queue {}", self.queue.as_raw_fd());
+        self.stats.wait();
+        match self.queue.read(events) {
+            Ok(n_events) => {
+                trace!(
+                    "queue {} woke up with {} events",
+                    self.queue.as_raw_fd(),
+                    n_events
+                );
+                self.full_selects.record(n_events, events.capacity());
+                Ok(())
+            }
+            Err(e) => {
+                debug!(
+                    "reading events from queue {} failed: {} (os error {:?})",
+                    self.queue.as_raw_fd(),
+                    e,
+                    e.raw_os_error()
+                );
+                Err(e)
+            }
+        }
+    }
+
+    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
+        Registrator {
+            queue: self.queue.clone(),
+            is_poll_dead,
+            changes: self.change_sender.clone(),
+            registrations: self.registrations.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Each of those probably left events behind in the queue, so a count that
+    /// keeps growing means the loop isn't keeping up and should shed load or poll with
+    /// a larger buffer. 0 if the last select had room to spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+}
+
+/// An event queue can be watched by another one, and is readable while it has events
+/// pending, so a selector can be registered with `Interests::READABLE` to nest it.
+/// Register it again after selecting from it, and expect the odd wakeup with nothing
+/// to select when a registrator has kicked it.
+impl AsRawFd for Selector {
+    fn as_raw_fd(&self) -> RawFd {
+        self.queue.as_raw_fd()
+    }
+}
+
+impl AsFd for Selector {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.queue.file.as_fd()
+    }
+}
+
+pub type Event = ffi::Event;
+impl Event {
+    // The scheme's `id` is the watched descriptor; the token lives in `data`.
+    #[allow(clippy::misnamed_getters)]
+    pub fn id(&self) -> Token {
+        self.data
+    }
+
+    /// Returns true if the source can be read from, or has hung up.
+    pub fn is_readable(&self) -> bool {
+        self.flags & ffi::EVENT_READ != 0
+    }
+
+    /// Returns true if the source can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.flags & ffi::EVENT_WRITE != 0
+    }
+
+    /// An event the way the event queue would have returned it, for events that are
+    /// rebuilt after being held back.
+    pub(crate) fn with_readiness(token: Token, readable: bool, writable: bool) -> Event {
+        let mut flags = 0;
+        if readable {
+            flags |= ffi::EVENT_READ;
+        }
+        if writable {
+            flags |= ffi::EVENT_WRITE;
+        }
+        // Nothing reads the fd back out of an event
+        ffi::Event::new(-1, flags, token)
+    }
+
+    /// Adds the readiness of `other`, an event for the same token, to this one. The
+    /// queue reports all of an fd's readiness in one event, so they can always be
+    /// merged.
+    pub(crate) fn merge(&mut self, other: &Event) -> bool {
+        self.flags |= other.flags;
+        true
+    }
+}
+
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    pub fn connect(adr: impl net::ToSocketAddrs) -> io::Result<Self> {
+        // Like on the other platforms this blocks while the connection is established
+        let stream = net::TcpStream::connect(adr)?;
+        TcpStream::from_std(stream)
+    }
+
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream { inner: stream })
+    }
+
+    /// `read` leaves the socket blocking, so anything that relies on getting
+    /// `WouldBlock` has to set it back first.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        self.inner.set_nonblocking(true)
+    }
+
+    /// Reads without changing the blocking mode, unlike `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+/// Redox keeps the statistics of a connection to itself.
+pub(crate) fn tcp_info(_stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Redox doesn't have TCP_INFO.",
+    ))
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Blocking while we read, like the other backends do. A caller that wants
+        // `WouldBlock` uses `read_ready` instead.
+        self.inner.set_nonblocking(false)?;
+
+        (&self.inner).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        (&self.inner).read_vectored(bufs)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+/// A non-blocking pipe, read end first.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [-1; 2];
+    if unsafe { ffi::pipe2(fds.as_mut_ptr(), ffi::O_CLOEXEC | ffi::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Nobody else has the fds we just got
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+mod ffi {
+    use std::time::Duration;
+
+    pub const EVENT_SCHEME: &str = "/scheme/event";
+    pub const TIME_SCHEME: &str = "/scheme/time";
+    /// Redox's own number for it, which relibc maps `CLOCK_MONOTONIC` to
+    pub const CLOCK_MONOTONIC: usize = 4;
+
+    pub const EVENT_NONE: usize = 0;
+    pub const EVENT_READ: usize = 1;
+    pub const EVENT_WRITE: usize = 2;
+
+    pub const O_NONBLOCK: i32 = 0x0004_0000;
+    pub const O_CLOEXEC: i32 = 0x0100_0000;
+
+    // https://gitlab.redox-os.org/redox-os/syscall/-/blob/master/src/data.rs
+    #[derive(Debug, Clone, Copy, Default)]
+    #[repr(C)]
+    pub struct Event {
+        /// The fd
+        pub id: usize,
+        pub flags: usize,
+        /// The token
+        pub data: usize,
+    }
+
+    impl Event {
+        pub fn new(fd: i32, flags: usize, token: usize) -> Self {
+            Event {
+                id: fd as usize,
+                flags,
+                data: token,
+            }
+        }
+    }
+
+    /// What a `time:` file reads and writes
+    #[derive(Debug, Clone, Copy, Default)]
+    #[repr(C)]
+    pub struct TimeSpec {
+        tv_sec: i64,
+        tv_nsec: i32,
+    }
+
+    impl TimeSpec {
+        pub fn add(self, duration: Duration) -> TimeSpec {
+            let nanos = self.tv_nsec as u32 + duration.subsec_nanos();
+            let secs = self
+                .tv_sec
+                .saturating_add(duration.as_secs().min(i64::MAX as u64) as i64)
+                .saturating_add((nanos / 1_000_000_000) as i64);
+            TimeSpec {
+                tv_sec: secs,
+                tv_nsec: (nanos % 1_000_000_000) as i32,
+            }
+        }
+
+        pub fn as_bytes(&self) -> &[u8] {
+            unsafe {
+                std::slice::from_raw_parts(
+                    self as *const TimeSpec as *const u8,
+                    std::mem::size_of::<TimeSpec>(),
+                )
+            }
+        }
+
+        pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+            unsafe {
+                std::slice::from_raw_parts_mut(
+                    self as *mut TimeSpec as *mut u8,
+                    std::mem::size_of::<TimeSpec>(),
+                )
+            }
+        }
+    }
+
+    #[link(name = "c")]
+    extern "C" {
+        pub fn pipe2(fds: *mut i32, flags: i32) -> i32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_pair;
+
+    fn selector() -> (Selector, Registrator) {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        (selector, registrator)
+    }
+
+    #[test]
+    fn registrations_are_oneshot() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        registrator.register(&a, 3, Interests::READABLE).unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![3], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        assert!(events[0].is_readable());
+
+        // Still readable, but not watched anymore until it's registered again
+        b.write_all(b"pong").unwrap();
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+        registrator.register(&a, 3, Interests::READABLE).unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+    }
+
+    #[test]
+    fn deferred_registrations_wake_up_select() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        b.write_all(b"ping").unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            registrator
+                .register_deferred(&a, 5, Interests::READABLE)
+                .unwrap();
+            a
+        });
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, None).unwrap();
+        assert_eq!(5, events[0].id());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn select_times_out() {
+        let (selector, _registrator) = selector();
+        let mut events = Vec::with_capacity(4);
+        let started = Instant::now();
+        selector
+            .select_timeout(&mut events, Some(Duration::from_millis(30)))
+            .unwrap();
+        assert!(events.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn deregistered_and_cleared_sources_are_quiet() {
+        let (selector, registrator) = selector();
+        let (a, mut b) = socket_pair().unwrap();
+        let (c, mut d) = socket_pair().unwrap();
+        registrator.register(&a, 1, Interests::READABLE).unwrap();
+        registrator.register(&c, 2, Interests::READABLE).unwrap();
+        registrator.deregister(&a).unwrap();
+        // Nothing is registered anymore, which isn't an error either
+        registrator.deregister(&a).unwrap();
+        selector.clear().unwrap();
+        b.write_all(b"ping").unwrap();
+        d.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! The sources the registration helpers on top of `Registrator::register` take: any
+//! `Source` on Unix, a `TcpStream` on Windows, where IOCP needs `&mut` access to the
+//! stream to hand its buffers out. It's what lets each helper be written once.
+use crate::{Interests, Registrator, Token};
+use std::io;
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::unix::RawSource;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::Source;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(target_os = "windows")]
+use crate::TcpStream;
+
+/// What a source can be deregistered with after the borrow it was registered through
+/// has ended
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+pub(crate) type Handle = RawFd;
+#[cfg(target_os = "windows")]
+pub(crate) type Handle = *mut TcpStream;
+
+/// A source that can be registered: anything that's a `Source` on Unix, a `TcpStream`
+/// on Windows. It's public since public methods take it, but it can't be named
+/// outside the crate.
+pub trait Registrable {
+    fn register(
+        &mut self,
+        registrator: &Registrator,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()>;
+
+    fn deregister(&mut self, registrator: &Registrator) -> io::Result<()>;
+
+    fn handle(&mut self) -> Handle;
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+impl<S: Source> Registrable for S {
+    fn register(
+        &mut self,
+        registrator: &Registrator,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        registrator.register(self, token, interests)
+    }
+
+    fn deregister(&mut self, registrator: &Registrator) -> io::Result<()> {
+        registrator.deregister(self)
+    }
+
+    fn handle(&mut self) -> Handle {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Registrable for TcpStream {
+    fn register(
+        &mut self,
+        registrator: &Registrator,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        registrator.register(self, token, interests)
+    }
+
+    fn deregister(&mut self, registrator: &Registrator) -> io::Result<()> {
+        registrator.deregister(self)
+    }
+
+    fn handle(&mut self) -> Handle {
+        self
+    }
+}
+
+/// Deregisters the source `handle` was taken from.
+///
+/// # Safety
+///
+/// The source must still be alive, so the fd hasn't been reused for something else.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+pub(crate) unsafe fn deregister(registrator: &Registrator, handle: Handle) -> io::Result<()> {
+    registrator.deregister(&RawSource(handle))
+}
+
+/// Deregisters the source `handle` was taken from.
+///
+/// # Safety
+///
+/// The stream must still be alive, and nothing else may be using it.
+#[cfg(target_os = "windows")]
+pub(crate) unsafe fn deregister(registrator: &Registrator, handle: Handle) -> io::Result<()> {
+    registrator.deregister(&mut *handle)
+}
@@ -0,0 +1,149 @@
+//! Keeping track of what's registered, so an application can dump what the selector is
+//! watching when it looks for connections that were never deregistered. The OS can't
+//! tell us, so the registrators note down every registration they make. That costs a
+//! lock per registration, which is why it's only done with the `debug` feature on;
+//! without it everything here compiles to nothing. IOCP always keeps them, since
+//! the handles are what its `Selector::clear` cancels the I/O of.
+use crate::{Interests, Token};
+
+#[cfg(any(
+    feature = "debug",
+    all(target_os = "windows", not(feature = "wsapoll"))
+))]
+use std::collections::HashMap;
+#[cfg(any(
+    feature = "debug",
+    all(target_os = "windows", not(feature = "wsapoll"))
+))]
+use std::sync::{Mutex, MutexGuard};
+
+/// The OS handle of a registered source: its file descriptor.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+pub type RawSource = std::os::unix::io::RawFd;
+
+/// The OS handle of a registered source: the value of its socket, or of its handle for
+/// a job object. Timers and named pipes aren't tracked.
+#[cfg(target_os = "windows")]
+pub type RawSource = u64;
+
+/// The registrations made through a selector and its registrators, shared between
+/// them.
+#[derive(Debug, Default)]
+pub(crate) struct Registrations {
+    #[cfg(any(
+        feature = "debug",
+        all(target_os = "windows", not(feature = "wsapoll"))
+    ))]
+    entries: Mutex<HashMap<RawSource, (Token, Interests)>>,
+}
+
+#[cfg(any(
+    feature = "debug",
+    all(target_os = "windows", not(feature = "wsapoll"))
+))]
+impl Registrations {
+    /// Notes that `source` is registered with `token` for `interests`, replacing what
+    /// it was registered with before.
+    pub(crate) fn insert(&self, source: RawSource, token: Token, interests: Interests) {
+        self.entries().insert(source, (token, interests));
+    }
+
+    pub(crate) fn remove(&self, source: RawSource) {
+        self.entries().remove(&source);
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto",
+        all(target_os = "windows", feature = "wsapoll")
+    ))]
+    pub(crate) fn clear(&self) {
+        self.entries().clear();
+    }
+
+    /// Forgets every registration, and returns the sources that were registered.
+    #[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+    pub(crate) fn take(&self) -> Vec<RawSource> {
+        self.entries().drain().map(|(source, _)| source).collect()
+    }
+
+    /// What's registered right now, ordered by token.
+    #[cfg(feature = "debug")]
+    pub(crate) fn snapshot(&self) -> Vec<(Token, Interests, RawSource)> {
+        let mut snapshot: Vec<_> = self
+            .entries()
+            .iter()
+            .map(|(&source, &(token, interests))| (token, interests, source))
+            .collect();
+        snapshot.sort_by_key(|&(token, _, source)| (token, source));
+        snapshot
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<RawSource, (Token, Interests)>> {
+        // Every change is a single map operation, so a panic while holding the lock
+        // can't leave the map half changed
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(not(any(
+    feature = "debug",
+    all(target_os = "windows", not(feature = "wsapoll"))
+)))]
+impl Registrations {
+    #[inline]
+    pub(crate) fn insert(&self, _source: RawSource, _token: Token, _interests: Interests) {}
+
+    #[inline]
+    pub(crate) fn remove(&self, _source: RawSource) {}
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto",
+        all(target_os = "windows", feature = "wsapoll")
+    ))]
+    #[inline]
+    pub(crate) fn clear(&self) {}
+}
+
+#[cfg(all(test, feature = "debug"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registering_again_replaces_the_entry() {
+        let registrations = Registrations::default();
+        registrations.insert(7, 2, Interests::READABLE);
+        registrations.insert(5, 1, Interests::WRITABLE);
+        registrations.insert(7, 3, Interests::all());
+        assert_eq!(
+            vec![(1, Interests::WRITABLE, 5), (3, Interests::all(), 7)],
+            registrations.snapshot()
+        );
+
+        registrations.remove(5);
+        registrations.remove(7);
+        assert!(registrations.snapshot().is_empty());
+    }
+}
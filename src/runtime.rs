@@ -0,0 +1,399 @@
+//! A multi-reactor skeleton: one `Poll` and event loop per thread, with connections
+//! from a listener spread across them and a `Handle` for sending messages between
+//! them.
+//!
+//! Each loop runs its own `Handler`, created on the loop's thread, so a handler
+//! doesn't need to be `Send` and never shares its connections with another loop.
+//! Connections are accepted on a separate thread and handed to the loops round-robin.
+//! Tokens below `FIRST_TOKEN` are used by the runtime itself.
+
+use crate::channel::{self, Receiver, Sender};
+use crate::clock::ceil_millis;
+use crate::registrable::Registrable;
+use crate::{Event, Events, Interests, Poll, Registrator, TcpStream, Token};
+use std::cell::RefCell;
+use std::io;
+use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The token new connections are announced with
+const NEW_CONNECTIONS: Token = 0;
+/// The token messages sent through a `Handle` are announced with
+const MESSAGES: Token = 1;
+/// The lowest token handlers can register with.
+pub const FIRST_TOKEN: Token = 2;
+
+/// The events of one loop of a `Runtime`. All methods are called on the loop's thread.
+pub trait Handler {
+    /// The messages loops can send each other through a `Handle`.
+    type Message: Send + 'static;
+
+    /// Called with a connection this loop got from the listener. The handler is
+    /// responsible for registering it with `ctx.register` and keeping it alive.
+    fn accepted(&mut self, ctx: &Context<Self::Message>, stream: TcpStream);
+
+    /// Called for every event with a token of `FIRST_TOKEN` or higher.
+    fn ready(&mut self, ctx: &Context<Self::Message>, event: &Event);
+
+    /// Called with each message sent to this loop, in the order they were sent.
+    fn message(&mut self, ctx: &Context<Self::Message>, msg: Self::Message) {
+        let _ = (ctx, msg);
+    }
+
+    /// The tokens of the connections that are still open. `Runtime::drain` waits for
+    /// this to be empty before it stops the loop.
+    fn live_tokens(&self) -> Vec<Token> {
+        Vec::new()
+    }
+
+    /// Called once for each of the `live_tokens` when the runtime starts draining.
+    /// The handler should finish what it's doing with the connection, close it and
+    /// remove its token from `live_tokens`. From now on `ctx.register` only takes
+    /// these tokens.
+    fn shutdown(&mut self, ctx: &Context<Self::Message>, token: Token) {
+        let _ = (ctx, token);
+    }
+}
+
+/// What a `Handler` gets to work with while handling an event.
+#[derive(Debug)]
+pub struct Context<M> {
+    index: usize,
+    registrator: Registrator,
+    handle: Handle<M>,
+    /// The tokens that were live when the loop started draining
+    draining: RefCell<Option<Vec<Token>>>,
+}
+
+impl<M> Context<M> {
+    /// The index of the loop we're running on, from 0 up to `handle().loops()`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Registers `source` with this loop's `Poll`, or registers it again. Once the
+    /// runtime is draining, only the sources of the tokens that were live then can
+    /// be registered, anything else fails.
+    pub fn register(
+        &self,
+        source: &mut impl Registrable,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if let Some(live) = &*self.draining.borrow() {
+            if !live.contains(&token) {
+                return Err(io::Error::other("The loop is draining."));
+            }
+        }
+        source.register(&self.registrator, token, interests)
+    }
+
+    /// Stops watching `source`.
+    pub fn deregister(&self, source: &mut impl Registrable) -> io::Result<()> {
+        source.deregister(&self.registrator)
+    }
+
+    /// Sends messages to this or any other loop.
+    pub fn handle(&self) -> &Handle<M> {
+        &self.handle
+    }
+}
+
+#[derive(Debug)]
+enum Envelope<M> {
+    Message(M),
+    /// Stop after giving the handler until the deadline to close its connections
+    Drain(Instant),
+    Stop,
+}
+
+/// Sends messages to the loops of a `Runtime`. It can be cloned and sent to other
+/// threads, including ones that aren't part of the runtime.
+#[derive(Debug)]
+pub struct Handle<M> {
+    loops: Arc<Vec<Sender<Envelope<M>>>>,
+}
+
+// Derive would require `M: Clone`
+impl<M> Clone for Handle<M> {
+    fn clone(&self) -> Self {
+        Handle {
+            loops: self.loops.clone(),
+        }
+    }
+}
+
+impl<M> Handle<M> {
+    /// How many loops the runtime has.
+    pub fn loops(&self) -> usize {
+        self.loops.len()
+    }
+
+    /// Sends `msg` to the loop with index `to`. Returns an error of kind
+    /// `BrokenPipe` if that loop has stopped.
+    pub fn send(&self, to: usize, msg: M) -> io::Result<()> {
+        match self.loops.get(to) {
+            Some(sender) => sender.send(Envelope::Message(msg)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "There is no loop with that index.",
+            )),
+        }
+    }
+
+    /// Sends a copy of `msg` to every loop.
+    pub fn broadcast(&self, msg: M) -> io::Result<()>
+    where
+        M: Clone,
+    {
+        for sender in self.loops.iter() {
+            sender.send(Envelope::Message(msg.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+/// A running set of event loops. See the module documentation.
+#[derive(Debug)]
+pub struct Runtime<M> {
+    handle: Handle<M>,
+    local_addr: net::SocketAddr,
+    stopping: Arc<AtomicBool>,
+    acceptor: Option<thread::JoinHandle<()>>,
+    loops: Vec<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl<M: Send + 'static> Runtime<M> {
+    /// Starts one loop per core, as reported by `std::thread::available_parallelism`.
+    pub fn per_core<H, F>(listener: net::TcpListener, new_handler: F) -> io::Result<Self>
+    where
+        H: Handler<Message = M>,
+        F: Fn(usize) -> H + Send + Sync + 'static,
+    {
+        let loops = thread::available_parallelism().map_or(1, |n| n.get());
+        Runtime::start(listener, loops, new_handler)
+    }
+
+    /// Starts `loops` event loops (at least one), each running a handler created with
+    /// `new_handler(index)`, and starts accepting connections on `listener`.
+    pub fn start<H, F>(listener: net::TcpListener, loops: usize, new_handler: F) -> io::Result<Self>
+    where
+        H: Handler<Message = M>,
+        F: Fn(usize) -> H + Send + Sync + 'static,
+    {
+        let new_handler = Arc::new(new_handler);
+        let mut polls = Vec::new();
+        let mut connection_senders = Vec::new();
+        let mut message_senders = Vec::new();
+        for _ in 0..loops.max(1) {
+            let poll = Poll::new()?;
+            let (connections, connection_receiver) = channel::channel(&poll, NEW_CONNECTIONS)?;
+            let (messages, message_receiver) = channel::channel(&poll, MESSAGES)?;
+            polls.push((poll, connection_receiver, message_receiver));
+            connection_senders.push(connections);
+            message_senders.push(messages);
+        }
+        let handle = Handle {
+            loops: Arc::new(message_senders),
+        };
+
+        let loops = polls
+            .into_iter()
+            .enumerate()
+            .map(|(index, (poll, connections, messages))| {
+                let new_handler = new_handler.clone();
+                let handle = handle.clone();
+                thread::Builder::new()
+                    .name(format!("minimio-loop-{}", index))
+                    .spawn(move || {
+                        let handler = new_handler(index);
+                        run_loop(index, poll, handler, connections, messages, handle)
+                    })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let local_addr = listener.local_addr()?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let acceptor = {
+            let stopping = stopping.clone();
+            thread::Builder::new()
+                .name("minimio-acceptor".to_string())
+                .spawn(move || accept(listener, connection_senders, &stopping))?
+        };
+
+        Ok(Runtime {
+            handle,
+            local_addr,
+            stopping,
+            acceptor: Some(acceptor),
+            loops,
+        })
+    }
+
+    pub fn handle(&self) -> Handle<M> {
+        self.handle.clone()
+    }
+
+    /// The address the runtime accepts connections on.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections, stops every loop once it has handled the events
+    /// it's working on and waits for all the threads to finish. Returns the first
+    /// error a loop stopped with.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    /// Shuts down gracefully: stops accepting connections, calls `Handler::shutdown`
+    /// for each of the handlers' `live_tokens` and keeps the loops running until
+    /// they have no live tokens left, or until `timeout` has passed. Then it stops
+    /// the loops like `shutdown` does. Connections that were accepted but not yet
+    /// handed to a handler are closed.
+    pub fn drain(mut self, timeout: Duration) -> io::Result<()> {
+        self.stop_accepting();
+        let deadline = Instant::now() + timeout;
+        for sender in self.handle.loops.iter() {
+            let _ = sender.send(Envelope::Drain(deadline));
+        }
+        self.stop()
+    }
+}
+
+impl<M> Runtime<M> {
+    fn stop(&mut self) -> io::Result<()> {
+        self.stop_accepting();
+        for sender in self.handle.loops.iter() {
+            // A loop that has stopped already has nothing to be told
+            let _ = sender.send(Envelope::Stop);
+        }
+        let mut result = Ok(());
+        for handle in self.loops.drain(..) {
+            let res = handle
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("Loop panicked.")));
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        result
+    }
+
+    fn stop_accepting(&mut self) {
+        if let Some(acceptor) = self.acceptor.take() {
+            self.stopping.store(true, Ordering::SeqCst);
+            // `accept` blocks, so we give it a connection to return with
+            let mut addr = self.local_addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    net::SocketAddr::V4(_) => net::Ipv4Addr::LOCALHOST.into(),
+                    net::SocketAddr::V6(_) => net::Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = net::TcpStream::connect(addr);
+            let _ = acceptor.join();
+        }
+    }
+}
+
+impl<M> Drop for Runtime<M> {
+    fn drop(&mut self) {
+        // Errors can't be reported from here, that's what `shutdown` is for
+        let _ = self.stop();
+    }
+}
+
+fn accept(listener: net::TcpListener, loops: Vec<Sender<TcpStream>>, stopping: &AtomicBool) {
+    let mut next = 0;
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            return;
+        }
+        let stream = match stream.and_then(TcpStream::from_std) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("accepting connection failed: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = loops[next].send(stream) {
+            debug!("dropping connection for loop {}: {}", next, e);
+        }
+        next = (next + 1) % loops.len();
+    }
+}
+
+fn run_loop<H: Handler>(
+    index: usize,
+    mut poll: Poll,
+    mut handler: H,
+    connections: Receiver<TcpStream>,
+    messages: Receiver<Envelope<H::Message>>,
+    handle: Handle<H::Message>,
+) -> io::Result<()> {
+    let ctx = Context {
+        index,
+        registrator: poll.registrator(),
+        handle,
+        draining: RefCell::new(None),
+    };
+    let mut events = Events::with_capacity(1024);
+    let mut draining: Option<Instant> = None;
+    loop {
+        if let Some(deadline) = draining {
+            if handler.live_tokens().is_empty() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                debug!(
+                    "loop {} stopped draining with {} connections left",
+                    index,
+                    handler.live_tokens().len()
+                );
+                return Ok(());
+            }
+            poll.poll(&mut events, Some(ceil_millis(deadline - now)))?;
+        } else {
+            poll.poll(&mut events, None)?;
+        }
+
+        for event in &events {
+            match event.id() {
+                NEW_CONNECTIONS => {
+                    for stream in connections.drain()? {
+                        if draining.is_some() {
+                            // Dropping it closes it
+                            continue;
+                        }
+                        handler.accepted(&ctx, stream);
+                    }
+                }
+                MESSAGES => {
+                    for envelope in messages.drain()? {
+                        match envelope {
+                            Envelope::Message(msg) => handler.message(&ctx, msg),
+                            Envelope::Drain(deadline) => {
+                                if draining.is_none() {
+                                    draining = Some(deadline);
+                                    let live = handler.live_tokens();
+                                    *ctx.draining.borrow_mut() = Some(live.clone());
+                                    for token in live {
+                                        handler.shutdown(&ctx, token);
+                                    }
+                                }
+                            }
+                            Envelope::Stop if draining.is_some() => (),
+                            Envelope::Stop => return Ok(()),
+                        }
+                    }
+                }
+                _ => handler.ready(&ctx, event),
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Registrations that can't outlive the sources they're for.
+use crate::registrable::{self, Handle, Registrable};
+use crate::{Interests, Poll, Registrator, Registry, Token};
+use std::cell::RefCell;
+use std::io;
+use std::marker::PhantomData;
+
+/// Registers sources with a `Poll` for the duration of `Registry::scope`. Sources
+/// registered through it have to outlive the scope, and are deregistered before it
+/// ends (on Windows that means their pending I/O is cancelled), so a source that lives
+/// on the stack can't be left registered after it's gone.
+///
+/// A source created inside the scope doesn't live long enough to be registered:
+///
+/// ```compile_fail
+/// use minimio::{socket_pair, Interests, Poll};
+///
+/// let mut poll = Poll::new()?;
+/// poll.scope(|scope, _| {
+///     let (mut a, _b) = socket_pair().unwrap();
+///     scope.register(&mut a, 1, Interests::READABLE).unwrap();
+/// })?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Scope<'env> {
+    registrator: Registrator,
+    registered: RefCell<Vec<Handle>>,
+    // Invariant, so the caller can't shorten `'env` to fit sources created in the scope
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+impl Registry {
+    /// Calls `f` with a `Scope` and deregisters everything registered through it
+    /// before returning, also if `f` panics. Returns what `f` returned, or the first
+    /// error from deregistering.
+    ///
+    /// The `Poll` the registry belongs to can't be polled while this borrows it, so
+    /// this is for when another thread is polling. Use `Poll::scope` to poll in the
+    /// scope.
+    pub fn scope<'env, T>(&self, f: impl FnOnce(&Scope<'env>) -> T) -> io::Result<T> {
+        run(self.registrator(), f)
+    }
+}
+
+impl Poll {
+    /// Like `Registry::scope`, but hands the closure the `Poll` too, so it can wait for
+    /// events for the sources it registers.
+    pub fn scope<'env, T>(
+        &mut self,
+        f: impl FnOnce(&Scope<'env>, &mut Poll) -> T,
+    ) -> io::Result<T> {
+        let registrator = self.registrator();
+        run(registrator, |scope| f(scope, self))
+    }
+}
+
+fn run<'env, T>(registrator: Registrator, f: impl FnOnce(&Scope<'env>) -> T) -> io::Result<T> {
+    let scope = Scope {
+        registrator,
+        registered: RefCell::new(Vec::new()),
+        _env: PhantomData,
+    };
+    let result = f(&scope);
+    scope.deregister_all()?;
+    Ok(result)
+}
+
+impl<'env> Scope<'env> {
+    /// Registers `source` until the end of the scope, and hands it back so it can
+    /// still be used inside the scope.
+    pub fn register<S: Registrable>(
+        &self,
+        source: &'env mut S,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<&'env mut S> {
+        source.register(&self.registrator, token, interests)?;
+        self.registered.borrow_mut().push(source.handle());
+        Ok(source)
+    }
+
+    /// The registrator the scope registers with, for registering the scope's sources
+    /// again after they've fired.
+    pub fn registrator(&self) -> &Registrator {
+        &self.registrator
+    }
+
+    fn deregister_all(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for handle in self.registered.borrow_mut().drain(..) {
+            // The sources outlive the scope, and nothing else is using them now that
+            // the closure has returned
+            if let Err(e) = unsafe { registrable::deregister(&self.registrator, handle) } {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<'env> Drop for Scope<'env> {
+    fn drop(&mut self) {
+        // Only left to do if the closure panicked
+        let _ = self.deregister_all();
+    }
+}
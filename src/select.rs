@@ -0,0 +1,522 @@
+//! A last-resort selector on `select(2)`, for constrained or sandboxed systems where
+//! the kernel event queue, or even `poll`, isn't available but `select` is. It keeps
+//! no state in the kernel: every `select` builds an `fd_set` for reading and one for
+//! writing from the registrations, and the kernel scans all of them each time, so it's
+//! only meant for a handful of fds.
+//!
+//! An `fd_set` is a bitmap with room for `FD_SETSIZE` fds, 1024 on most platforms
+//! and 256 on QNX, and writing a larger fd into one corrupts the memory after it. So fds
+//! from `FD_SETSIZE` up can't be registered at all, `register` fails with
+//! `InvalidInput` instead. Creating the selector and its sources before the program
+//! opens lots of files keeps their fds low.
+//!
+//! Registrations are oneshot like with the other selectors: an fd is forgotten once
+//! it has been reported. Events are the platform's `Event`s, so the rest of the
+//! program keeps using the same tokens and `Events`.
+//!
+//! On Haiku and QNX this is the backend behind `Selector`, since they have neither
+//! epoll nor kqueue.
+use crate::backlog::FullSelects;
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::unix::validate_token;
+use crate::{Event, Events, Interests, Source, Token};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The number of fds an `fd_set` has room for. Only fds below it can be registered.
+#[cfg(not(target_os = "nto"))]
+pub const FD_SETSIZE: usize = 1024;
+/// The number of fds an `fd_set` has room for. Only fds below it can be registered.
+#[cfg(target_os = "nto")]
+pub const FD_SETSIZE: usize = 256;
+
+/// What a selector shares with its registrators.
+struct Shared {
+    watched: Mutex<Watched>,
+    /// Registrators write to this socket to wake up `select`. The other end is in the
+    /// read set of every `select`.
+    kick_reader: UnixStream,
+    kick_writer: UnixStream,
+    closed: AtomicBool,
+    stats: SyscallCounters,
+}
+
+#[derive(Default)]
+struct Watched {
+    /// The registered fds, by the token and interests they're registered with. An fd
+    /// is taken out once it's been reported.
+    fds: HashMap<RawFd, (Token, Interests)>,
+    /// Whether `select` is waiting on the sets it built from `fds`, and needs to be
+    /// woken up to see changes. Only changed with the lock held, together with
+    /// building the sets, so a registration is either in the sets or kicks.
+    waiting: bool,
+    /// Set by `FdSetRegistrator::wake`, until `select` has returned for it
+    woken: bool,
+}
+
+impl Shared {
+    fn kick(&self) -> io::Result<()> {
+        self.stats.wakeup();
+        match (&self.kick_writer).write(&[1]) {
+            // A full socket wakes up `select` just the same
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    fn drain_kick(&self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        loop {
+            match (&self.kick_reader).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn watched(&self) -> MutexGuard<'_, Watched> {
+        // Every change is a single map operation or flag, so a panic while holding the
+        // lock can't leave it half changed
+        self.watched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Waits for registered fds to be ready with `select(2)`.
+pub struct FdSetSelector {
+    shared: Arc<Shared>,
+    full_selects: FullSelects,
+}
+
+impl FdSetSelector {
+    pub fn new() -> io::Result<FdSetSelector> {
+        let (kick_reader, kick_writer) = UnixStream::pair()?;
+        kick_reader.set_nonblocking(true)?;
+        kick_writer.set_nonblocking(true)?;
+        if kick_reader.as_raw_fd() as usize >= FD_SETSIZE {
+            return Err(over_fd_setsize(kick_reader.as_raw_fd()));
+        }
+        Ok(FdSetSelector {
+            shared: Arc::new(Shared {
+                watched: Mutex::default(),
+                kick_reader,
+                kick_writer,
+                closed: AtomicBool::new(false),
+                stats: SyscallCounters::default(),
+            }),
+            full_selects: FullSelects::default(),
+        })
+    }
+
+    pub fn registrator(&self) -> FdSetRegistrator {
+        FdSetRegistrator {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Forgets every registered fd, so the selector can be reused as if it was new. A
+    /// `select` already waiting in another thread keeps waiting on the old sets.
+    pub fn clear(&self) {
+        self.shared.watched().fds.clear();
+        debug!("cleared all registrations with select");
+    }
+
+    /// Blocks until a registered fd is ready, `FdSetRegistrator::wake` is called or
+    /// `timeout_ms` milliseconds have passed. `None` means it never times out.
+    pub fn select(&self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<()> {
+        let timeout = timeout_ms.map(|ms| Duration::from_millis(ms.max(0) as u64));
+        self.select_timeout(events, timeout)
+    }
+
+    /// Like `select`, but takes a `Duration`. `select(2)` takes microseconds, so the
+    /// timeout is rounded up to the next one.
+    ///
+    /// Ready fds that don't fit in `events` stay registered and are reported by the
+    /// next select. A registered fd that has been closed makes the whole select fail
+    /// with `EBADF`, since `select(2)` can't say which one it was, so deregister fds
+    /// before closing them.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        events.clear();
+        loop {
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let kicked = self.wait(events, timeout)?;
+            // Being kicked only means the registrations changed, so if nothing else
+            // happened and there's time left we wait again with the new sets
+            let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if events.is_empty() && kicked && !timed_out {
+                continue;
+            }
+            self.full_selects.record(events.len(), events.capacity());
+            return Ok(());
+        }
+    }
+
+    /// Waits on the registered fds once, and turns those that are ready into events.
+    /// Returns whether a registrator kicked us without waking us up.
+    fn wait(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<bool> {
+        let kick_fd = self.shared.kick_reader.as_raw_fd();
+        let mut read_set = ffi::FdSet::new();
+        let mut write_set = ffi::FdSet::new();
+        read_set.insert(kick_fd);
+        let mut nfds = kick_fd + 1;
+        {
+            let mut watched = self.shared.watched();
+            if watched.woken {
+                watched.woken = false;
+                return Ok(false);
+            }
+            watched.waiting = true;
+            for (&fd, &(_, interests)) in &watched.fds {
+                if interests.is_readable() {
+                    read_set.insert(fd);
+                }
+                if interests.is_writable() {
+                    write_set.insert(fd);
+                }
+                nfds = nfds.max(fd + 1);
+            }
+        }
+
+        let mut tv = timeout.map(ffi::timeval::ceil);
+        let tv_ptr = tv.as_mut().map_or(ptr::null_mut(), |tv| tv as *mut _);
+        trace!("select on {} fds with timeout {:?}", nfds, timeout);
+        self.shared.stats.wait();
+        let res =
+            unsafe { ffi::select(nfds, &mut read_set, &mut write_set, ptr::null_mut(), tv_ptr) };
+        let mut watched = self.shared.watched();
+        watched.waiting = false;
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            debug!("select failed: {} (os error {:?})", e, e.raw_os_error());
+            return Err(e);
+        }
+        trace!("select woke up with {} fds ready", res);
+
+        let kicked = read_set.contains(kick_fd);
+        if kicked {
+            self.shared.drain_kick()?;
+        }
+        // The sets only have bits for the fds that were registered when we started
+        // waiting, and an fd that was deregistered since isn't in `fds` anymore
+        let ready: Vec<_> = watched
+            .fds
+            .iter()
+            .map(|(&fd, &(token, interests))| {
+                let readable = interests.is_readable() && read_set.contains(fd);
+                let writable = interests.is_writable() && write_set.contains(fd);
+                (fd, token, readable, writable)
+            })
+            .filter(|&(_, _, readable, writable)| readable || writable)
+            .collect();
+        for (fd, token, readable, writable) in ready {
+            // What doesn't fit stays registered, and is reported again next time
+            if events.len() == events.capacity() {
+                break;
+            }
+            watched.fds.remove(&fd);
+            events.push(Event::with_readiness(token, readable, writable));
+        }
+        if watched.woken {
+            watched.woken = false;
+            return Ok(false);
+        }
+        Ok(kicked)
+    }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. The fds that didn't fit are reported next time, so a count that keeps
+    /// growing means the loop isn't keeping up. 0 if the last select had room to
+    /// spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    /// Registering doesn't need one, except to wake up a waiting `select`.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.shared.stats.snapshot()
+    }
+}
+
+impl Drop for FdSetSelector {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for FdSetSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdSetSelector")
+            .field("fds", &self.shared.watched().fds.len())
+            .finish()
+    }
+}
+
+/// Registers sources with an `FdSetSelector`, from any thread.
+pub struct FdSetRegistrator {
+    shared: Arc<Shared>,
+}
+
+impl FdSetRegistrator {
+    /// Registers `source` to be reported once with `token` when it's ready for any of
+    /// `interests`, replacing its earlier registration if it has one. Fails with
+    /// `InvalidInput` if its fd is `FD_SETSIZE` or more, since an `fd_set` has no
+    /// room for it.
+    pub fn register(
+        &self,
+        source: &impl Source,
+        token: Token,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Selector closed.",
+            ));
+        }
+
+        interests.validate()?;
+        validate_token(token)?;
+        let fd = source.as_fd().as_raw_fd();
+        if fd as usize >= FD_SETSIZE {
+            debug!("refusing to register fd {} with token {}", fd, token);
+            return Err(over_fd_setsize(fd));
+        }
+        let waiting = {
+            let mut watched = self.shared.watched();
+            watched.fds.insert(fd, (token, interests));
+            watched.waiting
+        };
+        debug!(
+            "registered fd {} with token {} for {} with select",
+            fd, token, interests
+        );
+        if waiting {
+            self.shared.kick()?;
+        }
+        Ok(())
+    }
+
+    /// Forgets `source`, so no more events are reported for it (one that `select` has
+    /// already returned can't be taken back). Deregistering a source that isn't
+    /// registered is not an error.
+    pub fn deregister(&self, source: &impl Source) -> io::Result<()> {
+        let fd = source.as_fd().as_raw_fd();
+        if self.shared.watched().fds.remove(&fd).is_some() {
+            debug!("deregistered fd {} from select", fd);
+        }
+        Ok(())
+    }
+
+    /// Makes a blocking `select` return, with whatever events there are.
+    pub fn wake(&self) -> io::Result<()> {
+        self.shared.watched().woken = true;
+        self.shared.kick()
+    }
+}
+
+impl fmt::Debug for FdSetRegistrator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdSetRegistrator").finish()
+    }
+}
+
+fn over_fd_setsize(fd: RawFd) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "fd {} doesn't fit in an fd_set, select can only watch fds below {}.",
+            fd, FD_SETSIZE
+        ),
+    )
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use super::FD_SETSIZE;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    const WORD_BITS: usize = usize::BITS as usize;
+
+    /// `fd_set` is an array of `long` on Linux and DragonFly, of `int32_t` on macOS
+    /// and Haiku, and of `unsigned int` on QNX. All of them are little-endian bitmaps of `FD_SETSIZE` bits on the platforms we
+    /// build for, so words of `usize` set the same bits as `FD_SET` does.
+    #[repr(C)]
+    pub struct FdSet {
+        bits: [usize; FD_SETSIZE / WORD_BITS],
+    }
+
+    impl FdSet {
+        /// An empty set, like after `FD_ZERO`.
+        pub fn new() -> Self {
+            FdSet {
+                bits: [0; FD_SETSIZE / WORD_BITS],
+            }
+        }
+
+        /// `FD_SET`. `fd` is below `FD_SETSIZE`, registering makes sure of that.
+        pub fn insert(&mut self, fd: RawFd) {
+            let fd = fd as usize;
+            self.bits[fd / WORD_BITS] |= 1 << (fd % WORD_BITS);
+        }
+
+        /// `FD_ISSET`
+        pub fn contains(&self, fd: RawFd) -> bool {
+            let fd = fd as usize;
+            self.bits[fd / WORD_BITS] & (1 << (fd % WORD_BITS)) != 0
+        }
+    }
+
+    #[repr(C)]
+    pub struct timeval {
+        tv_sec: isize,
+        #[cfg(any(target_os = "macos", target_os = "haiku", target_os = "nto"))]
+        tv_usec: i32,
+        #[cfg(not(any(target_os = "macos", target_os = "haiku", target_os = "nto")))]
+        tv_usec: isize,
+    }
+
+    impl timeval {
+        /// `timeout` rounded up to the next microsecond, so we never return early.
+        pub fn ceil(timeout: Duration) -> Self {
+            let timeout = Duration::from_micros(timeout.as_nanos().div_ceil(1000) as u64);
+            timeval {
+                tv_sec: timeout.as_secs().min(isize::MAX as u64) as isize,
+                tv_usec: timeout.subsec_micros() as _,
+            }
+        }
+    }
+
+    // https://man7.org/linux/man-pages/man2/select.2.html. Haiku's C library is
+    // libroot.
+    #[cfg_attr(not(target_os = "haiku"), link(name = "c"))]
+    #[cfg_attr(target_os = "haiku", link(name = "root"))]
+    extern "C" {
+        // The default `select` on Intel macOS is the one with the legacy behaviour
+        // from before Mac OS X 10.5, `select$1050` is the one C programs get
+        #[cfg_attr(
+            all(target_os = "macos", target_arch = "x86_64"),
+            link_name = "select$1050"
+        )]
+        pub fn select(
+            nfds: i32,
+            readfds: *mut FdSet,
+            writefds: *mut FdSet,
+            errorfds: *mut FdSet,
+            timeout: *mut timeval,
+        ) -> i32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{socket_pair, SourceFd};
+    use std::os::unix::io::RawFd;
+
+    #[test]
+    fn registrations_are_oneshot() {
+        let selector = FdSetSelector::new().unwrap();
+        let registrator = selector.registrator();
+        let (a, mut b) = socket_pair().unwrap();
+        let mut events = Events::with_capacity(16);
+
+        registrator.register(&a, 7, Interests::READABLE).unwrap();
+        b.write_all(b"ping").unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(7, events[0].id());
+        assert!(events[0].is_readable());
+        assert!(!events[0].is_writable());
+
+        // Still readable, but forgotten until it's registered again
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+        registrator
+            .register(&a, 8, Interests::READABLE.add(Interests::WRITABLE))
+            .unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(8, events[0].id());
+        assert!(events[0].is_readable());
+        assert!(events[0].is_writable());
+    }
+
+    #[test]
+    fn fds_over_fd_setsize_are_rejected() {
+        // Never touched, registering stops at the number
+        struct High;
+        impl AsRawFd for High {
+            fn as_raw_fd(&self) -> RawFd {
+                FD_SETSIZE as RawFd
+            }
+        }
+
+        let selector = FdSetSelector::new().unwrap();
+        // The fd is never used, so it doesn't matter that nobody owns it
+        let source = unsafe { SourceFd::new(&High) };
+        let err = selector
+            .registrator()
+            .register(&source, 1, Interests::READABLE)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn registering_from_another_thread_wakes_up_select() {
+        let selector = FdSetSelector::new().unwrap();
+        let registrator = selector.registrator();
+        let (a, mut b) = socket_pair().unwrap();
+        b.write_all(b"ping").unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            registrator.register(&a, 5, Interests::READABLE).unwrap();
+            a
+        });
+
+        let mut events = Events::with_capacity(16);
+        selector.select(&mut events, None).unwrap();
+        assert_eq!(5, events[0].id());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn fds_that_dont_fit_are_reported_next_time() {
+        let selector = FdSetSelector::new().unwrap();
+        let registrator = selector.registrator();
+        let (a, mut b) = socket_pair().unwrap();
+        let (c, mut d) = socket_pair().unwrap();
+        registrator.register(&a, 1, Interests::READABLE).unwrap();
+        registrator.register(&c, 2, Interests::READABLE).unwrap();
+        b.write_all(b"ping").unwrap();
+        d.write_all(b"ping").unwrap();
+
+        let mut events = Events::with_capacity(1);
+        selector.select(&mut events, Some(1000)).unwrap();
+        let first = events[0].id();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_ne!(first, events[0].id());
+    }
+
+    #[test]
+    fn wake_ends_a_blocking_select() {
+        let selector = FdSetSelector::new().unwrap();
+        selector.registrator().wake().unwrap();
+        let mut events = Events::with_capacity(16);
+        selector.select(&mut events, None).unwrap();
+        assert!(events.is_empty());
+    }
+}
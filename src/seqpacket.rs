@@ -0,0 +1,359 @@
+//! `SOCK_SEQPACKET` Unix domain sockets: connection oriented like a stream, but every
+//! `send` arrives as one message with its boundaries intact. The standard library
+//! doesn't have them, so these are built on the raw syscalls.
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::path::Path;
+
+/// A connected, non-blocking `SOCK_SEQPACKET` socket. Reading or writing when it isn't
+/// ready returns an error of kind `WouldBlock`, so register it and try again once
+/// `poll` says so.
+#[derive(Debug)]
+pub struct UnixSeqpacket {
+    fd: RawFd,
+}
+
+impl UnixSeqpacket {
+    /// Connects to the `UnixSeqpacketListener` bound to `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        let addr = ffi::SockAddrUn::new(path.as_ref())?;
+        let socket = UnixSeqpacket { fd: new_socket()? };
+        // Connecting a Unix socket doesn't wait for the other end to accept, so
+        // it's done in blocking mode and `socket` is made non-blocking afterwards
+        let res = unsafe {
+            ffi::connect(
+                socket.fd,
+                &addr as *const ffi::SockAddrUn as *const std::ffi::c_void,
+                std::mem::size_of::<ffi::SockAddrUn>() as u32,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        set_nonblocking(socket.fd)?;
+        Ok(socket)
+    }
+
+    /// Creates a pair of connected sockets.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let mut fds = [-1; 2];
+        let res = unsafe {
+            ffi::socketpair(
+                ffi::AF_UNIX,
+                ffi::SOCK_SEQPACKET | ffi::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pair = (UnixSeqpacket { fd: fds[0] }, UnixSeqpacket { fd: fds[1] });
+        for fd in &fds {
+            set_cloexec(*fd)?;
+            set_nonblocking(*fd)?;
+        }
+        Ok(pair)
+    }
+
+    /// Sends `buf` as one message. It's sent whole or not at all, so the returned
+    /// length is always the length of `buf`.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let res = unsafe { ffi::send(self.fd, buf.as_ptr(), buf.len(), ffi::MSG_NOSIGNAL) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+
+    /// Receives one message into `buf` and returns its length. If the message is
+    /// longer than `buf`, the rest of it is discarded. Returns 0 once the other end
+    /// has closed the connection.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = unsafe { ffi::recv(self.fd, buf.as_mut_ptr(), buf.len(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+}
+
+impl AsRawFd for UnixSeqpacket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for UnixSeqpacket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd is ours and stays open for as long as we're around
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl Drop for UnixSeqpacket {
+    fn drop(&mut self) {
+        close_on_drop(self.fd);
+    }
+}
+
+/// A non-blocking listener for `UnixSeqpacket` connections. It's readable when there
+/// are connections waiting to be accepted.
+#[derive(Debug)]
+pub struct UnixSeqpacketListener {
+    fd: RawFd,
+}
+
+impl UnixSeqpacketListener {
+    /// Binds to `path`, which mustn't exist yet.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let addr = ffi::SockAddrUn::new(path.as_ref())?;
+        let listener = UnixSeqpacketListener { fd: new_socket()? };
+        let res = unsafe {
+            ffi::bind(
+                listener.fd,
+                &addr as *const ffi::SockAddrUn as *const std::ffi::c_void,
+                std::mem::size_of::<ffi::SockAddrUn>() as u32,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { ffi::listen(listener.fd, 128) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        set_nonblocking(listener.fd)?;
+        Ok(listener)
+    }
+
+    /// Accepts a connection. Returns an error of kind `WouldBlock` if there are none
+    /// waiting.
+    pub fn accept(&self) -> io::Result<UnixSeqpacket> {
+        let fd = unsafe { ffi::accept(self.fd, std::ptr::null_mut(), std::ptr::null_mut()) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = UnixSeqpacket { fd };
+        set_cloexec(fd)?;
+        set_nonblocking(fd)?;
+        Ok(socket)
+    }
+}
+
+impl AsRawFd for UnixSeqpacketListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for UnixSeqpacketListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd is ours and stays open for as long as we're around
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl Drop for UnixSeqpacketListener {
+    fn drop(&mut self) {
+        close_on_drop(self.fd);
+    }
+}
+
+fn new_socket() -> io::Result<RawFd> {
+    let fd = unsafe { ffi::socket(ffi::AF_UNIX, ffi::SOCK_SEQPACKET | ffi::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if let Err(e) = set_cloexec(fd).and_then(|()| set_nosigpipe(fd)) {
+        close_on_drop(fd);
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+/// Linux and DragonFly create the sockets with `SOCK_CLOEXEC`, macOS doesn't have it
+/// so we set the flag right after.
+#[cfg(target_os = "macos")]
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    if unsafe { ffi::fcntl(fd, ffi::F_SETFD, ffi::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "dragonfly"))]
+fn set_cloexec(_fd: RawFd) -> io::Result<()> {
+    Ok(())
+}
+
+/// macOS doesn't have `MSG_NOSIGNAL`, instead the socket is told not to raise
+/// `SIGPIPE` when the other end is gone.
+#[cfg(target_os = "macos")]
+fn set_nosigpipe(fd: RawFd) -> io::Result<()> {
+    let on = 1i32;
+    let res = unsafe {
+        ffi::setsockopt(
+            fd,
+            ffi::SOL_SOCKET,
+            ffi::SO_NOSIGPIPE,
+            &on as *const i32 as *const std::ffi::c_void,
+            std::mem::size_of::<i32>() as u32,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "dragonfly"))]
+fn set_nosigpipe(_fd: RawFd) -> io::Result<()> {
+    Ok(())
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { ffi::fcntl(fd, ffi::F_GETFL) };
+    if flags < 0 || unsafe { ffi::fcntl(fd, ffi::F_SETFL, flags | ffi::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn close_on_drop(fd: RawFd) {
+    if unsafe { ffi::close(fd) } < 0 {
+        let e = io::Error::last_os_error();
+        if !std::thread::panicking() {
+            panic!("{}", e);
+        }
+    }
+}
+
+mod ffi {
+    use super::*;
+    use std::os::raw::c_char;
+
+    pub const AF_UNIX: i32 = 1;
+    pub const SOCK_SEQPACKET: i32 = 5;
+    pub const F_GETFL: i32 = 3;
+    pub const F_SETFL: i32 = 4;
+
+    #[cfg(target_os = "linux")]
+    pub const SOCK_CLOEXEC: i32 = 0x80000;
+    #[cfg(target_os = "linux")]
+    pub const O_NONBLOCK: i32 = 0x800;
+    #[cfg(target_os = "linux")]
+    pub const MSG_NOSIGNAL: i32 = 0x4000;
+
+    #[cfg(target_os = "dragonfly")]
+    pub const SOCK_CLOEXEC: i32 = 0x1000_0000;
+    #[cfg(target_os = "dragonfly")]
+    pub const O_NONBLOCK: i32 = 0x4;
+    #[cfg(target_os = "dragonfly")]
+    pub const MSG_NOSIGNAL: i32 = 0x400;
+
+    // macOS has neither flag, `set_cloexec` and `set_nosigpipe` make up for it
+    #[cfg(target_os = "macos")]
+    pub const SOCK_CLOEXEC: i32 = 0;
+    #[cfg(target_os = "macos")]
+    pub const F_SETFD: i32 = 2;
+    #[cfg(target_os = "macos")]
+    pub const FD_CLOEXEC: i32 = 1;
+    #[cfg(target_os = "macos")]
+    pub const O_NONBLOCK: i32 = 0x4;
+    #[cfg(target_os = "macos")]
+    pub const MSG_NOSIGNAL: i32 = 0;
+    #[cfg(target_os = "macos")]
+    pub const SOL_SOCKET: i32 = 0xffff;
+    #[cfg(target_os = "macos")]
+    pub const SO_NOSIGPIPE: i32 = 0x1022;
+
+    // http://man7.org/linux/man-pages/man7/unix.7.html
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    pub struct SockAddrUn {
+        sun_family: u16,
+        sun_path: [c_char; 108],
+    }
+
+    // https://opensource.apple.com/source/xnu/xnu-4570.1.46/bsd/sys/un.h, which
+    // DragonFly's matches
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    #[repr(C)]
+    pub struct SockAddrUn {
+        sun_len: u8,
+        sun_family: u8,
+        sun_path: [c_char; 104],
+    }
+
+    impl SockAddrUn {
+        pub fn new(path: &Path) -> io::Result<Self> {
+            #[cfg(target_os = "linux")]
+            let mut addr = SockAddrUn {
+                sun_family: AF_UNIX as u16,
+                sun_path: [0; 108],
+            };
+            #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+            let mut addr = SockAddrUn {
+                sun_len: std::mem::size_of::<SockAddrUn>() as u8,
+                sun_family: AF_UNIX as u8,
+                sun_path: [0; 104],
+            };
+            let bytes = path.as_os_str().as_bytes();
+            // There has to be room for the terminating nul
+            if bytes.len() >= addr.sun_path.len() || bytes.contains(&0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Path is too long or contains a nul byte.",
+                ));
+            }
+            for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+                *dst = *src as c_char;
+            }
+            Ok(addr)
+        }
+    }
+
+    #[link(name = "c")]
+    extern "C" {
+        /// http://man7.org/linux/man-pages/man2/socket.2.html
+        pub fn socket(domain: i32, ty: i32, protocol: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/socketpair.2.html
+        pub fn socketpair(domain: i32, ty: i32, protocol: i32, sv: *mut i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/bind.2.html
+        pub fn bind(sockfd: i32, addr: *const std::ffi::c_void, addrlen: u32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/listen.2.html
+        pub fn listen(sockfd: i32, backlog: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/accept.2.html
+        pub fn accept(sockfd: i32, addr: *mut std::ffi::c_void, addrlen: *mut u32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/connect.2.html
+        pub fn connect(sockfd: i32, addr: *const std::ffi::c_void, addrlen: u32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/send.2.html
+        pub fn send(sockfd: i32, buf: *const u8, len: usize, flags: i32) -> isize;
+
+        /// http://man7.org/linux/man-pages/man2/recv.2.html
+        pub fn recv(sockfd: i32, buf: *mut u8, len: usize, flags: i32) -> isize;
+
+        /// http://man7.org/linux/man-pages/man2/setsockopt.2.html
+        #[cfg(target_os = "macos")]
+        pub fn setsockopt(
+            sockfd: i32,
+            level: i32,
+            optname: i32,
+            optval: *const std::ffi::c_void,
+            optlen: u32,
+        ) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/fcntl.2.html
+        pub fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/close.2.html
+        pub fn close(fd: i32) -> i32;
+    }
+}
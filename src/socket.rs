@@ -0,0 +1,812 @@
+//! Sockets configured before they go live. Options like `SO_REUSEPORT` only take
+//! effect if they're set before the socket is bound, and the buffer sizes limit the
+//! TCP window the handshake negotiates, which `std` gives us no way to get in before.
+//! The socket options of live streams that `std` doesn't have are here too.
+use crate::{TcpListener, TcpStream, UdpSocket};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+/// A TCP socket that hasn't been connected or bound to listen yet.
+///
+/// ```no_run
+/// # use minimio::TcpSocket;
+/// let addr = "0.0.0.0:8080".parse().unwrap();
+/// let socket = TcpSocket::new_for_addr(addr)?;
+/// socket.set_reuseaddr(true)?;
+/// socket.set_recv_buffer_size(1 << 20)?;
+/// socket.bind(addr)?;
+/// let listener = socket.listen(1024)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct TcpSocket {
+    fd: OwnedFd,
+    family: i32,
+}
+
+impl TcpSocket {
+    pub fn new_v4() -> io::Result<TcpSocket> {
+        TcpSocket::new(ffi::AF_INET)
+    }
+
+    pub fn new_v6() -> io::Result<TcpSocket> {
+        TcpSocket::new(ffi::AF_INET6)
+    }
+
+    /// A socket of the family of `addr`, to bind or connect to it.
+    pub fn new_for_addr(addr: SocketAddr) -> io::Result<TcpSocket> {
+        TcpSocket::new(family_of(&addr))
+    }
+
+    /// A Multipath TCP socket of the family of `addr`, which can spread a connection
+    /// over several paths, like a phone's Wi-Fi and cellular, once the peer agrees.
+    /// Connections to and from peers without MPTCP fall back to plain TCP, and so
+    /// does the socket if the kernel has no MPTCP or has it disabled with
+    /// `net.mptcp.enabled`. `is_mptcp` tells which one it got.
+    #[cfg(target_os = "linux")]
+    pub fn new_mptcp_for_addr(addr: SocketAddr) -> io::Result<TcpSocket> {
+        let family = family_of(&addr);
+        match new_socket(family, ffi::SOCK_STREAM, ffi::IPPROTO_MPTCP) {
+            Ok(fd) => Ok(TcpSocket { fd, family }),
+            Err(ref e) if mptcp_unavailable(e) => {
+                debug!("MPTCP is unavailable, falling back to TCP: {}", e);
+                TcpSocket::new(family)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the socket was created for Multipath TCP.
+    #[cfg(target_os = "linux")]
+    pub fn is_mptcp(&self) -> io::Result<bool> {
+        is_mptcp(self.as_raw_fd())
+    }
+
+    fn new(family: i32) -> io::Result<TcpSocket> {
+        new_socket(family, ffi::SOCK_STREAM, 0).map(|fd| TcpSocket { fd, family })
+    }
+
+    /// Sets `SO_REUSEADDR`, which lets a listener bind to the address of one that
+    /// has just closed, while its connections are still in `TIME_WAIT`.
+    pub fn set_reuseaddr(&self, reuseaddr: bool) -> io::Result<()> {
+        set_int_option(self.as_raw_fd(), ffi::SO_REUSEADDR, reuseaddr as i32)
+    }
+
+    pub fn reuseaddr(&self) -> io::Result<bool> {
+        get_int_option(self.as_raw_fd(), ffi::SO_REUSEADDR).map(|value| value != 0)
+    }
+
+    /// Sets `SO_REUSEPORT`, which lets several sockets bind to the same address as
+    /// long as all of them set it. On Linux the kernel spreads the connections to a
+    /// port between its listeners, so every loop can accept on one of its own.
+    pub fn set_reuseport(&self, reuseport: bool) -> io::Result<()> {
+        set_int_option(self.as_raw_fd(), ffi::SO_REUSEPORT, reuseport as i32)
+    }
+
+    pub fn reuseport(&self) -> io::Result<bool> {
+        get_int_option(self.as_raw_fd(), ffi::SO_REUSEPORT).map(|value| value != 0)
+    }
+
+    /// Sets `SO_SNDBUF`. Linux doubles the size to leave room for its bookkeeping,
+    /// and reports the doubled size back.
+    pub fn set_send_buffer_size(&self, size: u32) -> io::Result<()> {
+        set_int_option(self.as_raw_fd(), ffi::SO_SNDBUF, clamp(size))
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<u32> {
+        get_int_option(self.as_raw_fd(), ffi::SO_SNDBUF).map(|size| size as u32)
+    }
+
+    /// Sets `SO_RCVBUF`. Linux doubles the size to leave room for its bookkeeping,
+    /// and reports the doubled size back.
+    pub fn set_recv_buffer_size(&self, size: u32) -> io::Result<()> {
+        set_int_option(self.as_raw_fd(), ffi::SO_RCVBUF, clamp(size))
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<u32> {
+        get_int_option(self.as_raw_fd(), ffi::SO_RCVBUF).map(|size| size as u32)
+    }
+
+    /// Sets `IPV6_V6ONLY` on an IPv6 socket. A listener bound to `[::]` with it
+    /// accepts IPv6 connections only, and one without it IPv4 connections too, with
+    /// IPv4-mapped addresses. Linux defaults to dual-stack unless `net.ipv6.bindv6only`
+    /// is set, so set it either way rather than relying on the default. Linux also
+    /// makes a socket bound to an address other than `[::]` v6-only regardless.
+    pub fn set_only_v6(&self, only_v6: bool) -> io::Result<()> {
+        set_option(
+            self.as_raw_fd(),
+            ffi::IPPROTO_IPV6,
+            ffi::IPV6_V6ONLY,
+            only_v6 as i32,
+        )
+    }
+
+    pub fn only_v6(&self) -> io::Result<bool> {
+        only_v6(self.as_raw_fd())
+    }
+
+    /// Pins the socket to the network interface named `interface`, like `eth1`, so
+    /// its traffic goes through it whatever the routing table says, or unpins it with
+    /// `None`. It's `SO_BINDTODEVICE` on Linux, which needs `CAP_NET_RAW` on kernels
+    /// older than 5.7, and `IP_BOUND_IF` or `IPV6_BOUND_IF` on macOS.
+    pub fn bind_device(&self, interface: Option<&str>) -> io::Result<()> {
+        bind_device(self.as_raw_fd(), self.family, interface)
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<()> {
+        bind(self.as_raw_fd(), addr)
+    }
+
+    /// Connects to `addr`. Like `TcpStream::connect`, it waits for the connection to
+    /// be established, and the stream is non-blocking after that.
+    pub fn connect(self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let raw_addr = ffi::SockAddr::new(&addr);
+        let res = unsafe { ffi::connect(self.as_raw_fd(), raw_addr.as_ptr(), raw_addr.len()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        TcpStream::from_std(std::net::TcpStream::from(self.fd))
+    }
+
+    /// Starts listening for connections, with room for `backlog` of them to wait
+    /// for `accept`.
+    pub fn listen(self, backlog: u32) -> io::Result<TcpListener> {
+        let backlog = backlog.min(i32::MAX as u32) as i32;
+        if unsafe { ffi::listen(self.as_raw_fd(), backlog) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        TcpListener::from_std(std::net::TcpListener::from(self.fd))
+    }
+}
+
+impl AsRawFd for TcpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for TcpSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// The options of UDP sockets, applied to each one `bind` creates before binding it,
+/// so several processes can bind the same port, or a receiver gets a buffer large
+/// enough for a burst of datagrams. Options that aren't set are left at the
+/// system's defaults.
+///
+/// ```no_run
+/// # use minimio::UdpSocketBuilder;
+/// let socket = UdpSocketBuilder::new()
+///     .reuseport(true)
+///     .recv_buffer_size(4 << 20)
+///     .bind("0.0.0.0:5353".parse().unwrap())?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UdpSocketBuilder {
+    reuseaddr: bool,
+    reuseport: bool,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    only_v6: Option<bool>,
+    device: Option<String>,
+}
+
+impl UdpSocketBuilder {
+    pub fn new() -> UdpSocketBuilder {
+        UdpSocketBuilder::default()
+    }
+
+    /// Sets `SO_REUSEADDR`. On Linux that lets sockets that all set it bind the
+    /// same address, but only the last one bound receives the datagrams.
+    pub fn reuseaddr(mut self, reuseaddr: bool) -> UdpSocketBuilder {
+        self.reuseaddr = reuseaddr;
+        self
+    }
+
+    /// Sets `SO_REUSEPORT`, which lets sockets that all set it bind the same address.
+    /// On Linux the datagrams are spread between them by their source address, so
+    /// processes can share the load of a port.
+    pub fn reuseport(mut self, reuseport: bool) -> UdpSocketBuilder {
+        self.reuseport = reuseport;
+        self
+    }
+
+    /// Sets `SO_SNDBUF`. Linux doubles it, like `TcpSocket::set_send_buffer_size`.
+    pub fn send_buffer_size(mut self, size: u32) -> UdpSocketBuilder {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `SO_RCVBUF`, how many bytes of datagrams wait to be received before the
+    /// kernel drops new ones. Linux doubles it, and caps what it's asked for at
+    /// `net.core.rmem_max`.
+    pub fn recv_buffer_size(mut self, size: u32) -> UdpSocketBuilder {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `IPV6_V6ONLY`, whether a socket bound to an IPv6 address leaves IPv4 to
+    /// another socket rather than taking its datagrams as IPv4-mapped addresses. It's
+    /// ignored when binding an IPv4 address. See `TcpSocket::set_only_v6` for the
+    /// default.
+    pub fn only_v6(mut self, only_v6: bool) -> UdpSocketBuilder {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Pins the sockets to the network interface named `interface`, see
+    /// `TcpSocket::bind_device`. They only receive the datagrams that arrive through
+    /// it, and send theirs through it.
+    pub fn bind_device(mut self, interface: &str) -> UdpSocketBuilder {
+        self.device = Some(interface.to_owned());
+        self
+    }
+
+    /// Creates a socket of the family of `addr`, sets the options on it, and binds it
+    /// to `addr`.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let family = family_of(&addr);
+        let fd = new_socket(family, ffi::SOCK_DGRAM, 0)?;
+        let raw_fd = fd.as_raw_fd();
+        if self.reuseaddr {
+            set_int_option(raw_fd, ffi::SO_REUSEADDR, 1)?;
+        }
+        if self.reuseport {
+            set_int_option(raw_fd, ffi::SO_REUSEPORT, 1)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            set_int_option(raw_fd, ffi::SO_SNDBUF, clamp(size))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            set_int_option(raw_fd, ffi::SO_RCVBUF, clamp(size))?;
+        }
+        if let (Some(only_v6), SocketAddr::V6(_)) = (self.only_v6, addr) {
+            set_option(raw_fd, ffi::IPPROTO_IPV6, ffi::IPV6_V6ONLY, only_v6 as i32)?;
+        }
+        if let Some(ref device) = self.device {
+            bind_device(raw_fd, family, Some(device))?;
+        }
+        bind(raw_fd, addr)?;
+        UdpSocket::from_std(std::net::UdpSocket::from(fd))
+    }
+}
+
+/// How long data can go unacknowledged. Keepalive only probes an idle connection,
+/// so a peer that vanished while we were sending goes unnoticed for as long as the
+/// kernel keeps retransmitting, which is about 15 minutes on Linux.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl TcpStream {
+    /// Sets how long sent data can go unacknowledged before the kernel drops the
+    /// connection, and reads and writes fail with `TimedOut`, or goes back to the
+    /// system's default with `None`. It's `TCP_USER_TIMEOUT` on Linux, and
+    /// `TCP_RXT_CONNDROPTIME` on macOS, where it's rounded up to whole seconds.
+    pub fn set_user_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let value = match timeout {
+            #[cfg(target_os = "linux")]
+            Some(timeout) => timeout.as_millis().clamp(1, i32::MAX as u128) as i32,
+            #[cfg(target_os = "macos")]
+            Some(timeout) => {
+                let seconds = timeout.as_secs() + (timeout.subsec_nanos() > 0) as u64;
+                seconds.clamp(1, i32::MAX as u64) as i32
+            }
+            None => 0,
+        };
+        set_option(
+            self.as_raw_fd(),
+            ffi::IPPROTO_TCP,
+            ffi::TCP_USER_TIMEOUT,
+            value,
+        )
+    }
+
+    /// The timeout set with `set_user_timeout`, `None` for the system's default.
+    pub fn user_timeout(&self) -> io::Result<Option<Duration>> {
+        let value = get_option(self.as_raw_fd(), ffi::IPPROTO_TCP, ffi::TCP_USER_TIMEOUT)?;
+        Ok(match value {
+            value if value <= 0 => None,
+            #[cfg(target_os = "linux")]
+            ms => Some(Duration::from_millis(ms as u64)),
+            #[cfg(target_os = "macos")]
+            seconds => Some(Duration::from_secs(seconds as u64)),
+        })
+    }
+}
+
+/// DragonFly has no option for it, it always retransmits for as long as the kernel
+/// is configured to.
+#[cfg(target_os = "dragonfly")]
+impl TcpStream {
+    pub fn set_user_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Err(unsupported("DragonFly has no user timeout."))
+    }
+
+    pub fn user_timeout(&self) -> io::Result<Option<Duration>> {
+        Err(unsupported("DragonFly has no user timeout."))
+    }
+}
+
+/// Whether an IPv6 socket only takes IPv6 traffic, which can't be changed once it's
+/// bound
+pub(crate) fn only_v6(fd: RawFd) -> io::Result<bool> {
+    get_option(fd, ffi::IPPROTO_IPV6, ffi::IPV6_V6ONLY).map(|value| value != 0)
+}
+
+fn family_of(addr: &SocketAddr) -> i32 {
+    match addr {
+        SocketAddr::V4(_) => ffi::AF_INET,
+        SocketAddr::V6(_) => ffi::AF_INET6,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(fd: RawFd, _family: i32, interface: Option<&str>) -> io::Result<()> {
+    // An empty name unpins the socket
+    let name = interface.unwrap_or("").as_bytes();
+    let res = unsafe {
+        ffi::setsockopt(
+            fd,
+            ffi::SOL_SOCKET,
+            ffi::SO_BINDTODEVICE,
+            name.as_ptr() as *const std::ffi::c_void,
+            name.len() as u32,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn bind_device(fd: RawFd, family: i32, interface: Option<&str>) -> io::Result<()> {
+    // Index 0 unpins the socket
+    let index = match interface {
+        Some(interface) => {
+            let name = std::ffi::CString::new(interface).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The interface name contains a nul byte.",
+                )
+            })?;
+            let index = unsafe { ffi::if_nametoindex(name.as_ptr()) };
+            if index == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            index as i32
+        }
+        None => 0,
+    };
+    if family == ffi::AF_INET6 {
+        set_option(fd, ffi::IPPROTO_IPV6, ffi::IPV6_BOUND_IF, index)
+    } else {
+        set_option(fd, ffi::IPPROTO_IP, ffi::IP_BOUND_IF, index)
+    }
+}
+
+/// DragonFly can't pin a socket to an interface, only bind it to one of its
+/// addresses
+#[cfg(target_os = "dragonfly")]
+fn bind_device(_fd: RawFd, _family: i32, interface: Option<&str>) -> io::Result<()> {
+    match interface {
+        Some(_) => Err(unsupported("DragonFly can't pin a socket to an interface.")),
+        None => Ok(()),
+    }
+}
+
+#[cfg(target_os = "dragonfly")]
+fn unsupported(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+fn bind(fd: RawFd, addr: SocketAddr) -> io::Result<()> {
+    let raw_addr = ffi::SockAddr::new(&addr);
+    if unsafe { ffi::bind(fd, raw_addr.as_ptr(), raw_addr.len()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A close-on-exec socket. On macOS and DragonFly it doesn't raise `SIGPIPE` either,
+/// like the ones `std` creates.
+fn new_socket(family: i32, ty: i32, protocol: i32) -> io::Result<OwnedFd> {
+    #[cfg(any(target_os = "linux", target_os = "dragonfly"))]
+    let ty = ty | ffi::SOCK_CLOEXEC;
+    let fd = unsafe { ffi::socket(family, ty, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owning it from here on closes it if one of the calls below fails
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    #[cfg(target_os = "macos")]
+    {
+        if unsafe { ffi::fcntl(fd.as_raw_fd(), ffi::F_SETFD, ffi::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    set_int_option(fd.as_raw_fd(), ffi::SO_NOSIGPIPE, 1)?;
+    Ok(fd)
+}
+
+/// Whether creating an MPTCP socket failed because the kernel doesn't do MPTCP, as
+/// opposed to running out of fds and the like
+#[cfg(target_os = "linux")]
+fn mptcp_unavailable(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(ffi::EPROTONOSUPPORT) | Some(ffi::EINVAL) | Some(ffi::ENOPROTOOPT)
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn is_mptcp(fd: RawFd) -> io::Result<bool> {
+    get_int_option(fd, ffi::SO_PROTOCOL).map(|protocol| protocol == ffi::IPPROTO_MPTCP)
+}
+
+/// Socket options are ints, so larger sizes are capped rather than wrapping around.
+fn clamp(size: u32) -> i32 {
+    size.min(i32::MAX as u32) as i32
+}
+
+pub(crate) fn get_int_option(fd: RawFd, name: i32) -> io::Result<i32> {
+    get_option(fd, ffi::SOL_SOCKET, name)
+}
+
+fn get_option(fd: RawFd, level: i32, name: i32) -> io::Result<i32> {
+    let mut value = 0i32;
+    let mut len = std::mem::size_of::<i32>() as u32;
+    let res = unsafe {
+        ffi::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut i32 as *mut std::ffi::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+pub(crate) fn set_int_option(fd: RawFd, name: i32, value: i32) -> io::Result<()> {
+    set_option(fd, ffi::SOL_SOCKET, name, value)
+}
+
+fn set_option(fd: RawFd, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let res = unsafe {
+        ffi::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const i32 as *const std::ffi::c_void,
+            std::mem::size_of::<i32>() as u32,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+mod ffi {
+    pub const AF_INET: i32 = 2;
+    #[cfg(target_os = "linux")]
+    pub const AF_INET6: i32 = 10;
+    #[cfg(target_os = "macos")]
+    pub const AF_INET6: i32 = 30;
+    #[cfg(target_os = "dragonfly")]
+    pub const AF_INET6: i32 = 28;
+    pub const SOCK_STREAM: i32 = 1;
+    pub const SOCK_DGRAM: i32 = 2;
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub const IPPROTO_TCP: i32 = 6;
+    #[cfg(target_os = "linux")]
+    pub const TCP_USER_TIMEOUT: i32 = 18;
+    // `TCP_RXT_CONNDROPTIME`, under the name the option has on Linux
+    #[cfg(target_os = "macos")]
+    pub const TCP_USER_TIMEOUT: i32 = 0x80;
+    pub const IPPROTO_IPV6: i32 = 41;
+    #[cfg(target_os = "linux")]
+    pub const IPV6_V6ONLY: i32 = 26;
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    pub const IPV6_V6ONLY: i32 = 27;
+    #[cfg(target_os = "linux")]
+    pub const SOCK_CLOEXEC: i32 = 0x80000;
+    #[cfg(target_os = "dragonfly")]
+    pub const SOCK_CLOEXEC: i32 = 0x1000_0000;
+
+    #[cfg(target_os = "linux")]
+    pub const SOL_SOCKET: i32 = 1;
+    #[cfg(target_os = "linux")]
+    pub const SO_REUSEADDR: i32 = 2;
+    #[cfg(target_os = "linux")]
+    pub const SO_SNDBUF: i32 = 7;
+    #[cfg(target_os = "linux")]
+    pub const SO_RCVBUF: i32 = 8;
+    #[cfg(target_os = "linux")]
+    pub const SO_REUSEPORT: i32 = 15;
+    #[cfg(target_os = "linux")]
+    pub const SO_BINDTODEVICE: i32 = 25;
+    #[cfg(target_os = "linux")]
+    pub const SO_PROTOCOL: i32 = 38;
+    #[cfg(target_os = "linux")]
+    pub const IPPROTO_MPTCP: i32 = 262;
+    #[cfg(target_os = "linux")]
+    pub const EINVAL: i32 = 22;
+    #[cfg(target_os = "linux")]
+    pub const ENOPROTOOPT: i32 = 92;
+    #[cfg(target_os = "linux")]
+    pub const EPROTONOSUPPORT: i32 = 93;
+
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    pub const SOL_SOCKET: i32 = 0xffff;
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    pub const SO_REUSEADDR: i32 = 0x0004;
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    pub const SO_REUSEPORT: i32 = 0x0200;
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    pub const SO_SNDBUF: i32 = 0x1001;
+    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+    pub const SO_RCVBUF: i32 = 0x1002;
+    #[cfg(target_os = "macos")]
+    pub const SO_NOSIGPIPE: i32 = 0x1022;
+    #[cfg(target_os = "dragonfly")]
+    pub const SO_NOSIGPIPE: i32 = 0x0800;
+    #[cfg(target_os = "macos")]
+    pub const IPPROTO_IP: i32 = 0;
+    #[cfg(target_os = "macos")]
+    pub const IP_BOUND_IF: i32 = 25;
+    #[cfg(target_os = "macos")]
+    pub const IPV6_BOUND_IF: i32 = 125;
+    #[cfg(target_os = "macos")]
+    pub const F_SETFD: i32 = 2;
+    #[cfg(target_os = "macos")]
+    pub const FD_CLOEXEC: i32 = 1;
+
+    // http://man7.org/linux/man-pages/man7/ip.7.html and
+    // http://man7.org/linux/man-pages/man7/ipv6.7.html. BSD puts the length of the
+    // address in front of the family, which is a byte there.
+    #[repr(C)]
+    pub struct SockAddrIn {
+        #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+        len: u8,
+        #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+        family: u8,
+        #[cfg(target_os = "linux")]
+        family: u16,
+        port: u16,
+        addr: [u8; 4],
+        zero: [u8; 8],
+    }
+
+    #[repr(C)]
+    pub struct SockAddrIn6 {
+        #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+        len: u8,
+        #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+        family: u8,
+        #[cfg(target_os = "linux")]
+        family: u16,
+        port: u16,
+        flowinfo: u32,
+        addr: [u8; 16],
+        scope_id: u32,
+    }
+
+    pub enum SockAddr {
+        V4(SockAddrIn),
+        V6(SockAddrIn6),
+    }
+
+    impl SockAddr {
+        pub fn new(addr: &std::net::SocketAddr) -> Self {
+            match addr {
+                std::net::SocketAddr::V4(addr) => SockAddr::V4(SockAddrIn {
+                    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+                    len: std::mem::size_of::<SockAddrIn>() as u8,
+                    family: AF_INET as _,
+                    port: addr.port().to_be(),
+                    addr: addr.ip().octets(),
+                    zero: [0; 8],
+                }),
+                std::net::SocketAddr::V6(addr) => SockAddr::V6(SockAddrIn6 {
+                    #[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+                    len: std::mem::size_of::<SockAddrIn6>() as u8,
+                    family: AF_INET6 as _,
+                    port: addr.port().to_be(),
+                    flowinfo: addr.flowinfo(),
+                    addr: addr.ip().octets(),
+                    scope_id: addr.scope_id(),
+                }),
+            }
+        }
+
+        pub fn as_ptr(&self) -> *const std::ffi::c_void {
+            match self {
+                SockAddr::V4(addr) => addr as *const SockAddrIn as *const std::ffi::c_void,
+                SockAddr::V6(addr) => addr as *const SockAddrIn6 as *const std::ffi::c_void,
+            }
+        }
+
+        pub fn len(&self) -> u32 {
+            match self {
+                SockAddr::V4(_) => std::mem::size_of::<SockAddrIn>() as u32,
+                SockAddr::V6(_) => std::mem::size_of::<SockAddrIn6>() as u32,
+            }
+        }
+    }
+
+    #[link(name = "c")]
+    extern "C" {
+        /// http://man7.org/linux/man-pages/man2/socket.2.html
+        pub fn socket(domain: i32, ty: i32, protocol: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/bind.2.html
+        pub fn bind(sockfd: i32, addr: *const std::ffi::c_void, addrlen: u32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/connect.2.html
+        pub fn connect(sockfd: i32, addr: *const std::ffi::c_void, addrlen: u32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/listen.2.html
+        pub fn listen(sockfd: i32, backlog: i32) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/setsockopt.2.html
+        pub fn setsockopt(
+            sockfd: i32,
+            level: i32,
+            optname: i32,
+            optval: *const std::ffi::c_void,
+            optlen: u32,
+        ) -> i32;
+
+        /// http://man7.org/linux/man-pages/man2/getsockopt.2.html
+        pub fn getsockopt(
+            sockfd: i32,
+            level: i32,
+            optname: i32,
+            optval: *mut std::ffi::c_void,
+            optlen: *mut u32,
+        ) -> i32;
+
+        /// https://developer.apple.com/library/archive/documentation/System/Conceptual/ManPages_iPhoneOS/man2/fcntl.2.html
+        #[cfg(target_os = "macos")]
+        pub fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+
+        /// https://developer.apple.com/library/archive/documentation/System/Conceptual/ManPages_iPhoneOS/man3/if_nametoindex.3.html
+        #[cfg(target_os = "macos")]
+        pub fn if_nametoindex(ifname: *const std::ffi::c_char) -> u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn options_set_before_listening_stick() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let socket = TcpSocket::new_for_addr(addr).unwrap();
+        socket.set_reuseaddr(true).unwrap();
+        socket.set_recv_buffer_size(64 * 1024).unwrap();
+        assert!(socket.reuseaddr().unwrap());
+        assert!(!socket.reuseport().unwrap());
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        socket.bind(addr).unwrap();
+        let listener = socket.listen(16).unwrap();
+
+        let client = TcpSocket::new_v4().unwrap();
+        client.set_send_buffer_size(32 * 1024).unwrap();
+        let mut client = client.connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"ping").unwrap();
+
+        let (mut server, _) = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+    }
+
+    #[test]
+    fn reuseport_lets_two_listeners_share_a_port() {
+        let first = TcpSocket::new_v4().unwrap();
+        first.set_reuseport(true).unwrap();
+        first.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let first = first.listen(16).unwrap();
+
+        let second = TcpSocket::new_v4().unwrap();
+        second.set_reuseport(true).unwrap();
+        second.bind(first.local_addr().unwrap()).unwrap();
+        second.listen(16).unwrap();
+    }
+
+    #[test]
+    fn udp_options_are_set_before_binding() {
+        let builder = UdpSocketBuilder::new()
+            .reuseport(true)
+            .recv_buffer_size(256 * 1024);
+        let first = builder.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let second = builder.bind(first.local_addr().unwrap()).unwrap();
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+
+        let fd = second.as_raw_fd();
+        assert_eq!(1, get_int_option(fd, ffi::SO_REUSEPORT).unwrap());
+        assert_eq!(0, get_int_option(fd, ffi::SO_REUSEADDR).unwrap());
+        assert!(get_int_option(fd, ffi::SO_RCVBUF).unwrap() > 0);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn sockets_pinned_to_loopback_talk_through_it() {
+        let loopback = if cfg!(target_os = "macos") {
+            "lo0"
+        } else {
+            "lo"
+        };
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let socket = TcpSocket::new_for_addr(addr).unwrap();
+        socket.bind_device(Some(loopback)).unwrap();
+        socket.bind(addr).unwrap();
+        let listener = socket.listen(16).unwrap();
+        let client = TcpSocket::new_v4().unwrap();
+        client.bind_device(Some(loopback)).unwrap();
+        client.connect(listener.local_addr().unwrap()).unwrap();
+
+        let udp = UdpSocketBuilder::new()
+            .bind_device(loopback)
+            .bind(addr)
+            .unwrap();
+        assert!(udp.local_addr().unwrap().port() != 0);
+    }
+
+    #[test]
+    fn pinning_to_a_missing_interface_fails() {
+        let socket = TcpSocket::new_v4().unwrap();
+        assert!(socket.bind_device(Some("minimio-none0")).is_err());
+        socket.bind_device(None).unwrap();
+    }
+
+    #[test]
+    fn only_v6_is_what_was_chosen_before_binding() {
+        let addr = "[::1]:0".parse().unwrap();
+        let socket = match TcpSocket::new_for_addr(addr) {
+            Ok(socket) => socket,
+            // No IPv6 on this host
+            Err(_) => return,
+        };
+        socket.set_only_v6(false).unwrap();
+        assert!(!socket.only_v6().unwrap());
+        socket.set_only_v6(true).unwrap();
+        assert!(socket.only_v6().unwrap());
+        if socket.bind(addr).is_err() {
+            return;
+        }
+        assert!(socket.listen(16).unwrap().only_v6().unwrap());
+
+        let udp = UdpSocketBuilder::new().only_v6(true).bind(addr).unwrap();
+        assert!(udp.only_v6().unwrap());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn user_timeout_reads_back_what_was_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert_eq!(None, stream.user_timeout().unwrap());
+        stream
+            .set_user_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            stream.user_timeout().unwrap()
+        );
+        stream.set_user_timeout(None).unwrap();
+        assert_eq!(None, stream.user_timeout().unwrap());
+    }
+}
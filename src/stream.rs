@@ -0,0 +1,84 @@
+//! Adapts a `Poll` to a `futures::Stream` of readiness events so they can be consumed
+//! with stream combinators.
+use crate::{Event, Events, Poll, Registrator};
+use futures_core::Stream;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll as TaskPoll};
+
+/// How many events we ask the selector for each time our buffer runs dry
+const EVENTS_CAPACITY: usize = 64;
+
+/// A `Stream` yielding the events from a `Poll` one at a time.
+///
+/// The selector is polled with a zero timeout so `poll_next` never blocks the
+/// thread. When there are no events we wake the task right away to be polled again,
+/// which means the stream keeps the executor busy while it waits. The stream ends
+/// when the selector returns an error, for example when `close_loop` is called on
+/// one of its `Registrator`s. The error can be retrieved with `take_error`.
+#[derive(Debug)]
+pub struct EventStream {
+    poll: Poll,
+    events: Events,
+    pending: VecDeque<Event>,
+    error: Option<io::Error>,
+}
+
+impl EventStream {
+    pub fn new(poll: Poll) -> EventStream {
+        EventStream {
+            poll,
+            events: Events::with_capacity(EVENTS_CAPACITY),
+            pending: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// Returns a `Registrator` tied to the `Poll` this stream reads from.
+    pub fn registrator(&self) -> Registrator {
+        self.poll.registrator()
+    }
+
+    /// Returns the error that ended the stream, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Returns the `Poll`. Events the stream has read but not yielded yet are lost.
+    pub fn into_inner(self) -> Poll {
+        self.poll
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> TaskPoll<Option<Event>> {
+        let this = self.get_mut();
+        if let Some(event) = this.pending.pop_front() {
+            return TaskPoll::Ready(Some(event));
+        }
+
+        if this.error.is_some() {
+            return TaskPoll::Ready(None);
+        }
+
+        match this.poll.poll(&mut this.events, Some(0)) {
+            Ok(_) => this.pending.extend(this.events.drain(..)),
+            Err(e) => {
+                debug!("event stream ended: {}", e);
+                this.error = Some(e);
+                return TaskPoll::Ready(None);
+            }
+        }
+
+        match this.pending.pop_front() {
+            Some(event) => TaskPoll::Ready(Some(event)),
+            None => {
+                cx.waker().wake_by_ref();
+                TaskPoll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Counting the system calls a selector and its registrators make, so the effect of
+//! batching registrations or polling with a larger `Events` buffer can be measured
+//! rather than guessed at.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How many system calls a selector and its registrators have made, by what they were
+/// made for. Returned from `Selector::syscall_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SyscallStats {
+    /// Waiting for events: `epoll_wait`, `kevent`, `GetQueuedCompletionStatusEx`,
+    /// `WSAPoll` or `select`.
+    pub waits: u64,
+    /// Changing what's registered: `epoll_ctl`, `kevent` without waiting, or
+    /// associating a socket with and posting to the completion port. Always 0 with
+    /// `select`, which keeps nothing in the kernel.
+    pub changes: u64,
+    /// Starting overlapped I/O with `WSARecv` or `WSARecvFrom`. Always 0 on Linux,
+    /// macOS and with the `wsapoll` feature, where there's nothing to start.
+    pub submissions: u64,
+    /// Waking up the polling thread to apply deferred registrations, or with
+    /// `select`, to wait on a new registration.
+    pub wakeups: u64,
+}
+
+/// The counters behind `SyscallStats`, shared between a selector and its registrators
+#[derive(Debug, Default)]
+pub(crate) struct SyscallCounters {
+    waits: AtomicU64,
+    changes: AtomicU64,
+    submissions: AtomicU64,
+    wakeups: AtomicU64,
+}
+
+// The counters are independent of each other and of everything else, so relaxed
+// ordering is all they need
+impl SyscallCounters {
+    pub(crate) fn wait(&self) {
+        self.waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(not(any(target_os = "haiku", target_os = "nto")))]
+    pub(crate) fn change(&self) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+    pub(crate) fn submission(&self) {
+        self.submissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto",
+        all(target_os = "windows", feature = "wsapoll")
+    ))]
+    pub(crate) fn wakeup(&self) {
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> SyscallStats {
+        SyscallStats {
+            waits: self.waits.load(Ordering::Relaxed),
+            changes: self.changes.load(Ordering::Relaxed),
+            submissions: self.submissions.load(Ordering::Relaxed),
+            wakeups: self.wakeups.load(Ordering::Relaxed),
+        }
+    }
+}
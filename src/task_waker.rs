@@ -0,0 +1,81 @@
+//! Waking a `std::task::Waker` straight from `poll` when a source is ready, for
+//! futures and executors of one's own that don't go through `EventStream` or
+//! `executor`. The waker is stored as the data of the registration, so it gets a
+//! token like `Registry::register_with_data` hands out, and its events are consumed
+//! by `poll` rather than returned.
+use crate::registrable::Registrable;
+use crate::{Events, Interests, Poll, Registry, Token};
+use std::io;
+use std::task;
+
+/// The data of a registration whose events wake a task
+struct TaskWaker(task::Waker);
+
+impl Registry {
+    /// Registers `source` to have `waker` woken by `poll` when it's ready for
+    /// `interests`, instead of returning an event for it. Returns the token it was
+    /// registered with, which `reregister_waker` and `remove_waker` take.
+    ///
+    /// Like every registration it's oneshot: a future that finds the source isn't
+    /// ready after all registers it again with `reregister_waker`.
+    pub fn register_waker(
+        &self,
+        source: &mut impl Registrable,
+        interests: Interests,
+        waker: task::Waker,
+    ) -> io::Result<Token> {
+        self.register_with_data(source, interests, TaskWaker(waker))
+    }
+
+    /// Registers `source` again with the `token` `register_waker` gave it, to wake
+    /// `waker`, which is usually the one of the task polling it now. Fails with
+    /// `NotFound` if `token` wasn't registered with a waker.
+    pub fn reregister_waker(
+        &self,
+        source: &mut impl Registrable,
+        token: Token,
+        interests: Interests,
+        waker: task::Waker,
+    ) -> io::Result<()> {
+        self.replace_waker(token, waker)?;
+        source.register(&self.registrator(), token, interests)
+    }
+
+    /// Takes the waker of `token` back out, once its source has been deregistered or
+    /// is about to be closed. The token can be handed out again after this.
+    pub fn remove_waker(&self, token: Token) -> Option<task::Waker> {
+        self.remove_data::<TaskWaker>(token).map(|waker| waker.0)
+    }
+
+    fn replace_waker(&self, token: Token, waker: task::Waker) -> io::Result<()> {
+        self.with_data(token, |stored: &mut TaskWaker| stored.0 = waker)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The token wasn't registered with a waker.",
+                )
+            })
+    }
+}
+
+impl Poll {
+    /// Wakes the wakers of the events' tokens, and drops their events.
+    pub(crate) fn wake_tasks(&mut self, events: &mut Events) {
+        let data = self
+            .registry
+            .data
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        if data.is_empty() {
+            return;
+        }
+        events.retain(|event| match data.get_mut::<TaskWaker>(event.id()) {
+            Some(waker) => {
+                trace!("waking the task waiting for token {}", event.id());
+                waker.0.wake_by_ref();
+                false
+            }
+            None => true,
+        });
+    }
+}
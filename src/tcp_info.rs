@@ -0,0 +1,93 @@
+//! What the kernel knows about a TCP connection: its round trip time, how much it's
+//! had to retransmit and how much it may have in flight. Monitoring can export it
+//! per connection, and a sender can pace itself by it.
+use crate::TcpStream;
+use std::io;
+use std::time::Duration;
+
+#[cfg(target_os = "aix")]
+use crate::aix::tcp_info as sys_tcp_info;
+#[cfg(target_os = "fuchsia")]
+use crate::fuchsia::tcp_info as sys_tcp_info;
+#[cfg(any(target_os = "haiku", target_os = "nto"))]
+use crate::haiku::tcp_info as sys_tcp_info;
+#[cfg(target_os = "linux")]
+use crate::linux::tcp_info as sys_tcp_info;
+#[cfg(any(target_os = "macos", target_os = "dragonfly"))]
+use crate::macos::tcp_info as sys_tcp_info;
+#[cfg(target_os = "redox")]
+use crate::redox::tcp_info as sys_tcp_info;
+#[cfg(all(target_os = "windows", not(feature = "wsapoll")))]
+use crate::windows::tcp_info as sys_tcp_info;
+#[cfg(all(target_os = "windows", feature = "wsapoll"))]
+use crate::wsapoll::tcp_info as sys_tcp_info;
+
+/// Statistics of a connection, returned from `TcpStream::tcp_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TcpInfo {
+    pub state: TcpState,
+    /// The smoothed round trip time. In milliseconds on macOS, and microseconds
+    /// elsewhere.
+    pub rtt: Duration,
+    /// The segments retransmitted over the life of the connection. Windows only
+    /// counts fast retransmits and timeouts, which can each stand for several.
+    pub retransmits: u64,
+    /// How many bytes can be sent before waiting for an acknowledgement.
+    pub congestion_window: u64,
+    /// The largest segment the connection sends.
+    pub mss: u32,
+}
+
+/// The state of a TCP connection, as in RFC 793.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    /// A state this version doesn't know, with the OS's number for it
+    Unknown(u8),
+}
+
+impl TcpStream {
+    /// Asks the kernel for the statistics of the connection. That's `TCP_INFO` on
+    /// Linux, `TCP_CONNECTION_INFO` on macOS and `SIO_TCP_INFO` on Windows, which needs
+    /// Windows 10 1703 or later. DragonFly, Redox and AIX have none of them, and it
+    /// isn't read on Fuchsia or by the `wsapoll` backend, so it fails with
+    /// `Unsupported` there.
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        sys_tcp_info(self)
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+    use super::*;
+    use crate::TcpListener;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn info_of_an_established_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        peer.read_exact(&mut buf).unwrap();
+
+        let info = stream.tcp_info().unwrap();
+        assert_eq!(TcpState::Established, info.state);
+        assert!(info.mss > 0);
+        assert!(info.congestion_window >= info.mss as u64);
+        assert!(info.rtt < Duration::from_secs(1));
+    }
+}
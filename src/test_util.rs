@@ -0,0 +1,103 @@
+//! Helpers for tests that need something to connect to. Everything listens on an
+//! ephemeral port on localhost, so the tests work offline and don't depend on
+//! anyone else's server staying up.
+//!
+//! This is public so the integration tests in `tests/` can use it, but it's not
+//! part of the API.
+use crate::registrable::Registrable;
+use crate::{Interests, Registrator, Token};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A request the responders accept. It asks the server to close the connection
+/// after responding, so reading the response to the end terminates.
+pub const HTTP_REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+/// What `delayed_responder` sends back.
+pub const HTTP_RESPONSE: &[u8] =
+    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nHello";
+
+/// Starts a server that echoes back everything sent to it until the client closes
+/// the connection. Returns the address it listens on.
+pub fn echo_server() -> SocketAddr {
+    serve(|mut stream| {
+        let mut buf = [0u8; 1024];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if stream.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Starts a server that reads a request (everything up to an empty line), waits
+/// for `delay`, then writes `HTTP_RESPONSE` and closes the connection. Stands in
+/// for a slow remote server so we get to wait for the response.
+pub fn delayed_responder(delay: Duration) -> SocketAddr {
+    serve(move |mut stream| {
+        if read_request(&mut stream).is_err() {
+            return;
+        }
+        thread::sleep(delay);
+        let _ = stream.write_all(HTTP_RESPONSE);
+    })
+}
+
+/// A connected stream of ours, and the std stream of its peer.
+pub fn tcp_pair() -> (crate::TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("binding test listener");
+    let addr = listener.local_addr().expect("test listener address");
+    let stream = crate::TcpStream::connect(addr).expect("connecting to test listener");
+    let (peer, _) = listener.accept().expect("accepting test connection");
+    (stream, peer)
+}
+
+/// Registers `source` with `registrator`. Tests that run everywhere have to pass
+/// `&mut`, which the Windows `Registrator` takes, and clippy complains about that
+/// wherever the `Registrator` only takes `&`. Registering through this keeps it quiet.
+pub fn register(
+    registrator: &Registrator,
+    source: &mut impl Registrable,
+    token: Token,
+    interests: Interests,
+) -> io::Result<()> {
+    source.register(registrator, token, interests)
+}
+
+/// Accepts connections on a background thread forever, handling each one on a
+/// thread of its own. The thread is never joined; it dies with the test process.
+fn serve(handler: impl Fn(TcpStream) + Send + Sync + Copy + 'static) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("binding test listener");
+    let addr = listener.local_addr().expect("test listener address");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || handler(stream));
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    addr
+}
+
+fn read_request(stream: &mut TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(())
+}
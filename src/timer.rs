@@ -0,0 +1,545 @@
+//! Timers that don't need a kernel object each.
+//!
+//! A `TimerWheel` keeps track of any number of timers and tells us how long we can
+//! wait before the next one is due, which is what we pass as the timeout to
+//! `Poll::poll`. That way all timers share the single deadline the selector already
+//! has. After polling, `advance` hands us the tokens of the timers that expired.
+//!
+//! The wheel is hierarchical: level 0 has 64 slots of one tick each, level 1 has 64
+//! slots of 64 ticks each and so on. A timer is put in the lowest level that covers
+//! its deadline and moves down a level each time the wheel gets close enough to it,
+//! so inserting, cancelling and expiring a timer are all O(1).
+//! Reference: https://www.cs.columbia.edu/~nahum/w6998/papers/ton97-timing-wheels.pdf
+//!
+//! The wheel reads the time from a `Clock`, the system's monotonic one unless it's
+//! created `with_clock`. The methods that take `now` should be given the time of
+//! that same clock.
+use crate::clock::ceil_millis;
+use crate::{Clock, MonotonicClock, Token};
+use std::time::{Duration, Instant};
+
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const SLOT_MASK: u64 = (SLOTS - 1) as u64;
+const NUM_LEVELS: usize = 6;
+/// The furthest into the future (in ticks) a timer can be. Timers further out than
+/// that fire at this point instead. With 1 ms ticks it's a bit more than two years.
+const MAX_TICKS: u64 = (1 << (LEVEL_BITS * NUM_LEVELS as u32)) - 1;
+
+/// Identifies a timer in a `TimerWheel`, so it can be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerKey {
+    index: usize,
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The tick the timer expires at
+    when: u64,
+    token: Token,
+    generation: u64,
+    /// The period in ticks of an interval timer
+    period: Option<u64>,
+    /// Bumped every time `reset` moves the timer to an earlier slot, which leaves
+    /// a stale copy of its key behind in the slot it was in
+    stamp: u64,
+}
+
+/// A key in one of the slots, along with the `stamp` of the entry when it was put
+/// there. Keys whose stamp no longer matches the entry are stale and skipped.
+type Slotted = (TimerKey, u64);
+
+#[derive(Debug)]
+struct Level {
+    level: usize,
+    /// Bit `n` is set if slot `n` has any timers in it
+    occupied: u64,
+    slots: Vec<Vec<Slotted>>,
+}
+
+impl Level {
+    fn new(level: usize) -> Self {
+        Level {
+            level,
+            occupied: 0,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// How many ticks one slot of this level covers
+    fn slot_range(&self) -> u64 {
+        1 << (LEVEL_BITS * self.level as u32)
+    }
+
+    /// How many ticks all the slots of this level cover together
+    fn level_range(&self) -> u64 {
+        self.slot_range() << LEVEL_BITS
+    }
+
+    fn slot_for(&self, when: u64) -> usize {
+        ((when >> (LEVEL_BITS * self.level as u32)) & SLOT_MASK) as usize
+    }
+
+    fn add(&mut self, when: u64, key: Slotted) {
+        let slot = self.slot_for(when);
+        self.slots[slot].push(key);
+        self.occupied |= 1 << slot;
+    }
+
+    fn take(&mut self, slot: usize) -> Vec<Slotted> {
+        self.occupied &= !(1 << slot);
+        std::mem::take(&mut self.slots[slot])
+    }
+
+    /// Returns the next occupied slot and the tick it starts at, looking from `now`.
+    fn next_expiration(&self, now: u64) -> Option<(usize, u64)> {
+        if self.occupied == 0 {
+            return None;
+        }
+
+        // `now` runs past `u32::MAX` ticks after 49 days of 1 ms ticks, so only
+        // the position within the level is narrowed
+        let now_slot = (now / self.slot_range()) % SLOTS as u64;
+        let distance = self.occupied.rotate_right(now_slot as u32).trailing_zeros();
+        let slot = (now_slot as usize + distance as usize) % SLOTS;
+
+        let level_start = now & !(self.level_range() - 1);
+        let mut deadline = level_start + slot as u64 * self.slot_range();
+        if deadline < now {
+            // The slot is behind us, so it's for the next round of this level
+            deadline += self.level_range();
+        }
+        Some((slot, deadline))
+    }
+}
+
+/// A hierarchical timer wheel. See the module documentation for how it's used.
+#[derive(Debug)]
+pub struct TimerWheel<C = MonotonicClock> {
+    clock: C,
+    start: Instant,
+    resolution: Duration,
+    /// How many ticks we've advanced since `start`
+    elapsed: u64,
+    levels: Vec<Level>,
+    entries: Vec<Option<Entry>>,
+    free: Vec<usize>,
+    next_generation: u64,
+    len: usize,
+}
+
+impl TimerWheel {
+    /// Creates a wheel where one tick is `resolution` long. Timers never fire early,
+    /// but can fire up to one tick late.
+    pub fn new(resolution: Duration) -> Self {
+        TimerWheel::with_clock(MonotonicClock, resolution)
+    }
+
+    #[cfg(test)]
+    fn starting_at(start: Instant, resolution: Duration) -> TimerWheel<crate::ManualClock> {
+        TimerWheel::with_clock(crate::ManualClock::starting_at(start), resolution)
+    }
+}
+
+impl<C: Clock> TimerWheel<C> {
+    /// Like `new`, but the wheel reads the time from `clock`.
+    pub fn with_clock(clock: C, resolution: Duration) -> Self {
+        assert!(
+            resolution > Duration::from_nanos(0),
+            "resolution must be larger than zero"
+        );
+        TimerWheel {
+            start: clock.now(),
+            clock,
+            resolution,
+            elapsed: 0,
+            levels: (0..NUM_LEVELS).map(Level::new).collect(),
+            entries: Vec::new(),
+            free: Vec::new(),
+            next_generation: 0,
+            len: 0,
+        }
+    }
+
+    /// Adds a timer that expires at `deadline`, reported with `token`.
+    pub fn insert(&mut self, deadline: Instant, token: Token) -> TimerKey {
+        self.insert_entry(deadline, token, None)
+    }
+
+    /// Adds a timer that expires after `timeout` from the clock's current time,
+    /// reported with `token`.
+    pub fn insert_after(&mut self, timeout: Duration, token: Token) -> TimerKey {
+        let deadline = self.clock.now() + timeout;
+        self.insert(deadline, token)
+    }
+
+    /// Adds a timer that first expires at `first_deadline` and then every `period`
+    /// after that until it's cancelled, reported with `token` every time. The key
+    /// stays valid for as long as the timer keeps going.
+    ///
+    /// Each deadline is computed from the previous deadline, not from when `advance`
+    /// noticed it, so being late doesn't make the ticks drift. If we're so late that
+    /// several ticks were missed, the timer fires once and continues with the next
+    /// tick that's still in the future rather than firing for each of them.
+    ///
+    /// The period is rounded up to whole ticks. Panics if it's zero.
+    pub fn insert_interval(
+        &mut self,
+        first_deadline: Instant,
+        period: Duration,
+        token: Token,
+    ) -> TimerKey {
+        assert!(
+            period > Duration::from_nanos(0),
+            "period must be larger than zero"
+        );
+        let ticks = period.as_nanos().div_ceil(self.resolution.as_nanos());
+        let ticks = ticks.clamp(1, u128::from(MAX_TICKS)) as u64;
+        self.insert_entry(first_deadline, token, Some(ticks))
+    }
+
+    fn insert_entry(&mut self, deadline: Instant, token: Token, period: Option<u64>) -> TimerKey {
+        let when = self.ticks_until(deadline, true).max(self.elapsed);
+        let when = when.min(self.elapsed + MAX_TICKS);
+
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        let entry = Entry {
+            when,
+            token,
+            generation,
+            period,
+            stamp: 0,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.entries[index] = Some(entry);
+                index
+            }
+            None => {
+                self.entries.push(Some(entry));
+                self.entries.len() - 1
+            }
+        };
+
+        let key = TimerKey { index, generation };
+        self.schedule(when, (key, 0));
+        self.len += 1;
+        key
+    }
+
+    /// Cancels a timer. Returns false if it had already expired or been cancelled.
+    ///
+    /// Once this returns true the timer's token is never reported by `advance` again,
+    /// not even by an interval timer. A timer that expired in an earlier `advance`
+    /// can't be cancelled, so its token might already be in the caller's list of
+    /// expired timers when this returns false.
+    pub fn cancel(&mut self, key: TimerKey) -> bool {
+        match self.entries.get(key.index) {
+            Some(Some(entry)) if entry.generation == key.generation => (),
+            _ => return false,
+        }
+        // The key stays in its slot until the wheel gets there, where it's skipped
+        self.remove(key.index);
+        true
+    }
+
+    /// Moves a timer to expire at `deadline` instead, keeping its key. For an interval
+    /// timer this is the next tick, with the ones after it following every period from
+    /// there. Returns false if the timer had already expired or been cancelled, with
+    /// the same guarantees as `cancel`.
+    ///
+    /// This is meant to be cheap enough to call on every read of a connection to push
+    /// its idle timeout forward: moving a timer later only updates its deadline, and
+    /// the wheel moves it along when it reaches the slot it was in.
+    pub fn reset(&mut self, key: TimerKey, deadline: Instant) -> bool {
+        let when = self.ticks_until(deadline, true).max(self.elapsed);
+        let when = when.min(self.elapsed + MAX_TICKS);
+
+        let entry = match self.entries.get_mut(key.index) {
+            Some(Some(entry)) if entry.generation == key.generation => entry,
+            _ => return false,
+        };
+        let earlier = when < entry.when;
+        entry.when = when;
+        if earlier {
+            // Its slot is too late now, so it goes in a new one. The copy that's left
+            // behind is skipped because its stamp is out of date.
+            entry.stamp += 1;
+            let stamp = entry.stamp;
+            self.schedule(when, (key, stamp));
+        }
+        true
+    }
+
+    /// The clock the wheel reads the time from.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Returns how many timers are waiting to expire.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns how long we can wait before the wheel needs to `advance`, or `None`
+    /// if there are no timers. The wheel might need to advance a bit before the next
+    /// timer is due to move timers down a level, which never fires anything early.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        let (_, _, deadline) = self.next_expiration()?;
+        let nanos = self.resolution.as_nanos() * u128::from(deadline);
+        let deadline = self.start + Duration::from_nanos(nanos.min(u64::MAX as u128) as u64);
+        Some(deadline.saturating_duration_since(now))
+    }
+
+    /// Like `next_timeout`, but in the format `Poll::poll` takes: whole milliseconds,
+    /// rounded up so we don't wake up before the deadline.
+    pub fn poll_timeout(&self, now: Instant) -> Option<i32> {
+        self.next_timeout(now).map(ceil_millis)
+    }
+
+    /// Moves the wheel forward to `now` and appends the tokens of all timers that
+    /// expired on the way to `expired`, earliest first.
+    pub fn advance(&mut self, now: Instant, expired: &mut Vec<Token>) {
+        let now = self.ticks_until(now, false);
+        while let Some((level, slot, deadline)) = self.next_expiration() {
+            if deadline > now {
+                break;
+            }
+            self.elapsed = deadline;
+
+            for (key, stamp) in self.levels[level].take(slot) {
+                let (when, period) = match &self.entries[key.index] {
+                    Some(entry) if entry.generation == key.generation && entry.stamp == stamp => {
+                        (entry.when, entry.period)
+                    }
+                    // Cancelled, or moved to an earlier slot by `reset`
+                    _ => continue,
+                };
+                if when > self.elapsed {
+                    // We're close enough now to move it down a level
+                    self.schedule(when, (key, stamp));
+                } else if let Some(period) = period {
+                    // Skip the ticks we've already missed, so we fire once per
+                    // `advance` at most
+                    let missed = now.saturating_sub(when) / period;
+                    let next = when + (missed + 1) * period;
+                    let entry = self.entries[key.index]
+                        .as_mut()
+                        .expect("timer entry exists");
+                    entry.when = next;
+                    expired.push(entry.token);
+                    self.schedule(next, (key, stamp));
+                } else {
+                    expired.push(self.remove(key.index).token);
+                }
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+    }
+
+    /// Returns the level, slot and tick of the next slot we need to process. The
+    /// lowest level with a timer in it always has the earliest one.
+    fn next_expiration(&self) -> Option<(usize, usize, u64)> {
+        self.levels.iter().find_map(|level| {
+            level
+                .next_expiration(self.elapsed)
+                .map(|(slot, deadline)| (level.level, slot, deadline))
+        })
+    }
+
+    fn schedule(&mut self, when: u64, key: Slotted) {
+        // The highest bit where `when` differs from `elapsed` decides how far up the
+        // hierarchy the timer needs to go
+        let masked = ((self.elapsed ^ when) | SLOT_MASK).min(MAX_TICKS);
+        let significant = 63 - masked.leading_zeros();
+        let level = (significant / LEVEL_BITS) as usize;
+        self.levels[level].add(when, key);
+    }
+
+    fn remove(&mut self, index: usize) -> Entry {
+        let entry = self.entries[index].take().expect("timer entry exists");
+        self.free.push(index);
+        self.len -= 1;
+        entry
+    }
+
+    fn ticks_until(&self, instant: Instant, round_up: bool) -> u64 {
+        let since_start = instant.saturating_duration_since(self.start);
+        let resolution = self.resolution.as_nanos();
+        let nanos = since_start.as_nanos();
+        let ticks = if round_up {
+            nanos.div_ceil(resolution)
+        } else {
+            nanos / resolution
+        };
+        ticks.min(u64::MAX as u128) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn next_expiration_past_u32_max_ticks() {
+        let key = TimerKey {
+            index: 0,
+            generation: 0,
+        };
+        let now = u64::from(u32::MAX);
+        let mut level = Level::new(0);
+        level.add(now + 3, (key, 0));
+        assert_eq!(Some((2, now + 3)), level.next_expiration(now));
+    }
+
+    #[test]
+    fn timers_expire_in_order() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start, ms(1));
+        wheel.insert(start + ms(30), 3);
+        wheel.insert(start + ms(10), 1);
+        wheel.insert(start + ms(20), 2);
+        assert_eq!(3, wheel.len());
+
+        let mut expired = vec![];
+        wheel.advance(start + ms(9), &mut expired);
+        assert!(expired.is_empty());
+
+        wheel.advance(start + ms(25), &mut expired);
+        assert_eq!(vec![1, 2], expired);
+
+        wheel.advance(start + ms(30), &mut expired);
+        assert_eq!(vec![1, 2, 3], expired);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn far_away_timers_move_down_the_levels() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start, ms(1));
+        let deadlines = [5, 64, 65, 4_095, 4_096, 300_000, 10_000_000];
+        for (token, &deadline) in deadlines.iter().enumerate() {
+            wheel.insert(start + ms(deadline), token);
+        }
+
+        let mut expired = vec![];
+        for (token, &deadline) in deadlines.iter().enumerate() {
+            wheel.advance(start + ms(deadline - 1), &mut expired);
+            assert_eq!(token, expired.len(), "timer {} fired early", token);
+            wheel.advance(start + ms(deadline), &mut expired);
+            assert_eq!(token, expired[token]);
+        }
+    }
+
+    #[test]
+    fn cancelled_timers_dont_fire() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start, ms(1));
+        let key = wheel.insert(start + ms(10), 1);
+        assert!(wheel.cancel(key));
+        assert!(!wheel.cancel(key));
+
+        // The freed entry is reused, which mustn't make the old key valid again
+        let other = wheel.insert(start + ms(10), 2);
+        assert!(!wheel.cancel(key));
+
+        let mut expired = vec![];
+        wheel.advance(start + ms(10), &mut expired);
+        assert_eq!(vec![2], expired);
+        assert!(!wheel.cancel(other));
+    }
+
+    #[test]
+    fn next_timeout_points_at_the_earliest_timer() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start, ms(1));
+        assert_eq!(None, wheel.next_timeout(start));
+
+        wheel.insert(start + ms(40), 1);
+        wheel.insert(start + ms(15), 2);
+        assert_eq!(Some(ms(15)), wheel.next_timeout(start));
+        assert_eq!(Some(5), wheel.poll_timeout(start + ms(10)));
+        assert_eq!(Some(0), wheel.poll_timeout(start + ms(20)));
+    }
+
+    #[test]
+    fn interval_timers_dont_drift() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start, ms(1));
+        let key = wheel.insert_interval(start + ms(10), ms(10), 1);
+
+        let mut expired = vec![];
+        // Being a bit late for a tick doesn't push the following ones back
+        wheel.advance(start + ms(13), &mut expired);
+        assert_eq!(vec![1], expired);
+        assert_eq!(Some(ms(7)), wheel.next_timeout(start + ms(13)));
+
+        wheel.advance(start + ms(20), &mut expired);
+        assert_eq!(vec![1, 1], expired);
+
+        // Missed ticks are skipped instead of all firing at once
+        expired.clear();
+        wheel.advance(start + ms(55), &mut expired);
+        assert_eq!(vec![1], expired);
+        assert_eq!(Some(ms(5)), wheel.next_timeout(start + ms(55)));
+
+        assert!(wheel.cancel(key));
+        wheel.advance(start + ms(100), &mut expired);
+        assert_eq!(vec![1], expired);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn timeouts_are_relative_to_the_wheels_clock() {
+        let clock = crate::ManualClock::new();
+        let mut wheel = TimerWheel::with_clock(clock.clone(), ms(1));
+        wheel.insert_after(ms(10), 1);
+        assert_eq!(Some(ms(10)), wheel.next_timeout(clock.now()));
+
+        let mut expired = vec![];
+        clock.advance(ms(9));
+        wheel.advance(wheel.clock().now(), &mut expired);
+        assert!(expired.is_empty());
+        wheel.insert_after(ms(10), 2);
+
+        clock.advance(ms(1));
+        wheel.advance(clock.now(), &mut expired);
+        assert_eq!(vec![1], expired);
+        assert_eq!(Some(ms(9)), wheel.next_timeout(clock.now()));
+    }
+
+    #[test]
+    fn reset_moves_timers_both_ways() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start, ms(1));
+        let later = wheel.insert(start + ms(10), 1);
+        let earlier = wheel.insert(start + ms(5_000), 2);
+
+        assert!(wheel.reset(later, start + ms(100)));
+        assert!(wheel.reset(earlier, start + ms(50)));
+
+        let mut expired = vec![];
+        wheel.advance(start + ms(49), &mut expired);
+        assert!(expired.is_empty());
+        wheel.advance(start + ms(50), &mut expired);
+        assert_eq!(vec![2], expired);
+        wheel.advance(start + ms(100), &mut expired);
+        assert_eq!(vec![2, 1], expired);
+
+        // The stale copy of the timer we moved earlier mustn't fire again
+        wheel.advance(start + ms(10_000), &mut expired);
+        assert_eq!(vec![2, 1], expired);
+        assert!(!wheel.reset(later, start + ms(20_000)));
+        assert!(wheel.is_empty());
+    }
+}
@@ -0,0 +1,583 @@
+//! Sources that work the same way on every Unix platform, no matter which kernel
+//! event queue the `Selector` is built on.
+#[cfg(not(any(target_os = "haiku", target_os = "nto")))]
+use crate::Interests;
+use crate::{TcpStream, Token};
+use std::io::{self, IoSliceMut, Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net;
+use std::path::Path;
+
+/// Anything backed by a file descriptor that can be registered with a `Registrator`.
+/// Every `AsFd` type is one, and since registering borrows the fd, an fd can't be
+/// closed while it's being registered.
+pub trait Source: AsFd {}
+
+impl<T: AsFd + ?Sized> Source for T {}
+
+/// Registers types that only implement `AsRawFd`, like ones from crates written
+/// before `AsFd` existed:
+///
+/// ```no_run
+/// # use minimio::{Interests, Poll, SourceFd};
+/// # fn device() -> std::fs::File { unimplemented!() }
+/// let poll = Poll::new()?;
+/// let device = device();
+/// // `device` owns its fd
+/// let source = unsafe { SourceFd::new(&device) };
+/// poll.registrator().register(&source, 1, Interests::READABLE)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// It borrows the value the fd comes from, so the fd can't be closed while it's
+/// being registered.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceFd<'a>(BorrowedFd<'a>);
+
+impl<'a> SourceFd<'a> {
+    /// # Safety
+    ///
+    /// `source` must own the fd it returns from `as_raw_fd`, or otherwise keep it
+    /// open for as long as it's borrowed, and return the same fd every time. A bare
+    /// `RawFd` implements `AsRawFd` too, but doesn't own anything.
+    pub unsafe fn new<T: AsRawFd + ?Sized>(source: &'a T) -> Self {
+        SourceFd(BorrowedFd::borrow_raw(source.as_raw_fd()))
+    }
+}
+
+impl AsFd for SourceFd<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0
+    }
+}
+
+/// A file descriptor we don't own, like one handed to us from C, or one we only
+/// kept the number of.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RawSource(pub RawFd);
+
+impl AsRawFd for RawSource {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl AsFd for RawSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // Whoever made the `RawSource` is responsible for the fd being open while
+        // it's used
+        unsafe { BorrowedFd::borrow_raw(self.0) }
+    }
+}
+
+/// A registration made with `Registrator::register_deferred`, waiting for the polling
+/// thread to apply it at the start of its next `select`. Haiku and QNX register right
+/// away, there's nothing to defer with `select(2)`.
+#[cfg(not(any(target_os = "haiku", target_os = "nto")))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Change {
+    pub fd: RawFd,
+    pub token: Token,
+    pub interests: Interests,
+}
+
+/// The token the selectors use internally to wake themselves up when there are
+/// deferred registrations to apply. It can't be used for registrations.
+pub(crate) const KICK_TOKEN: Token = usize::MAX;
+
+/// Returns an error if `token` is reserved for internal use.
+pub(crate) fn validate_token(token: Token) -> io::Result<()> {
+    if token == KICK_TOKEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Token usize::MAX is reserved.",
+        ));
+    }
+    Ok(())
+}
+
+/// Creates a pair of connected, non-blocking Unix domain sockets. Handy as an
+/// in-process wakeup channel, or as both ends of a connection in tests.
+pub fn socket_pair() -> io::Result<(UnixStream, UnixStream)> {
+    let (a, b) = net::UnixStream::pair()?;
+    Ok((UnixStream::from_std(a)?, UnixStream::from_std(b)?))
+}
+
+/// A non-blocking Unix domain stream socket. Unlike `TcpStream`, reading from it
+/// when no data is available returns an error of kind `WouldBlock` instead of
+/// blocking, so the right thing to do is to wait for a new event.
+#[derive(Debug)]
+pub struct UnixStream {
+    inner: net::UnixStream,
+}
+
+impl UnixStream {
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        // Like `TcpStream::connect` this blocks while the connection is established
+        UnixStream::from_std(net::UnixStream::connect(path)?)
+    }
+
+    fn from_std(stream: net::UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(UnixStream { inner: stream })
+    }
+
+    /// The credentials of the process on the other end, as they were when it
+    /// connected (or created the pair). The kernel vouches for them, so they can be
+    /// used to decide what a local client is allowed to do.
+    #[cfg(any(target_os = "linux", target_os = "redox", target_os = "haiku"))]
+    pub fn peer_cred(&self) -> io::Result<PeerCred> {
+        let mut cred = ffi::Ucred::default();
+        let mut len = std::mem::size_of::<ffi::Ucred>() as u32;
+        let res = unsafe {
+            ffi::getsockopt(
+                self.as_raw_fd(),
+                ffi::SOL_SOCKET,
+                ffi::SO_PEERCRED,
+                &mut cred as *mut ffi::Ucred as *mut std::ffi::c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCred {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: Some(cred.pid as u32),
+        })
+    }
+
+    /// The credentials of the process on the other end, as they were when it
+    /// connected (or created the pair). The kernel vouches for them, so they can be
+    /// used to decide what a local client is allowed to do.
+    #[cfg(target_os = "macos")]
+    pub fn peer_cred(&self) -> io::Result<PeerCred> {
+        let fd = self.as_raw_fd();
+        let (mut uid, mut gid) = (0, 0);
+        if unsafe { ffi::getpeereid(fd, &mut uid, &mut gid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Only newer versions of macOS know `LOCAL_PEERPID`, so failing to get the pid
+        // isn't an error
+        let mut pid = 0i32;
+        let mut len = std::mem::size_of::<i32>() as u32;
+        let res = unsafe {
+            ffi::getsockopt(
+                fd,
+                ffi::SOL_LOCAL,
+                ffi::LOCAL_PEERPID,
+                &mut pid as *mut i32 as *mut std::ffi::c_void,
+                &mut len,
+            )
+        };
+        Ok(PeerCred {
+            uid,
+            gid,
+            pid: if res < 0 { None } else { Some(pid as u32) },
+        })
+    }
+
+    /// The credentials of the process on the other end, as they were when it
+    /// connected (or created the pair). DragonFly, AIX and QNX don't record its pid.
+    #[cfg(any(target_os = "dragonfly", target_os = "aix", target_os = "nto"))]
+    pub fn peer_cred(&self) -> io::Result<PeerCred> {
+        let (mut uid, mut gid) = (0, 0);
+        if unsafe { ffi::getpeereid(self.as_raw_fd(), &mut uid, &mut gid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCred {
+            uid,
+            gid,
+            pid: None,
+        })
+    }
+
+    /// Fuchsia has no user or group ids, so this returns an error of kind
+    /// `Unsupported`.
+    #[cfg(target_os = "fuchsia")]
+    pub fn peer_cred(&self) -> io::Result<PeerCred> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Fuchsia has no peer credentials.",
+        ))
+    }
+}
+
+/// Who is on the other end of a `UnixStream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    /// `None` if the OS doesn't tell
+    pub pid: Option<u32>,
+}
+
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        (&self.inner).read_vectored(bufs)
+    }
+}
+
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for UnixStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+/// A non-blocking TCP listener. Register it for `READABLE` interest to be told when
+/// there are connections waiting to be accepted.
+#[derive(Debug)]
+pub struct TcpListener {
+    inner: std::net::TcpListener,
+}
+
+impl TcpListener {
+    pub fn bind(addr: impl std::net::ToSocketAddrs) -> io::Result<Self> {
+        TcpListener::from_std(std::net::TcpListener::bind(addr)?)
+    }
+
+    /// Wraps a listener from the standard library, setting it to non-blocking.
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(TcpListener { inner: listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Whether a listener bound to an IPv6 address only accepts IPv6 connections.
+    /// It's set before binding, with `TcpSocket::set_only_v6`.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+    pub fn only_v6(&self) -> io::Result<bool> {
+        crate::socket::only_v6(self.as_raw_fd())
+    }
+
+    /// Accepts a connection. Returns an error of kind `WouldBlock` if there are none
+    /// waiting.
+    pub fn accept(&self) -> io::Result<(TcpStream, std::net::SocketAddr)> {
+        let (stream, addr) = self.inner.accept()?;
+        Ok((TcpStream::from_std(stream)?, addr))
+    }
+
+    /// Accepts up to `max` connections and appends them to `accepted`, stopping early
+    /// when there are no more waiting. Returns how many were accepted. One readiness
+    /// event can stand for dozens of queued connections, so this takes care of all
+    /// of them before registering the listener again.
+    ///
+    /// An error is only returned if no connection was accepted. If it was caused by
+    /// something like running out of file descriptors, the next call returns it.
+    pub fn accept_batch(
+        &self,
+        accepted: &mut Vec<(TcpStream, std::net::SocketAddr)>,
+        max: usize,
+    ) -> io::Result<usize> {
+        let mut n = 0;
+        while n < max {
+            match self.accept() {
+                Ok(connection) => {
+                    accepted.push(connection);
+                    n += 1;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if n == 0 => return Err(e),
+                Err(e) => {
+                    debug!("accept failed after accepting {} connections: {}", n, e);
+                    break;
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for TcpListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+/// A non-blocking UDP socket. Sending or receiving when the socket isn't ready returns
+/// an error of kind `WouldBlock`.
+#[derive(Debug)]
+pub struct UdpSocket {
+    inner: std::net::UdpSocket,
+}
+
+impl UdpSocket {
+    pub fn bind(addr: impl std::net::ToSocketAddrs) -> io::Result<Self> {
+        UdpSocket::from_std(std::net::UdpSocket::bind(addr)?)
+    }
+
+    /// Wraps a socket from the standard library, setting it to non-blocking.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket { inner: socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Connects the socket to `addr`. From then on the kernel drops datagrams from
+    /// any other address, `send` and `recv` can be used instead of `send_to` and
+    /// `recv_from`, and ICMP errors for what we sent, like a port being unreachable,
+    /// are reported by the next `send` or `recv` as an error like `ConnectionRefused`.
+    /// Connecting again replaces the peer.
+    pub fn connect(&self, addr: impl std::net::ToSocketAddrs) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    /// The address the socket is connected to. Returns an error of kind
+    /// `NotConnected` if it isn't.
+    pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// Sends a datagram to the peer the socket is connected to.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    /// Receives a datagram from the peer the socket is connected to. If it's longer
+    /// than `buf`, the rest of it is discarded.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: std::net::SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, std::net::SocketAddr)> {
+        self.inner.recv_from(buf)
+    }
+
+    /// Takes the pending error reported by the kernel, if any, without sending or
+    /// receiving. An ICMP error wakes up a registration for `READABLE`, so this tells
+    /// such a wakeup apart from a datagram having arrived.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    /// Whether a socket bound to an IPv6 address only takes IPv6 datagrams. It's set
+    /// before binding, with `UdpSocketBuilder::only_v6`.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+    pub fn only_v6(&self) -> io::Result<bool> {
+        crate::socket::only_v6(self.as_raw_fd())
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for UdpSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+mod ffi {
+    #[cfg(any(target_os = "linux", target_os = "redox"))]
+    pub const SOL_SOCKET: i32 = 1;
+    #[cfg(any(target_os = "linux", target_os = "redox"))]
+    pub const SO_PEERCRED: i32 = 17;
+    #[cfg(target_os = "haiku")]
+    pub const SOL_SOCKET: i32 = -1;
+    #[cfg(target_os = "haiku")]
+    pub const SO_PEERCRED: i32 = 0x4000_000b;
+    #[cfg(target_os = "macos")]
+    pub const SOL_LOCAL: i32 = 0;
+    #[cfg(target_os = "macos")]
+    pub const LOCAL_PEERPID: i32 = 0x002;
+
+    // http://man7.org/linux/man-pages/man7/unix.7.html, which relibc and Haiku copy
+    #[cfg(any(target_os = "linux", target_os = "redox", target_os = "haiku"))]
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct Ucred {
+        pub pid: i32,
+        pub uid: u32,
+        pub gid: u32,
+    }
+
+    // Haiku's C library is libroot
+    #[cfg_attr(not(target_os = "haiku"), link(name = "c"))]
+    #[cfg_attr(target_os = "haiku", link(name = "root"))]
+    extern "C" {
+        /// http://man7.org/linux/man-pages/man2/getsockopt.2.html
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "redox",
+            target_os = "haiku"
+        ))]
+        pub fn getsockopt(
+            sockfd: i32,
+            level: i32,
+            optname: i32,
+            optval: *mut std::ffi::c_void,
+            optlen: *mut u32,
+        ) -> i32;
+
+        /// https://developer.apple.com/library/archive/documentation/System/Conceptual/ManPages_iPhoneOS/man3/getpeereid.3.html
+        #[cfg(any(
+            target_os = "macos",
+            target_os = "dragonfly",
+            target_os = "aix",
+            target_os = "nto"
+        ))]
+        pub fn getpeereid(socket: i32, euid: *mut u32, egid: *mut u32) -> i32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_batch_stops_at_max_and_when_drained() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _clients: Vec<_> = (0..5)
+            .map(|_| std::net::TcpStream::connect(addr).unwrap())
+            .collect();
+
+        let mut accepted = vec![];
+        assert_eq!(3, listener.accept_batch(&mut accepted, 3).unwrap());
+        assert_eq!(2, listener.accept_batch(&mut accepted, 3).unwrap());
+        assert_eq!(0, listener.accept_batch(&mut accepted, 3).unwrap());
+        assert_eq!(5, accepted.len());
+    }
+
+    #[test]
+    fn connected_udp_socket_only_hears_from_its_peer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let stranger = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(peer.local_addr().unwrap()).unwrap();
+        assert_eq!(peer.local_addr().unwrap(), socket.peer_addr().unwrap());
+
+        let addr = socket.local_addr().unwrap();
+        stranger.send_to(b"stranger", addr).unwrap();
+        peer.send_to(b"peer", addr).unwrap();
+        socket.send(b"ping").unwrap();
+
+        let mut buf = [0; 16];
+        let (n, from) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!((b"ping" as &[u8], addr), (&buf[..n], from));
+        // Loopback delivers right away, so the stranger's datagram would be here too
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(b"peer", &buf[..n]);
+        let err = socket.recv(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn connected_udp_socket_reports_icmp_errors() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // Bind and drop a socket to get a port nobody is listening on
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(closed.local_addr().unwrap()).unwrap();
+        drop(closed);
+
+        socket.send(b"anyone there?").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let err = socket.recv(&mut [0; 16]).unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionRefused, err.kind());
+    }
+
+    #[test]
+    fn source_fd_registers_raw_fd_only_types() {
+        use crate::{Events, Interests, Poll};
+
+        // Only implements `AsRawFd`, like a wrapper from an older crate
+        struct Legacy(UnixStream);
+        impl AsRawFd for Legacy {
+            fn as_raw_fd(&self) -> RawFd {
+                self.0.as_raw_fd()
+            }
+        }
+
+        let mut poll = Poll::new().unwrap();
+        let (a, mut b) = socket_pair().unwrap();
+        let legacy = Legacy(a);
+        // `legacy` owns the stream and its fd
+        let source = unsafe { SourceFd::new(&legacy) };
+        poll.registrator()
+            .register(&source, 7, Interests::READABLE)
+            .unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(1000)).unwrap();
+        assert_eq!(7, events[0].id());
+    }
+
+    // Fuchsia has no uids to compare with
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    #[test]
+    fn peer_cred_is_our_own_for_a_pair() {
+        let (a, _b) = socket_pair().unwrap();
+        let cred = a.peer_cred().unwrap();
+        assert_eq!(unsafe { getuid() }, cred.uid);
+        assert_eq!(unsafe { getgid() }, cred.gid);
+        if let Some(pid) = cred.pid {
+            assert_eq!(std::process::id(), pid);
+        }
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    extern "C" {
+        fn getuid() -> u32;
+        fn getgid() -> u32;
+    }
+}
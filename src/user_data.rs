@@ -0,0 +1,124 @@
+//! Data attached to registrations, so the state of a connection can be looked up from
+//! the token of its events instead of a `HashMap<Token, _>` kept next to the `Poll`.
+use crate::registrable::Registrable;
+use crate::{Interests, Poll, Registry, Token};
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+/// The first token handed out by `Registry::register_with_data`. Tokens are handed
+/// out counting down from here, `usize::MAX` itself is reserved for internal use.
+pub const FIRST_DATA_TOKEN: Token = usize::MAX - 1;
+
+/// The data attached to registrations, and the tokens they were given.
+#[derive(Debug, Default)]
+pub(crate) struct UserData {
+    entries: HashMap<Token, Box<dyn Any + Send>>,
+    /// Tokens of removed entries, handed out again before new ones
+    free: Vec<Token>,
+    /// How many tokens have been handed out counting down from `FIRST_DATA_TOKEN`
+    used: usize,
+}
+
+impl UserData {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn insert(&mut self, data: Box<dyn Any + Send>) -> Token {
+        let token = self.free.pop().unwrap_or_else(|| {
+            self.used += 1;
+            FIRST_DATA_TOKEN - (self.used - 1)
+        });
+        self.entries.insert(token, data);
+        token
+    }
+
+    pub(crate) fn get_mut<T: Any>(&mut self, token: Token) -> Option<&mut T> {
+        self.entries.get_mut(&token)?.downcast_mut()
+    }
+
+    fn remove<T: Any>(&mut self, token: Token) -> Option<T> {
+        if !self.entries.get(&token)?.is::<T>() {
+            return None;
+        }
+        let data = self.entries.remove(&token)?;
+        self.free.push(token);
+        data.downcast().ok().map(|data| *data)
+    }
+}
+
+impl Registry {
+    /// Registers `source` with a token picked for it, and stores `data` under that
+    /// token until it's taken back out with `remove_data`. Returns the token, which is
+    /// also the id of the source's events and what registering it again takes.
+    ///
+    /// The tokens count down from `FIRST_DATA_TOKEN`, so tokens passed to `register`
+    /// directly should stay well below it.
+    pub fn register_with_data<T: Any + Send>(
+        &self,
+        source: &mut impl Registrable,
+        interests: Interests,
+        data: T,
+    ) -> io::Result<Token> {
+        let token = self.user_data().insert(Box::new(data));
+        if let Err(e) = source.register(&self.registrator(), token, interests) {
+            self.user_data().remove::<T>(token);
+            return Err(e);
+        }
+        Ok(token)
+    }
+
+    /// Calls `f` with the data stored under `token`. Returns `None` if there is none,
+    /// or if it isn't a `T`.
+    ///
+    /// The data of every registration is behind the same lock, so `f` mustn't call
+    /// back into the registry's data methods. `Poll::data_mut` needs no lock.
+    pub fn with_data<T: Any, R>(&self, token: Token, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.user_data().get_mut(token).map(f)
+    }
+
+    /// Takes the data stored under `token` back out, typically after deregistering
+    /// the source or when it's about to be closed. The token can be handed out again
+    /// after this. Returns `None`, and leaves the data alone, if it isn't a `T`.
+    pub fn remove_data<T: Any>(&self, token: Token) -> Option<T> {
+        self.user_data().remove(token)
+    }
+
+    fn user_data(&self) -> MutexGuard<'_, UserData> {
+        // The data is never left half changed, so a panic while holding the lock is
+        // no reason to stop using it
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Poll {
+    /// The data stored under `token` with `Registry::register_with_data`, usually the
+    /// id of an event we just got. Returns `None` if there is none, or if it isn't a
+    /// `T`.
+    pub fn data_mut<T: Any>(&mut self, token: Token) -> Option<&mut T> {
+        let data: &mut Mutex<UserData> = &mut self.registry.data;
+        data.get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_count_down_and_are_reused() {
+        let mut data = UserData::default();
+        assert_eq!(FIRST_DATA_TOKEN, data.insert(Box::new(1u32)));
+        assert_eq!(FIRST_DATA_TOKEN - 1, data.insert(Box::new("two")));
+
+        assert_eq!(None, data.remove::<u64>(FIRST_DATA_TOKEN));
+        assert_eq!(Some(1), data.remove::<u32>(FIRST_DATA_TOKEN));
+        assert_eq!(None, data.get_mut::<u32>(FIRST_DATA_TOKEN));
+        assert_eq!(FIRST_DATA_TOKEN, data.insert(Box::new(3u32)));
+        assert_eq!(Some(&mut "two"), data.get_mut(FIRST_DATA_TOKEN - 1));
+    }
+}
@@ -0,0 +1,188 @@
+//! A `Waker` lets other threads wake up a thread blocked in `Poll::poll`, optionally
+//! telling it why.
+use crate::{Interests, Poll, Registrator, Token};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(not(target_os = "linux"))]
+use crate::registrable::Registrable;
+#[cfg(not(target_os = "linux"))]
+use crate::socket_pair;
+#[cfg(target_os = "linux")]
+use crate::EventFd;
+#[cfg(not(target_os = "linux"))]
+use std::io::{Read, Write};
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+type Stream = crate::UnixStream;
+#[cfg(target_os = "windows")]
+type Stream = crate::TcpStream;
+
+/// Wakes up the thread polling a `Poll` by making an event with the waker's token
+/// show up. Clones share the same registration, so a waker can be cloned and handed
+/// to as many threads as needed.
+///
+/// Every waker created with `new` is independent of the others, so one `Poll` can
+/// have a waker per subsystem (shutdown, a job queue, a timer thread...) each with
+/// its own token, and the event loop can tell from the token who woke it up.
+///
+/// Like every other registration the waker is oneshot: when the polling thread gets
+/// an event with the waker's token it must call `reset` to be woken again. Waking
+/// several times before that results in a single event.
+///
+/// `wake_with` sends a value along, for example to tell which job finished. Since
+/// wakeups are merged the values are queued and `reset` returns all of them.
+#[derive(Debug, Clone)]
+pub struct Waker {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    token: Token,
+    registrator: Registrator,
+    channel: Channel,
+    notified: AtomicBool,
+    payloads: Mutex<Vec<usize>>,
+}
+
+impl Waker {
+    /// Creates a waker that wakes up `poll` with an event with `token` as its id.
+    pub fn new(poll: &Poll, token: Token) -> io::Result<Waker> {
+        let registrator = poll.registrator();
+        let channel = Channel::new()?;
+        channel.rearm(&registrator, token)?;
+        Ok(Waker {
+            inner: Arc::new(Inner {
+                token,
+                registrator,
+                channel,
+                notified: AtomicBool::new(false),
+                payloads: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    pub fn token(&self) -> Token {
+        self.inner.token
+    }
+
+    /// Wakes up the polling thread.
+    pub fn wake(&self) -> io::Result<()> {
+        // One notification is enough no matter how many times we're woken before a
+        // `reset`
+        if self.inner.notified.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        trace!("waking up poll with token {}", self.inner.token);
+        self.inner.channel.notify()
+    }
+
+    /// Wakes up the polling thread and queues `data` to be returned by the next `reset`.
+    pub fn wake_with(&self, data: usize) -> io::Result<()> {
+        lock(&self.inner.payloads).push(data);
+        self.wake()
+    }
+
+    /// Re-arms the waker after its event was returned from `poll` and returns the
+    /// values sent with `wake_with` since the last reset, oldest first.
+    pub fn reset(&self) -> io::Result<Vec<usize>> {
+        self.inner
+            .channel
+            .rearm(&self.inner.registrator, self.inner.token)?;
+        // Only now that the channel is empty and registered again may a wakeup notify
+        // it again, or its notification could be drained by `rearm` and the flag left
+        // set with nothing to wake us up. A wakeup that found the flag still set is
+        // part of the event we're resetting, and its value is taken below.
+        self.inner.notified.store(false, Ordering::SeqCst);
+        Ok(std::mem::take(&mut *lock(&self.inner.payloads)))
+    }
+}
+
+/// An eventfd on Linux: writing to it makes it readable, and one read of its counter
+/// empties it however often it was written to.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Channel(EventFd);
+
+#[cfg(target_os = "linux")]
+impl Channel {
+    fn new() -> io::Result<Channel> {
+        EventFd::new(0).map(Channel)
+    }
+
+    fn notify(&self) -> io::Result<()> {
+        self.0.write(1)
+    }
+
+    /// Empties the channel and registers it again.
+    fn rearm(&self, registrator: &Registrator, token: Token) -> io::Result<()> {
+        match self.0.read() {
+            // `WouldBlock` means we're already empty
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+        registrator.register(&self.0, token, Interests::READABLE)
+    }
+}
+
+/// A socket pair everywhere else: we write a byte to one end, and the other end is
+/// readable until it's been drained.
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+struct Channel {
+    sender: Mutex<Stream>,
+    receiver: Mutex<Stream>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Channel {
+    fn new() -> io::Result<Channel> {
+        let (receiver, sender) = socket_pair()?;
+        Ok(Channel {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+        })
+    }
+
+    fn notify(&self) -> io::Result<()> {
+        match lock(&self.sender).write(&[1]) {
+            Ok(_) => Ok(()),
+            // A full socket buffer means there is plenty to wake up to already
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Empties the channel and registers it again.
+    fn rearm(&self, registrator: &Registrator, token: Token) -> io::Result<()> {
+        let mut receiver = lock(&self.receiver);
+        let mut buf = [0u8; 64];
+        loop {
+            match receiver.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        receiver.register(registrator, token, Interests::READABLE)
+    }
+}
+
+/// A panic while holding one of our locks can't leave the data in a bad state, so we
+/// ignore poisoning.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -1,25 +1,63 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
-use crate::{Interests, Token};
-use std::collections::LinkedList;
-use std::io::{self, Read, Write};
+use crate::backlog::FullSelects;
+use crate::clock::ceil_millis;
+use crate::registrations::Registrations;
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::{Events, Interests, Token};
+use std::collections::{LinkedList, VecDeque};
+use std::io::{self, IoSliceMut, Read, Write};
 use std::net;
-use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::os::windows::io::{
+    AsHandle, AsRawHandle, AsRawSocket, AsSocket, BorrowedHandle, BorrowedSocket, FromRawHandle,
+    OwnedHandle, RawHandle, RawSocket,
+};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub type Event = ffi::OVERLAPPED_ENTRY;
 
+/// How large a buffer we lend to `WSARecv` for each stream unless the stream or
+/// the `Selector` says otherwise.
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 1024;
+
 #[derive(Debug)]
 pub struct TcpStream {
     inner: net::TcpStream,
-    buffer: Vec<u8>,
+    buffers: Vec<Vec<u8>>,
     wsabuf: Vec<ffi::WSABUF>,
     event: Option<ffi::WSAOVERLAPPED>,
     token: Option<usize>,
     pos: usize,
     operations: LinkedList<ffi::Operation>,
+    status: TcpReadiness,
+    recv_buffer_size: Option<usize>,
+    recv_buffer_count: usize,
+    /// Whether reading should lend the buffers to a new `WSARecv` once they're empty
+    registered: bool,
+    /// The counters of the selector we were last registered with
+    stats: Option<Arc<SyscallCounters>>,
+}
+
+// The raw pointers in `wsabuf` and `operations` point into heap memory the stream
+// owns, so they stay valid when the stream is moved to another thread.
+unsafe impl Send for TcpStream {}
+
+/// Where a `TcpStream` is in the cycle of lending its buffer to a `WSARecv` and reading
+/// the received data back out of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpReadiness {
+    /// Not registered. Nobody else is using the buffer and reads go straight to the socket.
+    Idle,
+    /// A `WSARecv` owns the buffer and we're waiting for it to complete.
+    Pending,
+    /// The buffers hold this many bytes received by the last `WSARecv`.
+    Ready(usize),
+    /// The last `WSARecv` completed without any data, meaning the peer closed the connection.
+    Closed,
 }
 
 // On Windows we need to be careful when using IOCP on a server. Since we're "lending"
@@ -45,31 +83,330 @@ impl TcpStream {
         // Rust creates a WSASocket set to overlapped by default which is just what we need
         // https://github.com/rust-lang/rust/blob/f86521e0a33a2b54c4c23dbfc5250013f7a33b11/src/libstd/sys/windows/net.rs#L99
         let stream = net::TcpStream::connect(adr)?;
+        TcpStream::from_std(stream)
+    }
+
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking. The standard library
+    /// creates its sockets for overlapped I/O, which is what IOCP needs.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
         stream.set_nonblocking(true)?;
 
-        let mut buffer = vec![0_u8; 1024];
-        let wsabuf = vec![ffi::WSABUF::new(buffer.len() as u32, buffer.as_mut_ptr())];
+        // The buffers are allocated when they're first lent to `WSARecv`, when we know
+        // their size
         Ok(TcpStream {
             inner: stream,
-            buffer,
-            wsabuf,
+            buffers: vec![],
+            wsabuf: vec![],
             event: None,
             token: None,
             pos: 0,
             operations: LinkedList::new(),
+            status: TcpReadiness::Idle,
+            recv_buffer_size: None,
+            recv_buffer_count: 1,
+            registered: false,
+            stats: None,
+        })
+    }
+
+    /// The socket is non-blocking from `from_std` on, reads never change that.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reading never blocks here, so this is just `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+
+    /// Sets the size of the buffer IOCP receives data into for this stream, which is
+    /// the most a single read event can deliver. Large buffers mean fewer round trips
+    /// for throughput-heavy connections, small ones save memory when there are many
+    /// mostly idle connections. Overrides the default of the `Selector` the stream is
+    /// registered with.
+    ///
+    /// The buffer can't be resized while it's lent out, so the new size takes effect
+    /// the next time a `WSARecv` is queued.
+    pub fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        validate_recv_buffer_size(size)?;
+        self.recv_buffer_size = Some(size);
+        Ok(())
+    }
+
+    /// Returns the receive buffer size set with `set_recv_buffer_size`, or the one
+    /// we got from the `Selector` when we were registered.
+    pub fn recv_buffer_size(&self) -> Option<usize> {
+        self.recv_buffer_size
+    }
+
+    /// Splits what we lend to `WSARecv` into `count` buffers of the receive buffer
+    /// size each. They're all handed over in one call and filled in order, so a large
+    /// burst of data is received at once without needing one large allocation. Read
+    /// them back out with `read_vectored` to avoid copying into a single buffer first.
+    ///
+    /// Like the buffer size, this takes effect the next time a `WSARecv` is queued.
+    pub fn set_recv_buffer_count(&mut self, count: usize) -> io::Result<()> {
+        if count == 0 || count > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Receive buffer count must be between 1 and u32::MAX.",
+            ));
+        }
+        self.recv_buffer_count = count;
+        Ok(())
+    }
+
+    pub fn recv_buffer_count(&self) -> usize {
+        self.recv_buffer_count
+    }
+
+    /// Lends our buffer to a new `WSARecv`. It will report its completion to the
+    /// completion port we're associated with, as an event with `token` as its id.
+    fn queue_recv(&mut self, token: Token) -> io::Result<()> {
+        let size = self.recv_buffer_size.unwrap_or(DEFAULT_RECV_BUFFER_SIZE);
+        let count = self.recv_buffer_count;
+        if self.buffers.len() != count || self.buffers[0].len() != size {
+            self.buffers = (0..count).map(|_| vec![0_u8; size]).collect();
+            self.wsabuf = self
+                .buffers
+                .iter_mut()
+                .map(|buf| ffi::WSABUF::new(size as u32, buf.as_mut_ptr()))
+                .collect();
+        }
+
+        let socket = self.inner.as_raw_socket();
+        // There is only ever one `Operation`. It lives in a `LinkedList` node so its
+        // address doesn't change when the stream is moved.
+        let op = self
+            .operations
+            .back_mut()
+            .expect("registered stream has an operation");
+        op.reset(token);
+        self.pos = 0;
+        self.status = TcpReadiness::Pending;
+        if let Some(stats) = &self.stats {
+            stats.submission();
+        }
+        if let Err(e) = ffi::wsa_recv(socket, &mut self.wsabuf, op) {
+            self.status = TcpReadiness::Idle;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Checks if the pending `WSARecv` has completed, and if it has, takes back the
+    /// buffer it filled. Returns an error of kind `WouldBlock` if it's still in flight.
+    fn complete_recv(&mut self) -> io::Result<()> {
+        self.finish_recv(false)
+    }
+
+    /// Like `complete_recv`, but if `wait` is set it blocks until the `WSARecv` is done.
+    fn finish_recv(&mut self, wait: bool) -> io::Result<()> {
+        let socket = self.inner.as_raw_socket();
+        let op = self
+            .operations
+            .back_mut()
+            .expect("registered stream has an operation");
+        match ffi::wsa_get_overlapped_result(socket, op, wait) {
+            Ok(0) => self.status = TcpReadiness::Closed,
+            Ok(n) => self.status = TcpReadiness::Ready(n as usize),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    self.status = TcpReadiness::Idle;
+                }
+                return Err(e);
+            }
+        }
+        self.pos = 0;
+        Ok(())
+    }
+
+    /// Takes our buffers and operation back from IOCP, so the stream can be dropped or
+    /// registered somewhere else without the OS writing to memory we no longer own.
+    /// Data that was already received stays in the buffers for the next read.
+    fn deregister(&mut self) -> io::Result<()> {
+        self.registered = false;
+        if self.operations.is_empty() {
+            return Ok(());
+        }
+
+        // Unless we're idle there's a completion for the operation that's either on
+        // its way to the port, in the port, or already dequeued by `select`
+        let has_completion = self.status != TcpReadiness::Idle;
+        if self.status == TcpReadiness::Pending {
+            let socket = self.inner.as_raw_socket();
+            let op = self.operations.back_mut().unwrap();
+            ffi::cancel_operation(socket, op)?;
+            // Once the `WSARecv` is done, whether it was cancelled or not, the OS won't
+            // touch the buffers anymore
+            match self.finish_recv(true) {
+                Ok(()) => (),
+                // Cancelled
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if has_completion {
+            // We can't tell if `select` has dequeued the completion already, and it
+            // points at the operation. Rather than freeing the operation under it we
+            // leak it, which is a few dozen bytes.
+            std::mem::forget(std::mem::take(&mut self.operations));
+            // The socket is still associated with the port, so we need an operation
+            // to be able to register again
+            self.operations.push_back(ffi::Operation::new(0));
+        }
+        trace!("deregistered socket {}", self.inner.as_raw_socket());
+        Ok(())
+    }
+
+    /// Copies received data into `dst`, starting at `pos` and continuing across the
+    /// buffers as if they were one. Returns how many bytes were copied.
+    fn copy_received(&mut self, dst: &mut [u8], len: usize) -> usize {
+        let chunk_size = self.buffers[0].len();
+        let mut copied = 0;
+        while copied < dst.len() && self.pos < len {
+            let chunk = &self.buffers[self.pos / chunk_size];
+            let offset = self.pos % chunk_size;
+            let n = (dst.len() - copied)
+                .min(chunk_size - offset)
+                .min(len - self.pos);
+            dst[copied..copied + n].copy_from_slice(&chunk[offset..offset + n]);
+            copied += n;
+            self.pos += n;
+        }
+        copied
+    }
+}
+
+fn validate_recv_buffer_size(size: usize) -> io::Result<()> {
+    if size == 0 || size > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Receive buffer size must be between 1 and u32::MAX bytes.",
+        ));
+    }
+    Ok(())
+}
+
+/// Creates a pair of connected, non-blocking streams. Windows has no `socketpair` so
+/// we emulate it by connecting two TCP sockets over the loopback interface.
+pub fn socket_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = net::TcpListener::bind("127.0.0.1:0")?;
+    let a = net::TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (b, peer) = listener.accept()?;
+        // Somebody else could connect to the port before we do, so make sure we
+        // accepted our own connection
+        if peer == a.local_addr()? {
+            return Ok((TcpStream::from_std(a)?, TcpStream::from_std(b)?));
+        }
+    }
+}
+
+/// Once registered, reading hands out the data IOCP put in our buffer. When it's all
+/// read we lend the buffer to a new `WSARecv`, which reports an event with the same
+/// token when more data arrives. Until then reading returns `WouldBlock`.
+/// Reads `SIO_TCP_INFO`.
+pub(crate) fn tcp_info(stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    let info = ffi::tcp_info(stream.inner.as_raw_socket())?;
+    use crate::TcpState::*;
+    let state = match info.state {
+        0 => Closed,
+        1 => Listen,
+        2 => SynSent,
+        3 => SynReceived,
+        4 => Established,
+        5 => FinWait1,
+        6 => FinWait2,
+        7 => CloseWait,
+        8 => Closing,
+        9 => LastAck,
+        10 => TimeWait,
+        other => Unknown(other as u8),
+    };
+    Ok(crate::TcpInfo {
+        state,
+        rtt: Duration::from_micros(info.rtt_us as u64),
+        retransmits: info.fast_retrans as u64 + info.timeout_episodes as u64,
+        congestion_window: info.cwnd as u64,
+        mss: info.mss,
+    })
+}
+
+impl TcpStream {
+    /// Sets how long sent data can go unacknowledged before the connection is given
+    /// up on, after which reads and writes fail. That's `TCP_MAXRT`, in whole seconds,
+    /// rounded up. `None` sets it to -1, which retransmits for as long as keepalive
+    /// doesn't give up, since Windows has no value for its default.
+    pub fn set_user_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let seconds = match timeout {
+            Some(timeout) => {
+                let seconds = timeout.as_secs() + (timeout.subsec_nanos() > 0) as u64;
+                seconds.clamp(1, i32::MAX as u64) as i32
+            }
+            None => -1,
+        };
+        ffi::set_int_option(
+            self.inner.as_raw_socket(),
+            ffi::IPPROTO_TCP,
+            ffi::TCP_MAXRT,
+            seconds,
+        )
+    }
+
+    /// The timeout set with `set_user_timeout`. `None` if the connection is never
+    /// given up on for unacknowledged data.
+    pub fn user_timeout(&self) -> io::Result<Option<Duration>> {
+        let seconds =
+            ffi::get_int_option(self.inner.as_raw_socket(), ffi::IPPROTO_TCP, ffi::TCP_MAXRT)?;
+        Ok(if seconds < 0 {
+            None
+        } else {
+            Some(Duration::from_secs(seconds as u64))
         })
     }
 }
 
 impl Read for TcpStream {
     fn read(&mut self, buff: &mut [u8]) -> io::Result<usize> {
-        let mut bytes_read = 0;
-        for (a, b) in self.buffer.iter().skip(self.pos).zip(buff) {
-            *b = *a;
-            bytes_read += 1;
+        self.read_vectored(&mut [IoSliceMut::new(buff)])
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        loop {
+            match self.status {
+                TcpReadiness::Idle => return self.inner.read_vectored(bufs),
+                TcpReadiness::Pending => self.complete_recv()?,
+                TcpReadiness::Closed => return Ok(0),
+                TcpReadiness::Ready(len) => {
+                    let mut n = 0;
+                    for buf in bufs.iter_mut() {
+                        n += self.copy_received(buf, len);
+                        if self.pos == len {
+                            break;
+                        }
+                    }
+                    if self.pos == len && !self.registered {
+                        self.status = TcpReadiness::Idle;
+                    } else if self.pos == len {
+                        let token = self.operations.back().map(|op| op.token()).unwrap_or(0);
+                        // If this fails we're back to reading from the socket directly,
+                        // which reports the error on the next read
+                        if let Err(e) = self.queue_recv(token) {
+                            debug!(
+                                "WSARecv on socket {} with token {} failed: {}",
+                                self.inner.as_raw_socket(),
+                                token,
+                                e
+                            );
+                        }
+                    }
+                    return Ok(n);
+                }
+            }
         }
-        self.pos += bytes_read;
-        Ok(bytes_read)
     }
 }
 
@@ -89,358 +426,2426 @@ impl AsRawSocket for TcpStream {
     }
 }
 
-pub struct Registrator {
-    completion_port: isize,
-    is_poll_dead: Arc<AtomicBool>,
+impl AsSocket for TcpStream {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.inner.as_socket()
+    }
 }
 
-impl Registrator {
-    pub fn register(
-        &self,
-        soc: &mut TcpStream,
-        token: usize,
-        interests: Interests,
-    ) -> io::Result<()> {
-        if self.is_poll_dead.load(Ordering::SeqCst) {
-            return Err(io::Error::new(
-                io::ErrorKind::Interrupted,
-                "Poll instance is dead.",
-            ));
-        }
+/// A non-blocking UDP socket. Once it's registered with `Registrator::register_udp`
+/// there is always a `WSARecvFrom` in flight receiving into a buffer we own, and
+/// `recv_from` hands out what it received, so like on the other platforms receiving
+/// returns `WouldBlock` until the next event and then a datagram.
+#[derive(Debug)]
+pub struct UdpSocket {
+    inner: net::UdpSocket,
+    buffer: Vec<u8>,
+    wsabuf: Vec<ffi::WSABUF>,
+    // `WSARecvFrom` writes the sender's address into these after the call returns, so
+    // they live on the heap where moving the socket doesn't move them
+    from: Box<ffi::SOCKADDR_STORAGE>,
+    from_len: Box<i32>,
+    flags: Box<u32>,
+    operations: LinkedList<ffi::Operation>,
+    status: UdpReadiness,
+    recv_buffer_size: Option<usize>,
+    registered: bool,
+    /// The counters of the selector we were last registered with
+    stats: Option<Arc<SyscallCounters>>,
+}
 
-        ffi::create_io_completion_port(soc.as_raw_socket(), self.completion_port, 0)?;
+// Like `TcpStream`, the raw pointers point into heap memory the socket owns
+unsafe impl Send for UdpSocket {}
 
-        let op = ffi::Operation::new(token);
-        soc.operations.push_back(op);
+/// Where a `UdpSocket` is in the cycle of lending its buffer to a `WSARecvFrom`.
+#[derive(Debug)]
+enum UdpReadiness {
+    /// Not registered. Nobody else is using the buffer and receives go straight to the
+    /// socket.
+    Idle,
+    /// A `WSARecvFrom` owns the buffer and we're waiting for it to complete.
+    Pending,
+    /// The buffer holds a datagram of this length.
+    Ready(usize),
+    /// The last `WSARecvFrom` failed, for example because an ICMP error reported an
+    /// earlier send as unreachable. The error is returned by the next receive.
+    Failed(Option<io::Error>),
+}
 
-        if interests.is_readable() {
-            ffi::wsa_recv(
-                soc.as_raw_socket(),
-                &mut soc.wsabuf,
-                soc.operations.back_mut().unwrap(),
-            )?;
-        } else {
-            unimplemented!();
-        }
+impl UdpSocket {
+    pub fn bind(addr: impl net::ToSocketAddrs) -> io::Result<Self> {
+        UdpSocket::from_std(net::UdpSocket::bind(addr)?)
+    }
 
-        Ok(())
+    /// Wraps a socket from the standard library, setting it to non-blocking. The
+    /// standard library creates its sockets for overlapped I/O, which is what IOCP
+    /// needs.
+    pub fn from_std(socket: net::UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket {
+            inner: socket,
+            buffer: vec![],
+            wsabuf: vec![],
+            from: Box::new(ffi::SOCKADDR_STORAGE::zeroed()),
+            from_len: Box::new(0),
+            flags: Box::new(0),
+            operations: LinkedList::new(),
+            status: UdpReadiness::Idle,
+            recv_buffer_size: None,
+            registered: false,
+            stats: None,
+        })
     }
 
-    /// NOTE: An alternative solution is to use the `CompletionKey` to signal that
-    /// this is a close event. We don't use it for anything else so it is a
-    /// good candidate to use for timers and special events like this
-    pub fn close_loop(&self) -> io::Result<()> {
-        if self
-            .is_poll_dead
-            .compare_and_swap(false, true, Ordering::SeqCst)
-        {
-            return Err(io::Error::new(
-                io::ErrorKind::Interrupted,
-                "Poll instance is dead.",
-            ));
-        }
-        let mut overlapped = ffi::WSAOVERLAPPED::zeroed();
-        ffi::post_queued_completion_status(self.completion_port, 0, 0, &mut overlapped)?;
+    /// Sets the size of the buffer IOCP receives datagrams into, which is the largest
+    /// datagram we can receive whole. Overrides the default of the `Selector` the
+    /// socket is registered with, and takes effect the next time a `WSARecvFrom` is
+    /// queued.
+    pub fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        validate_recv_buffer_size(size)?;
+        self.recv_buffer_size = Some(size);
         Ok(())
     }
-}
-
-// possible Arc<InnerSelector> needed
-#[derive(Debug)]
-pub struct Selector {
-    completion_port: isize,
-}
 
-impl Selector {
-    pub fn new() -> io::Result<Self> {
-        // set up the queue
-        let completion_port = ffi::create_completion_port()?;
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
 
-        Ok(Selector { completion_port })
+    /// Connects the socket to `addr`, so the OS drops datagrams from anybody else and
+    /// `send` and `recv` can be used. ICMP errors for what we sent are reported by the
+    /// next receive.
+    pub fn connect(&self, addr: impl net::ToSocketAddrs) -> io::Result<()> {
+        self.inner.connect(addr)
     }
 
-    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
-        Registrator {
-            completion_port: self.completion_port,
-            is_poll_dead,
-        }
+    pub fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.peer_addr()
     }
 
-    /// Blocks until an Event has occured. Never times out. We could take a parameter
-    /// for a timeout and pass it on but we'll not do that in our example.
-    pub fn select(
-        &mut self,
-        events: &mut Vec<ffi::OVERLAPPED_ENTRY>,
-        timeout: Option<i32>,
-    ) -> io::Result<()> {
-        // calling GetQueueCompletionStatus will either return a handle to a "port" ready to read or
-        // block if the queue is empty.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
 
-        // Windows want timeout as u32 so we cast it as such
-        let timeout = timeout.map(|t| t as u32);
+    pub fn send_to(&self, buf: &[u8], addr: net::SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
 
-        // first let's clear events for any previous events and wait until we get som more
-        events.clear();
-        let ul_count = events.capacity() as u32;
+    /// Receives a datagram from the peer the socket is connected to.
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(n, _)| n)
+    }
 
-        let removed_res = ffi::get_queued_completion_status_ex(
-            self.completion_port as isize,
-            events,
-            ul_count,
-            timeout,
-            false,
-        );
+    /// Receives a datagram. If it's longer than `buf`, the rest of it is discarded.
+    /// Returns an error of kind `WouldBlock` while the socket is registered and the
+    /// `WSARecvFrom` hasn't completed yet.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)> {
+        loop {
+            match self.status {
+                UdpReadiness::Idle => return self.inner.recv_from(buf),
+                UdpReadiness::Pending => self.complete_recv()?,
+                UdpReadiness::Failed(ref mut e) => {
+                    let e = e.take().expect("failed receive holds its error");
+                    self.requeue();
+                    return Err(e);
+                }
+                UdpReadiness::Ready(len) => {
+                    let n = len.min(buf.len());
+                    buf[..n].copy_from_slice(&self.buffer[..n]);
+                    let addr = self.from.to_socket_addr();
+                    self.requeue();
+                    return addr.map(|addr| (n, addr));
+                }
+            }
+        }
+    }
 
-        // We need to handle the case that the "error" was a WAIT_TIMEOUT error.
-        // the code for this error is 258 on Windows. We don't treat this as an error
-        // but set the events returned to 0.
-        // (i tried to do this in the `ffi` function but there was an error)
-        let removed = match removed_res {
-            Ok(n) => n,
-            Err(ref e) if e.raw_os_error() == Some(258) => 0,
-            Err(e) => return Err(e),
-        };
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
 
-        unsafe {
-            events.set_len(removed as usize);
+    /// Lends the buffer to a new `WSARecvFrom` reporting to `token`.
+    fn queue_recv(&mut self, token: Token) -> io::Result<()> {
+        let size = self.recv_buffer_size.unwrap_or(DEFAULT_RECV_BUFFER_SIZE);
+        if self.buffer.len() != size {
+            self.buffer = vec![0_u8; size];
+            self.wsabuf = vec![ffi::WSABUF::new(size as u32, self.buffer.as_mut_ptr())];
         }
 
+        let socket = self.inner.as_raw_socket();
+        let op = self
+            .operations
+            .back_mut()
+            .expect("registered socket has an operation");
+        op.reset(token);
+        *self.from_len = std::mem::size_of::<ffi::SOCKADDR_STORAGE>() as i32;
+        *self.flags = 0;
+        self.status = UdpReadiness::Pending;
+        if let Some(stats) = &self.stats {
+            stats.submission();
+        }
+        let res = ffi::wsa_recv_from(
+            socket,
+            &mut self.wsabuf,
+            &mut self.flags,
+            &mut self.from,
+            &mut self.from_len,
+            op,
+        );
+        if let Err(e) = res {
+            self.status = UdpReadiness::Idle;
+            return Err(e);
+        }
         Ok(())
     }
-}
 
-impl Drop for Selector {
-    fn drop(&mut self) {
-        match ffi::close_handle(self.completion_port) {
-            Ok(_) => (),
-            Err(e) => {
-                if !std::thread::panicking() {
-                    panic!(e);
-                }
-            }
+    /// Queues the next `WSARecvFrom` once a datagram or error has been handed out, or
+    /// goes back to receiving from the socket directly if we're not registered.
+    fn requeue(&mut self) {
+        if !self.registered {
+            self.status = UdpReadiness::Idle;
+            return;
+        }
+        let token = self.operations.back().map(|op| op.token()).unwrap_or(0);
+        if let Err(e) = self.queue_recv(token) {
+            debug!(
+                "WSARecvFrom on socket {} with token {} failed: {}",
+                self.inner.as_raw_socket(),
+                token,
+                e
+            );
         }
     }
-}
-
-mod ffi {
-    use super::*;
-    use std::io;
-    use std::os::windows::io::RawSocket;
-    use std::ptr;
 
-    #[repr(C)]
-    #[derive(Clone, Debug)]
-    pub struct WSABUF {
-        len: u32,
-        buf: *mut u8,
+    fn complete_recv(&mut self) -> io::Result<()> {
+        self.finish_recv(false)
     }
 
-    impl WSABUF {
-        pub fn new(len: u32, buf: *mut u8) -> Self {
-            WSABUF { len, buf }
+    fn finish_recv(&mut self, wait: bool) -> io::Result<()> {
+        let socket = self.inner.as_raw_socket();
+        let op = self
+            .operations
+            .back_mut()
+            .expect("registered socket has an operation");
+        match ffi::wsa_get_overlapped_result(socket, op, wait) {
+            Ok(n) => self.status = UdpReadiness::Ready(n as usize),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(e),
+            Err(e) => self.status = UdpReadiness::Failed(Some(e)),
         }
+        Ok(())
     }
 
-    #[repr(C)]
-    #[derive(Debug, Clone)]
-    pub struct OVERLAPPED_ENTRY {
-        lp_completion_key: *mut usize,
-        lp_overlapped: *mut WSAOVERLAPPED,
-        internal: usize,
-        bytes_transferred: u32,
-    }
-
-    impl OVERLAPPED_ENTRY {
-        pub fn id(&self) -> Token {
-            // TODO: this might be solvable wihtout sacrifising so much of Rust safety guarantees
-            let operation: &Operation = unsafe { &*(self.lp_overlapped as *const Operation) };
-            operation.token
+    /// Like `TcpStream::deregister`: cancels the `WSARecvFrom` and waits for it so the
+    /// OS doesn't write to the buffer after we're gone.
+    fn deregister(&mut self) -> io::Result<()> {
+        self.registered = false;
+        if self.operations.is_empty() {
+            return Ok(());
         }
 
-        pub(crate) fn zeroed() -> Self {
-            OVERLAPPED_ENTRY {
-                lp_completion_key: ptr::null_mut(),
-                lp_overlapped: ptr::null_mut(),
-                internal: 0,
-                bytes_transferred: 0,
+        let has_completion = !matches!(self.status, UdpReadiness::Idle);
+        if let UdpReadiness::Pending = self.status {
+            let socket = self.inner.as_raw_socket();
+            let op = self.operations.back_mut().unwrap();
+            ffi::cancel_operation(socket, op)?;
+            self.finish_recv(true)?;
+            // A cancelled receive didn't receive anything
+            if let UdpReadiness::Failed(Some(ref e)) = self.status {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    self.status = UdpReadiness::Idle;
+                }
             }
         }
+
+        if has_completion {
+            // See `TcpStream::deregister`
+            std::mem::forget(std::mem::take(&mut self.operations));
+            self.operations.push_back(ffi::Operation::new(0));
+        }
+        trace!("deregistered socket {}", self.inner.as_raw_socket());
+        Ok(())
     }
+}
 
-    // Reference: https://docs.microsoft.com/en-us/windows/win32/api/winsock2/ns-winsock2-wsaoverlapped
-    #[repr(C)]
-    #[derive(Debug)]
-    pub struct WSAOVERLAPPED {
-        /// Reserved for internal use
-        internal: ULONG_PTR,
-        /// Reserved
-        internal_high: ULONG_PTR,
-        /// Reserved for service providers
-        offset: DWORD,
-        /// Reserved for service providers
-        offset_high: DWORD,
-        /// If an overlapped I/O operation is issued without an I/O completion routine
-        /// (the operation's lpCompletionRoutine parameter is set to null), then this parameter
-        /// should either contain a valid handle to a WSAEVENT object or be null. If the
-        /// lpCompletionRoutine parameter of the call is non-null then applications are free
-        /// to use this parameter as necessary.
-        h_event: HANDLE,
+impl AsRawSocket for UdpSocket {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.inner.as_raw_socket()
     }
+}
 
-    impl WSAOVERLAPPED {
-        pub fn zeroed() -> Self {
-            WSAOVERLAPPED {
-                internal: ptr::null_mut(),
-                internal_high: ptr::null_mut(),
-                offset: 0,
-                offset_high: 0,
-                h_event: 0,
+impl AsSocket for UdpSocket {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.inner.as_socket()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        // The buffer and address are freed with us, so the OS must be done with them
+        if let Err(e) = self.deregister() {
+            if !std::thread::panicking() {
+                panic!("{}", e);
             }
         }
     }
+}
 
-    /// Operation is a way for us to attach additional context to the `WSAOVERLAPPED`
-    /// event. Inpired by [BOOST ASIO](https://www.boost.org/doc/libs/1_42_0/boost/asio/detail/win_iocp_io_service.hpp)
-    #[derive(Debug)]
-    #[repr(C)]
-    pub struct Operation {
-        wsaoverlapped: WSAOVERLAPPED,
+/// An I/O completion port, closed when it's dropped. The selector and its
+/// registrators share it through an `Arc`, so it stays open for as long as any of
+/// them is around.
+#[derive(Debug)]
+struct CompletionPort(OwnedHandle);
+
+impl CompletionPort {
+    fn new() -> io::Result<CompletionPort> {
+        let port = ffi::create_completion_port()?;
+        // Nobody else has the handle we just got
+        Ok(CompletionPort(unsafe {
+            OwnedHandle::from_raw_handle(port as RawHandle)
+        }))
+    }
+
+    /// The handle, for the calls that take one. It's only valid for as long as we're
+    /// around, so anything that keeps it has to keep us alive as well.
+    fn raw(&self) -> ffi::HANDLE {
+        self.0.as_raw_handle() as ffi::HANDLE
+    }
+}
+
+impl AsRawHandle for CompletionPort {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.0.as_raw_handle()
+    }
+}
+
+impl AsHandle for CompletionPort {
+    fn as_handle(&self) -> BorrowedHandle<'_> {
+        self.0.as_handle()
+    }
+}
+
+#[derive(Debug)]
+pub struct Registrator {
+    /// Shared with the `Selector`, so the port stays open for as long as anybody can
+    /// register with it
+    completion_port: Arc<CompletionPort>,
+    is_poll_dead: Arc<AtomicBool>,
+    recv_buffer_size: usize,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+}
+
+impl Registrator {
+    fn port(&self) -> ffi::HANDLE {
+        self.completion_port.raw()
+    }
+
+    /// Registers `soc` for `interests`. Unlike the Unix backends this can't take any
+    /// `AsSocket`, since IOCP reads into buffers the stream lends it and the stream
+    /// has to know when to take them back.
+    pub fn register(
+        &self,
+        soc: &mut TcpStream,
         token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+
+        interests.validate()?;
+
+        // A socket can only be associated with a completion port once
+        if soc.operations.is_empty() {
+            self.stats.change();
+            ffi::create_io_completion_port(soc.as_raw_socket(), self.port(), 0)?;
+            soc.operations.push_back(ffi::Operation::new(token));
+        }
+        if soc.recv_buffer_size.is_none() {
+            soc.recv_buffer_size = Some(self.recv_buffer_size);
+        }
+        soc.stats = Some(self.stats.clone());
+        soc.registered = true;
+        self.registrations
+            .insert(soc.as_raw_socket(), token, interests);
+
+        if interests.is_readable() {
+            if soc.status == TcpReadiness::Pending {
+                match soc.complete_recv() {
+                    Ok(()) => (),
+                    // Cancelled by `Selector::clear`, which left us idle
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    // The `WSARecv` we already queued will report to the new token
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        soc.operations.back_mut().unwrap().set_token(token);
+                        trace!(
+                            "socket {} has a pending WSARecv, now reporting to token {}",
+                            soc.as_raw_socket(),
+                            token
+                        );
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let res = match soc.status {
+                // There is unread data (or the connection is closed), so the stream is
+                // readable right now. We post the event ourselves.
+                TcpReadiness::Ready(_) | TcpReadiness::Closed => {
+                    let op = soc.operations.back_mut().unwrap();
+                    op.set_token(token);
+                    self.stats.change();
+                    ffi::post_queued_completion_status(self.port(), 0, 0, op.overlapped_mut())
+                }
+                _ => soc.queue_recv(token),
+            };
+            if let Err(e) = res {
+                debug!(
+                    "WSARecv on socket {} with token {} failed: {} (os error {:?})",
+                    soc.as_raw_socket(),
+                    token,
+                    e,
+                    e.raw_os_error()
+                );
+                return Err(e);
+            }
+        } else {
+            unimplemented!();
+        }
+
+        debug!(
+            "registered socket {} with token {} for {}",
+            soc.as_raw_socket(),
+            token,
+            interests
+        );
+        Ok(())
     }
 
-    impl Operation {
-        pub(crate) fn new(token: usize) -> Self {
-            Operation {
-                wsaoverlapped: WSAOVERLAPPED::zeroed(),
+    /// Registers a `UdpSocket` for `READABLE` interest. An event with `token` is
+    /// returned from `select` once a datagram has been received, and `recv_from`
+    /// hands it out and queues the next `WSARecvFrom`. Returns an error of kind
+    /// `Unsupported` for any other interest.
+    pub fn register_udp(
+        &self,
+        soc: &mut UdpSocket,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+        interests.validate()?;
+        if interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Writable interest is not supported on Windows.",
+            ));
+        }
+
+        if soc.operations.is_empty() {
+            self.stats.change();
+            ffi::create_io_completion_port(soc.as_raw_socket(), self.port(), 0)?;
+            soc.operations.push_back(ffi::Operation::new(token));
+        }
+        if soc.recv_buffer_size.is_none() {
+            soc.recv_buffer_size = Some(self.recv_buffer_size);
+        }
+        soc.stats = Some(self.stats.clone());
+        soc.registered = true;
+        self.registrations
+            .insert(soc.as_raw_socket(), token, interests);
+
+        if let UdpReadiness::Pending = soc.status {
+            match soc.complete_recv() {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    soc.operations.back_mut().unwrap().set_token(token);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let res = match soc.status {
+            // There's a datagram or an error waiting to be picked up already
+            UdpReadiness::Ready(_) | UdpReadiness::Failed(_) => {
+                let op = soc.operations.back_mut().unwrap();
+                op.set_token(token);
+                self.stats.change();
+                ffi::post_queued_completion_status(self.port(), 0, 0, op.overlapped_mut())
+            }
+            _ => soc.queue_recv(token),
+        };
+        if let Err(e) = res {
+            debug!(
+                "WSARecvFrom on socket {} with token {} failed: {}",
+                soc.as_raw_socket(),
                 token,
+                e
+            );
+            return Err(e);
+        }
+        debug!(
+            "registered udp socket {} with token {}",
+            soc.as_raw_socket(),
+            token
+        );
+        Ok(())
+    }
+
+    /// Like `deregister`, for a `UdpSocket`.
+    pub fn deregister_udp(&self, soc: &mut UdpSocket) -> io::Result<()> {
+        soc.deregister()?;
+        self.registrations.remove(soc.as_raw_socket());
+        Ok(())
+    }
+
+    /// Writable interest isn't supported with IOCP yet, so neither is a send
+    /// low-water mark. Always returns an error of kind `Unsupported`.
+    pub fn register_with_send_lowat(
+        &self,
+        _soc: &mut TcpStream,
+        _token: usize,
+        _interests: Interests,
+        _send_lowat: usize,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Writable interest is not supported on Windows.",
+        ))
+    }
+
+    /// Cancels the I/O the stream has lent its buffers to and waits for it to stop, so
+    /// the stream can be dropped safely. The socket stays associated with the port,
+    /// since that can't be undone, so a completion that was already queued can still
+    /// show up as one last event with the old token. Data that was already received is
+    /// kept for the next read, and reads after that go straight to the socket.
+    pub fn deregister(&self, soc: &mut TcpStream) -> io::Result<()> {
+        soc.deregister()?;
+        self.registrations.remove(soc.as_raw_socket());
+        Ok(())
+    }
+
+    /// Associates a job object with our completion port so the job's notifications
+    /// (a process was added or exited, a memory limit was exceeded...) are returned
+    /// from `select` as events with `token` as their id. Use `Event::job_message` to
+    /// find out what happened.
+    ///
+    /// A job can only be associated with one completion port, and the association
+    /// can't be undone, so this should be done right after the job is created.
+    pub fn register_job(&self, job: &impl AsHandle, token: usize) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+
+        if token & ffi::KEY_TAG_MASK != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Token is too large to be used for a job object.",
+            ));
+        }
+
+        let job = job.as_handle().as_raw_handle();
+        debug!("registering job object {:?} with token {}", job, token);
+        self.stats.change();
+        ffi::associate_job_object(job as isize, self.port(), token | ffi::JOB_KEY)?;
+        // The association lasts as long as the job, so it's never removed
+        self.registrations
+            .insert(job as usize as u64, token, Interests::READABLE);
+        Ok(())
+    }
+
+    /// Nests `selector` in ours: an event with `token` as its id is returned from our
+    /// `select` whenever `selector` has events to select, so a library can keep its
+    /// sources on a private selector and only tell the application's loop that it has
+    /// something to do. A completion port can't be associated with another one, so a
+    /// thread dequeues `selector`'s completions and keeps them for its `select`, and
+    /// posts the event each time there were none waiting. Until `selector` is dropped,
+    /// its `select` waits for that thread rather than for the port, so it can't wait
+    /// alertably anymore.
+    ///
+    /// A selector can only be nested in one other selector, and only once.
+    pub fn register_selector(&self, selector: &Selector, token: usize) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+
+        if token & ffi::KEY_TAG_MASK != 0 || token | ffi::NESTED_KEY == ffi::BRIDGE_STOP_KEY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Token is too large to be used for a selector.",
+            ));
+        }
+
+        let mut queue = selector.bridge.queue();
+        if queue.notify.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "The selector is already nested in another one.",
+            ));
+        }
+
+        debug!(
+            "nesting completion port {} in port {} with token {}",
+            selector.port(),
+            self.port(),
+            token
+        );
+        let inner = selector.completion_port.clone();
+        let bridge = selector.bridge.clone();
+        let stats = selector.stats.clone();
+        thread::Builder::new()
+            .name("minimio-bridge".to_string())
+            .spawn(move || bridge_completions(&inner, &bridge, &stats))?;
+        queue.notify = Some((self.completion_port.clone(), token | ffi::NESTED_KEY));
+        self.registrations
+            .insert(selector.port() as u64, token, Interests::READABLE);
+        Ok(())
+    }
+
+    /// Arms `timer` to fire once after `timeout`. When it does, an event with `token` as
+    /// its id is returned from `select`. Registering a timer that is already armed
+    /// replaces the previous registration.
+    pub fn register_timer(
+        &self,
+        timer: &mut Timer,
+        token: usize,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+
+        if token & ffi::KEY_TAG_MASK != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Token is too large to be used for a timer.",
+            ));
+        }
+
+        trace!("arming timer with token {} to fire in {:?}", token, timeout);
+        timer.arm(self.port(), token, timeout, None)
+    }
+
+    /// Arms `timer` to fire every `period`, starting one `period` from now, until it's
+    /// registered again or dropped. Every tick returns an event with `token` as its id
+    /// from `select`. Ticks are scheduled from the first deadline rather than from when
+    /// the previous one was handled, so they don't drift. The period is rounded to
+    /// whole milliseconds.
+    pub fn register_timer_interval(
+        &self,
+        timer: &mut Timer,
+        token: usize,
+        period: Duration,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+
+        if token & ffi::KEY_TAG_MASK != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Token is too large to be used for a timer.",
+            ));
+        }
+
+        trace!(
+            "arming timer with token {} to fire every {:?}",
+            token,
+            period
+        );
+        timer.arm(self.port(), token, period, Some(period))
+    }
+
+    /// Starts waiting for clients to connect to the named pipe. Each client that
+    /// connects is reported as an event with `token` as its id, after which it can be
+    /// accepted with `NamedPipeListener::accept`.
+    pub fn register_pipe_listener(
+        &self,
+        listener: &mut NamedPipeListener,
+        token: usize,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+
+        if listener.state != PipeState::Idle {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Named pipe listener is already registered.",
+            ));
+        }
+
+        debug!("registering named pipe listener with token {}", token);
+        listener.connect(self.port(), token)
+    }
+
+    /// NOTE: An alternative solution is to use the `CompletionKey` to signal that
+    /// this is a close event. We don't use it for anything else so it is a
+    /// good candidate to use for timers and special events like this
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self
+            .is_poll_dead
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+        debug!("closing event loop on completion port {}", self.port());
+        let mut overlapped = ffi::WSAOVERLAPPED::zeroed();
+        ffi::post_queued_completion_status(self.port(), 0, 0, &mut overlapped)?;
+        Ok(())
+    }
+}
+
+/// How many completions the bridging thread of a nested selector dequeues at a time
+const BRIDGE_BATCH: usize = 64;
+
+/// What a selector nested with `Registrator::register_selector` shares with the
+/// thread that dequeues its completions.
+#[derive(Debug, Default)]
+struct Bridge {
+    queue: Mutex<BridgeQueue>,
+    cond: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct BridgeQueue {
+    /// Completions dequeued by the thread that `select` hasn't returned yet
+    entries: VecDeque<ffi::OVERLAPPED_ENTRY>,
+    /// The port of the selector we're nested in and the completion key to post to it,
+    /// once we are
+    notify: Option<(Arc<CompletionPort>, usize)>,
+}
+
+// The entries point to the `Operation`s of streams registered with the nested
+// selector, which only its `select` hands out, the same as without the thread.
+unsafe impl Send for BridgeQueue {}
+
+impl Bridge {
+    fn queue(&self) -> MutexGuard<'_, BridgeQueue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl BridgeQueue {
+    /// Tells the selector we're nested in that we have events
+    fn notify(&self) {
+        if let Some((port, key)) = &self.notify {
+            if let Err(e) = ffi::post_queued_completion_key(port.raw(), *key) {
+                debug!("notifying completion port {} failed: {}", port.raw(), e);
             }
         }
     }
+}
 
-    // You can find most of these here: https://docs.microsoft.com/en-us/windows/win32/winprog/windows-data-types
-    /// The HANDLE type is actually a `*mut c_void` but windows preserves backwards compatibility by allowing
-    /// a INVALID_HANDLE_VALUE which is `-1`. We can't express that in Rust so it's much easier for us to treat
-    /// this as an isize instead;
-    pub type HANDLE = isize;
-    pub type BOOL = bool;
-    pub type WORD = u16;
-    pub type DWORD = u32;
-    pub type ULONG = u32;
-    pub type PULONG = *mut ULONG;
-    pub type ULONG_PTR = *mut usize;
-    pub type PULONG_PTR = *mut ULONG_PTR;
-    pub type LPDWORD = *mut DWORD;
-    pub type LPWSABUF = *mut WSABUF;
-    pub type LPWSAOVERLAPPED = *mut WSAOVERLAPPED;
-    pub type LPWSAOVERLAPPED_COMPLETION_ROUTINE = *const extern "C" fn();
+/// Moves the completions of a nested selector's `port` to its `bridge` until the
+/// selector is dropped.
+fn bridge_completions(port: &CompletionPort, bridge: &Bridge, stats: &SyscallCounters) {
+    let port = port.raw();
+    let mut entries: Vec<ffi::OVERLAPPED_ENTRY> = Vec::with_capacity(BRIDGE_BATCH);
+    loop {
+        entries.clear();
+        stats.wait();
+        let removed = match ffi::get_queued_completion_status_ex(
+            port,
+            &mut entries,
+            BRIDGE_BATCH as u32,
+            None,
+            false,
+        ) {
+            Ok(n) => n,
+            Err(e) => {
+                debug!("bridging completion port {} failed: {}", port, e);
+                return;
+            }
+        };
+        unsafe { entries.set_len(removed as usize) };
+        if entries.iter().any(|entry| entry.is_bridge_stop()) {
+            trace!("stopped bridging completion port {}", port);
+            return;
+        }
 
-    // https://referencesource.microsoft.com/#System.Runtime.Remoting/channels/ipc/win32namedpipes.cs,edc09ced20442fea,references
-    // read this! https://devblogs.microsoft.com/oldnewthing/20040302-00/?p=40443
-    /// Defined in `win32.h` which you can find on your windows system
-    pub const INVALID_HANDLE_VALUE: HANDLE = -1;
+        let mut queue = bridge.queue();
+        let was_empty = queue.entries.is_empty();
+        queue.entries.extend(entries.drain(..));
+        bridge.cond.notify_all();
+        if was_empty {
+            queue.notify();
+        }
+    }
+}
 
-    // https://docs.microsoft.com/en-us/windows/win32/winsock/windows-sockets-error-codes-2
-    pub const WSA_IO_PENDING: i32 = 997;
+// possible Arc<InnerSelector> needed
+#[derive(Debug)]
+pub struct Selector {
+    completion_port: Arc<CompletionPort>,
+    recv_buffer_size: usize,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+    full_selects: FullSelects,
+    alertable: bool,
+    bridge: Arc<Bridge>,
+}
+
+impl Selector {
+    fn port(&self) -> ffi::HANDLE {
+        self.completion_port.raw()
+    }
+
+    pub fn new() -> io::Result<Self> {
+        Selector::with_recv_buffer_size(DEFAULT_RECV_BUFFER_SIZE)
+    }
+
+    /// Creates a selector whose registrators give every `TcpStream` that hasn't set
+    /// its own size a receive buffer of `size` bytes.
+    pub fn with_recv_buffer_size(size: usize) -> io::Result<Self> {
+        validate_recv_buffer_size(size)?;
+        Ok(Selector {
+            completion_port: Arc::new(CompletionPort::new()?),
+            recv_buffer_size: size,
+            registrations: Arc::default(),
+            stats: Arc::default(),
+            full_selects: FullSelects::default(),
+            alertable: false,
+            bridge: Arc::default(),
+        })
+    }
+
+    /// Makes `select` wait alertably, so APCs queued to the polling thread with
+    /// `QueueUserAPC`, and the completion routines of I/O started on it with
+    /// `ReadFileEx` or `WSARecv`, run while it waits instead of waiting for the thread
+    /// to enter an alertable state some other way. That's what hosts that deliver
+    /// their callbacks as APCs on the thread that owns the loop need. `select` returns
+    /// without events after running them, like a waker would make it. Our own sockets
+    /// keep completing through the port either way.
+    pub fn set_alertable(&mut self, alertable: bool) {
+        self.alertable = alertable;
+    }
+
+    /// Deregisters every socket registered with this selector or one of its
+    /// registrators by cancelling the `WSARecv` it has pending, so nothing it receives
+    /// is reported anymore. A handle stays associated with the port until it's
+    /// closed, so a completion that was already queued, or the one of a cancelled
+    /// `WSARecv`, can still show up as one last event with the old token. The next
+    /// read of a stream whose `WSARecv` was cancelled fails with `Interrupted`, and
+    /// reads after that go straight to the socket until it's registered again.
+    ///
+    /// Job objects and nested selectors can't be dissociated from the port, so they
+    /// keep reporting events. A socket dropped without being deregistered is still
+    /// on our list, so deregister sockets before dropping them, or this could cancel
+    /// the I/O of another socket that got the same handle.
+    pub fn clear(&self) -> io::Result<()> {
+        let mut res = Ok(());
+        for source in self.registrations.take() {
+            match ffi::cancel_io(source as ffi::HANDLE) {
+                Ok(()) => (),
+                // Nothing was pending, or it's a job object or a completion port,
+                // which have no I/O to cancel
+                Err(ref e)
+                    if e.raw_os_error() == Some(ffi::ERROR_NOT_FOUND)
+                        || e.raw_os_error() == Some(ffi::ERROR_INVALID_HANDLE) => {}
+                // Keep going, so one socket we can't cancel doesn't leave the rest
+                // registered
+                Err(e) => {
+                    if res.is_ok() {
+                        res = Err(e);
+                    }
+                }
+            }
+        }
+        debug!("cleared all registrations");
+        res
+    }
+
+    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
+        Registrator {
+            completion_port: self.completion_port.clone(),
+            is_poll_dead,
+            recv_buffer_size: self.recv_buffer_size,
+            registrations: self.registrations.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Each of those probably left events behind in the queue, so a count that
+    /// keeps growing means the loop isn't keeping up and should shed load or poll with
+    /// a larger buffer. 0 if the last select had room to spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+
+    /// Like `select`, with the timeout as a `Duration`. `GetQueuedCompletionStatusEx`
+    /// only takes milliseconds, so it's rounded up to the next one to never return
+    /// early.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        self.select(events, timeout.map(ceil_millis))
+    }
+
+    /// Blocks until an event has occured, or `timeout` milliseconds have passed. `None`
+    /// means it never times out. Like with the other backends this only needs `&self`:
+    /// the completion port keeps track of what's been queued, and each stream of the
+    /// buffers it has lent it.
+    pub fn select(&self, events: &mut Events, timeout: Option<i32>) -> io::Result<()> {
+        // calling GetQueueCompletionStatus will either return a handle to a "port" ready to read or
+        // block if the queue is empty.
+
+        // Windows want timeout as u32 so we cast it as such
+        let timeout = timeout.map(|t| t as u32);
+
+        // first let's clear events for any previous events and wait until we get som more
+        events.clear();
+        let queue = self.bridge.queue();
+        if queue.notify.is_some() {
+            return self.select_bridged(queue, events, timeout);
+        }
+        drop(queue);
+        let ul_count = events.capacity() as u32;
+
+        self.stats.wait();
+        let removed_res = ffi::get_queued_completion_status_ex(
+            self.port(),
+            events,
+            ul_count,
+            timeout,
+            self.alertable,
+        );
+
+        // We need to handle the case that the "error" was a WAIT_TIMEOUT error.
+        // the code for this error is 258 on Windows. We don't treat this as an error
+        // but set the events returned to 0.
+        // (i tried to do this in the `ffi` function but there was an error)
+        let removed = match removed_res {
+            Ok(n) => n,
+            Err(ref e) if e.raw_os_error() == Some(258) => 0,
+            // An alertable wait ran APCs or completion routines instead of dequeuing
+            Err(ref e) if e.raw_os_error() == Some(ffi::WAIT_IO_COMPLETION) => {
+                trace!("completion port {} ran queued APCs", self.port());
+                0
+            }
+            Err(e) => {
+                debug!(
+                    "GetQueuedCompletionStatusEx on port {} failed: {} (os error {:?})",
+                    self.port(),
+                    e,
+                    e.raw_os_error()
+                );
+                return Err(e);
+            }
+        };
+        trace!(
+            "completion port {} woke up with {} events",
+            self.port(),
+            removed
+        );
+
+        unsafe {
+            events.set_len(removed as usize);
+        }
+        self.full_selects
+            .record(removed as usize, ul_count as usize);
+
+        Ok(())
+    }
+
+    /// Selects the completions the bridging thread has dequeued for us once we're
+    /// nested in another selector.
+    fn select_bridged(
+        &self,
+        mut queue: MutexGuard<'_, BridgeQueue>,
+        events: &mut Events,
+        timeout: Option<u32>,
+    ) -> io::Result<()> {
+        let deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms as u64));
+        while queue.entries.is_empty() {
+            queue = match deadline {
+                None => self
+                    .bridge
+                    .cond
+                    .wait(queue)
+                    .unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    self.bridge
+                        .cond
+                        .wait_timeout(queue, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+        let n = queue.entries.len().min(events.capacity());
+        events.extend(queue.entries.drain(..n));
+        // The thread only tells the outer selector about completions it adds to an
+        // empty queue, so we do if we leave some behind
+        if !queue.entries.is_empty() {
+            queue.notify();
+        }
+        self.full_selects.record(n, events.capacity());
+        Ok(())
+    }
+}
+
+impl Drop for Selector {
+    fn drop(&mut self) {
+        if self.bridge.queue().notify.is_some() {
+            if let Err(e) = ffi::post_queued_completion_key(self.port(), ffi::BRIDGE_STOP_KEY) {
+                debug!("stopping the bridge of port {} failed: {}", self.port(), e);
+            }
+        }
+    }
+}
+
+/// The completion port, for backend calls the crate doesn't wrap yet. `select` takes
+/// every completion on the port for one of its own and reads the operation behind
+/// it, so don't dequeue from the port, or post or associate anything that queues
+/// completions of another kind.
+impl AsRawHandle for Selector {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.completion_port.as_raw_handle()
+    }
+}
+
+impl AsHandle for Selector {
+    fn as_handle(&self) -> BorrowedHandle<'_> {
+        self.completion_port.as_handle()
+    }
+}
+
+/// A named pipe server. Every client connects to its own instance of the pipe, so
+/// the listener always keeps one instance waiting in `ConnectNamedPipe` for the next
+/// client. Once registered, a connecting client is reported as an event with the
+/// listener's token and `accept` hands over the connected instance.
+///
+/// Reference: https://docs.microsoft.com/en-us/windows/win32/ipc/named-pipe-server-using-completion-routines
+#[derive(Debug)]
+pub struct NamedPipeListener {
+    name: Vec<u16>,
+    pipe: ffi::HANDLE,
+    operation: Box<ffi::Operation>,
+    state: PipeState,
+    registration: Option<(isize, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PipeState {
+    /// Waiting for a registration before we can start waiting for a client
+    Idle,
+    /// `ConnectNamedPipe` is in progress and owns our `Operation`
+    Pending,
+    /// A client has connected to the current instance
+    Connected,
+}
+
+impl NamedPipeListener {
+    /// Creates the first instance of the pipe `name`, which must be on the form
+    /// `\\.\pipe\pipename`. Fails if a pipe with this name already exists.
+    pub fn bind(name: &str) -> io::Result<Self> {
+        let name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+        let pipe = ffi::create_named_pipe(&name, true)?;
+
+        Ok(NamedPipeListener {
+            name,
+            pipe,
+            operation: Box::new(ffi::Operation::new(0)),
+            state: PipeState::Idle,
+            registration: None,
+        })
+    }
+
+    /// Returns the connected pipe if a client has connected, or an error of kind
+    /// `WouldBlock` if we're still waiting for one. A new instance is created to wait
+    /// for the next client.
+    pub fn accept(&mut self) -> io::Result<NamedPipe> {
+        if self.state == PipeState::Pending {
+            if !ffi::get_overlapped_result(self.pipe, &mut self.operation)? {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.state = PipeState::Connected;
+        }
+
+        if self.state != PipeState::Connected {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let next = ffi::create_named_pipe(&self.name, false)?;
+        let connected = NamedPipe {
+            handle: std::mem::replace(&mut self.pipe, next),
+        };
+        self.state = PipeState::Idle;
+
+        if let Some((completion_port, token)) = self.registration {
+            self.connect(completion_port, token)?;
+        }
+
+        Ok(connected)
+    }
+
+    fn connect(&mut self, completion_port: isize, token: usize) -> io::Result<()> {
+        ffi::create_io_completion_port(self.pipe as RawSocket, completion_port, 0)?;
+        self.registration = Some((completion_port, token));
+        // The previous operation is completed so nobody else is pointing to it
+        *self.operation = ffi::Operation::new(token);
+
+        if ffi::connect_named_pipe(self.pipe, &mut self.operation)? {
+            // A client connected between creating the instance and us calling
+            // `ConnectNamedPipe`. No completion is queued in that case so we post one
+            // ourselves to get the same event as otherwise.
+            self.state = PipeState::Connected;
+            ffi::post_operation(completion_port, &mut self.operation)?;
+        } else {
+            self.state = PipeState::Pending;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for NamedPipeListener {
+    fn drop(&mut self) {
+        if self.state == PipeState::Pending {
+            // The completion port might still hand out a pointer to the operation once
+            // the cancelled `ConnectNamedPipe` completes, so we leak it instead of
+            // letting `select` read freed memory.
+            let _ = ffi::cancel_io(self.pipe);
+            let operation =
+                std::mem::replace(&mut self.operation, Box::new(ffi::Operation::new(0)));
+            Box::leak(operation);
+        }
+
+        if let Err(e) = ffi::close_handle(self.pipe) {
+            if !std::thread::panicking() {
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+/// A pipe instance a client has connected to, returned by `NamedPipeListener::accept`.
+/// Reads and writes wait for the operation to finish and never post completions to
+/// the completion port.
+#[derive(Debug)]
+pub struct NamedPipe {
+    handle: ffi::HANDLE,
+}
+
+impl Read for NamedPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match ffi::read_file(self.handle, buf) {
+            // The client closing its end is the pipe's way of reporting EOF
+            Err(ref e) if e.raw_os_error() == Some(ffi::ERROR_BROKEN_PIPE) => Ok(0),
+            res => res,
+        }
+    }
+}
+
+impl Write for NamedPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        ffi::write_file(self.handle, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        ffi::flush_file_buffers(self.handle)
+    }
+}
+
+impl Drop for NamedPipe {
+    fn drop(&mut self) {
+        if let Err(e) = ffi::close_handle(self.handle) {
+            if !std::thread::panicking() {
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+/// A timer backed by a waitable timer object. Instead of keeping a helper thread
+/// sleeping until the deadline we ask the system thread pool to wait on the timer
+/// object for us, and the wait callback posts a completion to our port when it's
+/// signaled. The timer object is high resolution where Windows supports it, see
+/// `is_high_resolution`.
+///
+/// Reference: https://docs.microsoft.com/en-us/windows/win32/sync/waitable-timer-objects
+#[derive(Debug)]
+pub struct Timer {
+    handle: ffi::HANDLE,
+    /// Whether the timer object was created with
+    /// `CREATE_WAITABLE_TIMER_HIGH_RESOLUTION`
+    high_resolution: bool,
+    wait: Option<ffi::HANDLE>,
+    // The callback gets a pointer to this so it needs a stable address
+    context: Box<ffi::TimerContext>,
+    /// The period it was last armed with, so `reset` can keep an interval going
+    period: Option<Duration>,
+}
+
+impl Timer {
+    pub fn new() -> io::Result<Self> {
+        let (handle, high_resolution) = ffi::create_waitable_timer()?;
+        Ok(Timer {
+            handle,
+            high_resolution,
+            wait: None,
+            context: Box::new(ffi::TimerContext {
+                completion_port: 0,
+                completion_key: 0,
+            }),
+            period: None,
+        })
+    }
+
+    /// Whether the timer expires within a fraction of a millisecond of its deadline.
+    /// Timers are high resolution on Windows 10 1803 and later. Older versions only
+    /// look at their timers every tick of the system clock, which is 15.6 ms unless
+    /// something has asked for a faster one with `timeBeginPeriod`, so a timer can
+    /// expire up to a tick late. The period of an interval timer is in whole
+    /// milliseconds either way.
+    pub fn is_high_resolution(&self) -> bool {
+        self.high_resolution
+    }
+
+    /// Stops the timer. No callback of ours is running or will run once this returns,
+    /// but an expiration that has already been posted to the completion port is still
+    /// returned from `select`, so be prepared to see one more event with its token.
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.unregister_wait()?;
+        ffi::cancel_waitable_timer(self.handle)
+    }
+
+    /// Re-arms the timer to fire after `timeout` with the same token (and period, for
+    /// an interval timer) it was last registered with. Like `cancel`, this can't take
+    /// back an expiration that has already been posted.
+    pub fn reset(&mut self, timeout: Duration) -> io::Result<()> {
+        if self.context.completion_port == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Timer has not been registered.",
+            ));
+        }
+        let completion_port = self.context.completion_port;
+        let token = self.context.completion_key & !ffi::KEY_TAG_MASK;
+        self.arm(completion_port, token, timeout, self.period)
+    }
+
+    /// Arms the timer to fire after `timeout`, and then every `period` after that if
+    /// one is given.
+    fn arm(
+        &mut self,
+        completion_port: isize,
+        token: usize,
+        timeout: Duration,
+        period: Option<Duration>,
+    ) -> io::Result<()> {
+        let period_ms = match period {
+            Some(period) => interval_millis(period)?,
+            None => 0,
+        };
+
+        // We can't change the context while the thread pool might be reading it
+        self.unregister_wait()?;
+        self.context.completion_port = completion_port;
+        self.context.completion_key = token | ffi::TIMER_KEY;
+        self.period = period;
+
+        // A periodic waitable timer is re-armed by the kernel relative to its previous
+        // due time, not to when we got around to handling it, so it doesn't drift.
+        // It's a synchronization timer, so it resets itself when the wait completes
+        // and the thread pool goes back to waiting on it.
+        ffi::set_waitable_timer(self.handle, timeout, period_ms)?;
+        let context: *mut ffi::TimerContext = &mut *self.context;
+        self.wait = Some(ffi::register_wait(self.handle, context, period_ms == 0)?);
+        Ok(())
+    }
+
+    fn unregister_wait(&mut self) -> io::Result<()> {
+        match self.wait.take() {
+            // Blocks until a callback that's already running has returned
+            Some(wait) => ffi::unregister_wait(wait),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Converts the period of an interval timer to the whole milliseconds
+/// `SetWaitableTimer` takes. A period shorter than that is rounded up to 1 ms.
+fn interval_millis(period: Duration) -> io::Result<i32> {
+    if period == Duration::from_millis(0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The period of an interval timer must be larger than zero.",
+        ));
+    }
+    if period.as_millis() > i32::MAX as u128 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The period of an interval timer can't be longer than i32::MAX milliseconds.",
+        ));
+    }
+    Ok((period.as_millis() as i32).max(1))
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let res = self
+            .unregister_wait()
+            .and_then(|_| ffi::close_handle(self.handle));
+        if let Err(e) = res {
+            if !std::thread::panicking() {
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+/// A notification posted by a job object registered with `Registrator::register_job`.
+/// The value carried by most of the variants is the id of the process the message
+/// concerns.
+///
+/// Reference: https://docs.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-jobobject_associate_completion_port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobMessage {
+    EndOfJobTime,
+    EndOfProcessTime(u32),
+    ActiveProcessLimit,
+    ActiveProcessZero,
+    NewProcess(u32),
+    ExitProcess(u32),
+    AbnormalExitProcess(u32),
+    ProcessMemoryLimit(u32),
+    JobMemoryLimit(u32),
+    NotificationLimit,
+    /// A message we don't know about. Carries the raw message id.
+    Unknown(u32),
+}
+
+impl JobMessage {
+    fn from_raw(msg: u32, pid: u32) -> Self {
+        match msg {
+            ffi::JOB_OBJECT_MSG_END_OF_JOB_TIME => JobMessage::EndOfJobTime,
+            ffi::JOB_OBJECT_MSG_END_OF_PROCESS_TIME => JobMessage::EndOfProcessTime(pid),
+            ffi::JOB_OBJECT_MSG_ACTIVE_PROCESS_LIMIT => JobMessage::ActiveProcessLimit,
+            ffi::JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO => JobMessage::ActiveProcessZero,
+            ffi::JOB_OBJECT_MSG_NEW_PROCESS => JobMessage::NewProcess(pid),
+            ffi::JOB_OBJECT_MSG_EXIT_PROCESS => JobMessage::ExitProcess(pid),
+            ffi::JOB_OBJECT_MSG_ABNORMAL_EXIT_PROCESS => JobMessage::AbnormalExitProcess(pid),
+            ffi::JOB_OBJECT_MSG_PROCESS_MEMORY_LIMIT => JobMessage::ProcessMemoryLimit(pid),
+            ffi::JOB_OBJECT_MSG_JOB_MEMORY_LIMIT => JobMessage::JobMemoryLimit(pid),
+            ffi::JOB_OBJECT_MSG_NOTIFICATION_LIMIT => JobMessage::NotificationLimit,
+            other => JobMessage::Unknown(other),
+        }
+    }
+}
+
+// The names are the ones the Windows API docs use
+#[allow(clippy::upper_case_acronyms)]
+mod ffi {
+    use super::*;
+    use std::fmt;
+    use std::io;
+    use std::os::windows::io::RawSocket;
+    use std::ptr;
+
+    #[repr(C)]
+    #[derive(Clone, Debug)]
+    pub struct WSABUF {
+        len: u32,
+        buf: *mut u8,
+    }
+
+    impl WSABUF {
+        pub fn new(len: u32, buf: *mut u8) -> Self {
+            WSABUF { len, buf }
+        }
+    }
+
+    /// What `GetQueuedCompletionStatusEx` returns for each completion. We only keep the
+    /// pointers the OS gave us and never borrow from them, so entries can be stored in
+    /// an `Events` buffer that's reused for as long as we like.
+    #[repr(C)]
+    #[derive(Clone)]
+    pub struct OVERLAPPED_ENTRY {
+        lp_completion_key: *mut usize,
+        lp_overlapped: *mut WSAOVERLAPPED,
+        internal: usize,
+        bytes_transferred: u32,
+    }
+
+    impl OVERLAPPED_ENTRY {
+        pub fn id(&self) -> Token {
+            // Job objects and timers post their notifications with the completion key we
+            // gave them and no `Operation` behind `lp_overlapped`, so we can't dereference it.
+            let key = self.lp_completion_key as usize;
+            if key & KEY_TAG_MASK != 0 {
+                return key & !KEY_TAG_MASK;
+            }
+            // TODO: this might be solvable wihtout sacrifising so much of Rust safety guarantees
+            let operation: &Operation = unsafe { &*(self.lp_overlapped as *const Operation) };
+            operation.token
+        }
+
+        /// Returns the notification if this event was posted by a job object
+        /// registered with `Registrator::register_job`.
+        pub fn job_message(&self) -> Option<JobMessage> {
+            if self.lp_completion_key as usize & KEY_TAG_MASK != JOB_KEY {
+                return None;
+            }
+            // For job notifications `lp_overlapped` is not a pointer but the process id
+            let pid = self.lp_overlapped as usize as u32;
+            Some(JobMessage::from_raw(self.bytes_transferred, pid))
+        }
+
+        /// Returns how many bytes the operation behind this event transferred. For
+        /// sockets the data is already in the stream's buffer, waiting to be read.
+        pub fn bytes_transferred(&self) -> u32 {
+            if self.lp_completion_key as usize & KEY_TAG_MASK == JOB_KEY {
+                // For job notifications this field holds the message id
+                return 0;
+            }
+            self.bytes_transferred
+        }
+
+        /// Returns true if this event was posted by a `Timer` expiring.
+        pub fn is_timer(&self) -> bool {
+            self.lp_completion_key as usize & KEY_TAG_MASK == TIMER_KEY
+        }
+
+        /// Returns true if there's data to read, or a connection to accept, like the
+        /// event of the other backends. Every completion of a read is, and so is the
+        /// event of a nested selector. Job and timer notifications aren't.
+        pub fn is_readable(&self) -> bool {
+            let tag = self.lp_completion_key as usize & KEY_TAG_MASK;
+            tag == 0 || tag == NESTED_KEY
+        }
+
+        /// Always false, since writable interest isn't supported with IOCP yet. It's
+        /// here so handlers can check for both the same way on every platform.
+        pub fn is_writable(&self) -> bool {
+            false
+        }
+
+        /// Adds `other`, a completion for the same token, to this one. The data of
+        /// both is in the stream's buffer already, so only the byte counts add up. Job
+        /// and timer notifications carry more than that and aren't merged.
+        pub(crate) fn merge(&mut self, other: &OVERLAPPED_ENTRY) -> bool {
+            let tagged = |entry: &OVERLAPPED_ENTRY| {
+                entry.lp_completion_key as usize & KEY_TAG_MASK != 0
+                    || entry.lp_overlapped.is_null()
+            };
+            if tagged(self) || tagged(other) {
+                return false;
+            }
+            self.bytes_transferred = self
+                .bytes_transferred
+                .saturating_add(other.bytes_transferred);
+            true
+        }
+
+        /// A readable event for `token` that no `Operation` stands behind, for events
+        /// that are rebuilt after being held back. It's tagged like a nested
+        /// selector's, so `id` takes the token from the key, and carries no byte
+        /// count. IOCP events are never writable.
+        pub(crate) fn with_readiness(token: Token, _readable: bool, _writable: bool) -> Self {
+            OVERLAPPED_ENTRY {
+                lp_completion_key: (token | NESTED_KEY) as *mut usize,
+                ..OVERLAPPED_ENTRY::zeroed()
+            }
+        }
+
+        pub(crate) fn is_bridge_stop(&self) -> bool {
+            self.lp_completion_key as usize == BRIDGE_STOP_KEY
+        }
+
+        pub(crate) fn zeroed() -> Self {
+            OVERLAPPED_ENTRY {
+                lp_completion_key: ptr::null_mut(),
+                lp_overlapped: ptr::null_mut(),
+                internal: 0,
+                bytes_transferred: 0,
+            }
+        }
+    }
+
+    impl fmt::Debug for OVERLAPPED_ENTRY {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let key = self.lp_completion_key as usize;
+            let mut d = f.debug_struct("OVERLAPPED_ENTRY");
+            match key & KEY_TAG_MASK {
+                JOB_KEY => d
+                    .field("kind", &"job")
+                    .field("token", &self.id())
+                    .field("message", &self.job_message()),
+                TIMER_KEY => d.field("kind", &"timer").field("token", &self.id()),
+                NESTED_KEY => d.field("kind", &"nested").field("token", &self.id()),
+                // `close_loop` and zeroed entries don't point to an `Operation`
+                _ if self.lp_overlapped.is_null() => d.field("kind", &"wakeup"),
+                _ => d
+                    .field("kind", &"io")
+                    .field("token", &self.id())
+                    .field("bytes_transferred", &self.bytes_transferred),
+            };
+            d.field("completion_key", &format_args!("{:#x}", key))
+                .field("lp_overlapped", &self.lp_overlapped)
+                .finish()
+        }
+    }
+
+    // Reference: https://docs.microsoft.com/en-us/windows/win32/api/winsock2/ns-winsock2-wsaoverlapped
+    #[repr(C)]
+    #[derive(Debug)]
+    pub struct WSAOVERLAPPED {
+        /// Reserved for internal use
+        internal: ULONG_PTR,
+        /// Reserved
+        internal_high: ULONG_PTR,
+        /// Reserved for service providers
+        offset: DWORD,
+        /// Reserved for service providers
+        offset_high: DWORD,
+        /// If an overlapped I/O operation is issued without an I/O completion routine
+        /// (the operation's lpCompletionRoutine parameter is set to null), then this parameter
+        /// should either contain a valid handle to a WSAEVENT object or be null. If the
+        /// lpCompletionRoutine parameter of the call is non-null then applications are free
+        /// to use this parameter as necessary.
+        h_event: HANDLE,
+    }
+
+    impl WSAOVERLAPPED {
+        pub fn zeroed() -> Self {
+            WSAOVERLAPPED {
+                internal: ptr::null_mut(),
+                internal_high: ptr::null_mut(),
+                offset: 0,
+                offset_high: 0,
+                h_event: 0,
+            }
+        }
+    }
+
+    // https://docs.microsoft.com/en-us/windows/win32/api/ws2def/ns-ws2def-sockaddr_storage_lh
+    #[repr(C, align(8))]
+    pub struct SOCKADDR_STORAGE {
+        family: u16,
+        data: [u8; 126],
+    }
+
+    impl fmt::Debug for SOCKADDR_STORAGE {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("SOCKADDR_STORAGE")
+                .field("family", &self.family)
+                .finish()
+        }
+    }
+
+    impl SOCKADDR_STORAGE {
+        pub fn zeroed() -> Self {
+            SOCKADDR_STORAGE {
+                family: 0,
+                data: [0; 126],
+            }
+        }
+
+        /// Reads the `sockaddr_in` or `sockaddr_in6` the OS wrote into us. The port
+        /// comes first in both, in network byte order.
+        pub fn to_socket_addr(&self) -> io::Result<std::net::SocketAddr> {
+            let d = &self.data;
+            let port = u16::from_be_bytes([d[0], d[1]]);
+            match self.family {
+                AF_INET => {
+                    let ip = std::net::Ipv4Addr::new(d[2], d[3], d[4], d[5]);
+                    Ok(std::net::SocketAddrV4::new(ip, port).into())
+                }
+                AF_INET6 => {
+                    let flowinfo = u32::from_ne_bytes([d[2], d[3], d[4], d[5]]);
+                    let mut ip = [0; 16];
+                    ip.copy_from_slice(&d[6..22]);
+                    let scope_id = u32::from_ne_bytes([d[22], d[23], d[24], d[25]]);
+                    Ok(std::net::SocketAddrV6::new(ip.into(), port, flowinfo, scope_id).into())
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Datagram came from an address that isn't IPv4 or IPv6.",
+                )),
+            }
+        }
+    }
+
+    pub const AF_INET: u16 = 2;
+    pub const AF_INET6: u16 = 23;
+
+    /// Operation is a way for us to attach additional context to the `WSAOVERLAPPED`
+    /// event. Inpired by [BOOST ASIO](https://www.boost.org/doc/libs/1_42_0/boost/asio/detail/win_iocp_io_service.hpp)
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct Operation {
+        wsaoverlapped: WSAOVERLAPPED,
+        token: usize,
+    }
+
+    impl Operation {
+        pub(crate) fn new(token: usize) -> Self {
+            Operation {
+                wsaoverlapped: WSAOVERLAPPED::zeroed(),
+                token,
+            }
+        }
+
+        pub(crate) fn token(&self) -> usize {
+            self.token
+        }
+
+        pub(crate) fn set_token(&mut self, token: usize) {
+            self.token = token;
+        }
+
+        /// Prepares the operation to be used for a new overlapped call. Must not be
+        /// called while the previous one is in flight.
+        pub(crate) fn reset(&mut self, token: usize) {
+            self.wsaoverlapped = WSAOVERLAPPED::zeroed();
+            self.token = token;
+        }
+
+        pub(crate) fn overlapped_mut(&mut self) -> &mut WSAOVERLAPPED {
+            &mut self.wsaoverlapped
+        }
+
+        /// The `WSAOVERLAPPED` is the first field so a pointer to the `Operation` is a
+        /// valid pointer to the overlapped structure as well.
+        fn as_overlapped(&mut self) -> LPWSAOVERLAPPED {
+            let operation_ptr: *mut Operation = self;
+            operation_ptr as LPWSAOVERLAPPED
+        }
+    }
+
+    // You can find most of these here: https://docs.microsoft.com/en-us/windows/win32/winprog/windows-data-types
+    /// The HANDLE type is actually a `*mut c_void` but windows preserves backwards compatibility by allowing
+    /// a INVALID_HANDLE_VALUE which is `-1`. We can't express that in Rust so it's much easier for us to treat
+    /// this as an isize instead;
+    pub type HANDLE = isize;
+    pub type BOOL = bool;
+    pub type WORD = u16;
+    pub type DWORD = u32;
+    pub type ULONG = u32;
+    pub type PULONG = *mut ULONG;
+    pub type ULONG_PTR = *mut usize;
+    pub type PULONG_PTR = *mut ULONG_PTR;
+    pub type LPDWORD = *mut DWORD;
+    pub type LPWSABUF = *mut WSABUF;
+    pub type LPWSAOVERLAPPED = *mut WSAOVERLAPPED;
+    pub type LPWSAOVERLAPPED_COMPLETION_ROUTINE = *const extern "C" fn();
+
+    // https://referencesource.microsoft.com/#System.Runtime.Remoting/channels/ipc/win32namedpipes.cs,edc09ced20442fea,references
+    // read this! https://devblogs.microsoft.com/oldnewthing/20040302-00/?p=40443
+    /// Defined in `win32.h` which you can find on your windows system
+    pub const INVALID_HANDLE_VALUE: HANDLE = -1;
+
+    // https://docs.microsoft.com/en-us/windows/win32/winsock/windows-sockets-error-codes-2
+    pub const ERROR_INVALID_HANDLE: i32 = 6;
+    pub const ERROR_NOT_FOUND: i32 = 1168;
+    pub const WSA_IO_PENDING: i32 = 997;
+    pub const WSA_IO_INCOMPLETE: i32 = 996;
+    pub const WSA_OPERATION_ABORTED: i32 = 995;
+    pub const WSAEINTR: i32 = 10004;
+    pub const WSAEACCES: i32 = 10013;
+    pub const WSAEINVAL: i32 = 10022;
+    pub const WSAEWOULDBLOCK: i32 = 10035;
+    pub const WSAEINPROGRESS: i32 = 10036;
+    pub const WSAEALREADY: i32 = 10037;
+    pub const WSAEADDRINUSE: i32 = 10048;
+    pub const WSAEADDRNOTAVAIL: i32 = 10049;
+    pub const WSAENETDOWN: i32 = 10050;
+    pub const WSAENETUNREACH: i32 = 10051;
+    pub const WSAENETRESET: i32 = 10052;
+    pub const WSAECONNABORTED: i32 = 10053;
+    pub const WSAECONNRESET: i32 = 10054;
+    pub const WSAENOTCONN: i32 = 10057;
+    pub const WSAESHUTDOWN: i32 = 10058;
+    pub const WSAETIMEDOUT: i32 = 10060;
+    pub const WSAECONNREFUSED: i32 = 10061;
+    pub const WSAEHOSTUNREACH: i32 = 10065;
+    pub const IPPROTO_TCP: i32 = 6;
+    pub const TCP_MAXRT: i32 = 5;
+    /// `_WSAIORW(IOC_VENDOR, 39)`
+    pub const SIO_TCP_INFO: DWORD = 0xD800_0027;
+
+    // https://docs.microsoft.com/en-us/windows/win32/api/mstcpip/ns-mstcpip-tcp_info_v0
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct TCP_INFO_v0 {
+        pub state: i32,
+        pub mss: u32,
+        connection_time_ms: u64,
+        timestamps_enabled: u8,
+        pub rtt_us: u32,
+        min_rtt_us: u32,
+        bytes_in_flight: u32,
+        pub cwnd: u32,
+        snd_wnd: u32,
+        rcv_wnd: u32,
+        rcv_buf: u32,
+        bytes_out: u64,
+        bytes_in: u64,
+        bytes_reordered: u32,
+        bytes_retrans: u32,
+        pub fast_retrans: u32,
+        dup_acks_in: u32,
+        pub timeout_episodes: u32,
+        syn_retrans: u8,
+    }
+
+    const KEY_TAG_SHIFT: usize = std::mem::size_of::<usize>() * 8 - 2;
+    /// Sockets are registered with a completion key of 0. For everything else we use the
+    /// two highest bits of the completion key to tell what posted the completion and
+    /// store the token in the rest of the bits.
+    pub const KEY_TAG_MASK: usize = 0b11 << KEY_TAG_SHIFT;
+    pub const JOB_KEY: usize = 0b01 << KEY_TAG_SHIFT;
+    pub const TIMER_KEY: usize = 0b10 << KEY_TAG_SHIFT;
+    /// Posted by the thread bridging a nested selector to the port it's nested in
+    pub const NESTED_KEY: usize = 0b11 << KEY_TAG_SHIFT;
+    /// Posted to a nested selector's own port to stop its bridging thread
+    pub const BRIDGE_STOP_KEY: usize = usize::MAX;
+
+    // https://docs.microsoft.com/en-us/windows/win32/api/jobapi2/nf-jobapi2-setinformationjobobject
+    pub const JOB_OBJECT_ASSOCIATE_COMPLETION_PORT_INFORMATION: i32 = 7;
+    pub const JOB_OBJECT_MSG_END_OF_JOB_TIME: u32 = 1;
+    pub const JOB_OBJECT_MSG_END_OF_PROCESS_TIME: u32 = 2;
+    pub const JOB_OBJECT_MSG_ACTIVE_PROCESS_LIMIT: u32 = 3;
+    pub const JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO: u32 = 4;
+    pub const JOB_OBJECT_MSG_NEW_PROCESS: u32 = 6;
+    pub const JOB_OBJECT_MSG_EXIT_PROCESS: u32 = 7;
+    pub const JOB_OBJECT_MSG_ABNORMAL_EXIT_PROCESS: u32 = 8;
+    pub const JOB_OBJECT_MSG_PROCESS_MEMORY_LIMIT: u32 = 9;
+    pub const JOB_OBJECT_MSG_JOB_MEMORY_LIMIT: u32 = 10;
+    pub const JOB_OBJECT_MSG_NOTIFICATION_LIMIT: u32 = 11;
+
+    // https://docs.microsoft.com/en-us/windows/win32/debug/system-error-codes--0-499-
+    pub const ERROR_BROKEN_PIPE: i32 = 109;
+    pub const ERROR_PIPE_CONNECTED: i32 = 535;
+    pub const ERROR_IO_INCOMPLETE: i32 = 996;
+    pub const ERROR_IO_PENDING: i32 = 997;
+
+    // https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-createnamedpipea
+    pub const PIPE_ACCESS_DUPLEX: DWORD = 0x3;
+    pub const FILE_FLAG_FIRST_PIPE_INSTANCE: DWORD = 0x0008_0000;
+    pub const FILE_FLAG_OVERLAPPED: DWORD = 0x4000_0000;
+    pub const PIPE_TYPE_BYTE: DWORD = 0x0;
+    pub const PIPE_READMODE_BYTE: DWORD = 0x0;
+    pub const PIPE_WAIT: DWORD = 0x0;
+    pub const PIPE_REJECT_REMOTE_CLIENTS: DWORD = 0x8;
+    pub const PIPE_UNLIMITED_INSTANCES: DWORD = 255;
+    pub const PIPE_BUFFER_SIZE: DWORD = 4096;
+
+    // https://docs.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-createwaitabletimerexw
+    pub const TIMER_ALL_ACCESS: DWORD = 0x1F0003;
+    pub const CREATE_WAITABLE_TIMER_HIGH_RESOLUTION: DWORD = 0x0000_0002;
+    pub const ERROR_INVALID_PARAMETER: i32 = 87;
+    // https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-registerwaitforsingleobject
+    pub const WT_EXECUTEINWAITTHREAD: ULONG = 0x4;
+    pub const WT_EXECUTEONLYONCE: ULONG = 0x8;
+
+    /// What the thread pool hands our wait callback when a `Timer` is signaled.
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct TimerContext {
+        pub completion_port: HANDLE,
+        pub completion_key: usize,
+    }
+
+    pub type PAPCFUNC = extern "system" fn(data: usize);
+    pub type WAITORTIMERCALLBACK = extern "system" fn(context: *mut TimerContext, timer_fired: u8);
+
+    // https://docs.microsoft.com/en-us/windows/win32/api/winnt/ns-winnt-jobobject_associate_completion_port
+    #[repr(C)]
+    struct JOBOBJECT_ASSOCIATE_COMPLETION_PORT {
+        completion_key: ULONG_PTR,
+        completion_port: HANDLE,
+    }
+
+    // This can also be written as `4294967295` if you look at sources on the internet.
+    // Interpreted as an i32 the value is -1
+    // see for yourself: https://play.rust-lang.org/?version=stable&mode=debug&edition=2018&gist=4b93de7d7eb43fa9cd7f5b60933d8935
+    pub const INFINITE: u32 = 0xFFFFFFFF;
+    /// https://docs.microsoft.com/en-us/windows/win32/debug/system-error-codes--0-499-
+    /// The wait returned because APCs or completion routines were run
+    pub const WAIT_IO_COMPLETION: i32 = 192;
+
+    #[link(name = "Kernel32")]
+    extern "system" {
+
+        // https://docs.microsoft.com/en-us/windows/win32/fileio/createiocompletionport
+        fn CreateIoCompletionPort(
+            filehandle: HANDLE,
+            existing_completionport: HANDLE,
+            completion_key: ULONG_PTR,
+            number_of_concurrent_threads: DWORD,
+        ) -> HANDLE;
+        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-wsarecv
+        fn WSARecv(
+            s: RawSocket,
+            lpBuffers: LPWSABUF,
+            dwBufferCount: DWORD,
+            lpNumberOfBytesRecvd: LPDWORD,
+            lpFlags: LPDWORD,
+            lpOverlapped: LPWSAOVERLAPPED,
+            lpCompletionRoutine: LPWSAOVERLAPPED_COMPLETION_ROUTINE,
+        ) -> i32;
+        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-wsarecvfrom
+        fn WSARecvFrom(
+            s: RawSocket,
+            lpBuffers: LPWSABUF,
+            dwBufferCount: DWORD,
+            lpNumberOfBytesRecvd: LPDWORD,
+            lpFlags: LPDWORD,
+            lpFrom: *mut SOCKADDR_STORAGE,
+            lpFromlen: *mut i32,
+            lpOverlapped: LPWSAOVERLAPPED,
+            lpCompletionRoutine: LPWSAOVERLAPPED_COMPLETION_ROUTINE,
+        ) -> i32;
+        // https://docs.microsoft.com/en-us/windows/win32/fileio/postqueuedcompletionstatus
+        fn PostQueuedCompletionStatus(
+            CompletionPort: HANDLE,
+            dwNumberOfBytesTransferred: DWORD,
+            dwCompletionKey: ULONG_PTR,
+            lpOverlapped: LPWSAOVERLAPPED,
+        ) -> i32;
+        /// https://docs.microsoft.com/nb-no/windows/win32/api/ioapiset/nf-ioapiset-getqueuedcompletionstatus
+        /// Errors: https://docs.microsoft.com/nb-no/windows/win32/debug/system-error-codes--0-499-
+        /// From this we can see that error `WAIT_TIMEOUT` has the code 258 which we'll
+        /// need later on
+        fn GetQueuedCompletionStatusEx(
+            CompletionPort: HANDLE,
+            lpCompletionPortEntries: *mut OVERLAPPED_ENTRY,
+            ulCount: ULONG,
+            ulNumEntriesRemoved: PULONG,
+            dwMilliseconds: DWORD,
+            fAlertable: BOOL,
+        ) -> i32;
+
+        fn GetQueuedCompletionStatus(
+            CompletionPort: HANDLE,
+            lpNumberOfBytesTransferred: LPDWORD,
+            lpCompletionKey: PULONG_PTR,
+            lpOverlapped: LPWSAOVERLAPPED,
+            dwMilliseconds: DWORD,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-createnamedpipew
+        fn CreateNamedPipeW(
+            lpName: *const u16,
+            dwOpenMode: DWORD,
+            dwPipeMode: DWORD,
+            nMaxInstances: DWORD,
+            nOutBufferSize: DWORD,
+            nInBufferSize: DWORD,
+            nDefaultTimeOut: DWORD,
+            lpSecurityAttributes: *mut usize,
+        ) -> HANDLE;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/namedpipeapi/nf-namedpipeapi-connectnamedpipe
+        fn ConnectNamedPipe(hNamedPipe: HANDLE, lpOverlapped: LPWSAOVERLAPPED) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-readfile
+        fn ReadFile(
+            hFile: HANDLE,
+            lpBuffer: *mut u8,
+            nNumberOfBytesToRead: DWORD,
+            lpNumberOfBytesRead: LPDWORD,
+            lpOverlapped: LPWSAOVERLAPPED,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-writefile
+        fn WriteFile(
+            hFile: HANDLE,
+            lpBuffer: *const u8,
+            nNumberOfBytesToWrite: DWORD,
+            lpNumberOfBytesWritten: LPDWORD,
+            lpOverlapped: LPWSAOVERLAPPED,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-flushfilebuffers
+        fn FlushFileBuffers(hFile: HANDLE) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/ioapiset/nf-ioapiset-getoverlappedresult
+        fn GetOverlappedResult(
+            hFile: HANDLE,
+            lpOverlapped: LPWSAOVERLAPPED,
+            lpNumberOfBytesTransferred: LPDWORD,
+            bWait: BOOL,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/fileio/cancelioex-func
+        fn CancelIoEx(hFile: HANDLE, lpOverlapped: LPWSAOVERLAPPED) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-createeventw
+        fn CreateEventW(
+            lpEventAttributes: *mut usize,
+            bManualReset: BOOL,
+            bInitialState: BOOL,
+            lpName: *const u16,
+        ) -> HANDLE;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-createwaitabletimerexw
+        fn CreateWaitableTimerExW(
+            lpTimerAttributes: *mut usize,
+            lpTimerName: *const u16,
+            dwFlags: DWORD,
+            dwDesiredAccess: DWORD,
+        ) -> HANDLE;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-setwaitabletimer
+        fn SetWaitableTimer(
+            hTimer: HANDLE,
+            lpDueTime: *const i64,
+            lPeriod: i32,
+            pfnCompletionRoutine: *mut usize,
+            lpArgToCompletionRoutine: *mut usize,
+            fResume: BOOL,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/synchapi/nf-synchapi-cancelwaitabletimer
+        fn CancelWaitableTimer(hTimer: HANDLE) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-registerwaitforsingleobject
+        fn RegisterWaitForSingleObject(
+            phNewWaitObject: *mut HANDLE,
+            hObject: HANDLE,
+            Callback: WAITORTIMERCALLBACK,
+            Context: *mut TimerContext,
+            dwMilliseconds: ULONG,
+            dwFlags: ULONG,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/sync/unregisterwaitex
+        fn UnregisterWaitEx(WaitHandle: HANDLE, CompletionEvent: HANDLE) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/jobapi2/nf-jobapi2-setinformationjobobject
+        fn SetInformationJobObject(
+            hJob: HANDLE,
+            JobObjectInformationClass: i32,
+            lpJobObjectInformation: *mut JOBOBJECT_ASSOCIATE_COMPLETION_PORT,
+            cbJobObjectInformationLength: DWORD,
+        ) -> i32;
+
+        // https://docs.microsoft.com/nb-no/windows/win32/api/handleapi/nf-handleapi-closehandle
+        fn CloseHandle(hObject: HANDLE) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-queueuserapc
+        fn QueueUserAPC(pfnAPC: PAPCFUNC, hThread: HANDLE, dwData: usize) -> DWORD;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/processthreadsapi/nf-processthreadsapi-getcurrentthread
+        fn GetCurrentThread() -> HANDLE;
+
+        // https://docs.microsoft.com/nb-no/windows/win32/api/winsock/nf-winsock-wsagetlasterror
+        fn WSAGetLastError() -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-wsagetoverlappedresult
+        fn WSAGetOverlappedResult(
+            s: RawSocket,
+            lpOverlapped: LPWSAOVERLAPPED,
+            lpcbTransfer: LPDWORD,
+            fWait: i32,
+            lpdwFlags: LPDWORD,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/winsock/nf-winsock-setsockopt
+        fn setsockopt(
+            s: RawSocket,
+            level: i32,
+            optname: i32,
+            optval: *const u8,
+            optlen: i32,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-wsaioctl
+        fn WSAIoctl(
+            s: RawSocket,
+            dwIoControlCode: DWORD,
+            lpvInBuffer: *const u8,
+            cbInBuffer: DWORD,
+            lpvOutBuffer: *mut u8,
+            cbOutBuffer: DWORD,
+            lpcbBytesReturned: LPDWORD,
+            lpOverlapped: LPWSAOVERLAPPED,
+            lpCompletionRoutine: LPWSAOVERLAPPED_COMPLETION_ROUTINE,
+        ) -> i32;
+
+        // https://docs.microsoft.com/en-us/windows/win32/api/winsock/nf-winsock-getsockopt
+        fn getsockopt(
+            s: RawSocket,
+            level: i32,
+            optname: i32,
+            optval: *mut u8,
+            optlen: *mut i32,
+        ) -> i32;
+    }
+
+    // ===== SAFE WRAPPERS =====
+
+    pub fn set_int_option(s: RawSocket, level: i32, name: i32, value: i32) -> io::Result<()> {
+        let res = unsafe {
+            setsockopt(
+                s,
+                level,
+                name,
+                &value as *const i32 as *const u8,
+                std::mem::size_of::<i32>() as i32,
+            )
+        };
+        if res != 0 {
+            return Err(wsa_error(unsafe { WSAGetLastError() }));
+        }
+        Ok(())
+    }
+
+    /// Asks for version 0 of `TCP_INFO`, which every Windows that has it supports.
+    pub fn tcp_info(s: RawSocket) -> io::Result<TCP_INFO_v0> {
+        let version: DWORD = 0;
+        let mut info = TCP_INFO_v0::default();
+        let mut returned: DWORD = 0;
+        let res = unsafe {
+            WSAIoctl(
+                s,
+                SIO_TCP_INFO,
+                &version as *const DWORD as *const u8,
+                std::mem::size_of::<DWORD>() as DWORD,
+                &mut info as *mut TCP_INFO_v0 as *mut u8,
+                std::mem::size_of::<TCP_INFO_v0>() as DWORD,
+                &mut returned,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if res != 0 {
+            return Err(wsa_error(unsafe { WSAGetLastError() }));
+        }
+        Ok(info)
+    }
+
+    pub fn get_int_option(s: RawSocket, level: i32, name: i32) -> io::Result<i32> {
+        let mut value = 0i32;
+        let mut len = std::mem::size_of::<i32>() as i32;
+        let res =
+            unsafe { getsockopt(s, level, name, &mut value as *mut i32 as *mut u8, &mut len) };
+        if res != 0 {
+            return Err(wsa_error(unsafe { WSAGetLastError() }));
+        }
+        Ok(value)
+    }
+
+    /// Turns a Winsock error code into an `io::Error` with the same `ErrorKind` std and
+    /// the Unix backends report for the same condition, so users can handle errors
+    /// like `WouldBlock` or `ConnectionReset` the same way on every platform. The
+    /// original os error is kept as the inner error so the message isn't lost.
+    pub fn wsa_error(code: i32) -> io::Error {
+        let kind = match code {
+            WSAEWOULDBLOCK | WSAEINPROGRESS | WSAEALREADY | WSA_IO_INCOMPLETE => {
+                io::ErrorKind::WouldBlock
+            }
+            WSAEINTR | WSA_OPERATION_ABORTED => io::ErrorKind::Interrupted,
+            WSAEACCES => io::ErrorKind::PermissionDenied,
+            WSAEINVAL => io::ErrorKind::InvalidInput,
+            WSAEADDRINUSE => io::ErrorKind::AddrInUse,
+            WSAEADDRNOTAVAIL => io::ErrorKind::AddrNotAvailable,
+            WSAENETDOWN => io::ErrorKind::NetworkDown,
+            WSAENETUNREACH => io::ErrorKind::NetworkUnreachable,
+            WSAEHOSTUNREACH => io::ErrorKind::HostUnreachable,
+            WSAECONNABORTED => io::ErrorKind::ConnectionAborted,
+            WSAECONNRESET | WSAENETRESET => io::ErrorKind::ConnectionReset,
+            WSAECONNREFUSED => io::ErrorKind::ConnectionRefused,
+            WSAENOTCONN => io::ErrorKind::NotConnected,
+            WSAESHUTDOWN => io::ErrorKind::BrokenPipe,
+            WSAETIMEDOUT => io::ErrorKind::TimedOut,
+            _ => return io::Error::from_raw_os_error(code),
+        };
+        io::Error::new(kind, io::Error::from_raw_os_error(code))
+    }
+
+    /// Returns the last Winsock error of the calling thread as an `io::Error`.
+    pub fn last_wsa_error() -> io::Error {
+        wsa_error(unsafe { WSAGetLastError() })
+    }
+
+    /// Queues `apc` to run on the calling thread the next time it waits alertably.
+    #[cfg(test)]
+    pub fn queue_user_apc(apc: PAPCFUNC, data: usize) -> io::Result<()> {
+        // The pseudo handle of the current thread doesn't need closing
+        let res = unsafe { QueueUserAPC(apc, GetCurrentThread(), data) };
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn close_handle(handle: isize) -> io::Result<()> {
+        let res = unsafe { CloseHandle(handle) };
+
+        if res == 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn create_completion_port() -> io::Result<HANDLE> {
+        unsafe {
+            // number_of_concurrent_threads = 0 means use the number of physical threads but the argument is
+            // ignored when existing_completionport is set to null.
+            let res = CreateIoCompletionPort(INVALID_HANDLE_VALUE, 0, ptr::null_mut(), 0);
+            if (res as *mut usize).is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(res)
+        }
+    }
+
+    /// Returns the file handle to the completion port we passed in
+    pub fn create_io_completion_port(
+        s: RawSocket,
+        completion_port: isize,
+        token: usize,
+    ) -> io::Result<isize> {
+        let res =
+            unsafe { CreateIoCompletionPort(s as isize, completion_port, token as *mut usize, 0) };
+
+        if (res as *mut usize).is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(res)
+    }
+
+    /// Tells the job object to post its notifications to `completion_port` using
+    /// `completion_key` as the key.
+    pub fn associate_job_object(
+        job: HANDLE,
+        completion_port: isize,
+        completion_key: usize,
+    ) -> io::Result<()> {
+        let mut info = JOBOBJECT_ASSOCIATE_COMPLETION_PORT {
+            completion_key: completion_key as *mut usize,
+            completion_port,
+        };
+
+        let res = unsafe {
+            SetInformationJobObject(
+                job,
+                JOB_OBJECT_ASSOCIATE_COMPLETION_PORT_INFORMATION,
+                &mut info,
+                std::mem::size_of::<JOBOBJECT_ASSOCIATE_COMPLETION_PORT>() as DWORD,
+            )
+        };
+
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Creates a new instance of the named pipe `name` (a null terminated UTF-16 string)
+    /// opened for overlapped I/O.
+    pub fn create_named_pipe(name: &[u16], first_instance: bool) -> io::Result<HANDLE> {
+        let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+        if first_instance {
+            open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+
+        let res = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                open_mode,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                PIPE_BUFFER_SIZE,
+                PIPE_BUFFER_SIZE,
+                0,
+                ptr::null_mut(),
+            )
+        };
+
+        if res == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(res)
+    }
+
+    /// Starts waiting for a client to connect to the pipe instance.
+    /// ## Returns
+    /// `true` if a client was already connected, in which case no completion is queued.
+    pub fn connect_named_pipe(pipe: HANDLE, op: &mut Operation) -> io::Result<bool> {
+        let res = unsafe { ConnectNamedPipe(pipe, op.as_overlapped()) };
+        if res != 0 {
+            return Ok(false);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(ERROR_IO_PENDING) => Ok(false),
+            Some(ERROR_PIPE_CONNECTED) => Ok(true),
+            _ => Err(err),
+        }
+    }
+
+    /// Checks if the overlapped operation on `handle` has completed without waiting.
+    pub fn get_overlapped_result(handle: HANDLE, op: &mut Operation) -> io::Result<bool> {
+        let mut transferred: DWORD = 0;
+        let res =
+            unsafe { GetOverlappedResult(handle, op.as_overlapped(), &mut transferred, false) };
+        if res != 0 {
+            return Ok(true);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(ERROR_IO_INCOMPLETE) => Ok(false),
+            _ => Err(err),
+        }
+    }
+
+    /// Cancels `op` if it's still in flight. It completes with `WSA_OPERATION_ABORTED`.
+    pub fn cancel_operation(s: RawSocket, op: &mut Operation) -> io::Result<()> {
+        let res = unsafe { CancelIoEx(s as HANDLE, op.as_overlapped()) };
+        if res == 0 {
+            let e = io::Error::last_os_error();
+            // It has completed already
+            if e.raw_os_error() == Some(ERROR_NOT_FOUND) {
+                return Ok(());
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn cancel_io(handle: HANDLE) -> io::Result<()> {
+        let res = unsafe { CancelIoEx(handle, ptr::null_mut()) };
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Queues a completion for `op` just as if the OS had completed it.
+    pub fn post_operation(completion_port: isize, op: &mut Operation) -> io::Result<()> {
+        let res = unsafe {
+            PostQueuedCompletionStatus(completion_port, 0, ptr::null_mut(), op.as_overlapped())
+        };
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs an overlapped `ReadFile`/`WriteFile` on `handle` and waits for it to finish.
+    /// Setting the low-order bit of `hEvent` tells the OS not to queue a completion to
+    /// the port the handle is associated with, since nobody is waiting for it there.
+    fn wait_overlapped(
+        handle: HANDLE,
+        f: impl FnOnce(LPWSAOVERLAPPED) -> i32,
+    ) -> io::Result<usize> {
+        let event = unsafe { CreateEventW(ptr::null_mut(), true, false, ptr::null()) };
+        if (event as *mut usize).is_null() {
+            return Err(io::Error::last_os_error());
+        }
 
-    // This can also be written as `4294967295` if you look at sources on the internet.
-    // Interpreted as an i32 the value is -1
-    // see for yourself: https://play.rust-lang.org/?version=stable&mode=debug&edition=2018&gist=4b93de7d7eb43fa9cd7f5b60933d8935
-    pub const INFINITE: u32 = 0xFFFFFFFF;
+        let mut overlapped = WSAOVERLAPPED::zeroed();
+        overlapped.h_event = event | 1;
 
-    #[link(name = "Kernel32")]
-    extern "stdcall" {
+        let mut transferred: DWORD = 0;
+        let started = f(&mut overlapped as LPWSAOVERLAPPED) != 0
+            || io::Error::last_os_error().raw_os_error() == Some(ERROR_IO_PENDING);
+        // Either failing leaves the reason in the thread's last error
+        let res = if !started
+            || unsafe { GetOverlappedResult(handle, &mut overlapped, &mut transferred, true) } == 0
+        {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(transferred as usize)
+        };
 
-        // https://docs.microsoft.com/en-us/windows/win32/fileio/createiocompletionport
-        fn CreateIoCompletionPort(
-            filehandle: HANDLE,
-            existing_completionport: HANDLE,
-            completion_key: ULONG_PTR,
-            number_of_concurrent_threads: DWORD,
-        ) -> HANDLE;
-        // https://docs.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-wsarecv
-        fn WSARecv(
-            s: RawSocket,
-            lpBuffers: LPWSABUF,
-            dwBufferCount: DWORD,
-            lpNumberOfBytesRecvd: LPDWORD,
-            lpFlags: LPDWORD,
-            lpOverlapped: LPWSAOVERLAPPED,
-            lpCompletionRoutine: LPWSAOVERLAPPED_COMPLETION_ROUTINE,
-        ) -> i32;
-        // https://docs.microsoft.com/en-us/windows/win32/fileio/postqueuedcompletionstatus
-        fn PostQueuedCompletionStatus(
-            CompletionPort: HANDLE,
-            dwNumberOfBytesTransferred: DWORD,
-            dwCompletionKey: ULONG_PTR,
-            lpOverlapped: LPWSAOVERLAPPED,
-        ) -> i32;
-        /// https://docs.microsoft.com/nb-no/windows/win32/api/ioapiset/nf-ioapiset-getqueuedcompletionstatus
-        /// Errors: https://docs.microsoft.com/nb-no/windows/win32/debug/system-error-codes--0-499-
-        /// From this we can see that error `WAIT_TIMEOUT` has the code 258 which we'll
-        /// need later on
-        fn GetQueuedCompletionStatusEx(
-            CompletionPort: HANDLE,
-            lpCompletionPortEntries: *mut OVERLAPPED_ENTRY,
-            ulCount: ULONG,
-            ulNumEntriesRemoved: PULONG,
-            dwMilliseconds: DWORD,
-            fAlertable: BOOL,
-        ) -> i32;
+        close_handle(event)?;
+        res
+    }
 
-        fn GetQueuedCompletionStatus(
-            CompletionPort: HANDLE,
-            lpNumberOfBytesTransferred: LPDWORD,
-            lpCompletionKey: PULONG_PTR,
-            lpOverlapped: LPWSAOVERLAPPED,
-            dwMilliseconds: DWORD,
-        ) -> i32;
+    pub fn read_file(handle: HANDLE, buf: &mut [u8]) -> io::Result<usize> {
+        wait_overlapped(handle, |overlapped| unsafe {
+            ReadFile(
+                handle,
+                buf.as_mut_ptr(),
+                buf.len() as DWORD,
+                ptr::null_mut(),
+                overlapped,
+            )
+        })
+    }
 
-        // https://docs.microsoft.com/nb-no/windows/win32/api/handleapi/nf-handleapi-closehandle
-        fn CloseHandle(hObject: HANDLE) -> i32;
+    pub fn write_file(handle: HANDLE, buf: &[u8]) -> io::Result<usize> {
+        wait_overlapped(handle, |overlapped| unsafe {
+            WriteFile(
+                handle,
+                buf.as_ptr(),
+                buf.len() as DWORD,
+                ptr::null_mut(),
+                overlapped,
+            )
+        })
+    }
 
-        // https://docs.microsoft.com/nb-no/windows/win32/api/winsock/nf-winsock-wsagetlasterror
-        fn WSAGetLastError() -> i32;
+    pub fn flush_file_buffers(handle: HANDLE) -> io::Result<()> {
+        let res = unsafe { FlushFileBuffers(handle) };
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
     }
 
-    // ===== SAFE WRAPPERS =====
+    /// Creates a high resolution timer, or a regular one on versions of Windows that
+    /// don't have them and reject the flag. Returns whether it's high resolution.
+    pub fn create_waitable_timer() -> io::Result<(HANDLE, bool)> {
+        let create = |flags| unsafe {
+            CreateWaitableTimerExW(ptr::null_mut(), ptr::null(), flags, TIMER_ALL_ACCESS)
+        };
+        let res = create(CREATE_WAITABLE_TIMER_HIGH_RESOLUTION);
+        if !(res as *mut usize).is_null() {
+            return Ok((res, true));
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(ERROR_INVALID_PARAMETER) {
+            return Err(err);
+        }
+        debug!("high resolution timers are not supported, falling back to a regular one");
+        let res = create(0);
+        if (res as *mut usize).is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((res, false))
+    }
 
-    pub fn close_handle(handle: isize) -> io::Result<()> {
-        let res = unsafe { CloseHandle(handle) };
+    /// Arms the timer to be signaled after `timeout`, and then every `period_ms`
+    /// milliseconds if that isn't 0.
+    pub fn set_waitable_timer(
+        timer: HANDLE,
+        timeout: std::time::Duration,
+        period_ms: i32,
+    ) -> io::Result<()> {
+        // The due time is given in 100 nanosecond intervals where a negative value
+        // means a time relative to now.
+        let due_time = -((timeout.as_nanos() / 100) as i64);
+        let res = unsafe {
+            SetWaitableTimer(
+                timer,
+                &due_time,
+                period_ms,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                false,
+            )
+        };
 
         if res == 0 {
-            Err(std::io::Error::last_os_error().into())
+            Err(io::Error::last_os_error())
         } else {
             Ok(())
         }
     }
 
-    pub fn create_completion_port() -> io::Result<isize> {
-        unsafe {
-            // number_of_concurrent_threads = 0 means use the number of physical threads but the argument is
-            // ignored when existing_completionport is set to null.
-            let res = CreateIoCompletionPort(INVALID_HANDLE_VALUE, 0, ptr::null_mut(), 0);
-            if (res as *mut usize).is_null() {
-                return Err(std::io::Error::last_os_error());
-            }
+    /// Deactivates the timer so it won't be signaled again until it's set.
+    pub fn cancel_waitable_timer(timer: HANDLE) -> io::Result<()> {
+        let res = unsafe { CancelWaitableTimer(timer) };
 
-            Ok(res)
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
         }
     }
 
-    /// Returns the file handle to the completion port we passed in
-    pub fn create_io_completion_port(
-        s: RawSocket,
-        completion_port: isize,
-        token: usize,
-    ) -> io::Result<isize> {
-        let res =
-            unsafe { CreateIoCompletionPort(s as isize, completion_port, token as *mut usize, 0) };
+    /// Called on a thread pool thread when the timer is signaled. All we do is to
+    /// post a completion to the port that the `Selector` is waiting on.
+    extern "system" fn timer_callback(context: *mut TimerContext, _timer_fired: u8) {
+        let context = unsafe { &*context };
+        // There is nobody to report an error to here. If the port is closed the
+        // selector is gone and nobody is waiting for the event anyway.
+        let _ = post_queued_completion_key(context.completion_port, context.completion_key);
+    }
 
-        if (res as *mut usize).is_null() {
-            return Err(std::io::Error::last_os_error());
+    /// Asks the system thread pool to wait for `object` to be signaled and then run
+    /// our callback with `context`. Unless `once` is set the wait stays registered and
+    /// the callback runs every time the object is signaled.
+    pub fn register_wait(
+        object: HANDLE,
+        context: *mut TimerContext,
+        once: bool,
+    ) -> io::Result<HANDLE> {
+        let mut wait: HANDLE = 0;
+        let flags = if once {
+            WT_EXECUTEINWAITTHREAD | WT_EXECUTEONLYONCE
+        } else {
+            WT_EXECUTEINWAITTHREAD
+        };
+        let res = unsafe {
+            RegisterWaitForSingleObject(&mut wait, object, timer_callback, context, INFINITE, flags)
+        };
+
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(wait)
         }
+    }
 
-        Ok(res)
+    /// Cancels a wait registered with `register_wait` and blocks until any callback
+    /// that is already running has completed.
+    pub fn unregister_wait(wait: HANDLE) -> io::Result<()> {
+        let res = unsafe { UnregisterWaitEx(wait, INVALID_HANDLE_VALUE) };
+
+        if res == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
     }
 
     /// Creates a socket read event.
@@ -458,7 +2863,7 @@ mod ffi {
             WSARecv(
                 s,
                 wsabuffers.as_mut_ptr(),
-                1,
+                wsabuffers.len() as u32,
                 ptr::null_mut(),
                 &mut flags,
                 operation_ptr as *mut WSAOVERLAPPED,
@@ -471,7 +2876,7 @@ mod ffi {
                 // Everything is OK, and we can wait this with GetQueuedCompletionStatus
                 Ok(())
             } else {
-                Err(std::io::Error::last_os_error())
+                Err(wsa_error(err))
             }
         } else {
             // The socket is already ready so we don't need to queue it
@@ -480,6 +2885,66 @@ mod ffi {
         }
     }
 
+    /// Like `wsa_recv`, but also has the OS fill in the address the datagram came
+    /// from. `flags`, `from` and `from_len` must stay valid until the operation is done.
+    pub fn wsa_recv_from(
+        s: RawSocket,
+        wsabuffers: &mut [WSABUF],
+        flags: &mut DWORD,
+        from: &mut SOCKADDR_STORAGE,
+        from_len: &mut i32,
+        op: &mut Operation,
+    ) -> io::Result<()> {
+        let res = unsafe {
+            WSARecvFrom(
+                s,
+                wsabuffers.as_mut_ptr(),
+                wsabuffers.len() as u32,
+                ptr::null_mut(),
+                flags,
+                from,
+                from_len,
+                op.as_overlapped(),
+                ptr::null_mut(),
+            )
+        };
+        if res != 0 {
+            let err = unsafe { WSAGetLastError() };
+            if err != WSA_IO_PENDING {
+                return Err(wsa_error(err));
+            }
+        }
+        // Even if it completed right away the completion is queued to the port
+        Ok(())
+    }
+
+    /// Returns how many bytes the overlapped operation received, or an error of kind
+    /// `WouldBlock` if it hasn't completed yet. Never waits for it to complete.
+    /// Returns how many bytes the operation transferred. If it's still in flight this
+    /// blocks until it's done if `wait` is set, and returns `WouldBlock` if not.
+    pub fn wsa_get_overlapped_result(
+        s: RawSocket,
+        op: &mut Operation,
+        wait: bool,
+    ) -> io::Result<u32> {
+        let mut transferred = 0;
+        let mut flags = 0;
+        let res = unsafe {
+            WSAGetOverlappedResult(
+                s,
+                op.as_overlapped(),
+                &mut transferred,
+                wait as i32,
+                &mut flags,
+            )
+        };
+        if res == 0 {
+            Err(last_wsa_error())
+        } else {
+            Ok(transferred)
+        }
+    }
+
     pub fn post_queued_completion_status(
         completion_port: isize,
         bytes_to_transfer: u32,
@@ -495,7 +2960,27 @@ mod ffi {
             )
         };
         if res == 0 {
-            Err(std::io::Error::last_os_error().into())
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Posts a completion that only carries a completion key, no `OVERLAPPED` structure.
+    pub fn post_queued_completion_key(
+        completion_port: isize,
+        completion_key: usize,
+    ) -> io::Result<()> {
+        let res = unsafe {
+            PostQueuedCompletionStatus(
+                completion_port,
+                0,
+                completion_key as *mut usize,
+                ptr::null_mut(),
+            )
+        };
+        if res == 0 {
+            Err(std::io::Error::last_os_error())
         } else {
             Ok(())
         }
@@ -507,9 +2992,9 @@ mod ffi {
     /// - *ul_count:* The maximum number of entries to remove
     /// - *timeout:* The timeout in milliseconds, if set to NONE, timeout is set to INFINITE
     /// - *alertable:* If this parameter is FALSE, the function does not return until the time-out period has elapsed or
-    /// an entry is retrieved. If the parameter is TRUE and there are no available entries, the function performs
-    /// an alertable wait. The thread returns when the system queues an I/O completion routine or APC to the thread
-    /// and the thread executes the function.
+    ///   an entry is retrieved. If the parameter is TRUE and there are no available entries, the function performs
+    ///   an alertable wait. The thread returns when the system queues an I/O completion routine or APC to the thread
+    ///   and the thread executes the function.
     ///
     /// ## Returns
     /// The number of items actually removed from the queue
@@ -548,11 +3033,132 @@ mod ffi {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util;
 
     #[test]
     fn selector_new_creates_valid_port() {
         let selector = Selector::new().expect("create completion port failed");
-        assert!(selector.completion_port > 0);
+        assert!(selector.port() > 0);
+    }
+
+    #[test]
+    fn tcp_info_of_a_connected_stream() {
+        let (stream, _peer) = socket_pair().unwrap();
+        let info = stream.tcp_info().unwrap();
+        assert_eq!(crate::TcpState::Established, info.state);
+        assert!(info.mss > 0);
+    }
+
+    #[test]
+    fn user_timeout_is_rounded_up_to_seconds() {
+        let (stream, _peer) = socket_pair().unwrap();
+        stream
+            .set_user_timeout(Some(Duration::from_millis(2500)))
+            .unwrap();
+        assert_eq!(Some(Duration::from_secs(3)), stream.user_timeout().unwrap());
+    }
+
+    #[test]
+    fn registrators_keep_the_port_open() {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        drop(selector);
+        ffi::post_queued_completion_key(registrator.port(), 0).unwrap();
+    }
+
+    #[test]
+    fn clear_cancels_the_pending_receives() {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let (mut a, mut b) = socket_pair().unwrap();
+        let mut events = Vec::with_capacity(16);
+        registrator
+            .register(&mut a, 1, Interests::READABLE)
+            .unwrap();
+        selector.clear().unwrap();
+
+        // At most the completion of the cancelled `WSARecv` is left
+        selector.select(&mut events, Some(100)).unwrap();
+        assert!(events.len() <= 1);
+        b.write_all(b"ping").unwrap();
+        selector.select(&mut events, Some(100)).unwrap();
+        assert!(events.is_empty());
+
+        registrator
+            .register(&mut a, 1, Interests::READABLE)
+            .unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(1, events[0].id());
+        registrator.deregister(&mut a).unwrap();
+    }
+
+    #[test]
+    fn raw_handle_is_the_completion_port() {
+        let selector = Selector::new().unwrap();
+        assert_eq!(selector.port(), selector.as_raw_handle() as ffi::HANDLE);
+        assert_eq!(
+            selector.as_raw_handle(),
+            selector.as_handle().as_raw_handle()
+        );
+    }
+
+    #[test]
+    fn alertable_select_runs_queued_apcs() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+        extern "system" fn apc(_data: usize) {
+            RAN.store(true, Ordering::SeqCst);
+        }
+
+        let mut selector = Selector::new().unwrap();
+        selector.set_alertable(true);
+        ffi::queue_user_apc(apc, 0).unwrap();
+        let mut events = Vec::with_capacity(16);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert!(RAN.load(Ordering::SeqCst));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn short_timer_fires_on_time() {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        let mut timer = Timer::new().unwrap();
+        let start = Instant::now();
+        registrator
+            .register_timer(&mut timer, 3, Duration::from_millis(2))
+            .unwrap();
+        let mut events = Vec::with_capacity(16);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(3, events[0].id());
+        // A regular timer can be a whole clock tick late
+        if timer.is_high_resolution() {
+            assert!(start.elapsed() < Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn job_message_from_raw() {
+        assert_eq!(JobMessage::ExitProcess(42), JobMessage::from_raw(7, 42));
+        assert_eq!(JobMessage::ActiveProcessZero, JobMessage::from_raw(4, 0));
+        assert_eq!(JobMessage::Unknown(100), JobMessage::from_raw(100, 42));
+    }
+
+    #[test]
+    fn wsa_errors_map_to_std_error_kinds() {
+        let err = ffi::wsa_error(ffi::WSAEWOULDBLOCK);
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        assert_eq!(
+            io::ErrorKind::ConnectionReset,
+            ffi::wsa_error(ffi::WSAECONNRESET).kind()
+        );
+        assert_eq!(
+            io::ErrorKind::ConnectionAborted,
+            ffi::wsa_error(ffi::WSAECONNABORTED).kind()
+        );
+        // Codes we don't map are passed on as plain os errors
+        assert_eq!(Some(10009), ffi::wsa_error(10009).raw_os_error());
     }
 
     #[test]
@@ -560,12 +3166,9 @@ mod tests {
         let selector = Selector::new().expect("create completion port failed");
         let poll_is_alive = Arc::new(AtomicBool::new(false));
         let registrator = selector.registrator(poll_is_alive.clone());
-        let mut sock: TcpStream = TcpStream::connect("slowwly.robertomurray.co.uk:80").unwrap();
-        let request = "GET /delay/1000/url/http://www.google.com HTTP/1.1\r\n\
-                       Host: slowwly.robertomurray.co.uk\r\n\
-                       Connection: close\r\n\
-                       \r\n";
-        sock.write_all(request.as_bytes())
+        let addr = test_util::delayed_responder(Duration::from_millis(200));
+        let mut sock: TcpStream = TcpStream::connect(addr).unwrap();
+        sock.write_all(test_util::HTTP_REQUEST)
             .expect("Error writing to stream");
 
         registrator
@@ -575,15 +3178,12 @@ mod tests {
 
     #[test]
     fn selector_select() {
-        let mut selector = Selector::new().expect("create completion port failed");
+        let selector = Selector::new().expect("create completion port failed");
         let poll_is_alive = Arc::new(AtomicBool::new(false));
         let registrator = selector.registrator(poll_is_alive.clone());
-        let mut sock: TcpStream = TcpStream::connect("slowwly.robertomurray.co.uk:80").unwrap();
-        let request = "GET /delay/1000/url/http://www.google.com HTTP/1.1\r\n\
-                       Host: slowwly.robertomurray.co.uk\r\n\
-                       Connection: close\r\n\
-                       \r\n";
-        sock.write_all(request.as_bytes())
+        let addr = test_util::delayed_responder(Duration::from_millis(200));
+        let mut sock: TcpStream = TcpStream::connect(addr).unwrap();
+        sock.write_all(test_util::HTTP_REQUEST)
             .expect("Error writing to stream");
 
         registrator
@@ -593,16 +3193,161 @@ mod tests {
         let mut events: Vec<ffi::OVERLAPPED_ENTRY> = vec![entry; 255];
         selector.select(&mut events, None).expect("Select failed");
 
-        for event in events {
+        for event in &events {
             println!("COMPL_KEY: {:?}", event.id());
             assert_eq!(2, event.id());
         }
 
         println!("SOCKET AFTER EVENT RETURN: {:?}", sock);
 
-        let mut buffer = String::new();
-        sock.read_to_string(&mut buffer).unwrap();
-        println!("BUFFERS: {}", buffer);
-        assert!(!buffer.is_empty())
+        // The response might not arrive in one piece, so we wait for the next event
+        // whenever we've read all there is
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 16];
+        loop {
+            match sock.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    selector.select(&mut events, None).expect("Select failed");
+                }
+                Err(e) => panic!("Read failed: {}", e),
+            }
+        }
+        assert!(buffer.ends_with(b"Hello"));
+    }
+
+    #[test]
+    fn reads_are_limited_by_the_recv_buffer_size() {
+        let (mut a, mut b) = socket_pair().unwrap();
+        a.set_recv_buffer_size(4).unwrap();
+        assert!(a.set_recv_buffer_size(0).is_err());
+
+        let selector = Selector::with_recv_buffer_size(64).unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        registrator
+            .register(&mut a, 4, Interests::READABLE)
+            .expect("Error registering sock read event");
+        assert_eq!(Some(4), a.recv_buffer_size());
+
+        b.write_all(b"0123456789").unwrap();
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, None).expect("Select failed");
+
+        let mut buf = [0u8; 16];
+        assert_eq!(4, a.read(&mut buf).unwrap());
+        assert_eq!(b"0123", &buf[..4]);
+    }
+
+    #[test]
+    fn vectored_read_spans_recv_buffers() {
+        let (mut a, mut b) = socket_pair().unwrap();
+        a.set_recv_buffer_size(4).unwrap();
+        a.set_recv_buffer_count(3).unwrap();
+
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        registrator
+            .register(&mut a, 5, Interests::READABLE)
+            .expect("Error registering sock read event");
+
+        b.write_all(b"0123456789").unwrap();
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, None).expect("Select failed");
+
+        // One `WSARecv` got all of it, spread over three buffers
+        let (mut first, mut second) = ([0u8; 5], [0u8; 16]);
+        let mut bufs = [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)];
+        assert_eq!(10, a.read_vectored(&mut bufs).unwrap());
+        assert_eq!(b"01234", &first);
+        assert_eq!(b"56789", &second[..5]);
+    }
+
+    #[test]
+    fn udp_recv_from_would_block_until_the_event() {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        registrator
+            .register_udp(&mut socket, 6, Interests::READABLE)
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        let err = socket.recv_from(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+        sender
+            .send_to(b"ping", socket.local_addr().unwrap())
+            .unwrap();
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, None).expect("Select failed");
+        assert_eq!(6, events[0].id());
+        let (n, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf[..n]);
+        assert_eq!(sender.local_addr().unwrap(), from);
+
+        // The next `WSARecvFrom` is already queued
+        let err = socket.recv_from(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+    }
+
+    #[test]
+    fn tcp_readiness_transitions() {
+        let (mut a, mut b) = socket_pair().unwrap();
+        assert_eq!(TcpReadiness::Idle, a.status);
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        registrator
+            .register(&mut a, 7, Interests::READABLE)
+            .unwrap();
+        // The buffer is lent to a `WSARecv` until its completion is read
+        assert_eq!(TcpReadiness::Pending, a.status);
+
+        b.write_all(b"0123456789").unwrap();
+        let mut events = Vec::with_capacity(8);
+        selector.select(&mut events, None).unwrap();
+        assert_eq!(7, events[0].id());
+        let mut buf = [0u8; 4];
+        assert_eq!(4, a.read(&mut buf).unwrap());
+        assert_eq!(TcpReadiness::Ready(10), a.status);
+
+        // Draining the buffer lends it to the next `WSARecv`
+        let mut rest = [0u8; 16];
+        assert_eq!(6, a.read(&mut rest).unwrap());
+        assert_eq!(TcpReadiness::Pending, a.status);
+
+        // Deregistering cancels the `WSARecv` that hasn't received anything
+        registrator.deregister(&mut a).unwrap();
+        assert_eq!(TcpReadiness::Idle, a.status);
+
+        registrator
+            .register(&mut a, 7, Interests::READABLE)
+            .unwrap();
+        drop(b);
+        // The completion of the cancelled `WSARecv` can show up first
+        loop {
+            selector.select(&mut events, None).unwrap();
+            match a.read(&mut rest) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => assert_eq!(0, res.unwrap()),
+            }
+            break;
+        }
+        assert_eq!(TcpReadiness::Closed, a.status);
+    }
+
+    #[test]
+    fn reading_before_completion_would_block() {
+        let (mut a, _b) = socket_pair().unwrap();
+        let selector = Selector::new().expect("create completion port failed");
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        registrator
+            .register(&mut a, 3, Interests::READABLE)
+            .expect("Error registering sock read event");
+
+        let mut buf = [0u8; 8];
+        let err = a.read(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
     }
 }
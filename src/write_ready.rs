@@ -0,0 +1,153 @@
+//! Writing as much as the socket takes right now, the loop every non-blocking server
+//! ends up writing by hand: write until the send buffer is full, remember how far we
+//! got, and write the rest once the stream is writable again.
+use crate::TcpStream;
+use std::io::{self, Write};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+use crate::{Interests, Registrator, Token};
+
+/// How far `TcpStream::write_all_ready` got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteProgress {
+    /// Everything was written.
+    Complete,
+    /// The send buffer filled up after this many bytes. The rest has to wait until the
+    /// stream is writable again.
+    Blocked(usize),
+}
+
+impl WriteProgress {
+    pub fn is_complete(&self) -> bool {
+        *self == WriteProgress::Complete
+    }
+
+    /// How many bytes of a buffer of `len` bytes were written.
+    pub fn written(&self, len: usize) -> usize {
+        match *self {
+            WriteProgress::Complete => len,
+            WriteProgress::Blocked(n) => n,
+        }
+    }
+}
+
+impl TcpStream {
+    /// Writes `buf` until it's all written or writing would block, and returns which
+    /// it was. Pass what's left, `&buf[progress.written(buf.len())..]`, once the
+    /// stream is writable again.
+    ///
+    /// A write that writes nothing at all fails with an error of kind `WriteZero`,
+    /// like `write_all` does.
+    pub fn write_all_ready(&mut self, buf: &[u8]) -> io::Result<WriteProgress> {
+        self.set_nonblocking()?;
+        let mut written = 0;
+        while written < buf.len() {
+            match self.write(&buf[written..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    trace!("send buffer full after {} of {} bytes", written, buf.len());
+                    return Ok(WriteProgress::Blocked(written));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(WriteProgress::Complete)
+    }
+
+    /// Like `write_all_ready`, but if writing blocks the stream is registered with
+    /// `registrator` for `WRITABLE` interest with `token`, so the event to write the
+    /// rest on is on its way.
+    ///
+    /// Windows doesn't support writable interest, so it doesn't have this.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    pub fn write_all_ready_or_register(
+        &mut self,
+        buf: &[u8],
+        registrator: &Registrator,
+        token: Token,
+    ) -> io::Result<WriteProgress> {
+        let progress = self.write_all_ready(buf)?;
+        if !progress.is_complete() {
+            registrator.register(self, token, Interests::WRITABLE)?;
+        }
+        Ok(progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tcp_pair;
+
+    // More than any send and receive buffer put together will take
+    const LARGE: usize = 64 * 1024 * 1024;
+
+    #[test]
+    fn write_all_ready_stops_when_the_send_buffer_is_full() {
+        let (mut a, _b) = tcp_pair();
+        assert_eq!(WriteProgress::Complete, a.write_all_ready(b"ping").unwrap());
+
+        let buf = vec![0; LARGE];
+        let progress = a.write_all_ready(&buf).unwrap();
+        assert!(!progress.is_complete());
+        assert!(progress.written(buf.len()) < buf.len());
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    ))]
+    #[test]
+    fn blocked_write_is_registered_for_writable() {
+        use crate::{Events, Poll};
+        use std::io::Read;
+
+        let mut poll = Poll::new().unwrap();
+        let (mut a, mut b) = tcp_pair();
+        let mut events = Events::with_capacity(16);
+
+        let buf = vec![0; LARGE];
+        let progress = a
+            .write_all_ready_or_register(&buf, &poll.registrator(), 7)
+            .unwrap();
+        let written = progress.written(buf.len());
+        assert!(written < buf.len());
+
+        let mut received = vec![0; written];
+        b.read_exact(&mut received).unwrap();
+        poll.poll(&mut events, Some(1000)).unwrap();
+        assert_eq!(7, events[0].id());
+        assert!(events[0].is_writable());
+    }
+}
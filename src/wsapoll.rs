@@ -0,0 +1,633 @@
+//! A readiness-only Windows backend on `WSAPoll`, for when IOCP isn't an option, like
+//! in sandboxes that don't allow creating a completion port or issuing overlapped
+//! I/O. It replaces the IOCP backend when the `wsapoll` feature is on.
+//!
+//! `WSAPoll` is `poll` for sockets: it keeps no state between calls, so every `select`
+//! hands it the sockets registered at the time, and it reports a socket for as long
+//! as it's ready. To make registrations oneshot, like on the other backends, a
+//! socket is forgotten as soon as it's been reported. A socket registered while
+//! `select` waits isn't in the array it's waiting on, so the registrator wakes it up
+//! through a loopback socket pair, and `select` waits again with the new set.
+//!
+//! Nothing is lent to the OS here, so streams read straight from the socket and
+//! writable interest works, unlike with IOCP. Nesting selectors, timers, named pipes
+//! and job objects are IOCP features and aren't available.
+use crate::backlog::FullSelects;
+use crate::clock::ceil_millis;
+use crate::registrations::Registrations;
+use crate::syscall_stats::{SyscallCounters, SyscallStats};
+use crate::{Events, Interests, Token};
+use std::collections::HashMap;
+use std::io::{self, IoSliceMut, Read, Write};
+use std::net;
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    pub fn connect(adr: impl net::ToSocketAddrs) -> io::Result<Self> {
+        // Like on the other platforms this blocks while the connection is established
+        let stream = net::TcpStream::connect(adr)?;
+        TcpStream::from_std(stream)
+    }
+
+    /// Wraps a stream from the standard library, like one returned from
+    /// `std::net::TcpListener::accept`, setting it to non-blocking.
+    pub fn from_std(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream { inner: stream })
+    }
+
+    /// The socket is non-blocking from `from_std` on, reads never change that.
+    pub(crate) fn set_nonblocking(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reading never blocks here, so this is just `read`.
+    pub(crate) fn read_ready(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+}
+
+/// Creates a pair of connected, non-blocking streams. Windows has no `socketpair` so
+/// we emulate it by connecting two TCP sockets over the loopback interface.
+pub fn socket_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let (a, b) = loopback_pair()?;
+    Ok((TcpStream::from_std(a)?, TcpStream::from_std(b)?))
+}
+
+fn loopback_pair() -> io::Result<(net::TcpStream, net::TcpStream)> {
+    let listener = net::TcpListener::bind("127.0.0.1:0")?;
+    let a = net::TcpStream::connect(listener.local_addr()?)?;
+    loop {
+        let (b, peer) = listener.accept()?;
+        // Somebody else could connect to the port before we do, so make sure we
+        // accepted our own connection
+        if peer == a.local_addr()? {
+            return Ok((a, b));
+        }
+    }
+}
+
+/// `SIO_TCP_INFO` is only read by the IOCP backend.
+pub(crate) fn tcp_info(_stream: &TcpStream) -> io::Result<crate::TcpInfo> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_INFO isn't read by the WSAPoll backend.",
+    ))
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        (&self.inner).read_vectored(bufs)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsRawSocket for TcpStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.inner.as_raw_socket()
+    }
+}
+
+impl AsSocket for TcpStream {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.inner.as_socket()
+    }
+}
+
+#[derive(Debug)]
+pub struct Registrator {
+    /// Shared with the `Selector`, so the wakeup sockets stay open for as long as
+    /// anybody can register
+    shared: Arc<Shared>,
+    is_poll_dead: Arc<AtomicBool>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+}
+
+impl Registrator {
+    /// Registers `soc` for `interests`. It takes a `&mut TcpStream` like the IOCP
+    /// backend does, so code written for one works with the other.
+    pub fn register(
+        &self,
+        soc: &mut TcpStream,
+        token: usize,
+        interests: Interests,
+    ) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+
+        interests.validate()?;
+        let mut events = 0;
+        if interests.is_readable() {
+            events |= ffi::POLLRDNORM;
+        }
+        if interests.is_writable() {
+            events |= ffi::POLLWRNORM;
+        }
+        let socket = soc.as_raw_socket();
+        self.stats.change();
+        let waiting = {
+            let mut watched = self.shared.watched();
+            watched.sockets.insert(socket, (token, events));
+            watched.waiting
+        };
+        self.registrations.insert(socket, token, interests);
+        debug!(
+            "registered socket {} with token {} for {}",
+            socket, token, interests
+        );
+        if waiting {
+            self.stats.wakeup();
+            self.shared.kick()?;
+        }
+        Ok(())
+    }
+
+    /// Windows has no send low-water mark, so this returns an error of kind
+    /// `Unsupported`.
+    pub fn register_with_send_lowat(
+        &self,
+        _soc: &mut TcpStream,
+        _token: usize,
+        interests: Interests,
+        _send_lowat: usize,
+    ) -> io::Result<()> {
+        if !interests.is_writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A send low-water mark needs WRITABLE interest.",
+            ));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Windows has no send low-water mark.",
+        ))
+    }
+
+    /// Forgets `soc`, so no more events are reported for it (one that `select` has
+    /// already returned can't be taken back). A `select` waiting in another thread
+    /// still has the socket in its array, but drops what it reports for it.
+    /// Deregistering a stream that isn't registered is not an error.
+    pub fn deregister(&self, soc: &mut TcpStream) -> io::Result<()> {
+        let socket = soc.as_raw_socket();
+        if self.shared.watched().sockets.remove(&socket).is_some() {
+            self.stats.change();
+            debug!("deregistered socket {}", socket);
+        }
+        self.registrations.remove(socket);
+        Ok(())
+    }
+
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self
+            .is_poll_dead
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance is dead.",
+            ));
+        }
+        // `select` reports this as a readable event with token 0, like the other
+        // backends wake it up with
+        debug!("closing event loop");
+        self.shared.watched().closing = true;
+        self.shared.kick()
+    }
+}
+
+/// What a selector shares with its registrators.
+#[derive(Debug)]
+struct Shared {
+    watched: Mutex<Watched>,
+    /// Registrators write to this socket to wake up `select`. The other end is always
+    /// the first socket in the array `select` waits on.
+    kick_reader: net::TcpStream,
+    kick_writer: net::TcpStream,
+}
+
+#[derive(Debug, Default)]
+struct Watched {
+    /// The registered sockets, by the token and `WSAPoll` events they're registered
+    /// with. A socket is taken out once it's been reported.
+    sockets: HashMap<RawSocket, (Token, i16)>,
+    /// Whether `select` is waiting on the sockets it took from `sockets`, and needs to
+    /// be woken up to see new ones. Only changed with the lock held, together with
+    /// taking the sockets, so a registration is either in the array or kicks.
+    waiting: bool,
+    /// Set by `close_loop`, until `select` has reported it
+    closing: bool,
+}
+
+impl Shared {
+    fn new() -> io::Result<Shared> {
+        let (kick_reader, kick_writer) = loopback_pair()?;
+        kick_reader.set_nonblocking(true)?;
+        kick_writer.set_nonblocking(true)?;
+        Ok(Shared {
+            watched: Mutex::default(),
+            kick_reader,
+            kick_writer,
+        })
+    }
+
+    fn kick(&self) -> io::Result<()> {
+        match (&self.kick_writer).write(&[1]) {
+            // A full socket wakes up `select` just the same
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    fn drain_kick(&self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        loop {
+            match (&self.kick_reader).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn watched(&self) -> MutexGuard<'_, Watched> {
+        // Every change is a single map operation or flag, so a panic while holding the
+        // lock can't leave it half changed
+        self.watched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+pub struct Selector {
+    shared: Arc<Shared>,
+    registrations: Arc<Registrations>,
+    stats: Arc<SyscallCounters>,
+    full_selects: FullSelects,
+}
+
+impl Selector {
+    pub fn new() -> io::Result<Self> {
+        Ok(Selector {
+            shared: Arc::new(Shared::new()?),
+            registrations: Arc::default(),
+            stats: Arc::default(),
+            full_selects: FullSelects::default(),
+        })
+    }
+
+    /// Deregisters every stream registered with this selector or one of its
+    /// registrators, so the selector can be reused as if it was new. Wakers are
+    /// deregistered as well and need to be registered again.
+    ///
+    /// A `select` already waiting in another thread keeps waiting on the old
+    /// registrations, but drops what it reports for them.
+    pub fn clear(&self) -> io::Result<()> {
+        self.shared.watched().sockets.clear();
+        self.registrations.clear();
+        debug!("cleared all registrations");
+        Ok(())
+    }
+
+    pub fn registrator(&self, is_poll_dead: Arc<AtomicBool>) -> Registrator {
+        Registrator {
+            shared: self.shared.clone(),
+            is_poll_dead,
+            registrations: self.registrations.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// How many selects in a row have returned as many events as `events` had room
+    /// for. Sockets that didn't fit stay registered and are reported by the next
+    /// select, so a count that keeps growing means the loop isn't keeping up and
+    /// should shed load or poll with a larger buffer. 0 if the last select had room to
+    /// spare.
+    pub fn pending_hint(&self) -> usize {
+        self.full_selects.get()
+    }
+
+    /// How many system calls this selector and its registrators have made so far.
+    pub fn syscall_stats(&self) -> SyscallStats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "debug")]
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+
+    /// Like `select`, with the timeout as a `Duration`. `WSAPoll` only takes
+    /// milliseconds, so it's rounded up to the next one to never return early.
+    pub fn select_timeout(&self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        events.clear();
+        loop {
+            let timeout_ms = deadline.map_or(-1, |deadline| {
+                ceil_millis(deadline.saturating_duration_since(Instant::now()))
+            });
+            let kicked = self.wait(events, timeout_ms)?;
+            // Being kicked only means the set of sockets changed, so if nothing else
+            // happened and there's time left we wait again with the new set
+            let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if events.is_empty() && kicked && !timed_out {
+                continue;
+            }
+            self.full_selects.record(events.len(), events.capacity());
+            return Ok(());
+        }
+    }
+
+    /// Blocks until an event has occured, or `timeout` milliseconds have passed. `None`
+    /// means it never times out.
+    pub fn select(&self, events: &mut Events, timeout: Option<i32>) -> io::Result<()> {
+        let timeout = timeout.map(|ms| Duration::from_millis(ms.max(0) as u64));
+        self.select_timeout(events, timeout)
+    }
+
+    /// Waits on the registered sockets once, and turns those that are ready into
+    /// events. Returns whether a registrator kicked us.
+    fn wait(&self, events: &mut Events, timeout_ms: i32) -> io::Result<bool> {
+        let mut fds = vec![ffi::WSAPOLLFD::new(
+            self.shared.kick_reader.as_raw_socket(),
+            ffi::POLLRDNORM,
+        )];
+        {
+            let mut watched = self.shared.watched();
+            watched.waiting = true;
+            fds.extend(
+                watched
+                    .sockets
+                    .iter()
+                    .map(|(&socket, &(_, events))| ffi::WSAPOLLFD::new(socket, events)),
+            );
+        }
+        trace!(
+            "WSAPoll on {} sockets with timeout {}",
+            fds.len() - 1,
+            timeout_ms
+        );
+        self.stats.wait();
+        let res = unsafe { ffi::WSAPoll(fds.as_mut_ptr(), fds.len() as u32, timeout_ms) };
+        let mut watched = self.shared.watched();
+        watched.waiting = false;
+        if res < 0 {
+            let e = io::Error::from_raw_os_error(unsafe { ffi::WSAGetLastError() });
+            debug!("WSAPoll failed: {} (os error {:?})", e, e.raw_os_error());
+            return Err(e);
+        }
+        trace!("WSAPoll woke up with {} sockets ready", res);
+
+        let kicked = fds[0].revents != 0;
+        if kicked {
+            self.shared.drain_kick()?;
+        }
+        if watched.closing && events.len() < events.capacity() {
+            watched.closing = false;
+            events.push(Event {
+                token: 0,
+                revents: ffi::POLLRDNORM,
+            });
+        }
+        for fd in fds[1..].iter().filter(|fd| fd.revents != 0) {
+            // What doesn't fit stays registered, and is reported again next time
+            if events.len() == events.capacity() {
+                break;
+            }
+            if fd.revents & ffi::POLLNVAL != 0 {
+                debug!("socket {} was closed while registered", fd.fd);
+            }
+            match watched.sockets.remove(&fd.fd) {
+                Some((token, _)) => events.push(Event {
+                    token,
+                    revents: fd.revents,
+                }),
+                None => trace!("dropping stale event for socket {}", fd.fd),
+            }
+        }
+        Ok(kicked)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Event {
+    token: Token,
+    revents: i16,
+}
+
+impl Event {
+    pub fn id(&self) -> Token {
+        self.token
+    }
+
+    /// Returns true if the source can be read from, or has hung up.
+    pub fn is_readable(&self) -> bool {
+        self.revents & (ffi::POLLRDNORM | ffi::POLLHUP) != 0
+    }
+
+    /// Returns true if the source can be written to.
+    pub fn is_writable(&self) -> bool {
+        self.revents & ffi::POLLWRNORM != 0
+    }
+
+    /// An event the way `WSAPoll` would have reported it, for events that are rebuilt
+    /// after being held back.
+    pub(crate) fn with_readiness(token: Token, readable: bool, writable: bool) -> Event {
+        let mut revents = 0;
+        if readable {
+            revents |= ffi::POLLRDNORM;
+        }
+        if writable {
+            revents |= ffi::POLLWRNORM;
+        }
+        Event { token, revents }
+    }
+
+    /// Adds the readiness of `other`, an event for the same token, to this one.
+    /// `WSAPoll` reports all of a socket's readiness at once, so they can always be
+    /// merged.
+    pub(crate) fn merge(&mut self, other: &Event) -> bool {
+        self.revents |= other.revents;
+        true
+    }
+}
+
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
+mod ffi {
+    use std::os::windows::io::RawSocket;
+
+    // https://learn.microsoft.com/en-us/windows/win32/api/winsock2/ns-winsock2-wsapollfd
+    pub const POLLRDNORM: i16 = 0x0100;
+    pub const POLLWRNORM: i16 = 0x0010;
+    pub const POLLHUP: i16 = 0x0002;
+    pub const POLLNVAL: i16 = 0x0004;
+
+    #[repr(C)]
+    pub struct WSAPOLLFD {
+        pub fd: RawSocket,
+        pub events: i16,
+        pub revents: i16,
+    }
+
+    impl WSAPOLLFD {
+        pub fn new(fd: RawSocket, events: i16) -> Self {
+            WSAPOLLFD {
+                fd,
+                events,
+                revents: 0,
+            }
+        }
+    }
+
+    #[link(name = "ws2_32")]
+    extern "system" {
+        // https://learn.microsoft.com/en-us/windows/win32/api/winsock2/nf-winsock2-wsapoll
+        pub fn WSAPoll(fdArray: *mut WSAPOLLFD, fds: u32, timeout: i32) -> i32;
+
+        // https://docs.microsoft.com/nb-no/windows/win32/api/winsock/nf-winsock-wsagetlasterror
+        pub fn WSAGetLastError() -> i32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    fn selector() -> (Selector, Registrator) {
+        let selector = Selector::new().unwrap();
+        let registrator = selector.registrator(Arc::new(AtomicBool::new(false)));
+        (selector, registrator)
+    }
+
+    #[test]
+    fn registrations_are_oneshot() {
+        let (selector, registrator) = selector();
+        let (mut a, mut b) = socket_pair().unwrap();
+        registrator
+            .register(&mut a, 3, Interests::READABLE)
+            .unwrap();
+        b.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(vec![3], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        assert!(events[0].is_readable());
+
+        // Still readable, but forgotten until it's registered again
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+        registrator
+            .register(&mut a, 3, Interests::READABLE)
+            .unwrap();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+    }
+
+    #[test]
+    fn writable_interest_is_reported() {
+        let (selector, registrator) = selector();
+        let (mut a, _b) = socket_pair().unwrap();
+        registrator
+            .register(&mut a, 4, Interests::WRITABLE)
+            .unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert!(events[0].is_writable());
+        assert!(!events[0].is_readable());
+    }
+
+    #[test]
+    fn registering_from_another_thread_wakes_up_select() {
+        let (selector, registrator) = selector();
+        let (mut a, mut b) = socket_pair().unwrap();
+        b.write_all(b"ping").unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            registrator
+                .register(&mut a, 5, Interests::READABLE)
+                .unwrap();
+            a
+        });
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, None).unwrap();
+        assert_eq!(5, events[0].id());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn sockets_that_dont_fit_are_reported_next_time() {
+        let (selector, registrator) = selector();
+        let (mut a, mut b) = socket_pair().unwrap();
+        let (mut c, mut d) = socket_pair().unwrap();
+        registrator
+            .register(&mut a, 1, Interests::READABLE)
+            .unwrap();
+        registrator
+            .register(&mut c, 2, Interests::READABLE)
+            .unwrap();
+        b.write_all(b"ping").unwrap();
+        d.write_all(b"ping").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let mut events = Vec::with_capacity(1);
+        selector.select(&mut events, Some(1000)).unwrap();
+        let first = events[0].id();
+        selector.select(&mut events, Some(1000)).unwrap();
+        assert_eq!(1, events.len());
+        assert_ne!(first, events[0].id());
+    }
+
+    #[test]
+    fn deregistered_and_cleared_streams_are_quiet() {
+        let (selector, registrator) = selector();
+        let (mut a, mut b) = socket_pair().unwrap();
+        let (mut c, mut d) = socket_pair().unwrap();
+        registrator
+            .register(&mut a, 1, Interests::READABLE)
+            .unwrap();
+        registrator
+            .register(&mut c, 2, Interests::READABLE)
+            .unwrap();
+        registrator.deregister(&mut a).unwrap();
+        // Nothing is registered anymore, which isn't an error either
+        registrator.deregister(&mut a).unwrap();
+        selector.clear().unwrap();
+        b.write_all(b"ping").unwrap();
+        d.write_all(b"ping").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        selector.select(&mut events, Some(50)).unwrap();
+        assert!(events.is_empty());
+    }
+}
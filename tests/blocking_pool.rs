@@ -0,0 +1,31 @@
+use minimio::{BlockingPool, Events, Poll};
+use std::time::Duration;
+
+#[test]
+fn completed_closures_wake_up_poll() {
+    let mut poll = Poll::new().unwrap();
+    let pool = BlockingPool::new(&poll, 5, 2).expect("pool err.");
+    let mut events = Events::with_capacity(16);
+
+    pool.spawn_blocking(10, || {
+        std::thread::sleep(Duration::from_millis(50));
+        "slow"
+    })
+    .unwrap();
+    pool.spawn_blocking(11, || "fast").unwrap();
+    pool.spawn_blocking(12, || panic!("closure panicked"))
+        .unwrap();
+
+    let mut completed = vec![];
+    while completed.len() < 3 {
+        poll.poll(&mut events, Some(1000)).expect("poll err.");
+        assert!(!events.is_empty(), "timed out waiting for the pool");
+        assert_eq!(pool.token(), events[0].id());
+        completed.extend(pool.completed().unwrap());
+    }
+
+    completed.sort_by_key(|(token, _)| *token);
+    assert_eq!("slow", *completed[0].1.as_ref().unwrap());
+    assert_eq!("fast", *completed[1].1.as_ref().unwrap());
+    assert!(completed[2].1.is_err());
+}
@@ -0,0 +1,41 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, EventsExt, Interests, Poll};
+use std::io::Write;
+
+#[test]
+fn each_token_is_returned_once_with_all_of_its_events() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let (mut c, mut d) = socket_pair().unwrap();
+    let registrator = poll.registrator();
+    register(
+        &registrator,
+        &mut a,
+        2,
+        Interests::READABLE | Interests::WRITABLE,
+    )
+    .unwrap();
+    register(
+        &registrator,
+        &mut c,
+        1,
+        Interests::READABLE | Interests::WRITABLE,
+    )
+    .unwrap();
+    b.write_all(b"ping").unwrap();
+    d.write_all(b"ping").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut events = Events::with_capacity(16);
+    poll.poll(&mut events, Some(1000)).unwrap();
+    let received = events.len();
+    let groups: Vec<_> = events.by_token().collect();
+
+    let tokens: Vec<_> = groups.iter().map(|(token, _)| *token).collect();
+    assert_eq!(vec![1, 2], tokens);
+    for (token, group) in &groups {
+        assert!(group.iter().all(|event| event.id() == *token));
+    }
+    let grouped: usize = groups.iter().map(|(_, group)| group.len()).sum();
+    assert_eq!(received, grouped);
+}
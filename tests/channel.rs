@@ -0,0 +1,52 @@
+use minimio::{channel, Events, Poll};
+use std::thread;
+
+#[test]
+fn messages_from_workers_arrive_in_order() {
+    let mut poll = Poll::new().unwrap();
+    let (sender, receiver) = channel::channel(&poll, 3).expect("channel err.");
+    let mut events = Events::with_capacity(16);
+
+    let workers: Vec<_> = (0..2)
+        .map(|worker| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for n in 0..10 {
+                    sender.send((worker, n)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    poll.poll(&mut events, Some(1000)).expect("poll err.");
+    assert_eq!(1, events.len());
+    assert_eq!(receiver.token(), events[0].id());
+
+    let received: Vec<_> = receiver.drain().unwrap().collect();
+    for worker in 0..2 {
+        let from_worker: Vec<_> = received
+            .iter()
+            .filter(|(w, _)| *w == worker)
+            .map(|(_, n)| *n)
+            .collect();
+        assert_eq!((0..10).collect::<Vec<_>>(), from_worker);
+    }
+
+    // Sending after draining wakes the loop up again
+    sender.send((2, 0)).unwrap();
+    poll.poll(&mut events, Some(1000)).expect("poll err.");
+    assert_eq!(1, events.len());
+    assert_eq!(vec![(2, 0)], receiver.drain().unwrap().collect::<Vec<_>>());
+}
+
+#[test]
+fn sending_to_a_dropped_receiver_fails() {
+    let poll = Poll::new().unwrap();
+    let (sender, receiver) = channel::channel(&poll, 3).expect("channel err.");
+    drop(receiver);
+    let err = sender.send(1).unwrap_err();
+    assert_eq!(std::io::ErrorKind::BrokenPipe, err.kind());
+}
@@ -0,0 +1,59 @@
+#![cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "aix"
+))]
+
+use minimio::{ChildReaper, Events, Poll};
+use std::collections::HashMap;
+use std::io;
+use std::process::Command;
+use std::time::Duration;
+
+const CHILDREN: usize = 3;
+
+// A reaper reaps every child of the process, so this is the only test in the file.
+// The children are reaped by the reaper instead of `Child::wait`.
+#[allow(clippy::zombie_processes)]
+#[test]
+fn exited_children_are_reaped_with_their_status() {
+    let mut poll = Poll::new().unwrap();
+    // Exits before the reaper exists, and is reported by its first event anyway
+    let early = Command::new("sh").args(["-c", "exit 7"]).spawn().unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+
+    let reaper = ChildReaper::new(&poll, CHILDREN).unwrap();
+    assert_eq!(
+        io::ErrorKind::AlreadyExists,
+        ChildReaper::new(&poll, 4).unwrap_err().kind()
+    );
+    let mut expected = HashMap::new();
+    expected.insert(early.id(), 7);
+    for code in 0..3 {
+        let child = Command::new("sh")
+            .args(["-c", &format!("exit {}", code)])
+            .spawn()
+            .unwrap();
+        expected.insert(child.id(), code);
+    }
+
+    let mut events = Events::with_capacity(16);
+    let mut reaped = HashMap::new();
+    while reaped.len() < expected.len() {
+        poll.poll_timeout(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(
+            vec![CHILDREN],
+            events.iter().map(|e| e.id()).collect::<Vec<_>>()
+        );
+        for child in reaper.reap().unwrap() {
+            reaped.insert(child.pid, child.status.code().unwrap());
+        }
+    }
+    assert_eq!(expected, reaped);
+
+    drop(reaper);
+    ChildReaper::new(&poll, CHILDREN).unwrap();
+}
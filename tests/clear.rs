@@ -0,0 +1,63 @@
+// The Windows registrators take their sources by `&mut`, the IOCP `clear` is tested
+// in src/windows.rs
+#![cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::Write;
+use std::time::Duration;
+
+#[test]
+fn clear_deregisters_everything_and_the_registrators_keep_working() {
+    let mut poll = Poll::new().unwrap();
+    let (a, mut b) = socket_pair().unwrap();
+    let (c, mut d) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+    let registrator = poll.registrator();
+
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    registrator
+        .register_deferred(&c, 2, Interests::READABLE)
+        .unwrap();
+    poll.registry().clear().unwrap();
+
+    b.write_all(b"ping").unwrap();
+    d.write_all(b"ping").unwrap();
+    assert_eq!(0, poll.poll(&mut events, Some(100)).unwrap());
+
+    // Registering the same fd again is an add, not a modify, after the clear
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    assert_eq!(1, poll.poll(&mut events, Some(1000)).unwrap());
+    assert_eq!(1, events[0].id());
+}
+
+#[test]
+fn clear_drops_events_held_back_by_a_rate_limit() {
+    let mut poll = Poll::new().unwrap();
+    let (a, mut b) = socket_pair().unwrap();
+    let (c, mut d) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+    let registrator = poll.registrator();
+    poll.registry()
+        .rate_limit(1, 1, Duration::from_millis(50))
+        .unwrap();
+
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    registrator.register(&c, 1, Interests::READABLE).unwrap();
+    b.write_all(b"ping").unwrap();
+    d.write_all(b"ping").unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(1, poll.poll(&mut events, Some(1000)).unwrap());
+
+    // The second event is held back, and goes with the registrations
+    poll.registry().clear().unwrap();
+    assert_eq!(0, poll.poll(&mut events, Some(200)).unwrap());
+}
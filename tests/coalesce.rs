@@ -0,0 +1,32 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::Write;
+use std::time::Duration;
+
+/// Two sockets registered with the same token, both readable
+fn poll_twice_readable(coalesce: bool) -> Events {
+    let mut poll = Poll::new().unwrap();
+    poll.coalesce_events(coalesce);
+    let (mut a, mut b) = socket_pair().unwrap();
+    let (mut c, mut d) = socket_pair().unwrap();
+    let registrator = poll.registrator();
+    register(&registrator, &mut a, 1, Interests::READABLE).unwrap();
+    register(&registrator, &mut c, 1, Interests::READABLE).unwrap();
+    b.write_all(b"ping").unwrap();
+    d.write_all(b"ping").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+
+    let mut events = Events::with_capacity(16);
+    poll.poll(&mut events, Some(1000)).unwrap();
+    events
+}
+
+#[test]
+fn events_for_the_same_token_are_merged_when_asked_to() {
+    assert_eq!(2, poll_twice_readable(false).len());
+
+    let events = poll_twice_readable(true);
+    assert_eq!(1, events.len());
+    assert_eq!(1, events[0].id());
+    assert!(events[0].is_readable());
+}
@@ -0,0 +1,119 @@
+#![cfg(all(
+    feature = "compat",
+    any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    )
+))]
+
+use minimio::compat::mio::net::{TcpListener, TcpStream};
+use minimio::compat::mio::{Events, Interest, Poll, Token};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+const SERVER: Token = Token(0);
+const CONNECTION: Token = Token(1);
+
+fn timeout() -> Option<Duration> {
+    Some(Duration::from_secs(1))
+}
+
+/// Reads until `WouldBlock`, like code written for mio does.
+fn read_all(stream: &mut TcpStream) -> Vec<u8> {
+    let mut received = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return received,
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return received,
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+}
+
+#[test]
+fn accepts_and_reads_like_the_mio_tcp_server_example() {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    let mut server = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    poll.registry()
+        .register(&mut server, SERVER, Interest::READABLE)
+        .unwrap();
+
+    let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    poll.poll(&mut events, timeout()).unwrap();
+    let event = events.iter().next().unwrap();
+    assert_eq!(SERVER, event.token());
+    assert!(event.is_readable());
+
+    let (mut connection, _) = server.accept().unwrap();
+    assert_eq!(
+        io::ErrorKind::WouldBlock,
+        server.accept().unwrap_err().kind()
+    );
+    poll.registry()
+        .register(&mut connection, CONNECTION, Interest::READABLE)
+        .unwrap();
+
+    client.write_all(b"hello").unwrap();
+    poll.poll(&mut events, timeout()).unwrap();
+    assert_eq!(
+        vec![CONNECTION],
+        events.iter().map(|e| e.token()).collect::<Vec<_>>()
+    );
+    assert_eq!(b"hello".to_vec(), read_all(&mut connection));
+}
+
+#[test]
+fn sources_report_again_without_being_registered_again() {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    let server = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let (mut connection, _) = server.accept().unwrap();
+    poll.registry()
+        .register(&mut connection, CONNECTION, Interest::READABLE)
+        .unwrap();
+
+    for message in [&b"one"[..], b"two"] {
+        client.write_all(message).unwrap();
+        poll.poll(&mut events, timeout()).unwrap();
+        assert_eq!(1, events.iter().count());
+        assert_eq!(message.to_vec(), read_all(&mut connection));
+    }
+
+    // Drained, so nothing until more arrives
+    poll.poll(&mut events, Some(Duration::from_millis(50)))
+        .unwrap();
+    assert!(events.is_empty());
+}
+
+#[test]
+fn deregistered_sources_dont_report() {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    let server = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let (mut connection, _) = server.accept().unwrap();
+    let registry = poll.registry().try_clone().unwrap();
+    registry
+        .register(
+            &mut connection,
+            CONNECTION,
+            Interest::READABLE | Interest::WRITABLE,
+        )
+        .unwrap();
+    registry.deregister(&mut connection).unwrap();
+
+    client.write_all(b"ignored").unwrap();
+    poll.poll(&mut events, Some(Duration::from_millis(50)))
+        .unwrap();
+    assert!(events.is_empty());
+}
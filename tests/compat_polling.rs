@@ -0,0 +1,51 @@
+#![cfg(feature = "compat")]
+
+use minimio::compat::polling::{Event, Poller};
+use minimio::socket_pair;
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+// `Poller` takes `&mut` on Windows, for the reason given by `test_util::register`
+#[cfg_attr(not(target_os = "windows"), allow(clippy::unnecessary_mut_passed))]
+fn events_are_delivered_with_the_key_they_were_added_with() {
+    let poller = Poller::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    poller.add(&mut a, Event::readable(7)).unwrap();
+    b.write_all(b"ping").unwrap();
+
+    let mut events = Vec::new();
+    let timeout = Some(Duration::from_secs(1));
+    assert_eq!(1, poller.wait(&mut events, timeout).unwrap());
+    assert_eq!(7, events[0].key);
+    assert!(events[0].readable);
+
+    // Interest is oneshot, and `wait` appends
+    assert_eq!(
+        0,
+        poller
+            .wait(&mut events, Some(Duration::from_millis(50)))
+            .unwrap()
+    );
+    poller.modify(&mut a, Event::readable(8)).unwrap();
+    assert_eq!(1, poller.wait(&mut events, timeout).unwrap());
+    assert_eq!(vec![7, 8], events.iter().map(|e| e.key).collect::<Vec<_>>());
+
+    poller.delete(&mut a).unwrap();
+    assert!(poller.add(&mut a, Event::readable(usize::MAX)).is_err());
+}
+
+#[test]
+fn notify_wakes_up_wait() {
+    let poller = Arc::new(Poller::new().unwrap());
+    let notifier = poller.clone();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        notifier.notify().unwrap();
+    });
+    let mut events = Vec::new();
+    assert_eq!(0, poller.wait(&mut events, None).unwrap());
+    handle.join().unwrap();
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "connections")]
+
+use minimio::connections::{CloseReason, Connections};
+use minimio::{socket_pair, Clock, Interests, ManualClock, Poll};
+use std::io::{self, Read};
+use std::time::Duration;
+
+fn secs(n: u64) -> Duration {
+    Duration::from_secs(n)
+}
+
+#[test]
+fn idle_connections_are_closed() {
+    let poll = Poll::new().unwrap();
+    let clock = ManualClock::new();
+    let mut connections =
+        Connections::with_clock(poll.registrator(), clock.clone()).idle_timeout(secs(30));
+    let (stream, mut peer) = socket_pair().unwrap();
+    connections
+        .insert(1, stream, Interests::READABLE, "client")
+        .unwrap();
+    // The wheel can ask to be woken up early to move the timer along, never late
+    let timeout = connections.poll_timeout().unwrap();
+    assert!(timeout > 0 && timeout <= 30_000);
+
+    clock.advance(secs(29));
+    assert!(connections.expire().is_empty());
+    // Activity pushes the timeout back
+    assert!(connections.touch(1));
+    clock.advance(secs(29));
+    assert!(connections.expire().is_empty());
+
+    clock.advance(secs(1));
+    let closed = connections.expire();
+    assert_eq!(1, closed.len());
+    assert_eq!(1, closed[0].token);
+    assert_eq!(CloseReason::Idle, closed[0].reason);
+    assert_eq!("client", closed[0].meta);
+    assert_eq!(secs(59), closed[0].age);
+    assert!(connections.is_empty());
+    assert!(!connections.touch(1));
+    assert_eq!(None, connections.poll_timeout());
+
+    let mut buf = [0; 8];
+    assert_eq!(0, peer.read(&mut buf).unwrap());
+}
+
+#[test]
+fn busy_connections_are_closed_at_the_end_of_their_lifetime() {
+    let poll = Poll::new().unwrap();
+    let clock = ManualClock::new();
+    let mut connections = Connections::with_clock(poll.registrator(), clock.clone())
+        .idle_timeout(secs(30))
+        .max_lifetime(secs(60));
+    let (stream, _peer) = socket_pair().unwrap();
+    connections
+        .insert(1, stream, Interests::READABLE, ())
+        .unwrap();
+
+    for _ in 0..2 {
+        clock.advance(secs(20));
+        assert!(connections.expire().is_empty());
+        connections.reregister(1, Interests::READABLE).unwrap();
+    }
+    // Even though it was busy up to now, it doesn't get the full idle timeout
+    assert!(connections.poll_timeout().unwrap() <= 20_000);
+    clock.advance(secs(20));
+    let closed = connections.expire();
+    assert_eq!(1, closed.len());
+    assert_eq!(CloseReason::Lifetime, closed[0].reason);
+    assert_eq!(clock.now() - secs(60), clock.now() - closed[0].age);
+}
+
+#[test]
+fn removed_connections_are_handed_back() {
+    let poll = Poll::new().unwrap();
+    let mut connections = Connections::new(poll.registrator()).idle_timeout(secs(1));
+    let (a, _peer_a) = socket_pair().unwrap();
+    let (b, _peer_b) = socket_pair().unwrap();
+    connections.insert(1, a, Interests::READABLE, 10).unwrap();
+    let err = connections
+        .insert(1, b, Interests::READABLE, 20)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::AlreadyExists, err.kind());
+
+    *connections.meta_mut(1).unwrap() += 1;
+    let (_stream, meta) = connections.remove(1).unwrap();
+    assert_eq!(11, meta);
+    assert!(connections.get(1).is_none());
+    assert_eq!(
+        io::ErrorKind::NotFound,
+        connections.remove(1).unwrap_err().kind()
+    );
+    assert_eq!(None, connections.poll_timeout());
+}
@@ -0,0 +1,47 @@
+#![cfg(target_os = "linux")]
+
+use minimio::{TcpListener, TcpStream};
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+fn accept_from_either(listeners: &[TcpListener]) -> TcpStream {
+    for _ in 0..100 {
+        for listener in listeners {
+            match listener.accept() {
+                Ok((stream, _)) => return stream,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => panic!("accept failed: {}", e),
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("no connection was accepted");
+}
+
+#[test]
+fn reuseport_listeners_share_a_port_and_steer_by_cpu() {
+    let first = TcpListener::bind_reuseport("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr: SocketAddr = first.local_addr().unwrap();
+    let second = TcpListener::bind_reuseport(addr).unwrap();
+    first.attach_reuseport_cpu_steering(2).unwrap();
+    first.set_incoming_cpu(0).unwrap();
+
+    let mut client = std::net::TcpStream::connect(addr).unwrap();
+    client.write_all(b"ping").unwrap();
+    let stream = accept_from_either(&[first, second]);
+
+    // Give the ping time to arrive, which is when the kernel records the CPU
+    thread::sleep(Duration::from_millis(10));
+    assert!(stream.incoming_cpu().unwrap().is_some());
+}
+
+#[test]
+fn steering_needs_a_reuseport_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let err = listener.attach_reuseport_cpu_steering(2).unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, err.kind());
+    let err = listener.attach_reuseport_cpu_steering(0).unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, err.kind());
+}
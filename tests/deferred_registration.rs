@@ -0,0 +1,56 @@
+#![cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::Write;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn deferred_registration_applies_while_poll_is_blocked() {
+    let mut poll = Poll::new().unwrap();
+    let registrator = poll.registrator();
+    let (sender, receiver) = channel();
+
+    let handle = thread::spawn(move || {
+        let mut events = Events::with_capacity(16);
+        // Blocks without a timeout, so only our registration can wake it up
+        poll.poll(&mut events, None).expect("poll err.");
+        let tokens: Vec<_> = events.iter().map(|e| e.id()).collect();
+        sender.send(tokens).unwrap();
+    });
+
+    // Give the poll thread time to block
+    thread::sleep(Duration::from_millis(50));
+    let (a, mut b) = socket_pair().expect("socket pair err.");
+    registrator
+        .register_deferred(&a, 12, Interests::READABLE)
+        .expect("registration err.");
+    b.write_all(b"ping").expect("write err.");
+
+    let tokens = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("poll didn't return");
+    assert_eq!(vec![12], tokens);
+    handle.join().unwrap();
+}
+
+#[test]
+fn reserved_token_is_rejected() {
+    let poll = Poll::new().unwrap();
+    let registrator = poll.registrator();
+    let (a, _b) = socket_pair().expect("socket pair err.");
+    let err = registrator
+        .register(&a, usize::MAX, Interests::READABLE)
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+}
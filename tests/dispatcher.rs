@@ -0,0 +1,40 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Dispatcher, Interests};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[test]
+fn callbacks_run_for_their_token_until_they_break() {
+    let mut dispatcher = Dispatcher::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    register(dispatcher.registrator(), &mut a, 3, Interests::READABLE).unwrap();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let seen = received.clone();
+    dispatcher.insert(3, move |registrator, event| {
+        assert_eq!(3, event.id());
+        let mut buf = [0; 4];
+        a.read_exact(&mut buf).unwrap();
+        seen.borrow_mut().extend_from_slice(&buf);
+        if &buf == b"done" {
+            return ControlFlow::Break(());
+        }
+        register(registrator, &mut a, 3, Interests::READABLE).unwrap();
+        ControlFlow::Continue(())
+    });
+    assert_eq!(1, dispatcher.len());
+
+    b.write_all(b"ping").unwrap();
+    let timeout = Some(Duration::from_secs(1));
+    assert_eq!(1, dispatcher.poll_and_dispatch(timeout).unwrap());
+    assert_eq!(b"ping", &received.borrow()[..]);
+
+    b.write_all(b"done").unwrap();
+    assert_eq!(1, dispatcher.poll_and_dispatch(timeout).unwrap());
+    assert_eq!(b"pingdone", &received.borrow()[..]);
+    assert!(dispatcher.is_empty());
+    assert!(!dispatcher.remove(3));
+}
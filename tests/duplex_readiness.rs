@@ -0,0 +1,32 @@
+#![cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::Write;
+use std::time::Duration;
+
+#[test]
+fn a_readable_and_writable_source_gets_a_single_event() {
+    let mut poll = Poll::new().unwrap();
+    let (a, mut b) = socket_pair().unwrap();
+    b.write_all(b"ping").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+
+    poll.registrator()
+        .register(&a, 5, Interests::READABLE | Interests::WRITABLE)
+        .unwrap();
+    let mut events = Events::with_capacity(16);
+    poll.poll(&mut events, Some(1000)).unwrap();
+    assert_eq!(1, events.len());
+    assert_eq!(5, events[0].id());
+    assert!(events[0].is_readable());
+    assert!(events[0].is_writable());
+}
@@ -0,0 +1,60 @@
+#![cfg(feature = "futures")]
+
+use futures_core::Stream;
+use minimio::test_util::register;
+use minimio::{socket_pair, EventStream, Interests, Poll};
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{self, Context, Wake, Waker};
+use std::thread;
+use std::time::Duration;
+
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn event_stream_yields_events_and_ends_on_close() {
+    let mut stream = EventStream::new(Poll::new().unwrap());
+    let registrator = stream.registrator();
+
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(counter.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    // Nothing is registered so we're pending, and we ask to be polled again
+    assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+    assert_eq!(1, counter.0.load(Ordering::SeqCst));
+
+    let (mut a, mut b) = socket_pair().expect("socket pair err.");
+    register(&registrator, &mut a, 3, Interests::READABLE).expect("registration err.");
+    b.write_all(b"ping").expect("write err.");
+
+    let mut event = None;
+    for _ in 0..100 {
+        if let task::Poll::Ready(next) = Pin::new(&mut stream).poll_next(&mut cx) {
+            event = next;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(3, event.expect("stream ended early").id());
+
+    registrator.close_loop().expect("close loop err.");
+    let mut ended = false;
+    for _ in 0..100 {
+        if let task::Poll::Ready(next) = Pin::new(&mut stream).poll_next(&mut cx) {
+            ended = next.is_none();
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(ended, "stream didn't end after close_loop");
+    assert!(stream.take_error().is_some());
+}
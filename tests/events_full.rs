@@ -0,0 +1,30 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, EventsExt, Interests, Poll};
+use std::io::Write;
+
+#[test]
+fn events_report_when_filled_to_capacity() {
+    let mut poll = Poll::new().unwrap();
+    let registrator = poll.registrator();
+
+    let mut pairs = vec![];
+    for token in 0..3 {
+        let (mut a, mut b) = socket_pair().expect("socket pair err.");
+        register(&registrator, &mut a, token, Interests::READABLE).expect("registration err.");
+        b.write_all(b"ping").expect("write err.");
+        pairs.push((a, b));
+    }
+
+    let mut events = Events::with_capacity(2);
+    let capacity = events.capacity();
+    assert!(!events.is_full());
+
+    let mut received = 0;
+    while received < 3 {
+        let n = poll.poll(&mut events, Some(1000)).expect("poll err.");
+        assert!(n > 0, "timed out waiting for events");
+        assert_eq!(n == capacity, events.is_full());
+        received += n;
+    }
+    assert_eq!(3, received);
+}
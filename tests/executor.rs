@@ -0,0 +1,55 @@
+#![cfg(feature = "executor")]
+
+use minimio::executor::block_on;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn block_on_ready_future() {
+    assert_eq!(42, block_on(async { 42 }).unwrap());
+}
+
+/// Completes once another thread has set the value and called the waker
+struct Delayed {
+    shared: Arc<Mutex<(Option<u32>, Option<Waker>)>>,
+}
+
+impl Future for Delayed {
+    type Output = u32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u32> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.0 {
+            Some(value) => Poll::Ready(value),
+            None => {
+                shared.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[test]
+fn block_on_is_woken_from_another_thread() {
+    let shared = Arc::new(Mutex::new((None, None)));
+    let future = Delayed {
+        shared: shared.clone(),
+    };
+
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        let waker = {
+            let mut shared = shared.lock().unwrap();
+            shared.0 = Some(7);
+            shared.1.take()
+        };
+        waker.expect("future was polled").wake();
+    });
+
+    assert_eq!(7, block_on(future).unwrap());
+    handle.join().unwrap();
+}
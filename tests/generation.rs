@@ -0,0 +1,31 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, token_index, Events, Interests, Poll};
+use std::io::Write;
+use std::time::Duration;
+
+#[test]
+fn events_for_a_retired_token_are_dropped() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let (mut c, mut d) = socket_pair().unwrap();
+    let registry = poll.registry();
+
+    let old = registry.generational_token(3).unwrap();
+    register(&poll.registrator(), &mut a, old, Interests::READABLE).unwrap();
+    b.write_all(b"ping").unwrap();
+    registry.retire_token(old);
+    assert!(registry.is_stale(old));
+
+    let new = registry.generational_token(3).unwrap();
+    assert_ne!(old, new);
+    assert_eq!(3, token_index(new));
+    register(&poll.registrator(), &mut c, new, Interests::READABLE).unwrap();
+    d.write_all(b"ping").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+
+    let mut events = Events::with_capacity(16);
+    poll.poll(&mut events, Some(1000)).unwrap();
+    let tokens: Vec<_> = events.iter().map(|event| event.id()).collect();
+    assert_eq!(vec![new], tokens);
+    assert!(!poll.registry().is_stale(new));
+}
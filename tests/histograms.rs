@@ -0,0 +1,23 @@
+use minimio::{Events, Histogram, Poll, PollHistograms};
+
+#[test]
+fn every_poll_is_recorded_once_enabled() {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    poll.poll(&mut events, Some(0)).unwrap();
+    assert_eq!(None, poll.histograms());
+
+    poll.enable_histograms(PollHistograms::new(
+        Histogram::new(vec![1_000_000]),
+        Histogram::new(vec![0]),
+    ));
+    poll.poll(&mut events, Some(0)).unwrap();
+    poll.poll(&mut events, Some(20)).unwrap();
+
+    let histograms = poll.take_histograms().unwrap();
+    assert_eq!(2, histograms.select_micros.count());
+    assert!(histograms.select_micros.sum() >= 20_000);
+    let events_per_wakeup: Vec<_> = histograms.events_per_wakeup.buckets().collect();
+    assert_eq!(vec![(Some(0), 2), (None, 0)], events_per_wakeup);
+    assert_eq!(None, poll.histograms());
+}
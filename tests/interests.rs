@@ -0,0 +1,44 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Interests, Poll};
+use std::io;
+
+#[test]
+fn interests_combine_and_inspect() {
+    let both = Interests::READABLE | Interests::WRITABLE;
+    assert_eq!(Interests::all(), both);
+    assert_eq!(both, Interests::READABLE.add(Interests::WRITABLE));
+    assert!(both.contains(Interests::READABLE));
+    assert!(both.is_readable() && both.is_writable());
+
+    let read = both.remove(Interests::WRITABLE);
+    assert_eq!(Interests::READABLE, read);
+    assert!(!read.contains(Interests::WRITABLE));
+    assert!(read.remove(Interests::READABLE).is_empty());
+
+    assert_eq!("READABLE | WRITABLE", both.to_string());
+    assert_eq!("Interests(READABLE)", format!("{:?}", read));
+}
+
+#[test]
+fn register_without_interests_fails() {
+    let poll = Poll::new().unwrap();
+    let registrator = poll.registrator();
+    let (mut a, _b) = socket_pair().unwrap();
+
+    let none = Interests::READABLE.remove(Interests::READABLE);
+    let err = register(&registrator, &mut a, 1, none).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn interests_and_tokens_roundtrip_through_serde() {
+    let bookkeeping: Vec<(minimio::Token, Interests)> = vec![
+        (1, Interests::READABLE),
+        (2, Interests::READABLE | Interests::WRITABLE),
+    ];
+
+    let json = serde_json::to_string(&bookkeeping).unwrap();
+    let restored: Vec<(minimio::Token, Interests)> = serde_json::from_str(&json).unwrap();
+    assert_eq!(bookkeeping, restored);
+}
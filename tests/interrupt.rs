@@ -0,0 +1,53 @@
+#![cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+
+use minimio::{Events, Interrupt, InterruptKind, Poll};
+use std::time::Duration;
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+extern "C" {
+    fn raise(sig: i32) -> i32;
+}
+
+// The only test in this file, because a signal raised while no `Interrupt` exists
+// ends the process
+#[test]
+fn signals_are_delivered_to_every_interrupt() {
+    let mut first_poll = Poll::new().unwrap();
+    let mut second_poll = Poll::new().unwrap();
+    let first = Interrupt::new(&first_poll, 1).unwrap();
+    let second = Interrupt::new(&second_poll, 2).unwrap();
+    let mut events = Events::with_capacity(16);
+
+    assert_eq!(0, unsafe { raise(SIGINT) });
+    assert_eq!(0, unsafe { raise(SIGTERM) });
+    // Both signals are usually in by the first event, but don't have to be
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        first_poll
+            .poll_timeout(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(vec![1], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+        received.extend(first.reset().unwrap());
+    }
+    assert_eq!(
+        vec![InterruptKind::Interrupt, InterruptKind::Terminate],
+        received
+    );
+
+    second_poll
+        .poll_timeout(&mut events, Some(Duration::from_secs(1)))
+        .unwrap();
+    assert_eq!(vec![2], events.iter().map(|e| e.id()).collect::<Vec<_>>());
+    assert_eq!(InterruptKind::Interrupt, second.reset().unwrap()[0]);
+}
@@ -0,0 +1,28 @@
+#![cfg(target_os = "linux")]
+
+use minimio::{Events, Interests, Poll, TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::time::Duration;
+
+#[test]
+fn mptcp_streams_work_with_the_event_loop_with_or_without_kernel_support() {
+    let listener = TcpListener::bind_mptcp("127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut client = TcpStream::connect_mptcp(listener.local_addr().unwrap()).unwrap();
+    // Whichever it is, asking works
+    assert_eq!(listener.is_mptcp().unwrap(), client.is_mptcp().unwrap());
+
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(4);
+    poll.registrator()
+        .register(&listener, 0, Interests::READABLE)
+        .unwrap();
+    poll.poll_timeout(&mut events, Some(Duration::from_secs(1)))
+        .unwrap();
+    assert_eq!(0, events[0].id());
+
+    let (mut server, _) = listener.accept().unwrap();
+    client.write_all(b"subflow").unwrap();
+    let mut buf = [0; 7];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(b"subflow", &buf);
+}
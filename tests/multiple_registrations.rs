@@ -1,7 +1,8 @@
-use minimio::{Events, Interests, Poll, TcpStream};
+use minimio::{test_util, Events, Interests, Poll, TcpStream};
 use std::io::{self, Read, Write};
 use std::sync::mpsc::channel;
 use std::thread;
+use std::time::Duration;
 
 #[test]
 fn multiple_registraions() {
@@ -46,23 +47,15 @@ fn multiple_registraions() {
     });
 
     // ===== THIS IS "APPLICATION" CODE USING OUR INFRASTRUCTURE =====
-    let mut stream = TcpStream::connect("slowwly.robertomurray.co.uk:80").unwrap();
-    let request = "GET /delay/1000/url/http://www.google.com HTTP/1.1\r\n\
-                   Host: slowwly.robertomurray.co.uk\r\n\
-                   Connection: close\r\n\
-                   \r\n";
+    let addr = test_util::delayed_responder(Duration::from_millis(200));
+    let mut stream = TcpStream::connect(addr).unwrap();
     stream
-        .write_all(request.as_bytes())
+        .write_all(test_util::HTTP_REQUEST)
         .expect("Error writing to stream");
 
-    let mut stream2 = TcpStream::connect("slowwly.robertomurray.co.uk:80").unwrap();
-
-    let request2 = "GET /delay/1000/url/http://www.google.com HTTP/1.1\r\n\
-                    Host: slowwly.robertomurray.co.uk\r\n\
-                    Connection: close\r\n\
-                    \r\n";
+    let mut stream2 = TcpStream::connect(addr).unwrap();
     stream2
-        .write_all(request2.as_bytes())
+        .write_all(test_util::HTTP_REQUEST)
         .expect("Error writing to stream");
 
     // Mio does this
@@ -70,11 +63,9 @@ fn multiple_registraions() {
     // a reference to the buffer with our selector that which can fill it when data is ready
 
     // PROBLEM 2: We need to use registry here
-    registrator
-        .register(&mut stream, provided_token, Interests::READABLE)
+    test_util::register(&registrator, &mut stream, provided_token, Interests::READABLE)
         .expect("registration err.");
-    registrator
-        .register(&mut stream2, provided_token2, Interests::READABLE)
+    test_util::register(&registrator, &mut stream2, provided_token2, Interests::READABLE)
         .expect("registration err.");
     println!("HERE");
 
@@ -86,9 +77,8 @@ fn multiple_registraions() {
         println!("PROPOSED API:\n{}", buffer);
     });
 
-     rt.spawn(provided_token2, move || {
-        let mut buffer = String::new();
-        let mut buffer = [0u8; 2048* 2];
+    rt.spawn(provided_token2, move || {
+        let mut buffer = [0u8; 2048 * 2];
         while stream2.read(&mut buffer).unwrap() > 0 {}
         assert!(!buffer.is_empty(), "Got an empty buffer");
         println!("PROPOSED API2:\n{}", String::from_utf8(buffer.to_vec()).unwrap());
@@ -0,0 +1,47 @@
+// Fuchsia, AIX, Haiku and QNX selectors aren't fds, so they can't be registered like
+// one, and only IOCP can nest selectors on Windows
+#![cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto",
+    all(target_os = "windows", feature = "wsapoll")
+)))]
+
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::Write;
+
+#[test]
+fn a_nested_poll_reports_its_events_to_the_outer_one() {
+    let mut outer = Poll::new().unwrap();
+    let mut inner = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    register(&inner.registrator(), &mut a, 7, Interests::READABLE).unwrap();
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox"
+    ))]
+    outer
+        .registrator()
+        .register(&inner, 1, Interests::READABLE)
+        .unwrap();
+    #[cfg(target_os = "windows")]
+    outer.registrator().register_poll(&inner, 1).unwrap();
+
+    let mut events = Events::with_capacity(16);
+    outer.poll(&mut events, Some(100)).unwrap();
+    assert!(events.is_empty());
+
+    b.write_all(b"ping").unwrap();
+    outer.poll(&mut events, Some(1000)).unwrap();
+    assert_eq!(1, events.len());
+    assert_eq!(1, events[0].id());
+    assert!(events[0].is_readable());
+
+    inner.poll(&mut events, Some(0)).unwrap();
+    assert_eq!(1, events.len());
+    assert_eq!(7, events[0].id());
+}
@@ -0,0 +1,27 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::Write;
+
+#[test]
+fn polls_that_fill_the_buffer_are_counted_until_one_has_room() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let (mut c, mut d) = socket_pair().unwrap();
+    let registrator = poll.registrator();
+    register(&registrator, &mut a, 1, Interests::READABLE).unwrap();
+    register(&registrator, &mut c, 2, Interests::READABLE).unwrap();
+    assert_eq!(0, poll.registry().pending_hint());
+
+    b.write_all(b"ping").unwrap();
+    d.write_all(b"ping").unwrap();
+    // Give both a moment to arrive so the first poll can't return just one of them
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let mut events = Events::with_capacity(1);
+    poll.poll(&mut events, Some(1000)).unwrap();
+    assert_eq!(1, poll.registry().pending_hint());
+
+    let mut events = Events::with_capacity(16);
+    poll.poll(&mut events, Some(1000)).unwrap();
+    assert!(events.len() < 16);
+    assert_eq!(0, poll.registry().pending_hint());
+}
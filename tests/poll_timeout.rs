@@ -0,0 +1,20 @@
+use minimio::{Events, Poll};
+use std::time::{Duration, Instant};
+
+#[test]
+fn sub_millisecond_timeouts_are_waited_for_but_not_much_longer() {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    let timeout = Duration::from_micros(300);
+
+    let start = Instant::now();
+    assert_eq!(0, poll.poll_timeout(&mut events, Some(timeout)).unwrap());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= timeout, "returned after {:?}", elapsed);
+    // Generous, scheduling on a busy machine can take a while
+    assert!(
+        elapsed < Duration::from_millis(100),
+        "returned after {:?}",
+        elapsed
+    );
+}
@@ -1,5 +1,7 @@
-use minimio::{Events, Interests, Poll, Registrator, TcpStream};
+use minimio::prelude::*;
+use minimio::test_util;
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
 use std::{io, io::Read, io::Write, thread, thread::JoinHandle};
 
 const TEST_TOKEN: usize = 10; // Hard coded for this test only
@@ -10,21 +12,22 @@ fn proposed_api() {
     let mut reactor = Reactor::new(evt_sender);
     let mut executor = Excutor::new(evt_reciever);
 
-    let mut stream = TcpStream::connect("slowwly.robertomurray.co.uk:80").unwrap();
-    let request = b"GET /delay/1000/url/http://www.google.com HTTP/1.1\r\nHost: slowwly.robertomurray.co.uk\r\nConnection: close\r\n\r\n";
+    let addr = test_util::delayed_responder(Duration::from_millis(200));
+    let mut stream = TcpStream::connect(addr).unwrap();
 
-    stream.write_all(request).expect("Stream write err.");
+    stream
+        .write_all(test_util::HTTP_REQUEST)
+        .expect("Stream write err.");
 
     let registrator = reactor.registrator();
-    registrator
-        .register(&mut stream, TEST_TOKEN, Interests::READABLE)
+    test_util::register(&registrator, &mut stream, TEST_TOKEN, Interests::READABLE)
         .expect("registration err.");
 
     executor.suspend(TEST_TOKEN, move || {
         let mut buffer = String::new();
         stream.read_to_string(&mut buffer).unwrap();
         registrator.close_loop().expect("close loop err.");
-        assert!(!buffer.is_empty(), "Got an empty buffer");
+        assert!(buffer.ends_with("Hello"), "Got an unexpected response");
     });
 
     executor.block_on_all();
@@ -0,0 +1,51 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+#[test]
+fn events_over_the_limit_are_delivered_after_the_interval() {
+    let mut poll = Poll::new().unwrap();
+    let interval = Duration::from_millis(200);
+    let start = Instant::now();
+    poll.registry().rate_limit(1, 1, interval).unwrap();
+
+    let (mut a, mut b) = socket_pair().unwrap();
+    let (mut c, mut d) = socket_pair().unwrap();
+    let registrator = poll.registrator();
+    register(&registrator, &mut a, 1, Interests::READABLE).unwrap();
+    register(&registrator, &mut c, 1, Interests::READABLE).unwrap();
+    b.write_all(b"ping").unwrap();
+    d.write_all(b"ping").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+
+    let mut events = Events::with_capacity(16);
+    assert_eq!(1, poll.poll(&mut events, Some(1000)).unwrap());
+    assert_eq!(1, events[0].id());
+
+    // The poll wakes up for the held back event, not at its own timeout
+    assert_eq!(1, poll.poll(&mut events, Some(5000)).unwrap());
+    assert_eq!(1, events[0].id());
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= interval - Duration::from_millis(60),
+        "{:?}",
+        elapsed
+    );
+    assert!(elapsed < Duration::from_millis(2000), "{:?}", elapsed);
+}
+
+#[test]
+fn a_rate_limit_needs_events_and_an_interval() {
+    let poll = Poll::new().unwrap();
+    let err = poll
+        .registry()
+        .rate_limit(1, 0, Duration::from_secs(1))
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    let err = poll
+        .registry()
+        .rate_limit(1, 1, Duration::from_secs(0))
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "futures")]
+
+use minimio::test_util::tcp_pair;
+use minimio::Reactor;
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn readable_resolves_once_the_reactor_sees_the_stream_ready() {
+    let mut reactor = Reactor::new().unwrap();
+    let handle = reactor.handle();
+    // Windows hands the stream to IOCP, which takes `&mut` access
+    #[allow(unused_mut)]
+    let (mut a, mut b) = tcp_pair();
+
+    let woken = Arc::new(CountingWaker::default());
+    let waker = Waker::from(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut readable = Box::pin(a.readable(&handle));
+    assert!(readable.as_mut().poll(&mut cx).is_pending());
+
+    assert_eq!(0, reactor.turn(Some(Duration::from_millis(50))).unwrap());
+    assert!(readable.as_mut().poll(&mut cx).is_pending());
+
+    b.write_all(b"ping").unwrap();
+    assert_eq!(1, reactor.turn(Some(Duration::from_secs(1))).unwrap());
+    assert_eq!(1, woken.0.load(Ordering::SeqCst));
+    match readable.as_mut().poll(&mut cx) {
+        Poll::Ready(res) => res.unwrap(),
+        Poll::Pending => panic!("still pending after the reactor saw the stream ready"),
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+#[test]
+fn writable_resolves_for_a_connected_stream() {
+    let mut reactor = Reactor::new().unwrap();
+    let handle = reactor.handle();
+    let (a, _b) = tcp_pair();
+
+    let waker = Waker::from(Arc::new(CountingWaker::default()));
+    let mut cx = Context::from_waker(&waker);
+    let mut writable = Box::pin(a.writable(&handle));
+    assert!(writable.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(1, reactor.turn(Some(Duration::from_secs(1))).unwrap());
+    assert!(writable.as_mut().poll(&mut cx).is_ready());
+}
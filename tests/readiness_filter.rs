@@ -0,0 +1,45 @@
+// On Windows the read has completed into the stream's buffer by the time we get the
+// event, so registering again doesn't report the same data twice to begin with
+#![cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix",
+    target_os = "haiku",
+    target_os = "nto"
+))]
+
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::{Read, Write};
+
+#[test]
+fn unchanged_readiness_is_only_delivered_again_once_consumed() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+    let registrator = poll.registrator();
+
+    poll.registry().suppress_unchanged(1);
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    b.write_all(b"ping").unwrap();
+    assert_eq!(1, poll.poll(&mut events, Some(1000)).unwrap());
+    assert_eq!(1, events[0].id());
+
+    // Nothing was read, so the source is as readable as it was
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    assert_eq!(0, poll.poll(&mut events, Some(100)).unwrap());
+
+    let mut buf = [0; 4];
+    a.read_exact(&mut buf).unwrap();
+    poll.registry().readiness_consumed(1);
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    b.write_all(b"pong").unwrap();
+    assert_eq!(1, poll.poll(&mut events, Some(1000)).unwrap());
+
+    // Without the filter every event comes through
+    poll.registry().remove_filter(1);
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    assert_eq!(1, poll.poll(&mut events, Some(1000)).unwrap());
+}
@@ -0,0 +1,22 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::Write;
+use std::time::Instant;
+
+#[test]
+fn received_at_is_when_the_last_poll_returned() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+    assert_eq!(None, poll.received_at());
+
+    register(&poll.registrator(), &mut a, 1, Interests::READABLE).unwrap();
+    b.write_all(b"ping").unwrap();
+    let before = Instant::now();
+    poll.poll(&mut events, Some(1000)).unwrap();
+    let after = Instant::now();
+
+    let received_at = poll.received_at().unwrap();
+    assert!(before <= received_at && received_at <= after);
+    assert!(received_at.elapsed() >= after.elapsed());
+}
@@ -0,0 +1,57 @@
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Wake, Waker};
+
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn a_ready_source_wakes_its_waker_instead_of_returning_an_event() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let woken = Arc::new(CountingWaker::default());
+    let token = poll
+        .registry()
+        .register_waker(&mut a, Interests::READABLE, Waker::from(woken.clone()))
+        .unwrap();
+
+    let mut events = Events::with_capacity(16);
+    b.write_all(b"ping").unwrap();
+    assert_eq!(0, poll.poll(&mut events, Some(1000)).unwrap());
+    assert_eq!(1, woken.0.load(Ordering::SeqCst));
+
+    let mut buf = [0; 4];
+    a.read_exact(&mut buf).unwrap();
+    let rewoken = Arc::new(CountingWaker::default());
+    poll.registry()
+        .reregister_waker(
+            &mut a,
+            token,
+            Interests::READABLE,
+            Waker::from(rewoken.clone()),
+        )
+        .unwrap();
+    b.write_all(b"pong").unwrap();
+    assert_eq!(0, poll.poll(&mut events, Some(1000)).unwrap());
+    assert_eq!(1, woken.0.load(Ordering::SeqCst));
+    assert_eq!(1, rewoken.0.load(Ordering::SeqCst));
+
+    assert!(poll.registry().remove_waker(token).is_some());
+    let err = poll
+        .registry()
+        .reregister_waker(&mut a, token, Interests::READABLE, Waker::from(woken))
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::NotFound, err.kind());
+}
@@ -0,0 +1,41 @@
+#![cfg(all(
+    feature = "debug",
+    any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "dragonfly",
+        target_os = "redox",
+        target_os = "fuchsia",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto"
+    )
+))]
+
+use minimio::{socket_pair, Interests, Poll};
+use std::os::unix::io::AsRawFd;
+
+#[test]
+fn registrations_lists_what_has_not_been_deregistered() {
+    let poll = Poll::new().unwrap();
+    let (a, _b) = socket_pair().unwrap();
+    let (c, _d) = socket_pair().unwrap();
+    let registrator = poll.registrator();
+
+    registrator.register(&a, 1, Interests::READABLE).unwrap();
+    registrator.register(&c, 2, Interests::all()).unwrap();
+    registrator.register(&a, 3, Interests::WRITABLE).unwrap();
+    let registrations: Vec<_> = poll.registry().registrations().collect();
+    assert_eq!(
+        vec![
+            (2, Interests::all(), c.as_raw_fd()),
+            (3, Interests::WRITABLE, a.as_raw_fd())
+        ],
+        registrations
+    );
+
+    registrator.deregister(&c).unwrap();
+    assert_eq!(1, poll.registry().registrations().count());
+    poll.registry().clear().unwrap();
+    assert_eq!(0, poll.registry().registrations().count());
+}
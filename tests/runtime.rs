@@ -0,0 +1,201 @@
+#![cfg(feature = "runtime")]
+
+use minimio::runtime::{Context, Handler, Runtime, FIRST_TOKEN};
+use minimio::{test_util, Event, Interests, TcpStream, Token};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Answers every request with the index of the loop that handled it
+struct Responder {
+    index: usize,
+    streams: HashMap<Token, TcpStream>,
+    next_token: Token,
+    messages: mpsc::Sender<(usize, &'static str)>,
+}
+
+impl Handler for Responder {
+    type Message = &'static str;
+
+    fn accepted(&mut self, ctx: &Context<Self::Message>, mut stream: TcpStream) {
+        let token = self.next_token;
+        self.next_token += 1;
+        ctx.register(&mut stream, token, Interests::READABLE)
+            .unwrap();
+        self.streams.insert(token, stream);
+    }
+
+    fn ready(&mut self, _ctx: &Context<Self::Message>, event: &Event) {
+        let stream = self.streams.get_mut(&event.id()).unwrap();
+        let mut buf = [0; 64];
+        if let Ok(n) = stream.read(&mut buf) {
+            if n > 0 {
+                stream.write_all(self.index.to_string().as_bytes()).unwrap();
+            }
+        }
+    }
+
+    fn message(&mut self, _ctx: &Context<Self::Message>, msg: Self::Message) {
+        self.messages.send((self.index, msg)).unwrap();
+    }
+
+    fn live_tokens(&self) -> Vec<Token> {
+        self.streams.keys().copied().collect()
+    }
+
+    fn shutdown(&mut self, _ctx: &Context<Self::Message>, token: Token) {
+        // Dropping the stream closes the connection
+        let mut stream = self.streams.remove(&token).unwrap();
+        stream.write_all(b"bye").unwrap();
+    }
+}
+
+/// Never closes its connections
+struct Stubborn(Vec<TcpStream>);
+
+impl Handler for Stubborn {
+    type Message = ();
+
+    fn accepted(&mut self, _ctx: &Context<Self::Message>, stream: TcpStream) {
+        self.0.push(stream);
+    }
+
+    fn ready(&mut self, _ctx: &Context<Self::Message>, _event: &Event) {}
+
+    fn live_tokens(&self) -> Vec<Token> {
+        (FIRST_TOKEN..FIRST_TOKEN + self.0.len()).collect()
+    }
+}
+
+/// Tries to register a new connection and its live one again when it's told to shut
+/// down, and reports whether that worked
+struct Registering {
+    stream: Option<TcpStream>,
+    results: mpsc::Sender<(bool, bool)>,
+}
+
+impl Handler for Registering {
+    type Message = ();
+
+    fn accepted(&mut self, ctx: &Context<Self::Message>, mut stream: TcpStream) {
+        ctx.register(&mut stream, FIRST_TOKEN, Interests::READABLE)
+            .unwrap();
+        self.stream = Some(stream);
+    }
+
+    fn ready(&mut self, _ctx: &Context<Self::Message>, _event: &Event) {}
+
+    fn live_tokens(&self) -> Vec<Token> {
+        self.stream.iter().map(|_| FIRST_TOKEN).collect()
+    }
+
+    fn shutdown(&mut self, ctx: &Context<Self::Message>, token: Token) {
+        let (mut other, _peer) = test_util::tcp_pair();
+        let new = ctx.register(&mut other, token + 1, Interests::READABLE);
+        let mut stream = self.stream.take().unwrap();
+        let live = ctx.register(&mut stream, token, Interests::READABLE);
+        self.results.send((new.is_ok(), live.is_ok())).unwrap();
+    }
+}
+
+#[test]
+fn connections_are_spread_across_loops() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (messages, received) = mpsc::channel();
+    let runtime = Runtime::start(listener, 2, move |index| Responder {
+        index,
+        streams: HashMap::new(),
+        next_token: FIRST_TOKEN,
+        messages: messages.clone(),
+    })
+    .expect("runtime err.");
+
+    let mut served_by = vec![];
+    for _ in 0..4 {
+        let mut client = net::TcpStream::connect(runtime.local_addr()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut index = [0; 1];
+        client.read_exact(&mut index).unwrap();
+        served_by.push(index[0]);
+    }
+    assert_eq!(b"0101".to_vec(), served_by);
+
+    let handle = runtime.handle();
+    handle.broadcast("hi").unwrap();
+    handle.send(1, "just you").unwrap();
+    let mut got: Vec<_> = (0..3)
+        .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    got.sort();
+    assert_eq!(vec![(0, "hi"), (1, "hi"), (1, "just you")], got);
+
+    runtime.shutdown().unwrap();
+    assert!(handle.send(0, "too late").is_err());
+}
+
+#[test]
+fn drain_lets_handlers_close_their_connections() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (messages, _received) = mpsc::channel();
+    let runtime = Runtime::start(listener, 1, move |index| Responder {
+        index,
+        streams: HashMap::new(),
+        next_token: FIRST_TOKEN,
+        messages: messages.clone(),
+    })
+    .expect("runtime err.");
+
+    let mut client = net::TcpStream::connect(runtime.local_addr()).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.write_all(b"hello").unwrap();
+    let mut index = [0; 1];
+    client.read_exact(&mut index).unwrap();
+
+    runtime.drain(Duration::from_secs(5)).unwrap();
+    let mut goodbye = String::new();
+    client.read_to_string(&mut goodbye).unwrap();
+    assert_eq!("bye", goodbye);
+}
+
+#[test]
+fn drain_gives_up_after_the_timeout() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let runtime = Runtime::start(listener, 1, |_| Stubborn(vec![])).expect("runtime err.");
+
+    let _client = net::TcpStream::connect(runtime.local_addr()).unwrap();
+    // Give the loop a moment to get the connection
+    std::thread::sleep(Duration::from_millis(50));
+
+    let started = std::time::Instant::now();
+    runtime.drain(Duration::from_millis(100)).unwrap();
+    let took = started.elapsed();
+    assert!(took >= Duration::from_millis(100), "took {:?}", took);
+    assert!(took < Duration::from_secs(5), "took {:?}", took);
+}
+
+#[test]
+fn only_live_tokens_can_be_registered_while_draining() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (results, received) = mpsc::channel();
+    let runtime = Runtime::start(listener, 1, move |_| Registering {
+        stream: None,
+        results: results.clone(),
+    })
+    .expect("runtime err.");
+
+    let _client = net::TcpStream::connect(runtime.local_addr()).unwrap();
+    // Give the loop a moment to get the connection
+    std::thread::sleep(Duration::from_millis(50));
+
+    runtime.drain(Duration::from_secs(5)).unwrap();
+    let (new, live) = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(!new);
+    assert!(live);
+}
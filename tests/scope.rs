@@ -0,0 +1,38 @@
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::{Read, Write};
+
+#[test]
+fn registrations_end_with_the_scope() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+
+    let received = poll
+        .scope(|scope, poll| {
+            let a = scope.register(&mut a, 1, Interests::READABLE).unwrap();
+            b.write_all(b"ping").unwrap();
+            poll.poll(&mut events, Some(1000)).unwrap();
+            assert_eq!(1, events.len());
+            assert_eq!(1, events[0].id());
+
+            let mut buf = [0; 4];
+            a.read_exact(&mut buf).unwrap();
+            // Registered again, and still registered when the scope ends
+            scope
+                .registrator()
+                .register(a, 1, Interests::READABLE)
+                .unwrap();
+            buf
+        })
+        .expect("scope err.");
+    assert_eq!(b"ping", &received);
+
+    // Nothing is registered anymore, so this doesn't wake us up
+    b.write_all(b"pong").unwrap();
+    poll.poll(&mut events, Some(100)).unwrap();
+    assert!(events.is_empty());
+
+    let mut buf = [0; 4];
+    a.read_exact(&mut buf).unwrap();
+    assert_eq!(b"pong", &buf);
+}
@@ -0,0 +1,16 @@
+use minimio::{Events, Selector};
+
+// Every backend selects through a shared reference, so code driving a `Selector`
+// directly looks the same everywhere
+fn select_once(selector: &Selector, events: &mut Events) -> usize {
+    selector.select(events, Some(10)).unwrap();
+    events.len()
+}
+
+#[test]
+fn select_only_needs_a_shared_reference() {
+    let selector = Selector::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    assert_eq!(0, select_once(&selector, &mut events));
+    assert_eq!(0, select_once(&selector, &mut events));
+}
@@ -0,0 +1,42 @@
+#![cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+
+use minimio::{socket_pair, Events, Interests, Poll};
+
+#[test]
+fn writable_interest_reports_writable_events() {
+    let mut poll = Poll::new().unwrap();
+    let (a, _b) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+
+    poll.registrator()
+        .register(&a, 3, Interests::WRITABLE)
+        .unwrap();
+    poll.poll(&mut events, Some(1000)).expect("poll err.");
+    assert_eq!(1, events.len());
+    assert_eq!(3, events[0].id());
+    assert!(events[0].is_writable());
+    assert!(!events[0].is_readable());
+}
+
+#[test]
+fn send_lowat_needs_writable_interest() {
+    let poll = Poll::new().unwrap();
+    let (a, _b) = socket_pair().unwrap();
+    let err = poll
+        .registrator()
+        .register_with_send_lowat(&a, 3, Interests::READABLE, 1024)
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn send_lowat_is_unsupported_on_linux() {
+    let poll = Poll::new().unwrap();
+    let (a, _b) = socket_pair().unwrap();
+    let err = poll
+        .registrator()
+        .register_with_send_lowat(&a, 3, Interests::WRITABLE, 1024)
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::Unsupported, err.kind());
+}
@@ -0,0 +1,50 @@
+#![cfg(any(target_os = "linux", target_os = "macos", target_os = "dragonfly"))]
+
+use minimio::{Events, Interests, Poll, UnixSeqpacket, UnixSeqpacketListener};
+use std::io::ErrorKind;
+
+#[test]
+fn messages_keep_their_boundaries() {
+    let (a, b) = UnixSeqpacket::pair().unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(ErrorKind::WouldBlock, b.recv(&mut buf).unwrap_err().kind());
+
+    a.send(b"first").unwrap();
+    a.send(b"second").unwrap();
+    let n = b.recv(&mut buf).unwrap();
+    assert_eq!(b"first", &buf[..n]);
+    let n = b.recv(&mut buf).unwrap();
+    assert_eq!(b"second", &buf[..n]);
+}
+
+#[test]
+fn listener_and_connection_work_with_poll() {
+    let dir = std::env::temp_dir().join(format!("minimio-seqpacket-{}", std::process::id()));
+    let _ = std::fs::remove_file(&dir);
+    let listener = UnixSeqpacketListener::bind(&dir).unwrap();
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    poll.registrator()
+        .register(&listener, 1, Interests::READABLE)
+        .unwrap();
+
+    let client = UnixSeqpacket::connect(&dir).unwrap();
+    poll.poll(&mut events, Some(1000)).unwrap();
+    assert_eq!(1, events[0].id());
+    let server = listener.accept().unwrap();
+
+    poll.registrator()
+        .register(&server, 2, Interests::READABLE)
+        .unwrap();
+    client.send(b"hello").unwrap();
+    poll.poll(&mut events, Some(1000)).unwrap();
+    assert_eq!(2, events[0].id());
+    let mut buf = [0; 3];
+    // The rest of a message that doesn't fit is dropped
+    assert_eq!(3, server.recv(&mut buf).unwrap());
+    assert_eq!(b"hel", &buf);
+
+    drop(client);
+    assert_eq!(0, server.recv(&mut buf).unwrap());
+    std::fs::remove_file(&dir).unwrap();
+}
@@ -0,0 +1,23 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::{Read, Write};
+
+#[test]
+fn socket_pair_is_connected_and_registerable() {
+    let mut poll = Poll::new().unwrap();
+    let registrator = poll.registrator();
+
+    let (mut a, mut b) = socket_pair().expect("socket pair err.");
+    register(&registrator, &mut a, 7, Interests::READABLE).expect("registration err.");
+
+    b.write_all(b"ping").expect("write err.");
+
+    let mut events = Events::with_capacity(16);
+    poll.poll(&mut events, Some(1000)).expect("poll err.");
+    assert_eq!(1, events.len());
+    assert_eq!(7, events[0].id());
+
+    let mut buffer = [0u8; 4];
+    a.read_exact(&mut buffer).expect("read err.");
+    assert_eq!(b"ping", &buffer);
+}
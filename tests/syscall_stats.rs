@@ -0,0 +1,52 @@
+use minimio::test_util::register;
+use minimio::{socket_pair, Events, Interests, Poll, SyscallStats};
+
+#[test]
+fn registering_and_polling_are_counted() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, _b) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+    assert_eq!(SyscallStats::default(), poll.registry().syscall_stats());
+
+    register(&poll.registrator(), &mut a, 1, Interests::READABLE).unwrap();
+    poll.poll(&mut events, Some(0)).unwrap();
+    poll.poll(&mut events, Some(0)).unwrap();
+
+    let stats = poll.registry().syscall_stats();
+    assert_eq!(2, stats.waits);
+    // `select` keeps nothing in the kernel to change
+    if cfg!(any(target_os = "haiku", target_os = "nto")) {
+        assert_eq!(0, stats.changes);
+    } else {
+        assert_eq!(1, stats.changes);
+    }
+    assert_eq!(0, stats.wakeups);
+    if cfg!(all(target_os = "windows", not(feature = "wsapoll"))) {
+        assert_eq!(1, stats.submissions);
+    } else {
+        assert_eq!(0, stats.submissions);
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "redox",
+    target_os = "fuchsia",
+    target_os = "aix"
+))]
+#[test]
+fn deferred_registrations_wake_the_poll_up_once_each() {
+    let mut poll = Poll::new().unwrap();
+    let (a, _b) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+
+    poll.registrator()
+        .register_deferred(&a, 1, Interests::WRITABLE)
+        .unwrap();
+    poll.poll(&mut events, Some(1000)).unwrap();
+    let stats = poll.registry().syscall_stats();
+    assert_eq!(1, stats.wakeups);
+    assert_eq!(1, stats.changes);
+}
@@ -0,0 +1,47 @@
+use minimio::{socket_pair, Events, Interests, Poll};
+use std::io::{Read, Write};
+
+#[derive(Debug, PartialEq)]
+struct Connection {
+    name: &'static str,
+    received: usize,
+}
+
+#[test]
+fn events_lead_back_to_the_data_of_their_registration() {
+    let mut poll = Poll::new().unwrap();
+    let (mut a, mut b) = socket_pair().unwrap();
+    let mut events = Events::with_capacity(16);
+
+    let connection = Connection {
+        name: "a",
+        received: 0,
+    };
+    let token = poll
+        .registry()
+        .register_with_data(&mut a, Interests::READABLE, connection)
+        .unwrap();
+    b.write_all(b"ping").unwrap();
+    poll.poll(&mut events, Some(1000)).unwrap();
+    assert_eq!(token, events[0].id());
+
+    let mut buf = [0; 4];
+    let n = a.read(&mut buf).unwrap();
+    let connection = poll.data_mut::<Connection>(events[0].id()).unwrap();
+    assert_eq!("a", connection.name);
+    connection.received += n;
+    assert!(poll.data_mut::<String>(token).is_none());
+
+    let received = poll
+        .registry()
+        .with_data(token, |c: &mut Connection| c.received);
+    assert_eq!(Some(4), received);
+    assert_eq!(
+        Some(Connection {
+            name: "a",
+            received: 4
+        }),
+        poll.registry().remove_data(token)
+    );
+    assert!(poll.data_mut::<Connection>(token).is_none());
+}
@@ -0,0 +1,85 @@
+use minimio::{Events, Poll, Waker};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+#[test]
+fn waker_wakes_poll_and_delivers_payloads() {
+    let mut poll = Poll::new().unwrap();
+    let waker = Waker::new(&poll, 9).expect("waker err.");
+    let mut events = Events::with_capacity(16);
+
+    let remote = waker.clone();
+    thread::spawn(move || {
+        remote.wake_with(1).unwrap();
+        remote.wake_with(2).unwrap();
+    })
+    .join()
+    .unwrap();
+
+    poll.poll(&mut events, Some(1000)).expect("poll err.");
+    assert_eq!(1, events.len());
+    assert_eq!(9, events[0].id());
+    assert_eq!(vec![1, 2], waker.reset().unwrap());
+
+    // Nothing happened since the reset
+    poll.poll(&mut events, Some(0)).expect("poll err.");
+    assert!(events.is_empty());
+
+    waker.wake().unwrap();
+    poll.poll(&mut events, Some(1000)).expect("poll err.");
+    assert_eq!(1, events.len());
+    assert!(waker.reset().unwrap().is_empty());
+}
+
+#[test]
+fn wakers_with_different_tokens_are_independent() {
+    let mut poll = Poll::new().unwrap();
+    let shutdown = Waker::new(&poll, 1).expect("waker err.");
+    let jobs = Waker::new(&poll, 2).expect("waker err.");
+    let mut events = Events::with_capacity(16);
+
+    jobs.wake_with(42).unwrap();
+    poll.poll(&mut events, Some(1000)).expect("poll err.");
+    let tokens: Vec<_> = events.iter().map(|e| e.id()).collect();
+    assert_eq!(vec![2], tokens);
+    assert_eq!(vec![42], jobs.reset().unwrap());
+
+    shutdown.wake().unwrap();
+    jobs.wake_with(7).unwrap();
+    let mut tokens = vec![];
+    while tokens.len() < 2 {
+        poll.poll(&mut events, Some(1000)).expect("poll err.");
+        assert!(!events.is_empty(), "timed out waiting for wakers");
+        tokens.extend(events.iter().map(|e| e.id()));
+    }
+    tokens.sort();
+    assert_eq!(vec![1, 2], tokens);
+    assert!(shutdown.reset().unwrap().is_empty());
+    assert_eq!(vec![7], jobs.reset().unwrap());
+}
+
+#[test]
+fn wakeups_racing_reset_are_never_lost() {
+    let mut poll = Poll::new().unwrap();
+    let waker = Waker::new(&poll, 3).expect("waker err.");
+    let mut events = Events::with_capacity(16);
+
+    // Waking nonstop lands some wakeups in the middle of a `reset`
+    let done = Arc::new(AtomicBool::new(false));
+    let remote = waker.clone();
+    let stop = done.clone();
+    let handle = thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            remote.wake().unwrap();
+        }
+    });
+
+    for round in 0..5_000 {
+        poll.poll(&mut events, Some(1000)).expect("poll err.");
+        assert!(!events.is_empty(), "lost a wakeup in round {}", round);
+        waker.reset().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}